@@ -0,0 +1,158 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::backend::{self, Backend, Kind};
+use crate::compare::SizeMtime;
+use crate::engine;
+use crate::fat;
+use crate::log;
+use crate::manifest::{self, Entry, Manifest};
+use crate::unicode;
+use crate::{check_marker, finish, lock_destination, Options};
+
+
+
+/// Number of the entries of a mirror (below `path`, whose path relative to
+/// the root is `relative`) that aren't in the manifest of its source
+fn count_extra(target: &dyn Backend, path: &str, relative: &str, manifest: &Manifest) -> u64 {
+    let Ok(names) = target.list(path) else {
+        return 0;
+    };
+    let mut extra = 0;
+    for name in names {
+        if relative == "." && manifest::is_meta(&name) {
+            continue;
+        }
+        let child = format!("{}/{}", path, name);
+        let child_relative = match relative {
+            "." => name,
+            _ => format!("{}/{}", relative, name),
+        };
+        match manifest.entries.get(&child_relative) {
+            None => extra += 1,
+            Some(Entry::Directory { .. }) if target.metadata(&child).is_some_and(|m| m.kind == Kind::Directory) => {
+                extra += count_extra(target, &child, &child_relative, manifest)
+            }
+            Some(_) => (),
+        }
+    }
+    extra
+}
+
+
+/// Whether the copy of an entry of the source in the mirror is up to date,
+/// as a run would tell
+fn up_to_date(source: &str, destination: &str, entry: &Entry, opts: &Options, target: &dyn Backend) -> bool {
+    let Some(copy) = target.metadata(destination) else {
+        return false;
+    };
+    match entry {
+        Entry::Directory { .. } => copy.kind == Kind::Directory,
+        Entry::Symlink { target: link } if opts.symlinks.preserves() => {
+            copy.kind == Kind::Symlink && copy.target == Some(engine::stored_link(PathBuf::from(link), source, opts))
+        }
+        // Stored in some other way by backup-rs
+        Entry::Symlink { .. } => false,
+        Entry::File { .. } => {
+            let Ok(metadata) = fs::metadata(source) else {
+                return false;
+            };
+            let comparator = opts.comparator.as_deref().unwrap_or(&SizeMtime);
+            copy.kind == Kind::File
+                && opts.policy.transform(source).is_none()
+                && !comparator.changed(source, &metadata, destination, &copy, opts, target)
+        }
+    }
+}
+
+
+/// Take over a mirror of the source made by another tool (e.g. rsync, or
+/// restored from restic): mark it as a backup-rs destination and write
+/// the manifest of the source, without copying anything. The copies that
+/// aren't up to date (as `--compare` tells) are left out of the manifest,
+/// so that the next run (with `--manifest`) copies them again, and the
+/// entries that aren't in the source are only counted, the next run
+/// removing them.
+pub fn adopt(source: &str, destination: &str, opts: &Options) -> bool {
+    log::separator();
+    info!("Adopting {} as the mirror of {}", destination, source);
+    log::separator();
+    if opts.encrypt || opts.compress.is_some() {
+        error!("mirrors made by other tools can't be adopted with --encrypt or --compress");
+        return false;
+    }
+    let location = destination;
+    let (target, destination) = match backend::open(destination, opts) {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    let target = target.as_ref();
+    let destination = destination.as_str();
+    if target.metadata(destination).map(|m| m.kind) != Some(Kind::Directory) {
+        error!("{} isn't a directory", destination);
+        return false;
+    }
+    if target.metadata(&format!("{}/{}", destination, manifest::MARKER)).is_some() {
+        error!("{} is a backup-rs destination already", destination);
+        return false;
+    }
+    let _lock = match lock_destination(location, destination, opts, target) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    info!("Scanning {}...", source);
+    let mut opts = opts.clone();
+    opts.policy.root = source.to_string();
+    if target.is_local() {
+        fat::detect(destination, &mut opts);
+    }
+    let mut scan = Manifest::scan(source, opts.scan_jobs, opts.one_file_system);
+    let mut stale = Vec::new();
+    for (relative, entry) in &scan.entries {
+        let (source, copy) = match relative.as_str() {
+            "." => continue,
+            relative => {
+                let copy = format!("{}/{}", destination, unicode::normalize(relative, opts.normalize));
+                (format!("{}/{}", source, relative), copy)
+            }
+        };
+        if !opts.policy.is_excluded(&source) && !up_to_date(&source, &copy, entry, &opts, target) {
+            stale.push(relative.clone());
+        }
+    }
+    let extra = count_extra(target, destination, ".", &scan);
+    for relative in &stale {
+        scan.forget(relative);
+    }
+    let files = scan.files();
+    info!("{} files of {} are up to date in {}", files, source, destination);
+    if !stale.is_empty() {
+        info!("{} entries are missing or out of date, and will be copied by the next run", stale.len());
+    }
+    if extra > 0 {
+        warning!("{} entries of {} aren't in {}, and will be removed by the next run", extra, destination, source);
+    }
+    if opts.dry_run {
+        return true;
+    }
+    let mut force = opts.clone();
+    force.force = true;
+    if !check_marker(destination, &force, target) {
+        return false;
+    }
+    scan.note_transforms(&opts.policy);
+    let saved = scan.save(target, destination);
+    finish(target, destination);
+    if let Err(e) = saved {
+        error!("cannot write {}: {}", manifest::path(destination), e);
+        return false;
+    }
+    info!("Adopted {}; back it up with --manifest from now on", destination);
+    true
+}
@@ -0,0 +1,90 @@
+use std::io;
+use std::path::Path;
+
+use crate::backend::{self, Backend, LocalBackend, Metadata};
+
+
+
+/// Seconds apart timestamps can be and still be taken as equal: the shared
+/// storage of Android devices (and their SD cards, often FAT or exFAT) only
+/// keeps timestamps to within 2 seconds
+pub const MODIFY_WINDOW: u64 = 2;
+
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+
+/// A local destination on the shared storage of an Android device
+/// (/sdcard, /storage/...), as reached from Termux. That storage is an
+/// emulated filesystem that holds no symlinks, refuses to change
+/// permissions and doesn't let apps set timestamps reliably, so copies only
+/// write the contents and large files are copied again rather than updated
+/// in place.
+pub struct AndroidBackend {
+    local: LocalBackend,
+}
+
+
+impl AndroidBackend {
+    pub fn new() -> AndroidBackend {
+        AndroidBackend { local: LocalBackend }
+    }
+}
+
+
+impl Backend for AndroidBackend {
+    fn metadata(&self, path: &str) -> Option<Metadata> {
+        self.local.metadata(path)
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        self.local.list(path)
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.local.create_dir(path)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        self.local.remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        self.local.remove_dir_all(path)
+    }
+
+    fn copy_file(&self, source: &str, path: &str) -> io::Result<()> {
+        backend::copy_contents(source, path, BUFFER_SIZE)
+    }
+
+    fn symlink(&self, _target: &Path, _path: &str) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "the shared storage of Android can't hold symlinks"))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.local.rename(from, to)
+    }
+
+    fn sync(&self, path: &str) -> io::Result<()> {
+        self.local.sync(path)
+    }
+
+    fn fetch(&self, path: &str, local: &str) -> io::Result<()> {
+        backend::copy_contents(path, local, BUFFER_SIZE)
+    }
+
+    fn local_file(&self, path: &str) -> Option<String> {
+        Some(path.to_string())
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.local.read(path)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.local.write(path, data)
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
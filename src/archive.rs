@@ -0,0 +1,323 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::process::{Child, Stdio};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+use crate::backend::{Backend, Kind, Metadata};
+use crate::compressed::Compression;
+use crate::engine::backup;
+use crate::log;
+use crate::preconditions;
+use crate::summary::Summary;
+use crate::tar::{EntryType, Header, TarWriter};
+use crate::temporary;
+use crate::throttle::{self, Throttled};
+use crate::zip::ZipWriter;
+use crate::Options;
+
+
+
+/// Root of the tree the engine writes to, the paths of the archive being
+/// relative to it
+const ROOT: &str = ".";
+
+
+/// Kind of archive a backup can be written as
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Tar,
+    Zip,
+}
+
+
+/// Writer of the entries of an archive
+trait Writer {
+    fn append(&mut self, header: &Header, data: &mut dyn Read) -> io::Result<()>;
+
+    /// Write what ends the archive, and flush it
+    fn end(self: Box<Self>) -> io::Result<()>;
+}
+
+
+impl<W: Write> Writer for TarWriter<W> {
+    fn append(&mut self, header: &Header, data: &mut dyn Read) -> io::Result<()> {
+        TarWriter::append(self, header, data)
+    }
+
+    fn end(self: Box<Self>) -> io::Result<()> {
+        self.finish().map(|_| ())
+    }
+}
+
+
+impl Writer for ZipWriter {
+    fn append(&mut self, header: &Header, data: &mut dyn Read) -> io::Result<()> {
+        ZipWriter::append(self, header, data)
+    }
+
+    fn end(self: Box<Self>) -> io::Result<()> {
+        self.finish()?.sync_all()
+    }
+}
+
+
+/// Compressor implied by the name of an archive
+fn compression(output: &str) -> Option<Compression> {
+    if output.ends_with(".tar.gz") || output.ends_with(".tgz") {
+        Some(Compression::Gzip(6))
+    } else if output.ends_with(".tar.zst") || output.ends_with(".tzst") {
+        Some(Compression::Zstd(3))
+    } else {
+        None
+    }
+}
+
+
+/// Destination of a backup that is an archive written as the source is
+/// scanned, rather than a mirror: everything is new (nothing is ever read
+/// back or removed), directories and files being appended as the engine
+/// creates and copies them
+pub struct ArchiveBackend {
+    source: String,
+    writer: RefCell<Option<Box<dyn Writer>>>,
+    /// Compressor the archive is piped through, if any
+    compressor: RefCell<Option<Child>>,
+    /// Directories appended so far
+    directories: RefCell<HashSet<String>>,
+    /// The first error writing the archive, after which nothing more is
+    /// written to it
+    broken: RefCell<Option<io::Error>>,
+}
+
+
+/// Start a tar archive written to a file (or, without one, to the standard
+/// output), through a compressor if any
+fn tar(file: Option<fs::File>, compression: Option<Compression>) -> io::Result<(Box<dyn Writer>, Option<Child>)> {
+    let (out, compressor): (Box<dyn Write>, _) = match (compression, file) {
+        (Some(compression), file) => {
+            let mut child = compression
+                .command(false)
+                .stdin(Stdio::piped())
+                .stdout(file.map_or(Stdio::inherit(), Stdio::from))
+                .spawn()
+                .map_err(|e| io::Error::new(e.kind(), format!("cannot run {}: {}", compression.program(), e)))?;
+            (Box::new(BufWriter::new(child.stdin.take().unwrap())), Some(child))
+        }
+        (None, Some(file)) => (Box::new(BufWriter::new(file)), None),
+        (None, None) => (Box::new(BufWriter::new(io::stdout())), None),
+    };
+    Ok((Box::new(TarWriter::new(out)), compressor))
+}
+
+
+impl ArchiveBackend {
+    fn new(source: &str, writer: Box<dyn Writer>, compressor: Option<Child>) -> ArchiveBackend {
+        ArchiveBackend {
+            source: source.to_string(),
+            writer: RefCell::new(Some(writer)),
+            compressor: RefCell::new(compressor),
+            directories: RefCell::new(HashSet::new()),
+            broken: RefCell::new(None),
+        }
+    }
+
+    /// Path of an entry in the archive, relative to its root
+    fn name<'a>(&self, path: &'a str) -> &'a str {
+        path.strip_prefix(ROOT).unwrap_or(path).trim_start_matches('/')
+    }
+
+    /// Header of an entry, with the permissions and time of what it is in
+    /// the source
+    fn header(&self, path: &str, kind: EntryType) -> Header {
+        let mut header = Header::new(self.name(path), kind);
+        let source = format!("{}/{}", self.source, self.name(path));
+        // Symlinks to directories are followed, as they are by the engine
+        let metadata = match kind {
+            EntryType::Directory => fs::metadata(&source),
+            _ => fs::symlink_metadata(&source),
+        };
+        if let Ok(metadata) = metadata {
+            if kind != EntryType::Symlink {
+                header.mode = metadata.permissions().mode() & 0o7777;
+            }
+            header.mtime = metadata.mtime();
+        }
+        header
+    }
+
+    /// Append an entry, unless the archive can't be written anymore
+    fn append(&self, header: &Header, data: &mut dyn Read) -> io::Result<()> {
+        if self.broken.borrow().is_some() {
+            return Ok(());
+        }
+        let mut writer = self.writer.borrow_mut();
+        let result = match writer.as_mut() {
+            Some(writer) => writer.append(header, data),
+            None => Err(io::Error::other("the archive is already finished")),
+        };
+        if let Err(e) = result {
+            // What was written of the entry can't be taken back
+            self.broken.replace(Some(e));
+        }
+        Ok(())
+    }
+}
+
+
+impl Backend for ArchiveBackend {
+    fn metadata(&self, path: &str) -> Option<Metadata> {
+        (path == ROOT || self.directories.borrow().contains(path)).then_some(Metadata {
+            kind: Kind::Directory,
+            size: 0,
+            modified: UNIX_EPOCH,
+            target: None,
+        })
+    }
+
+    fn list(&self, _path: &str) -> io::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.directories.borrow_mut().insert(path.to_string());
+        self.append(&self.header(path, EntryType::Directory), &mut io::empty())
+    }
+
+    fn remove_file(&self, _path: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, _path: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn copy_file(&self, source: &str, path: &str) -> io::Result<()> {
+        // Errors reading the file are those of the file alone
+        let file = fs::File::open(source)?;
+        let size = file.metadata()?.len();
+        let mut header = self.header(path, EntryType::File);
+        header.size = size;
+        // A file that shrinks while being archived is padded, so that the
+        // archive stays readable
+        let mut data = Throttled(file).take(size).chain(io::repeat(0).take(size));
+        self.append(&header, &mut data)
+    }
+
+    fn symlink(&self, target: &Path, path: &str) -> io::Result<()> {
+        let mut header = self.header(path, EntryType::Symlink);
+        header.link = target.to_string_lossy().to_string();
+        self.append(&header, &mut io::empty())
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    fn fetch(&self, _path: &str, _local: &str) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    fn read(&self, _path: &str) -> io::Result<Vec<u8>> {
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    /// Append a file holding `data` (e.g. a symlink placeholder)
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let mut header = self.header(path, EntryType::File);
+        header.mode = 0o644;
+        header.size = data.len() as u64;
+        self.append(&header, &mut &data[..])
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+
+    /// End the archive and wait for the compressor to write all of it
+    fn finish(&self) -> io::Result<()> {
+        if let Some(writer) = self.writer.take() {
+            // Closing the input of the compressor lets it finish
+            if let Err(e) = writer.end() {
+                self.broken.borrow_mut().get_or_insert(e);
+            }
+        }
+        if let Some(mut child) = self.compressor.take() {
+            let status = child.wait()?;
+            if !status.success() {
+                self.broken.borrow_mut().get_or_insert(io::Error::other(format!("the compressor failed ({})", status)));
+            }
+        }
+        match self.broken.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+
+/// Write the source as an archive to `output`, going through the same
+/// scan, exclusions and symlink policy as a backup. A tar archive may be
+/// written to the standard output (`-`), and is compressed as asked or as
+/// its name implies (`.tar.gz`, `.tar.zst`); the entries of a zip archive
+/// are stored as they are.
+pub fn archive(source: &str, output: &str, format: Format, opts: &Options) -> Result<(), String> {
+    if opts.encrypt || opts.snapshot.is_some() || opts.atomic {
+        return Err("archives can't be made with --encrypt, --snapshot or --atomic".to_string());
+    }
+    if format == Format::Zip && (output == "-" || opts.compress.is_some()) {
+        return Err("zip archives can't be written to the standard output nor compressed".to_string());
+    }
+    if output == "-" {
+        log::use_stderr();
+    }
+    log::separator();
+    info!("Source: {}", source);
+    info!("Archive: {}", output);
+    log::separator();
+    preconditions::check_all(&opts.preconditions, source)
+        .map_err(|e| format!("the source isn't available: {}; not archiving it", e))?;
+    throttle::set(opts.bwlimit);
+    let mut opts = opts.clone();
+    opts.policy.root = source.to_string();
+    opts.summary = Some(Rc::new(Summary::new(ROOT)));
+    if opts.dry_run {
+        // Nothing is written, so there is no archive to make
+        let writer = Box::new(TarWriter::new(io::sink()));
+        backup(source, ROOT, &opts, &ArchiveBackend::new(source, writer, None));
+        if let Some(summary) = &opts.summary {
+            summary.print();
+        }
+        return Ok(());
+    }
+    // Written next to the archive and renamed over it once complete
+    let temporary = (output != "-").then(|| temporary::beside(output));
+    let file = match &temporary {
+        Some(temporary) => Some(fs::File::create(temporary).map_err(|e| format!("cannot create {}: {}", temporary, e))?),
+        None => None,
+    };
+    let (writer, compressor) = match (format, file) {
+        (Format::Zip, Some(file)) => (Box::new(ZipWriter::new(file)) as Box<dyn Writer>, None),
+        (_, file) => tar(file, opts.compress.or_else(|| compression(output))).map_err(|e| e.to_string())?,
+    };
+    let target = ArchiveBackend::new(source, writer, compressor);
+    backup(source, ROOT, &opts, &target);
+    let result = target.finish().and_then(|_| match &temporary {
+        Some(temporary) => fs::rename(temporary, output),
+        None => Ok(()),
+    });
+    if let Err(e) = result {
+        if let Some(temporary) = &temporary {
+            let _ = fs::remove_file(temporary);
+        }
+        return Err(format!("cannot write {}: {}", output, e));
+    }
+    if let Some(summary) = &opts.summary {
+        summary.print();
+    }
+    Ok(())
+}
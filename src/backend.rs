@@ -0,0 +1,396 @@
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::android::AndroidBackend;
+use crate::batched;
+use crate::capabilities;
+use crate::compressed;
+use crate::delta;
+use crate::encrypted;
+use crate::hash::{self, Digest, Sha256};
+use crate::nfs::NfsBackend;
+use crate::progress;
+use crate::sanitized;
+use crate::smb::SmbBackend;
+use crate::special;
+use crate::ssh::SshBackend;
+use crate::stream;
+use crate::temporary;
+use crate::throttle::{self, Throttled};
+use crate::uring;
+use crate::Options;
+
+
+
+/// Type of a filesystem entry (symlinks are not followed)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Metadata {
+    pub kind: Kind,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// Target of a symlink
+    pub target: Option<PathBuf>,
+}
+
+
+/// Copy a local file (the first path) to a path of the storage
+pub type Copier = fn(&str, &str) -> io::Result<()>;
+
+
+/// Storage holding the destination of a backup. Paths are the full paths
+/// of the entries inside the storage.
+///
+/// The diff engine only goes through this trait, so supporting a new kind
+/// of destination means implementing it and recognising the destination
+/// in `open`. Copies read the files they send through `Throttled`, to keep
+/// to `--bwlimit`.
+pub trait Backend {
+    /// Metadata of a path, without following symlinks, or None if it
+    /// doesn't exist
+    fn metadata(&self, path: &str) -> Option<Metadata>;
+
+    /// Names of the entries of a directory
+    fn list(&self, path: &str) -> io::Result<Vec<String>>;
+
+    fn create_dir(&self, path: &str) -> io::Result<()>;
+
+    /// Remove a file or a symlink
+    fn remove_file(&self, path: &str) -> io::Result<()>;
+
+    /// Remove a directory and everything in it
+    fn remove_dir_all(&self, path: &str) -> io::Result<()>;
+
+    /// Copy a local file to the storage, replacing the existing file if any
+    fn copy_file(&self, source: &str, path: &str) -> io::Result<()>;
+
+    fn symlink(&self, target: &Path, path: &str) -> io::Result<()>;
+
+    /// Create a special file (a FIFO, socket or device node) with the mode
+    /// and device number of one of the source
+    fn mknod(&self, _path: &str, _mode: u32, _device: u64) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "special files can't be stored there"))
+    }
+
+    /// Bring an existing file up to date with a local file by writing only
+    /// the data that differs, returning the number of bytes written, or
+    /// None if the storage can't (the file is then copied instead). Until the update is complete the file looks older than the
+    /// source, so that an interrupted update is done again by the next run.
+    fn update_file(&self, _source: &str, _path: &str) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Capabilities of a file (see `capabilities`), or None if it has none
+    /// or the storage doesn't keep them
+    fn capabilities(&self, _path: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Give a file capabilities read from a local file
+    fn set_capabilities(&self, _path: &str, _value: &[u8]) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    /// Give a directory the permissions, owner and modification time of a
+    /// local one
+    fn set_directory_metadata(&self, _path: &str, _source: &fs::Metadata) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+
+    /// Move a file or a directory to a path that doesn't exist yet
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Flush a file to the disk, or the entries of a directory (for
+    /// `--fsync`). Remote storages leave it to the machine they are on.
+    fn sync(&self, _path: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Copy a file of the storage to the local filesystem
+    fn fetch(&self, path: &str, local: &str) -> io::Result<()>;
+
+    /// Path of a local file holding a file of the storage as it is, if
+    /// there is one, so that it can be read without fetching it
+    fn local_file(&self, _path: &str) -> Option<String> {
+        None
+    }
+
+    /// Read a whole file (used for the files of backup-rs itself)
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+
+    /// Write a whole file, atomically replacing the existing one if any
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Whether the storage is the local filesystem
+    fn is_local(&self) -> bool;
+
+    /// A function copying a local file to the storage, as `copy_file`
+    /// does, that worker threads can call, if the storage takes copies
+    /// made in parallel (see `--copy-jobs`)
+    fn copier(&self) -> Option<Copier> {
+        None
+    }
+
+    /// Size up to which the files copied to the storage are better batched
+    /// into packs (see `batched`), as on storages charging per request, if
+    /// they are
+    fn small_file_size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Save any state kept in memory, at the end of a run
+    fn finish(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Hash of a file of the storage, reading it where it is if it is local,
+/// or else fetching it
+pub fn hash_stored(target: &dyn Backend, path: &str) -> io::Result<Digest> {
+    if let Some(local) = target.local_file(path) {
+        return hash::hash_file(&local);
+    }
+    let local = temporary::local("hash");
+    let result = target.fetch(path, &local).and_then(|_| hash::hash_file(&local));
+    let _ = fs::remove_file(&local);
+    result
+}
+
+
+/// Size of the blocks compared by `update_file`
+const LOCAL_BLOCK_SIZE: usize = 128 * 1024;
+
+
+/// Metadata of a local path, from what `lstat()` returned for it
+pub fn local_metadata(path: &str, metadata: &fs::Metadata) -> Option<Metadata> {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_symlink() {
+        Kind::Symlink
+    } else if file_type.is_dir() {
+        Kind::Directory
+    } else if file_type.is_file() {
+        Kind::File
+    } else {
+        Kind::Other
+    };
+    Some(Metadata {
+        kind,
+        size: metadata.len(),
+        modified: metadata.modified().ok()?,
+        target: if kind == Kind::Symlink {
+            fs::read_link(path).ok()
+        } else {
+            None
+        },
+    })
+}
+
+
+/// Copy the contents of a local file to another, through a buffer of the
+/// given size (unless --buffer-size asks for another), without copying its
+/// permissions (as `fs::copy()` does) for the filesystems that refuse
+/// chmod
+pub fn copy_contents(source: &str, path: &str, buffer_size: usize) -> io::Result<()> {
+    if let Some(copied) = uring::copy(source, path, buffer_size) {
+        return copied;
+    }
+    let mut input = Throttled(fs::File::open(source)?);
+    let mut output = fs::File::create(path)?;
+    let mut hasher = Sha256::new();
+    stream::copy(&mut input, &mut output, stream::buffer_size(buffer_size), |chunk| {
+        hasher.update(chunk);
+        progress::streamed(chunk.len() as u64)
+    })?;
+    stream::hashed(source, hasher.finish());
+    Ok(())
+}
+
+
+/// The local filesystem
+pub struct LocalBackend;
+
+
+impl Backend for LocalBackend {
+    fn metadata(&self, path: &str) -> Option<Metadata> {
+        local_metadata(path, &fs::symlink_metadata(path).ok()?)
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(path)? {
+            // Entries whose name isn't valid UTF-8 are skipped
+            if let Some(name) = entry?.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+
+    fn copy_file(&self, source: &str, path: &str) -> io::Result<()> {
+        copy_contents(source, path, LOCAL_BLOCK_SIZE)?;
+        fs::set_permissions(path, fs::metadata(source)?.permissions())
+    }
+
+    fn symlink(&self, target: &Path, path: &str) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, path)
+    }
+
+    fn mknod(&self, path: &str, mode: u32, device: u64) -> io::Result<()> {
+        special::create(path, mode, device)
+    }
+
+    fn update_file(&self, source: &str, path: &str) -> io::Result<Option<u64>> {
+        let mut input = Throttled(fs::File::open(source)?);
+        let mut output = fs::File::options().read(true).write(true).open(path)?;
+        let mut hasher = Sha256::new();
+        output.set_modified(UNIX_EPOCH)?;
+        let mut new = vec![0u8; LOCAL_BLOCK_SIZE];
+        let mut old = vec![0u8; LOCAL_BLOCK_SIZE];
+        let (mut offset, mut written) = (0, 0);
+        loop {
+            let n = delta::read_full(&mut input, &mut new)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&new[..n]);
+            let m = delta::read_full(&mut output, &mut old[..n])?;
+            if m != n || new[..n] != old[..n] {
+                output.seek(SeekFrom::Start(offset))?;
+                output.write_all(&new[..n])?;
+                written += n as u64;
+            }
+            offset += n as u64;
+        }
+        output.set_len(offset)?;
+        output.sync_all()?;
+        output.set_modified(SystemTime::now())?;
+        stream::hashed(source, hasher.finish());
+        Ok(Some(written))
+    }
+
+    fn capabilities(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        capabilities::get(Path::new(path))
+    }
+
+    fn set_capabilities(&self, path: &str, value: &[u8]) -> io::Result<()> {
+        capabilities::set(Path::new(path), value)
+    }
+
+    fn set_directory_metadata(&self, path: &str, source: &fs::Metadata) -> io::Result<()> {
+        // Only root can give files away, and others keep their own
+        match std::os::unix::fs::chown(path, Some(source.uid()), Some(source.gid())) {
+            Err(e) if e.kind() != io::ErrorKind::PermissionDenied => return Err(e),
+            _ => (),
+        }
+        // The copy stays writable, for the next runs to update it
+        fs::set_permissions(path, fs::Permissions::from_mode(source.mode() & 0o7777 | 0o700))?;
+        fs::File::open(path)?.set_modified(source.modified()?)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn sync(&self, path: &str) -> io::Result<()> {
+        fs::File::open(path)?.sync_all()
+    }
+
+    fn fetch(&self, path: &str, local: &str) -> io::Result<()> {
+        fs::copy(path, local).map(|_| ())
+    }
+
+    fn local_file(&self, path: &str) -> Option<String> {
+        Some(path.to_string())
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temporary_path = temporary::beside(path);
+        let mut file = fs::File::create(&temporary_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(temporary_path, path)
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    fn copier(&self) -> Option<Copier> {
+        Some(|source, path| LocalBackend.copy_file(source, path))
+    }
+}
+
+
+/// Split a `[USER@]HOST:PATH` destination into host and path
+pub fn parse_remote(destination: &str) -> Option<(&str, &str)> {
+    let (host, path) = destination.split_once(':')?;
+    // Like rsync, a colon after a slash is part of a local path
+    if host.is_empty() || host.contains('/') {
+        return None;
+    }
+    Some((host, if path.is_empty() { "." } else { path }))
+}
+
+
+/// Open the storage of a destination, returning it together with the path
+/// of the destination inside it
+pub fn open(destination: &str, opts: &Options) -> Result<(Box<dyn Backend>, String), String> {
+    throttle::set(opts.bwlimit);
+    stream::set_buffer_size(opts.buffer_size);
+    uring::set(opts.uring);
+    let (target, path): (Box<dyn Backend>, String) = match parse_remote(destination) {
+        _ if destination.starts_with("smb://") => {
+            let (backend, path) = SmbBackend::new(destination)?;
+            (Box::new(backend), path)
+        }
+        Some((host, path)) => {
+            // Paths are built by appending "/NAME", so drop trailing slashes
+            let path = match path.trim_end_matches('/') {
+                "" => "/",
+                trimmed => trimmed,
+            };
+            (Box::new(SshBackend::new(host, path)?), path.to_string())
+        }
+        None if opts.android => (Box::new(AndroidBackend::new()), destination.to_string()),
+        None if opts.nfs => (Box::new(NfsBackend::new()), destination.to_string()),
+        None => (Box::new(LocalBackend), destination.to_string()),
+    };
+    let target = sanitized::wrap(target, &path, opts)?;
+    let target = encrypted::wrap(target, &path, opts)?;
+    let target = compressed::wrap(target, &path, opts)?;
+    // Packs are written whole, so they are encrypted as the files of
+    // backup-rs are, and not compressed
+    let target = batched::wrap(target, &path, opts)?;
+    Ok((target, path))
+}
@@ -0,0 +1,374 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backend::{Backend, Kind, Metadata};
+use crate::manifest::{self, META_DIR};
+use crate::Options;
+
+
+
+/// File, inside META_DIR, telling where each batched file is
+const INDEX: &str = "batches";
+const INDEX_HEADER: &str = "backup-rs batches 1";
+/// Directory, inside META_DIR, holding the packs
+const PACKS: &str = "packs";
+/// A pack is written once this much is batched in it
+const PACK_SIZE: usize = 8 * 1024 * 1024;
+
+
+/// Where a batched file is
+#[derive(Clone)]
+struct Entry {
+    pack: u64,
+    offset: u64,
+    size: u64,
+    /// When it was copied, which stands for the modification time of its
+    /// copy, as for the other files
+    modified: SystemTime,
+}
+
+
+/// Store the small files of the destination batched into packs, written
+/// with one request each, rather than with one request per file, which
+/// costs more than the data on storages charging per request. The index,
+/// keyed by path relative to the root, tells the pack each one is in; the
+/// files are read back from their pack, so restores don't tell them apart
+/// from the others. Packs are written once full and at the end of the run,
+/// and removed once nothing in them is left.
+pub struct BatchedBackend {
+    inner: Box<dyn Backend>,
+    root: String,
+    /// Files up to this size are batched
+    threshold: Option<u64>,
+    /// Relative path -> entry
+    index: RefCell<BTreeMap<String, Entry>>,
+    /// The pack being filled, not written yet
+    pending: RefCell<Vec<u8>>,
+    next_pack: RefCell<u64>,
+    /// The pack read last, as restores read the files of a pack together
+    read: RefCell<Option<(u64, Vec<u8>)>>,
+    changed: RefCell<bool>,
+}
+
+
+fn index_path(root: &str) -> String {
+    format!("{}/{}/{}", root, META_DIR, INDEX)
+}
+
+
+fn pack_path(root: &str, pack: u64) -> String {
+    format!("{}/{}/{}/{:016x}", root, META_DIR, PACKS, pack)
+}
+
+
+/// A line of the index: the entry and the path it is for
+fn parse_line(line: &str) -> Option<(String, Entry)> {
+    let mut fields = line.splitn(5, '\t');
+    let pack = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let offset = fields.next()?.parse().ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let (seconds, nanoseconds) = fields.next()?.split_once('.')?;
+    let modified = UNIX_EPOCH + Duration::new(seconds.parse().ok()?, nanoseconds.parse().ok()?);
+    Some((manifest::unescape(fields.next()?), Entry { pack, offset, size, modified }))
+}
+
+
+/// Wrap the storage of a destination if it has (or is to get) batched
+/// files: with `--batch-small-files`, or if the storage asks for it
+pub fn wrap(inner: Box<dyn Backend>, root: &str, opts: &Options) -> Result<Box<dyn Backend>, String> {
+    let content = inner
+        .read(&index_path(root))
+        .ok()
+        .map(|data| String::from_utf8_lossy(&data).to_string());
+    let threshold = opts.batch_small_files.or(inner.small_file_size());
+    if content.is_none() && threshold.is_none() {
+        return Ok(inner);
+    }
+    let mut index = BTreeMap::new();
+    if let Some(content) = content {
+        let mut lines = content.lines();
+        if lines.next() != Some(INDEX_HEADER) {
+            return Err(format!("invalid {}", index_path(root)));
+        }
+        index.extend(lines.filter_map(parse_line));
+    }
+    let next_pack = index.values().map(|e| e.pack + 1).max().unwrap_or(0);
+    Ok(Box::new(BatchedBackend {
+        inner,
+        root: root.to_string(),
+        threshold,
+        index: RefCell::new(index),
+        pending: RefCell::default(),
+        next_pack: RefCell::new(next_pack),
+        read: RefCell::default(),
+        changed: RefCell::new(false),
+    }))
+}
+
+
+impl BatchedBackend {
+    fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(&self.root)?.strip_prefix('/')
+    }
+
+    fn lookup(&self, path: &str) -> Option<Entry> {
+        self.index.borrow().get(self.relative(path)?).cloned()
+    }
+
+    /// Forget the batched file at a path, or those below it
+    fn forget(&self, path: &str) {
+        if let Some(relative) = self.relative(path) {
+            let prefix = format!("{}/", relative);
+            self.index
+                .borrow_mut()
+                .retain(|p, _| p != relative && !p.starts_with(&prefix));
+            *self.changed.borrow_mut() = true;
+        }
+    }
+
+    /// Remove the file at a path, whether batched or not, before another
+    /// takes its place
+    fn replace(&self, path: &str) -> io::Result<()> {
+        if self.lookup(path).is_some() {
+            self.forget(path);
+        } else if self.inner.metadata(path).is_some_and(|m| m.kind != Kind::Directory) {
+            self.inner.remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Write the pack being filled
+    fn seal(&self) -> io::Result<()> {
+        let mut pending = self.pending.borrow_mut();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let pack = *self.next_pack.borrow();
+        self.inner.write(&pack_path(&self.root, pack), &pending)?;
+        pending.clear();
+        *self.next_pack.borrow_mut() += 1;
+        Ok(())
+    }
+
+    /// The contents of a batched file
+    fn contents(&self, entry: &Entry) -> io::Result<Vec<u8>> {
+        let (start, end) = (entry.offset as usize, (entry.offset + entry.size) as usize);
+        if entry.pack == *self.next_pack.borrow() {
+            return Ok(self.pending.borrow()[start..end].to_vec());
+        }
+        let mut read = self.read.borrow_mut();
+        if read.as_ref().map(|(pack, _)| *pack) != Some(entry.pack) {
+            *read = Some((entry.pack, self.inner.read(&pack_path(&self.root, entry.pack))?));
+        }
+        let data = &read.as_ref().unwrap().1;
+        match data.get(start..end) {
+            Some(contents) => Ok(contents.to_vec()),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, format!("pack {:016x} is truncated", entry.pack))),
+        }
+    }
+
+    /// Remove the packs nothing is left in
+    fn remove_empty_packs(&self) -> io::Result<()> {
+        let directory = format!("{}/{}/{}", self.root, META_DIR, PACKS);
+        let names = match self.inner.list(&directory) {
+            Ok(names) => names,
+            Err(_) => return Ok(()),
+        };
+        let index = self.index.borrow();
+        for name in names {
+            let Ok(pack) = u64::from_str_radix(&name, 16) else {
+                continue;
+            };
+            if !index.values().any(|e| e.pack == pack) {
+                self.inner.remove_file(&format!("{}/{}", directory, name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+impl Backend for BatchedBackend {
+    fn metadata(&self, path: &str) -> Option<Metadata> {
+        match self.lookup(path) {
+            Some(entry) => Some(Metadata { kind: Kind::File, size: entry.size, modified: entry.modified, target: None }),
+            None => self.inner.metadata(path),
+        }
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let mut names = self.inner.list(path)?;
+        let prefix = match self.relative(path) {
+            Some(relative) => format!("{}/", relative),
+            None if path == self.root => String::new(),
+            None => return Ok(names),
+        };
+        let index = self.index.borrow();
+        let batched = index
+            .range(prefix.clone()..)
+            .map(|(p, _)| p)
+            .take_while(|p| p.starts_with(&prefix))
+            .map(|p| &p[prefix.len()..])
+            .filter(|name| !name.contains('/'));
+        names.extend(batched.map(str::to_string));
+        Ok(names)
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.inner.create_dir(path)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        match self.lookup(path) {
+            Some(_) => {
+                self.forget(path);
+                Ok(())
+            }
+            None => self.inner.remove_file(path),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        self.inner.remove_dir_all(path)?;
+        self.forget(path);
+        Ok(())
+    }
+
+    fn copy_file(&self, source: &str, path: &str) -> io::Result<()> {
+        self.replace(path)?;
+        let size = fs::metadata(source)?.len();
+        let relative = match self.relative(path) {
+            Some(relative) if self.threshold.is_some_and(|threshold| size <= threshold) => relative.to_string(),
+            _ => return self.inner.copy_file(source, path),
+        };
+        let contents = fs::read(source)?;
+        let offset = self.pending.borrow().len() as u64;
+        self.pending.borrow_mut().extend_from_slice(&contents);
+        let entry = Entry {
+            pack: *self.next_pack.borrow(),
+            offset,
+            size: contents.len() as u64,
+            modified: SystemTime::now(),
+        };
+        self.index.borrow_mut().insert(relative, entry);
+        *self.changed.borrow_mut() = true;
+        if self.pending.borrow().len() >= PACK_SIZE {
+            self.seal()?;
+        }
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, path: &str) -> io::Result<()> {
+        self.replace(path)?;
+        self.inner.symlink(target, path)
+    }
+
+    fn mknod(&self, path: &str, mode: u32, device: u64) -> io::Result<()> {
+        self.replace(path)?;
+        self.inner.mknod(path, mode, device)
+    }
+
+    fn capabilities(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.lookup(path) {
+            Some(_) => Ok(None),
+            None => self.inner.capabilities(path),
+        }
+    }
+
+    fn set_capabilities(&self, path: &str, value: &[u8]) -> io::Result<()> {
+        match self.lookup(path) {
+            Some(_) => Err(io::Error::from(io::ErrorKind::Unsupported)),
+            None => self.inner.set_capabilities(path, value),
+        }
+    }
+
+    fn set_directory_metadata(&self, path: &str, source: &fs::Metadata) -> io::Result<()> {
+        self.inner.set_directory_metadata(path, source)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        if let Some(entry) = self.lookup(from) {
+            self.forget(from);
+            if let Some(relative) = self.relative(to) {
+                self.index.borrow_mut().insert(relative.to_string(), entry);
+            }
+            return Ok(());
+        }
+        self.inner.rename(from, to)?;
+        if let (Some(from), Some(to)) = (self.relative(from), self.relative(to)) {
+            let prefix = format!("{}/", from);
+            let mut index = self.index.borrow_mut();
+            let moved: Vec<String> = index.keys().filter(|p| p.starts_with(&prefix)).cloned().collect();
+            for path in moved {
+                let entry = index.remove(&path).unwrap();
+                index.insert(format!("{}/{}", to, &path[prefix.len()..]), entry);
+            }
+            *self.changed.borrow_mut() = true;
+        }
+        Ok(())
+    }
+
+    fn sync(&self, path: &str) -> io::Result<()> {
+        // Packs are flushed as they are written
+        match self.lookup(path) {
+            Some(_) => Ok(()),
+            None => self.inner.sync(path),
+        }
+    }
+
+    fn fetch(&self, path: &str, local: &str) -> io::Result<()> {
+        match self.lookup(path) {
+            Some(entry) => fs::write(local, self.contents(&entry)?),
+            None => self.inner.fetch(path, local),
+        }
+    }
+
+    fn local_file(&self, path: &str) -> Option<String> {
+        match self.lookup(path) {
+            Some(_) => None,
+            None => self.inner.local_file(path),
+        }
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.inner.write(path, data)
+    }
+
+    fn is_local(&self) -> bool {
+        self.inner.is_local()
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.seal()?;
+        if *self.changed.borrow() {
+            let mut content = format!("{}\n", INDEX_HEADER);
+            for (path, entry) in self.index.borrow().iter() {
+                let modified = entry.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+                writeln!(
+                    content,
+                    "{:016x}\t{}\t{}\t{}.{:09}\t{}",
+                    entry.pack,
+                    entry.offset,
+                    entry.size,
+                    modified.as_secs(),
+                    modified.subsec_nanos(),
+                    manifest::escape(path)
+                )
+                .unwrap();
+            }
+            self.inner.write(&index_path(&self.root), content.as_bytes())?;
+            self.remove_empty_packs()?;
+            *self.changed.borrow_mut() = false;
+        }
+        self.inner.finish()
+    }
+}
@@ -0,0 +1,99 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::stats::human_bytes;
+use crate::summary::Totals;
+
+
+
+/// Whether a run of this process went over its change budget
+static EXCEEDED: AtomicBool = AtomicBool::new(false);
+
+
+/// How much a run is expected to change at most: more than that is flagged,
+/// as it may be ransomware encrypting the source or a runaway process
+/// rewriting it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Budget {
+    /// Percentage of the files copied or removed
+    pub percent: Option<f64>,
+    /// Bytes copied
+    pub bytes: Option<u64>,
+}
+
+
+/// Parse a size such as `50G`, `512MiB` or `1000` (bytes)
+pub fn parse_size(text: &str) -> Option<u64> {
+    let digits = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+    let (number, unit) = text.split_at(digits);
+    let number: f64 = number.parse().ok()?;
+    let exponent = match unit.trim().trim_end_matches("iB").trim_end_matches('B') {
+        "" => 0,
+        "K" | "k" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return None,
+    };
+    Some((number * 1024f64.powi(exponent)) as u64)
+}
+
+
+impl Budget {
+    /// Parse a comma-separated list of limits: a percentage of the files
+    /// (`20%`) and/or a size copied (`50G`)
+    pub fn parse(text: &str) -> Result<Budget, String> {
+        let mut budget = Budget::default();
+        for limit in text.split(',').map(|l| l.trim()) {
+            let invalid = || format!("invalid change budget '{}' (expected e.g. 20%, 50G or 20%,50G)", limit);
+            match limit.strip_suffix('%') {
+                Some(percent) => {
+                    let percent: f64 = percent.trim().parse().map_err(|_| invalid())?;
+                    if !(0.0..=100.0).contains(&percent) {
+                        return Err(invalid());
+                    }
+                    budget.percent = Some(percent);
+                }
+                None => budget.bytes = Some(parse_size(limit).ok_or_else(invalid)?),
+            }
+        }
+        Ok(budget)
+    }
+
+    /// Check what a run changed against the budget, logging the limits it
+    /// went over. The percentage is only checked if the number of files in
+    /// the source is known. Returns false if it went over any limit.
+    pub fn check(&self, totals: Totals, files: Option<u64>) -> bool {
+        let mut within = true;
+        let changed = totals.copied + totals.removed;
+        // Removed files were part of the destination too
+        if let (Some(limit), Some(files)) = (self.percent, files) {
+            let all = files + totals.removed;
+            let percent = 100.0 * changed as f64 / all.max(1) as f64;
+            if percent > limit {
+                error!(
+                    "the run changed {:.1}% of the files ({} of {}), more than the budget of {}%",
+                    percent, changed, all, limit
+                );
+                within = false;
+            }
+        }
+        if let Some(limit) = self.bytes.filter(|&limit| totals.bytes > limit) {
+            error!(
+                "the run copied {}, more than the budget of {}",
+                human_bytes(totals.bytes),
+                human_bytes(limit)
+            );
+            within = false;
+        }
+        if !within {
+            EXCEEDED.store(true, Ordering::SeqCst);
+        }
+        within
+    }
+}
+
+
+/// Whether a run of this process went over its change budget
+pub fn exceeded() -> bool {
+    EXCEEDED.load(Ordering::SeqCst)
+}
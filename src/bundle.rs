@@ -0,0 +1,333 @@
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backend::{self, Backend};
+use crate::hash::{self, Digest};
+use crate::manifest::{self, Entry, Manifest, META_DIR};
+use crate::schedule;
+use crate::stats::human_bytes;
+use crate::tar::{EntryType, Header, TarReader, TarWriter};
+use crate::temporary;
+
+
+
+const INFO: &str = "backup-rs-bundle/info";
+const MANIFEST: &str = "backup-rs-bundle/manifest";
+const DATA: &str = "data/";
+const HEADER: &str = "backup-rs bundle 1";
+
+/// Directory, inside META_DIR, keeping the state of each export so that the
+/// next one can be made relative to it
+const EXPORTS_DIR: &str = "exports";
+
+
+/// Find the state a bundle is exported relative to: a btrfs snapshot, a ZFS
+/// snapshot, a previous export, or a directory or manifest given by path
+fn resolve_base(destination: &str, since: &str) -> Result<Manifest, String> {
+    let candidates = [
+        format!("{}/{}/snapshots/{}", destination, META_DIR, since),
+        format!("{}/.zfs/snapshot/{}", destination, since),
+        format!("{}/.zfs/snapshot/backup-rs-{}", destination, since),
+        format!("{}/{}/{}/{}", destination, META_DIR, EXPORTS_DIR, since),
+        since.to_string(),
+    ];
+    for candidate in &candidates {
+        let path = Path::new(candidate);
+        if path.is_dir() {
+            return Ok(Manifest::scan_mirror(candidate));
+        } else if path.is_file() {
+            let content = fs::read_to_string(path)
+                .map_err(|e| format!("cannot read {}: {}", candidate, e))?;
+            return Manifest::parse(&content)
+                .ok_or_else(|| format!("{} is not a backup-rs manifest", candidate));
+        }
+    }
+    Err(format!("no snapshot or export named '{}'", since))
+}
+
+
+fn root_rollup(manifest: &Manifest) -> Digest {
+    manifest.rollup(".").copied().unwrap_or_default()
+}
+
+
+fn text_header(path: &str, content: &str) -> Header {
+    let mut header = Header::new(path, EntryType::File);
+    header.size = content.len() as u64;
+    header.mtime = schedule::now();
+    header
+}
+
+
+/// Write a bundle with everything that changed in the destination since
+/// `since` (or everything, for a full bundle used to seed a new replica)
+pub fn export(destination: &str, since: Option<&str>, bundle: &str) -> Result<(), String> {
+    if backend::parse_remote(destination).is_some() {
+        return Err("export needs a local destination".to_string());
+    }
+    let base = match since {
+        Some(since) => Some(resolve_base(destination, since)?),
+        None => None,
+    };
+    info!("Scanning {}...", destination);
+    let state = Manifest::scan_mirror(destination);
+
+    let mut info = format!("{}\n", HEADER);
+    if let Some(base) = &base {
+        info += &format!("base {}\n", hash::to_hex(&root_rollup(base)));
+    }
+    info += &format!("state {}\n", hash::to_hex(&root_rollup(&state)));
+    let manifest = state.to_text();
+
+    let file = fs::File::create(bundle).map_err(|e| format!("cannot create {}: {}", bundle, e))?;
+    let mut writer = TarWriter::new(BufWriter::new(file));
+    let write_error = |e: io::Error| format!("cannot write {}: {}", bundle, e);
+    writer.append(&text_header(INFO, &info), &mut info.as_bytes()).map_err(write_error)?;
+    writer
+        .append(&text_header(MANIFEST, &manifest), &mut manifest.as_bytes())
+        .map_err(write_error)?;
+
+    let (mut count, mut bytes) = (0, 0);
+    for (relative, entry) in state.entries.iter().filter(|(r, _)| r.as_str() != ".") {
+        let previous = base.as_ref().and_then(|b| b.entries.get(relative));
+        match (entry, previous) {
+            // Directories that already exist need nothing; what changed below
+            // them is exported on its own
+            (Entry::Directory { .. }, Some(Entry::Directory { .. })) => continue,
+            (_, Some(previous)) if previous == entry => continue,
+            _ => (),
+        }
+        let path = format!("{}/{}", destination, relative);
+        let name = format!("{}{}", DATA, relative);
+        let metadata = fs::symlink_metadata(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        match entry {
+            Entry::Directory { .. } => {
+                let mut header = Header::new(&name, EntryType::Directory);
+                header.mode = mode;
+                header.mtime = metadata.mtime();
+                writer.append(&header, &mut io::empty()).map_err(write_error)?;
+            }
+            Entry::Symlink { target } => {
+                let mut header = Header::new(&name, EntryType::Symlink);
+                header.mode = 0o777;
+                header.mtime = metadata.mtime();
+                header.link = target.clone();
+                writer.append(&header, &mut io::empty()).map_err(write_error)?;
+            }
+            Entry::File { size, mtime, mtime_nsec } => {
+                if !metadata.is_file() {
+                    warning!("skipping special file {}", path);
+                    continue;
+                }
+                let mut header = Header::new(&name, EntryType::File);
+                header.size = *size;
+                header.mode = mode;
+                header.mtime = *mtime;
+                header.mtime_nsec = *mtime_nsec;
+                let mut file = fs::File::open(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+                writer.append(&header, &mut file).map_err(write_error)?;
+                bytes += size;
+            }
+        }
+        action!("Exporting {}", relative);
+        count += 1;
+    }
+    writer.finish().map_err(write_error)?;
+
+    let name = schedule::local_time(schedule::now()).format();
+    let exported = format!("{}/{}/{}/{}", destination, META_DIR, EXPORTS_DIR, name);
+    match backend::LocalBackend.write(&exported, manifest.as_bytes()) {
+        Ok(()) => info!("Exported {} entries ({}); the next export can use --since {}", count, human_bytes(bytes), name),
+        Err(e) => {
+            warning!("cannot write {}: {}", exported, e);
+            info!("Exported {} entries ({})", count, human_bytes(bytes));
+        }
+    }
+    Ok(())
+}
+
+
+/// Check that a path of a bundle stays inside the replica
+fn valid_relative(relative: &str) -> bool {
+    !relative.is_empty()
+        && !relative.starts_with('/')
+        && relative.split('/').all(|c| !c.is_empty() && c != "." && c != "..")
+        && !relative.split('/').next().is_some_and(manifest::is_meta)
+}
+
+
+fn system_time(mtime: i64, mtime_nsec: i64) -> SystemTime {
+    if mtime >= 0 {
+        UNIX_EPOCH + Duration::new(mtime as u64, mtime_nsec as u32)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs()) + Duration::from_nanos(mtime_nsec as u64)
+    }
+}
+
+
+/// Remove whatever is at `path` unless it is a directory and `keep_dir`
+fn clear(path: &Path, keep_dir: bool) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() && keep_dir => Ok(()),
+        Ok(m) if m.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => Ok(()),
+    }
+}
+
+
+/// Write one entry of a bundle into the replica
+fn apply<R: Read>(reader: &mut TarReader<R>, header: &Header, path: &Path) -> io::Result<()> {
+    match header.kind {
+        EntryType::Directory => {
+            clear(path, true)?;
+            if !path.exists() {
+                fs::create_dir(path)?;
+            }
+            fs::set_permissions(path, fs::Permissions::from_mode(header.mode))
+        }
+        EntryType::Symlink => {
+            clear(path, false)?;
+            std::os::unix::fs::symlink(&header.link, path)
+        }
+        EntryType::File => {
+            // Go through a temporary file, so that an interrupted import never
+            // leaves a truncated file behind
+            let temporary = temporary::beside(&path.to_string_lossy());
+            let mut file = fs::File::create(&temporary)?;
+            reader.read_data(&mut file)?;
+            file.set_permissions(fs::Permissions::from_mode(header.mode))?;
+            file.set_modified(system_time(header.mtime, header.mtime_nsec))?;
+            drop(file);
+            clear(path, false)?;
+            fs::rename(&temporary, path)
+        }
+    }
+}
+
+
+/// Remove the entries of the replica that aren't in the exported state
+fn remove_extra(directory: &Path, relative: &str, state: &Manifest, dry_run: bool) -> usize {
+    let mut removed = 0;
+    let mut names: Vec<String> = match fs::read_dir(directory) {
+        Ok(dir) => dir
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str().map(|s| s.to_string()))
+            .collect(),
+        Err(_) => return 0,
+    };
+    names.sort();
+    for name in names {
+        if relative.is_empty() && manifest::is_meta(&name) {
+            continue;
+        }
+        let child_relative = if relative.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", relative, name)
+        };
+        let path = directory.join(&name);
+        let is_dir = fs::symlink_metadata(&path).map(|m| m.is_dir()).unwrap_or(false);
+        match state.entries.get(&child_relative) {
+            Some(Entry::Directory { .. }) if is_dir => {
+                removed += remove_extra(&path, &child_relative, state, dry_run);
+            }
+            Some(_) => (),
+            None => {
+                action!("Removing {}", path.display());
+                if !dry_run {
+                    if let Err(e) = clear(&path, false) {
+                        warning!("cannot remove {}: {}", path.display(), e);
+                    }
+                }
+                removed += 1;
+            }
+        }
+    }
+    removed
+}
+
+
+/// Apply a bundle to a replica, making it identical to the destination the
+/// bundle was exported from
+pub fn import(bundle: &str, replica: &str, dry_run: bool, force: bool) -> Result<(), String> {
+    let file = fs::File::open(bundle).map_err(|e| format!("cannot open {}: {}", bundle, e))?;
+    let mut reader = TarReader::new(BufReader::new(file));
+    let read_error = |e: io::Error| format!("cannot read {}: {}", bundle, e);
+    let read_text = |reader: &mut TarReader<BufReader<fs::File>>, name: &str| {
+        match reader.next_header().map_err(read_error)? {
+            Some(header) if header.path == name => {
+                let mut content = Vec::new();
+                reader.read_data(&mut content).map_err(read_error)?;
+                String::from_utf8(content).map_err(|_| format!("{} is not a backup-rs bundle", bundle))
+            }
+            _ => Err(format!("{} is not a backup-rs bundle", bundle)),
+        }
+    };
+    let info = read_text(&mut reader, INFO)?;
+    let state = Manifest::parse(&read_text(&mut reader, MANIFEST)?)
+        .ok_or_else(|| format!("{} has an invalid manifest", bundle))?;
+    let mut lines = info.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(format!("{} is not a backup-rs bundle", bundle));
+    }
+    let mut base = None;
+    for line in lines {
+        if let Some(hex) = line.strip_prefix("base ") {
+            base = hash::from_hex(hex);
+        }
+    }
+
+    if Path::new(replica).exists() {
+        let current = root_rollup(&Manifest::scan_mirror(replica));
+        if current == root_rollup(&state) {
+            info!("{} is already up to date", replica);
+            return Ok(());
+        }
+        if base.is_some_and(|base| base != current) {
+            if !force {
+                return Err(format!(
+                    "{} doesn't match the state the bundle was exported from \
+                     (an earlier bundle may be missing); use --force to import anyway",
+                    replica
+                ));
+            }
+            warning!("{} doesn't match the state the bundle was exported from", replica);
+        }
+    } else if base.is_some() && !force {
+        return Err(format!(
+            "{} doesn't exist, and the bundle only has the changes since an earlier export",
+            replica
+        ));
+    } else if !dry_run {
+        fs::create_dir_all(replica).map_err(|e| format!("cannot create {}: {}", replica, e))?;
+    }
+
+    let mut count = 0;
+    while let Some(header) = reader.next_header().map_err(read_error)? {
+        let relative = match header.path.strip_prefix(DATA) {
+            Some(relative) if valid_relative(relative) => relative.to_string(),
+            _ => {
+                warning!("skipping {} from the bundle", header.path);
+                continue;
+            }
+        };
+        action!("Importing {}", relative);
+        count += 1;
+        if !dry_run {
+            let path = Path::new(replica).join(&relative);
+            apply(&mut reader, &header, &path).map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+        }
+    }
+    let removed = remove_extra(Path::new(replica), "", &state, dry_run);
+    info!("Imported {} entries, removed {}", count, removed);
+
+    if !dry_run && root_rollup(&Manifest::scan_mirror(replica)) != root_rollup(&state) {
+        warning!("{} doesn't match the exported state after the import", replica);
+    }
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use std::ffi::CString;
+use std::io;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn getxattr(path: *const c_char, name: *const c_char, value: *mut u8, size: usize) -> isize;
+    fn setxattr(path: *const c_char, name: *const c_char, value: *const u8, size: usize, flags: c_int) -> c_int;
+}
+
+
+/// Extended attribute holding the capabilities of an executable (as set
+/// with `setcap`, e.g. on `ping`)
+const NAME: &[u8] = b"security.capability\0";
+
+/// The attribute doesn't exist, or the filesystem has no extended
+/// attributes at all
+const ENODATA: c_int = 61;
+const ENOTSUP: c_int = 95;
+
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+
+/// The capabilities of a file, raw, or None if it has none
+#[cfg(target_os = "linux")]
+pub fn get(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let path = c_path(path)?;
+    // A capability set is at most 24 bytes (version 3)
+    let mut value = vec![0u8; 64];
+    let n = unsafe { getxattr(path.as_ptr(), NAME.as_ptr() as *const c_char, value.as_mut_ptr(), value.len()) };
+    if n < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(ENODATA) | Some(ENOTSUP) => Ok(None),
+            _ => Err(e),
+        };
+    }
+    value.truncate(n as usize);
+    Ok(Some(value))
+}
+
+
+/// Give a file capabilities read with `get()`, which takes CAP_SETFCAP
+/// (i.e. running as root). Writing to a file drops its capabilities, so
+/// this must come after the contents are copied.
+#[cfg(target_os = "linux")]
+pub fn set(path: &Path, value: &[u8]) -> io::Result<()> {
+    let path = c_path(path)?;
+    if unsafe { setxattr(path.as_ptr(), NAME.as_ptr() as *const c_char, value.as_ptr(), value.len(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+#[cfg(not(target_os = "linux"))]
+pub fn get(_path: &Path) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+
+#[cfg(not(target_os = "linux"))]
+pub fn set(_path: &Path, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
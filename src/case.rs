@@ -0,0 +1,121 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::backend::Backend;
+use crate::manifest::MARKER;
+use crate::unicode;
+use crate::Options;
+
+
+
+/// What to do with an entry of the source whose name would be the same as
+/// that of another entry of its directory in the destination, which
+/// doesn't tell names apart by case (or once they are normalized, with
+/// `--normalize`)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Collisions {
+    /// Leave it out of the backup
+    #[default]
+    Skip,
+    /// Store it under a name of its own, `NAME (2).EXT`
+    Rename,
+}
+
+
+impl Collisions {
+    pub fn parse(text: &str) -> Result<Collisions, String> {
+        match text {
+            "skip" => Ok(Collisions::Skip),
+            "rename" => Ok(Collisions::Rename),
+            _ => Err(format!("invalid case collision policy '{}' (expected skip or rename)", text)),
+        }
+    }
+}
+
+
+/// An entry of a directory of the source whose name collides with that of
+/// an earlier one (in the order of names)
+pub struct Collision {
+    /// Name of the earlier entry
+    pub with: String,
+    /// Name it is stored under, unless it is skipped
+    pub stored: Option<String>,
+}
+
+
+/// Take the destination as one which doesn't tell names apart by case
+/// (as FAT, exFAT, NTFS, SMB shares and APFS by default) if its marker can
+/// be found under its name in capitals, or if it is Android storage
+pub fn detect(destination: &str, opts: &mut Options, target: &dyn Backend) {
+    if opts.case_insensitive {
+        return;
+    }
+    let marker = format!("{}/{}", destination, MARKER);
+    let capitals = format!("{}/{}", destination, MARKER.to_uppercase());
+    if opts.android || (target.metadata(&marker).is_some() && target.metadata(&capitals).is_some()) {
+        if !opts.android {
+            info!("{} doesn't tell names apart by case: looking for names that differ only in case", destination);
+        }
+        opts.case_insensitive = true;
+    }
+}
+
+
+/// A name as the destination tells it apart from others
+fn key(name: &str, opts: &Options) -> String {
+    let normalized = unicode::normalize(name, opts.normalize);
+    match opts.case_insensitive {
+        true => normalized.to_lowercase(),
+        false => normalized.into_owned(),
+    }
+}
+
+
+/// `NAME (N).EXT` for `NAME.EXT`
+fn numbered(name: &str, n: u32) -> String {
+    match name.rfind('.').filter(|&i| i > 0) {
+        Some(i) => format!("{} ({}){}", &name[..i], n, &name[i..]),
+        None => format!("{} ({})", name, n),
+    }
+}
+
+
+/// The entries of a directory of the source (given by their names) that
+/// collide with earlier ones once stored in the destination, by name. The
+/// first one of each name (in the order of names) keeps it; with
+/// `--case-collisions rename`, the others get the first numbered name
+/// that no other entry takes.
+pub fn collisions(names: &[String], opts: &Options) -> HashMap<String, Collision> {
+    let mut collisions = HashMap::new();
+    if !opts.case_insensitive && opts.normalize.is_none() {
+        return collisions;
+    }
+    let mut sorted: Vec<&String> = names.iter().collect();
+    sorted.sort();
+    let mut taken: HashSet<String> = HashSet::new();
+    let mut first: HashMap<String, &str> = HashMap::new();
+    for name in &sorted {
+        let key = key(name, opts);
+        if !first.contains_key(&key) {
+            first.insert(key.clone(), name);
+            taken.insert(key);
+        }
+    }
+    for name in sorted {
+        let with = first[&key(name, opts)];
+        if with == name.as_str() {
+            continue;
+        }
+        let stored = match opts.case_collisions {
+            Collisions::Skip => None,
+            Collisions::Rename => {
+                let renamed = (2..).map(|n| numbered(name, n)).find(|candidate| !taken.contains(&key(candidate, opts)));
+                if let Some(renamed) = &renamed {
+                    taken.insert(key(renamed, opts));
+                }
+                renamed
+            }
+        };
+        collisions.insert(name.clone(), Collision { with: with.to_string(), stored });
+    }
+    collisions
+}
@@ -0,0 +1,58 @@
+use crate::backend;
+use crate::listing;
+use crate::log;
+use crate::manifest::{Entry, Manifest};
+use crate::Options;
+
+
+
+/// Print what changed in the source since a snapshot of its destination
+/// (`since`, a name listed by `snapshots` or `latest`) or, without one,
+/// since the last run, from the manifest kept then: the entries added
+/// (`+`), modified (`M`, including those that became another type) and
+/// removed (`-`), directories ending in `/`. Nothing is backed up.
+pub fn changes(source: &str, destination: &str, since: Option<&str>, opts: &Options) -> Result<(), String> {
+    let mirror = match since {
+        Some(name) => listing::resolve(&format!("{}::{}", destination, name), opts)?,
+        None => destination.to_string(),
+    };
+    let (target, root) = backend::open(&mirror, opts)?;
+    let previous = Manifest::load(target.as_ref(), &root)
+        .ok_or_else(|| format!("{} has no manifest (backups keep one with --manifest)", mirror))?;
+    info!("Scanning {}...", source);
+    let scan = Manifest::scan(source, opts.scan_jobs, opts.one_file_system);
+    let mut changes: Vec<(char, &str, &Entry)> = Vec::new();
+    for (path, entry) in scan.entries.iter().filter(|(path, _)| *path != ".") {
+        match previous.entries.get(path) {
+            None => changes.push(('+', path, entry)),
+            // Something below a directory whose rollup differs changed,
+            // which is listed on its own
+            Some(Entry::Directory { .. }) if matches!(entry, Entry::Directory { .. }) => (),
+            Some(old) if old != entry => changes.push(('M', path, entry)),
+            Some(_) => (),
+        }
+    }
+    for (path, entry) in previous.entries.iter().filter(|(path, _)| *path != ".") {
+        if !scan.entries.contains_key(path) {
+            changes.push(('-', path, entry));
+        }
+    }
+    changes.sort_by_key(|(_, path, _)| *path);
+    for (code, path, entry) in &changes {
+        let slash = if matches!(entry, Entry::Directory { .. }) { "/" } else { "" };
+        info!("{} {}{}", code, path, slash);
+    }
+    let count = |wanted: char| changes.iter().filter(|(code, _, _)| *code == wanted).count();
+    log::separator();
+    info!(
+        "{} added, {} modified, {} removed since {}",
+        count('+'),
+        count('M'),
+        count('-'),
+        match since {
+            Some(name) => format!("snapshot {}", name),
+            None => "the last run".to_string(),
+        }
+    );
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use std::fs;
+use std::time::UNIX_EPOCH;
+
+use crate::backend::{self, Backend};
+use crate::checksums::{self, Checksums};
+use crate::engine;
+use crate::hash::{self, Digest};
+use crate::log;
+use crate::manifest::{self, META_DIR};
+use crate::schedule;
+use crate::temporary;
+use crate::Options;
+
+
+
+/// What a copy of the destination was found to be
+enum Outcome {
+    Intact,
+    Missing,
+    /// Its contents aren't those it had when backed up, although it wasn't
+    /// modified since (as far as its modification time tells): bitrot
+    Corrupted(String),
+    /// Modified since the backup, by something else than backup-rs
+    Changed(String),
+}
+
+
+/// File, inside META_DIR, with where the scrub of the destination (checks
+/// of a part of it at a time, with --percent) got: when its round started
+/// and the last file checked, files being checked in order
+const SCRUB: &str = "scrub";
+const SCRUB_HEADER: &str = "backup-rs scrub 1";
+
+
+fn scrub_path(destination: &str) -> String {
+    format!("{}/{}/{}", destination, META_DIR, SCRUB)
+}
+
+
+/// When the round of the scrub started (every file having been checked
+/// once since the one before), and the last file it checked
+fn load_scrub(target: &dyn Backend, destination: &str) -> Option<(i64, String)> {
+    let content = target.read(&scrub_path(destination)).ok()?;
+    let content = String::from_utf8_lossy(&content);
+    let mut lines = content.lines();
+    if lines.next() != Some(SCRUB_HEADER) {
+        return None;
+    }
+    let started = lines.next()?.parse().ok()?;
+    Some((started, manifest::unescape(lines.next()?)))
+}
+
+
+fn save_scrub(target: &dyn Backend, destination: &str, started: i64, last: &str) -> std::io::Result<()> {
+    let content = format!("{}\n{}\n{}\n", SCRUB_HEADER, started, manifest::escape(last));
+    target.write(&scrub_path(destination), content.as_bytes())
+}
+
+
+/// Hash a copy of the destination as it is on the disk (a local one being
+/// read past the cache), or as it is fetched from a remote one
+fn read_back(target: &dyn Backend, path: &str) -> std::io::Result<Digest> {
+    match target.local_file(path) {
+        Some(local) => engine::drop_cache(&local).and_then(|_| hash::hash_file(&local)),
+        None => {
+            let fetched = temporary::local("check");
+            let read = target.fetch(path, &fetched).and_then(|_| hash::hash_file(&fetched));
+            let _ = fs::remove_file(&fetched);
+            read
+        }
+    }
+}
+
+
+/// Check one copy against the size and hash recorded when it was copied,
+/// telling the copies modified since then (whose modification time isn't
+/// the one recorded) from those that rotted
+fn check_copy(target: &dyn Backend, destination: &str, relative: &str, checksums: &Checksums) -> Outcome {
+    let path = format!("{}/{}", destination, relative);
+    let Some(copy) = target.metadata(&path) else {
+        return Outcome::Missing;
+    };
+    let (size, digest) = checksums.get(relative).unwrap();
+    let problem = if copy.size != size {
+        format!("it is {} bytes, but was {} when backed up", copy.size, size)
+    } else {
+        match read_back(target, &path) {
+            Ok(read) if read == digest => return Outcome::Intact,
+            Ok(_) => "it doesn't match the hash it had when backed up".to_string(),
+            Err(e) => format!("cannot read it: {}", e),
+        }
+    };
+    let modified = copy.modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    match checksums.modified(relative) {
+        Some(recorded) if recorded != modified => Outcome::Changed(problem),
+        _ => Outcome::Corrupted(problem),
+    }
+}
+
+
+/// Re-hash every file of the destination recorded by the runs that backed
+/// it up (with its size, hash and modification time, in the checksums of
+/// the destination), reporting those that are missing, modified since the
+/// backup or corrupted. Returns false if any is.
+///
+/// With `percent`, only that part of the files is checked, those after the
+/// last one the previous check with `percent` got to (starting over once
+/// past the last one), so that a large destination is scrubbed over
+/// several runs.
+pub fn check(destination: &str, percent: Option<usize>, opts: &Options) -> bool {
+    log::separator();
+    match percent {
+        Some(percent) => info!("Scrubbing {}% of the copies of {}", percent, destination),
+        None => info!("Checking the copies of {}", destination),
+    }
+    log::separator();
+    let (target, destination) = match backend::open(destination, opts) {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    let target = target.as_ref();
+    let checksums = Checksums::load(target, &destination);
+    let paths = checksums.paths();
+    if paths.is_empty() {
+        error!("no hashes recorded in {} (backups record them as they copy)", checksums::path(&destination));
+        return false;
+    }
+    let total = paths.len();
+    let scrub = percent.map(|_| load_scrub(target, &destination).unwrap_or((schedule::now(), String::new())));
+    let (paths, wrapped) = match (percent, &scrub) {
+        (Some(percent), Some((started, last))) => {
+            let count = (total * percent).div_ceil(100);
+            // Past the last one, the scrub starts over
+            let next = match paths.partition_point(|path| path <= last) {
+                next if next == total => 0,
+                next => next,
+            };
+            if next > 0 {
+                info!(
+                    "Resuming the scrub started on {} ({} of {} files checked since)",
+                    schedule::local_time(*started).format(),
+                    next,
+                    total
+                );
+            }
+            let wrapped = count >= total - next;
+            let chosen: Vec<String> = paths[next..].iter().chain(&paths[..next]).take(count).cloned().collect();
+            (chosen, wrapped)
+        }
+        _ => (paths, false),
+    };
+
+    let (mut intact, mut missing, mut corrupted, mut changed) = (0, 0, 0, 0);
+    for relative in &paths {
+        match check_copy(target, &destination, relative, &checksums) {
+            Outcome::Intact => {
+                action!("Intact {}", relative);
+                intact += 1;
+            }
+            Outcome::Missing => {
+                error!("{}: missing", relative);
+                missing += 1;
+            }
+            Outcome::Corrupted(problem) => {
+                error!("{}: corrupted ({})", relative, problem);
+                corrupted += 1;
+            }
+            Outcome::Changed(problem) => {
+                error!("{}: modified since the backup ({})", relative, problem);
+                changed += 1;
+            }
+        }
+    }
+
+    if let Some((started, _)) = scrub {
+        if wrapped {
+            info!("Every file was checked since {}; the scrub starts over", schedule::local_time(started).format());
+        }
+        let started = if wrapped { schedule::now() } else { started };
+        if let Err(e) = save_scrub(target, &destination, started, paths.last().unwrap()) {
+            warning!("cannot record how far the scrub got: {}; the next one starts over", e);
+        }
+    }
+
+    log::separator();
+    info!(
+        "Checked {} of {} files: {} intact, {} corrupted, {} modified, {} missing",
+        paths.len(),
+        total,
+        intact,
+        corrupted,
+        changed,
+        missing
+    );
+    if intact < paths.len() {
+        error!("check failed");
+        return false;
+    }
+    info!("Check passed");
+    true
+}
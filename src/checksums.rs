@@ -0,0 +1,137 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use crate::backend::Backend;
+use crate::hash::{self, Digest};
+use crate::manifest::{self, META_DIR};
+
+
+
+/// File, inside META_DIR, with the size and SHA-256 of the files of the
+/// destination as they were copied (before being encrypted or compressed),
+/// for restores to check them against, and the modification time the
+/// copies were left with. The first version didn't have the times.
+const CHECKSUMS: &str = "checksums";
+const CHECKSUMS_HEADER: &str = "backup-rs checksums 2";
+const CHECKSUMS_HEADER_1: &str = "backup-rs checksums 1";
+
+
+/// Size of a copy, hash of its contents and its modification time (in
+/// seconds since the epoch), if recorded
+type Recorded = (u64, Digest, Option<i64>);
+
+
+pub fn path(destination: &str) -> String {
+    format!("{}/{}/{}", destination, META_DIR, CHECKSUMS)
+}
+
+
+/// The hashes of the files copied to the destination, kept up to date by
+/// every run as it copies, moves and removes them
+pub struct Checksums {
+    destination: String,
+    /// Relative path -> what was recorded of its copy
+    entries: RefCell<BTreeMap<String, Recorded>>,
+    changed: Cell<bool>,
+}
+
+
+impl Checksums {
+    pub fn load(target: &dyn Backend, destination: &str) -> Checksums {
+        let mut entries = BTreeMap::new();
+        if let Ok(content) = target.read(&path(destination)) {
+            let content = String::from_utf8_lossy(&content);
+            let mut lines = content.lines();
+            let fields = match lines.next() {
+                Some(CHECKSUMS_HEADER) => 4,
+                Some(CHECKSUMS_HEADER_1) => 3,
+                _ => 0,
+            };
+            for line in lines.filter(|_| fields > 0) {
+                let (digest, size, modified, relative) = match line.splitn(fields, '\t').collect::<Vec<_>>()[..] {
+                    [digest, size, modified, relative] => (digest, size, modified.parse().ok(), relative),
+                    [digest, size, relative] => (digest, size, None, relative),
+                    _ => continue,
+                };
+                if let (Some(digest), Ok(size)) = (hash::from_hex(digest), size.parse()) {
+                    entries.insert(manifest::unescape(relative), (size, digest, modified));
+                }
+            }
+        }
+        Checksums { destination: destination.to_string(), entries: RefCell::new(entries), changed: Cell::new(false) }
+    }
+
+    fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        Some(path.strip_prefix(&self.destination)?.trim_start_matches('/')).filter(|r| !r.is_empty())
+    }
+
+    /// The size and hash recorded for a path (relative to the destination)
+    pub fn get(&self, relative: &str) -> Option<(u64, Digest)> {
+        self.entries.borrow().get(relative).map(|&(size, digest, _)| (size, digest))
+    }
+
+    /// The modification time recorded for a path, the one its copy was left
+    /// with
+    pub fn modified(&self, relative: &str) -> Option<i64> {
+        self.entries.borrow().get(relative).and_then(|&(_, _, modified)| modified)
+    }
+
+    /// The paths (relative to the destination) with a size and hash
+    /// recorded, in order
+    pub fn paths(&self) -> Vec<String> {
+        self.entries.borrow().keys().cloned().collect()
+    }
+
+    /// Record the size, hash and modification time of what was just copied
+    /// to a path of the destination
+    pub fn record(&self, path: &str, size: u64, modified: i64, digest: Digest) {
+        if let Some(relative) = self.relative(path) {
+            self.entries.borrow_mut().insert(relative.to_string(), (size, digest, Some(modified)));
+            self.changed.set(true);
+        }
+    }
+
+    /// Forget a path of the destination, and everything below it
+    pub fn forget(&self, path: &str) {
+        let Some(relative) = self.relative(path) else {
+            return;
+        };
+        let below = format!("{}/", relative);
+        let mut entries = self.entries.borrow_mut();
+        let before = entries.len();
+        entries.retain(|key, _| key != relative && !key.starts_with(&below));
+        if entries.len() != before {
+            self.changed.set(true);
+        }
+    }
+
+    /// Move what is recorded for a path of the destination (and everything
+    /// below it) to another one
+    pub fn rename(&self, from: &str, to: &str) {
+        let (Some(from), Some(to)) = (self.relative(from), self.relative(to)) else {
+            return;
+        };
+        let below = format!("{}/", from);
+        let mut entries = self.entries.borrow_mut();
+        let moved: Vec<String> = entries.keys().filter(|key| *key == from || key.starts_with(&below)).cloned().collect();
+        for key in moved {
+            let value = entries.remove(&key).unwrap();
+            entries.insert(format!("{}{}", to, &key[from.len()..]), value);
+            self.changed.set(true);
+        }
+    }
+
+    pub fn save(&self, target: &dyn Backend) -> std::io::Result<()> {
+        if !self.changed.get() {
+            return Ok(());
+        }
+        let mut content = format!("{}\n", CHECKSUMS_HEADER);
+        for (relative, (size, digest, modified)) in self.entries.borrow().iter() {
+            // Copies recorded by the first version have no time
+            let modified = modified.map(|m| m.to_string()).unwrap_or_else(|| "-".to_string());
+            writeln!(content, "{}\t{}\t{}\t{}", hash::to_hex(digest), size, modified, manifest::escape(relative)).unwrap();
+        }
+        target.write(&path(&self.destination), content.as_bytes())
+    }
+}
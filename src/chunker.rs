@@ -0,0 +1,65 @@
+use std::io::{self, Read};
+
+
+
+/// Smallest chunk, except for the end of a file
+pub const MIN_SIZE: usize = 256 * 1024;
+/// Largest chunk
+pub const MAX_SIZE: usize = 4 * 1024 * 1024;
+/// A boundary is placed where the top bits of the rolling hash are all zero,
+/// which gives chunks of about 1 MiB on average
+const AVERAGE_BITS: u32 = 20;
+
+
+/// Random values for each byte, for the gear rolling hash (FastCDC). They
+/// must never change, or files would no longer be split the same way and
+/// nothing would be deduplicated against older backups.
+const GEAR: [u64; 256] = gear_table();
+
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6261_636b_7570_2d72;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+
+/// Split a stream into content-defined chunks: boundaries depend on the
+/// bytes around them only, so an insertion in a file changes the chunks
+/// around it and not every chunk after it
+pub fn split(input: &mut dyn Read, emit: &mut dyn FnMut(&[u8]) -> io::Result<()>) -> io::Result<()> {
+    let mut chunk = Vec::with_capacity(MAX_SIZE);
+    let mut hash: u64 = 0;
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for &byte in &buffer[..n] {
+            chunk.push(byte);
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            if (chunk.len() >= MIN_SIZE && hash >> (64 - AVERAGE_BITS) == 0) || chunk.len() >= MAX_SIZE {
+                emit(&chunk)?;
+                chunk.clear();
+                hash = 0;
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        emit(&chunk)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backend::{self, Backend, Metadata};
+use crate::hash;
+use crate::manifest::Entry;
+use crate::schedule;
+use crate::Options;
+
+
+
+/// How the engine tells whether the copy of a file of the source is out of
+/// date and has to be copied again. Selected with `--compare`, or plugged
+/// into `Options::comparator` by code embedding the engine.
+pub trait Comparator {
+    /// Whether the copy of the regular file `source` (whose `lstat()` is
+    /// `metadata`), `destination` (whose metadata is `copy`), is out of date
+    fn changed(
+        &self,
+        source: &str,
+        metadata: &fs::Metadata,
+        destination: &str,
+        copy: &Metadata,
+        opts: &Options,
+        target: &dyn Backend,
+    ) -> bool;
+
+    /// Why the copy of a file is out of date, once `changed` tells it is,
+    /// as printed with --explain
+    fn reason(&self, source: &str, metadata: &fs::Metadata, copy: &Metadata, opts: &Options) -> String {
+        difference(source, metadata, copy, opts).unwrap_or_else(|| "it differs from its copy".to_string())
+    }
+
+    /// Whether it needs the manifest of the last run, in `Options::previous`
+    fn needs_manifest(&self) -> bool {
+        false
+    }
+
+    /// Whether it reads the contents of the files, rather than telling from
+    /// their metadata
+    fn reads_contents(&self) -> bool {
+        false
+    }
+}
+
+
+/// Whether a file has another size than its copy, which the copies of
+/// transformed files have anyway
+fn resized(source: &str, metadata: &fs::Metadata, copy: &Metadata, opts: &Options) -> bool {
+    opts.policy.transform(source).is_none() && metadata.len() != copy.size
+}
+
+
+/// Whether a time of a file is after the modification time of its copy,
+/// give or take `--modify-window`
+fn newer(seconds: i64, nanoseconds: i64, copy: &Metadata, opts: &Options) -> bool {
+    let time = UNIX_EPOCH + Duration::new(seconds.max(0) as u64, nanoseconds as u32);
+    time > copy.modified + Duration::from_secs(opts.modify_window)
+}
+
+
+fn format_time(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    schedule::local_time(seconds).format()
+}
+
+
+/// How a file differs from its copy by size and modification time, if it
+/// does
+fn difference(source: &str, metadata: &fs::Metadata, copy: &Metadata, opts: &Options) -> Option<String> {
+    if resized(source, metadata, copy, opts) {
+        return Some(format!("size differs ({} vs {} bytes)", metadata.len(), copy.size));
+    }
+    if newer(metadata.mtime(), metadata.mtime_nsec(), copy, opts) {
+        let modified = metadata.modified().map(format_time).unwrap_or_default();
+        return Some(format!("modified after it was copied ({} vs {})", modified, format_time(copy.modified)));
+    }
+    None
+}
+
+
+/// Size and modification time: a copy is out of date if its size differs
+/// or the file was modified after it was copied (the default)
+pub struct SizeMtime;
+
+
+impl Comparator for SizeMtime {
+    fn changed(
+        &self,
+        source: &str,
+        metadata: &fs::Metadata,
+        _destination: &str,
+        copy: &Metadata,
+        opts: &Options,
+        _target: &dyn Backend,
+    ) -> bool {
+        resized(source, metadata, copy, opts) || newer(metadata.mtime(), metadata.mtime_nsec(), copy, opts)
+    }
+}
+
+
+/// Contents: a copy is out of date if its size or its hash differs, which
+/// reads every file and its copy, but catches the changes that keep the
+/// modification time. The copies of transformed files are compared as by
+/// `SizeMtime`.
+pub struct Checksum;
+
+
+impl Comparator for Checksum {
+    fn changed(
+        &self,
+        source: &str,
+        metadata: &fs::Metadata,
+        destination: &str,
+        copy: &Metadata,
+        opts: &Options,
+        target: &dyn Backend,
+    ) -> bool {
+        if opts.policy.transform(source).is_some() {
+            return SizeMtime.changed(source, metadata, destination, copy, opts, target);
+        }
+        if metadata.len() != copy.size {
+            return true;
+        }
+        match (hash::hash_file(source), backend::hash_stored(target, destination)) {
+            (Ok(digest), Ok(copy)) => digest != copy,
+            // What can't be compared is copied again
+            _ => true,
+        }
+    }
+
+    fn reason(&self, source: &str, metadata: &fs::Metadata, copy: &Metadata, opts: &Options) -> String {
+        match opts.policy.transform(source).is_some() || metadata.len() != copy.size {
+            true => SizeMtime.reason(source, metadata, copy, opts),
+            false => "contents differ (by checksum)".to_string(),
+        }
+    }
+
+    fn reads_contents(&self) -> bool {
+        true
+    }
+}
+
+
+/// Status change time: a copy is out of date if its size differs or the
+/// file changed in any way (contents, permissions, owner, links) after it
+/// was copied, even to a modification time set back (e.g. by `touch -r` or
+/// an extracted archive)
+pub struct Ctime;
+
+
+impl Comparator for Ctime {
+    fn changed(
+        &self,
+        source: &str,
+        metadata: &fs::Metadata,
+        _destination: &str,
+        copy: &Metadata,
+        opts: &Options,
+        _target: &dyn Backend,
+    ) -> bool {
+        resized(source, metadata, copy, opts) || newer(metadata.ctime(), metadata.ctime_nsec(), copy, opts)
+    }
+
+    fn reason(&self, source: &str, metadata: &fs::Metadata, copy: &Metadata, opts: &Options) -> String {
+        match resized(source, metadata, copy, opts) {
+            true => SizeMtime.reason(source, metadata, copy, opts),
+            false => {
+                let changed = UNIX_EPOCH + Duration::from_secs(metadata.ctime().max(0) as u64);
+                format!("changed after it was copied (at {})", format_time(changed))
+            }
+        }
+    }
+}
+
+
+/// The manifest of the last run: a copy is out of date if the file isn't
+/// as the manifest has it (size and modification time, to the nanosecond),
+/// whatever the time of the copy. Files it has no record of (or as
+/// something else) are compared as by `SizeMtime`.
+pub struct ManifestComparator;
+
+
+impl Comparator for ManifestComparator {
+    fn changed(
+        &self,
+        source: &str,
+        metadata: &fs::Metadata,
+        destination: &str,
+        copy: &Metadata,
+        opts: &Options,
+        target: &dyn Backend,
+    ) -> bool {
+        let relative = source.strip_prefix(&opts.policy.root).unwrap_or(source).trim_start_matches('/');
+        let entry = opts.previous.as_ref().and_then(|previous| previous.entries.get(relative));
+        match entry {
+            Some(Entry::File { size, mtime, mtime_nsec }) => {
+                resized(source, metadata, copy, opts)
+                    || metadata.len() != *size
+                    || metadata.mtime() != *mtime
+                    || metadata.mtime_nsec() != *mtime_nsec
+            }
+            _ => SizeMtime.changed(source, metadata, destination, copy, opts, target),
+        }
+    }
+
+    fn reason(&self, source: &str, metadata: &fs::Metadata, copy: &Metadata, opts: &Options) -> String {
+        let relative = source.strip_prefix(&opts.policy.root).unwrap_or(source).trim_start_matches('/');
+        match opts.previous.as_ref().and_then(|previous| previous.entries.get(relative)) {
+            Some(Entry::File { size, .. }) if !resized(source, metadata, copy, opts) => match metadata.len() != *size {
+                true => format!("size differs from the last run ({} vs {} bytes)", metadata.len(), size),
+                false => "modified since the last run, as the manifest tells".to_string(),
+            },
+            _ => SizeMtime.reason(source, metadata, copy, opts),
+        }
+    }
+
+    fn needs_manifest(&self) -> bool {
+        true
+    }
+}
+
+
+/// Comparator named on the command line or in the configuration
+pub fn parse(name: &str) -> Result<Rc<dyn Comparator>, String> {
+    match name {
+        "size-mtime" => Ok(Rc::new(SizeMtime)),
+        "checksum" => Ok(Rc::new(Checksum)),
+        "ctime" => Ok(Rc::new(Ctime)),
+        "manifest" => Ok(Rc::new(ManifestComparator)),
+        _ => Err(format!("unknown comparison '{}' (expected size-mtime, checksum, ctime or manifest)", name)),
+    }
+}
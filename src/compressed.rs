@@ -0,0 +1,374 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::backend::{Backend, Metadata};
+use crate::manifest::{self, META_DIR};
+use crate::policy::Policy;
+use crate::temporary;
+use crate::Options;
+
+
+
+/// File, inside META_DIR, with the original size of each compressed file,
+/// which is needed to compare it with the source
+const INDEX: &str = "compressed";
+const INDEX_HEADER: &str = "backup-rs compressed 1";
+
+
+/// Compressor used for the files of the destination
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Zstd(u32),
+    Gzip(u32),
+}
+
+
+impl Compression {
+    /// Parse `zstd[:LEVEL]` or `gzip[:LEVEL]`
+    pub fn parse(text: &str) -> Result<Compression, String> {
+        let (name, level) = match text.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (text, None),
+        };
+        let level = |default: u32, max: u32| match level {
+            None => Ok(default),
+            Some(level) => match level.parse::<u32>() {
+                Ok(n) if (1..=max).contains(&n) => Ok(n),
+                _ => Err(format!("invalid {} compression level '{}' (1-{})", name, level, max)),
+            },
+        };
+        match name {
+            "zstd" => Ok(Compression::Zstd(level(3, 19)?)),
+            "gzip" => Ok(Compression::Gzip(level(6, 9)?)),
+            _ => Err(format!("invalid compression '{}' (expected zstd or gzip)", text)),
+        }
+    }
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            Compression::Zstd(_) => ".zst",
+            Compression::Gzip(_) => ".gz",
+        }
+    }
+
+    fn from_suffix(suffix: &str) -> Option<Compression> {
+        match suffix {
+            ".zst" => Some(Compression::Zstd(3)),
+            ".gz" => Some(Compression::Gzip(6)),
+            _ => None,
+        }
+    }
+
+    pub fn program(&self) -> &'static str {
+        match self {
+            Compression::Zstd(_) => "zstd",
+            Compression::Gzip(_) => "gzip",
+        }
+    }
+
+    /// Command running the compressor (or decompressor), writing to its
+    /// standard output
+    pub fn command(&self, decompress: bool) -> Command {
+        let mut command = Command::new(self.program());
+        command.arg("-c").arg("-q");
+        if decompress {
+            command.arg("-d");
+        } else {
+            match self {
+                Compression::Zstd(level) | Compression::Gzip(level) => {
+                    command.arg(format!("-{}", level))
+                }
+            };
+        }
+        command
+    }
+
+    /// Run the compressor (or decompressor) on a file, writing to another
+    fn run(&self, decompress: bool, input: &str, output: &str) -> io::Result<()> {
+        let status = self
+            .command(decompress)
+            .arg("--")
+            .arg(input)
+            .stdout(fs::File::create(output)?)
+            .stderr(Stdio::inherit())
+            .status()
+            .map_err(|e| io::Error::new(e.kind(), format!("cannot run {}: {}", self.program(), e)))?;
+        if !status.success() {
+            return Err(io::Error::other(format!("{} failed on {}", self.program(), input)));
+        }
+        Ok(())
+    }
+}
+
+
+/// Store the files of the destination compressed, with a suffix after their
+/// name. The index, keyed by path relative to the root, tells which files
+/// are compressed and their original size; files missing from it (e.g.
+/// after an interrupted run) show up with their suffix, and so are replaced.
+pub struct CompressedBackend {
+    inner: Box<dyn Backend>,
+    root: String,
+    /// Compressor for new files, if compression is enabled
+    compression: Option<Compression>,
+    policy: Policy,
+    /// Relative path -> (original size, suffix)
+    index: RefCell<BTreeMap<String, (u64, String)>>,
+    changed: RefCell<bool>,
+}
+
+
+fn index_path(root: &str) -> String {
+    format!("{}/{}/{}", root, META_DIR, INDEX)
+}
+
+
+/// Wrap the storage of a destination if it has (or is to get) compressed
+/// files
+pub fn wrap(inner: Box<dyn Backend>, root: &str, opts: &Options) -> Result<Box<dyn Backend>, String> {
+    let content = inner
+        .read(&index_path(root))
+        .ok()
+        .map(|data| String::from_utf8_lossy(&data).to_string());
+    if content.is_none() && opts.compress.is_none() {
+        return Ok(inner);
+    }
+    if let Some(compression) = opts.compress {
+        // Fail early rather than on every file
+        Command::new(compression.program())
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map_err(|e| format!("cannot run {}: {}", compression.program(), e))?;
+    }
+    let mut index = BTreeMap::new();
+    if let Some(content) = content {
+        let mut lines = content.lines();
+        if lines.next() != Some(INDEX_HEADER) {
+            return Err(format!("invalid {}", index_path(root)));
+        }
+        for line in lines {
+            let mut fields = line.splitn(3, '\t');
+            if let (Some(size), Some(suffix), Some(path)) = (fields.next(), fields.next(), fields.next()) {
+                if let Ok(size) = size.parse() {
+                    index.insert(manifest::unescape(path), (size, suffix.to_string()));
+                }
+            }
+        }
+    }
+    Ok(Box::new(CompressedBackend {
+        inner,
+        root: root.to_string(),
+        compression: opts.compress,
+        policy: opts.policy.clone(),
+        index: RefCell::new(index),
+        changed: RefCell::new(false),
+    }))
+}
+
+
+impl CompressedBackend {
+    fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(&self.root)?.strip_prefix('/')
+    }
+
+    /// The original size and the suffix of a compressed file
+    fn lookup(&self, path: &str) -> Option<(u64, String)> {
+        self.index.borrow().get(self.relative(path)?).cloned()
+    }
+
+    fn forget(&self, path: &str) {
+        if let Some(relative) = self.relative(path) {
+            let prefix = format!("{}/", relative);
+            self.index
+                .borrow_mut()
+                .retain(|p, _| p != relative && !p.starts_with(&prefix));
+            *self.changed.borrow_mut() = true;
+        }
+    }
+
+}
+
+
+impl Backend for CompressedBackend {
+    fn metadata(&self, path: &str) -> Option<Metadata> {
+        match self.lookup(path) {
+            Some((size, suffix)) => {
+                let mut metadata = self.inner.metadata(&format!("{}{}", path, suffix))?;
+                metadata.size = size;
+                Some(metadata)
+            }
+            None => self.inner.metadata(path),
+        }
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let names = self.inner.list(path)?;
+        let index = self.index.borrow();
+        let relative = match self.relative(path) {
+            Some(relative) => format!("{}/", relative),
+            None => String::new(),
+        };
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                for suffix in [".zst", ".gz"] {
+                    if let Some(original) = name.strip_suffix(suffix) {
+                        if index.get(&format!("{}{}", relative, original)).map(|e| e.1.as_str()) == Some(suffix) {
+                            return original.to_string();
+                        }
+                    }
+                }
+                name
+            })
+            .collect())
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.inner.create_dir(path)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        match self.lookup(path) {
+            Some((_, suffix)) => {
+                self.inner.remove_file(&format!("{}{}", path, suffix))?;
+                self.forget(path);
+                Ok(())
+            }
+            None => self.inner.remove_file(path),
+        }
+    }
+
+    fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        self.inner.remove_dir_all(path)?;
+        self.forget(path);
+        Ok(())
+    }
+
+    fn copy_file(&self, source: &str, path: &str) -> io::Result<()> {
+        // Replace the other form of the file, if any
+        if let Some((_, suffix)) = self.lookup(path) {
+            self.inner.remove_file(&format!("{}{}", path, suffix))?;
+            self.forget(path);
+        } else if self.inner.metadata(path).is_some() {
+            self.inner.remove_file(path)?;
+        }
+        let compression = match self.compression {
+            Some(c) if self.policy.attributes(source).compress => c,
+            _ => return self.inner.copy_file(source, path),
+        };
+        let relative = match self.relative(path) {
+            Some(relative) => relative.to_string(),
+            None => return self.inner.copy_file(source, path),
+        };
+        let size = fs::metadata(source)?.len();
+        let temporary = temporary::local("compressed");
+        let result = compression
+            .run(false, source, &temporary)
+            .and_then(|_| self.inner.copy_file(&temporary, &format!("{}{}", path, compression.suffix())));
+        let _ = fs::remove_file(&temporary);
+        result?;
+        self.index
+            .borrow_mut()
+            .insert(relative, (size, compression.suffix().to_string()));
+        *self.changed.borrow_mut() = true;
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, path: &str) -> io::Result<()> {
+        if self.lookup(path).is_some() {
+            self.remove_file(path)?;
+        }
+        self.inner.symlink(target, path)
+    }
+
+    fn mknod(&self, path: &str, mode: u32, device: u64) -> io::Result<()> {
+        if self.lookup(path).is_some() {
+            self.remove_file(path)?;
+        }
+        self.inner.mknod(path, mode, device)
+    }
+
+    fn set_directory_metadata(&self, path: &str, source: &fs::Metadata) -> io::Result<()> {
+        self.inner.set_directory_metadata(path, source)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        if let Some((size, suffix)) = self.lookup(from) {
+            self.inner.rename(&format!("{}{}", from, suffix), &format!("{}{}", to, suffix))?;
+            self.forget(from);
+            if let Some(relative) = self.relative(to) {
+                self.index.borrow_mut().insert(relative.to_string(), (size, suffix));
+            }
+            return Ok(());
+        }
+        self.inner.rename(from, to)?;
+        if let (Some(from), Some(to)) = (self.relative(from), self.relative(to)) {
+            let prefix = format!("{}/", from);
+            let mut index = self.index.borrow_mut();
+            let moved: Vec<String> = index.keys().filter(|p| p.starts_with(&prefix)).cloned().collect();
+            for path in moved {
+                let entry = index.remove(&path).unwrap();
+                index.insert(format!("{}/{}", to, &path[prefix.len()..]), entry);
+            }
+            *self.changed.borrow_mut() = true;
+        }
+        Ok(())
+    }
+
+    fn sync(&self, path: &str) -> io::Result<()> {
+        match self.lookup(path) {
+            Some((_, suffix)) => self.inner.sync(&format!("{}{}", path, suffix)),
+            None => self.inner.sync(path),
+        }
+    }
+
+    fn fetch(&self, path: &str, local: &str) -> io::Result<()> {
+        let (_, suffix) = match self.lookup(path) {
+            Some(entry) => entry,
+            None => return self.inner.fetch(path, local),
+        };
+        let compression = Compression::from_suffix(&suffix)
+            .ok_or_else(|| io::Error::other(format!("unknown compression {}", suffix)))?;
+        let temporary = temporary::beside(local);
+        let result = self
+            .inner
+            .fetch(&format!("{}{}", path, suffix), &temporary)
+            .and_then(|_| compression.run(true, &temporary, local));
+        let _ = fs::remove_file(&temporary);
+        result
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.inner.write(path, data)
+    }
+
+    fn is_local(&self) -> bool {
+        self.inner.is_local()
+    }
+
+    fn small_file_size(&self) -> Option<u64> {
+        self.inner.small_file_size()
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        if *self.changed.borrow() {
+            let mut content = format!("{}\n", INDEX_HEADER);
+            for (path, (size, suffix)) in self.index.borrow().iter() {
+                writeln!(content, "{}\t{}\t{}", size, suffix, manifest::escape(path)).unwrap();
+            }
+            self.inner.write(&index_path(&self.root), content.as_bytes())?;
+            *self.changed.borrow_mut() = false;
+        }
+        self.inner.finish()
+    }
+}
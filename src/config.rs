@@ -0,0 +1,569 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::budget::{self, Budget};
+use crate::case::Collisions;
+use crate::compare;
+use crate::compressed::Compression;
+use crate::mail::{self, Mail, MailOn};
+use crate::order::Order;
+use crate::policy::Policy;
+use crate::preconditions::Precondition;
+use crate::schedule;
+use crate::snapshot::SnapshotKind;
+use crate::stream;
+use crate::symlinks::SymlinkPolicy;
+use crate::throttle;
+use crate::unicode::Form;
+use crate::uring;
+use crate::MAX_JOBS;
+
+
+
+/// A value in the configuration file
+#[derive(Clone, Debug)]
+pub enum Value {
+    String(String),
+    Boolean(bool),
+    Integer(i64),
+    Array(Vec<Value>),
+}
+
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "a string",
+            Value::Boolean(_) => "a boolean",
+            Value::Integer(_) => "an integer",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+
+/// The keys and values of one `[section]` of the configuration file
+pub type Table = BTreeMap<String, Value>;
+
+
+/// A backup job, defined in a `[job.NAME]` section
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub name: String,
+    pub source: String,
+    pub destination: String,
+    /// Cron-like schedule (`minute hour day-of-month month day-of-week`)
+    pub schedule: Option<String>,
+    pub dry_run: bool,
+    pub manifest: bool,
+    pub snapshot: Option<SnapshotKind>,
+    pub encrypt: bool,
+    pub encrypt_names: bool,
+    pub key_file: Option<String>,
+    /// Per-path attributes, from `PATTERN attribute:value,...` rules, and
+    /// the patterns of the excluded paths
+    pub policy: Policy,
+    pub delta: bool,
+    pub detect_renames: bool,
+    pub trust_manifest: bool,
+    pub itemize_changes: bool,
+    pub one_file_system: bool,
+    pub nfs: bool,
+    pub android: bool,
+    /// Seconds apart timestamps can be and still be taken as equal
+    pub modify_window: Option<u64>,
+    pub atomic: bool,
+    pub sanitize_names: bool,
+    pub normalize: Option<Form>,
+    pub case_collisions: Collisions,
+    pub ignore_space: bool,
+    pub verify_writes: bool,
+    pub fsync: bool,
+    pub fsync_at_end: bool,
+    pub sorted: bool,
+    pub emergency_prune: bool,
+    /// Wait this many seconds for space when the destination is full
+    pub wait_for_space: Option<i64>,
+    pub symlinks: SymlinkPolicy,
+    pub devices: bool,
+    pub specials: bool,
+    /// What must hold for the source to be backed up at all
+    pub preconditions: Vec<Precondition>,
+    /// Compressor for the files written to the destination
+    pub compress: Option<Compression>,
+    /// Size up to which files are batched into packs
+    pub batch_small_files: Option<u64>,
+    /// Keep the files missing from the source for this many days before
+    /// removing them from the destination
+    pub delete_after_days: Option<u64>,
+    /// Keep this many previous versions of the files overwritten
+    pub versions: Option<usize>,
+    /// Flag the runs that change more than this
+    pub change_budget: Option<Budget>,
+    /// Give up on the paths of the source that don't answer in this many
+    /// seconds
+    pub timeout: Option<i64>,
+    /// Bytes per second the copies are limited to
+    pub bwlimit: Option<u64>,
+    pub buffer_size: Option<usize>,
+    /// Whether the local files go through io_uring (`engine = "uring"`)
+    pub uring: bool,
+    pub order: Option<Order>,
+    /// File to write the paths that couldn't be backed up to, as JSON
+    pub error_report: Option<String>,
+    /// Times to try again the copies that fail with a transient error, and
+    /// milliseconds to wait before each
+    pub retries: u32,
+    pub retry_delay: Option<u64>,
+    /// Threads scanning the source, and threads copying files
+    pub scan_jobs: usize,
+    pub copy_jobs: usize,
+    /// How to tell whether the copy of a file is out of date, as for
+    /// `compare::parse`
+    pub compare: Option<String>,
+    /// Watch the source between runs, so that runs only sync what changed
+    pub prewarm: bool,
+    /// With prewarm, how often (in seconds) to do a full run anyway
+    pub full_scan_interval: i64,
+    /// Shell commands run before and after each run
+    pub pre_hook: Option<String>,
+    pub post_hook: Option<String>,
+    /// URL to POST a summary of each run to, as for `--notify-url`
+    pub notify_url: Option<String>,
+    pub notify_desktop: bool,
+    /// Where and when to mail the outcome of each run
+    pub mail: Option<Mail>,
+    /// File to write the metrics of each run to, as for `--metrics-file`
+    pub metrics_file: Option<String>,
+}
+
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub jobs: Vec<Job>,
+}
+
+
+/// Default location of the configuration file
+pub fn default_path() -> String {
+    match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => format!("{}/backup-rs/config.toml", dir),
+        _ => {
+            let home = std::env::var("HOME").unwrap_or_default();
+            format!("{}/.config/backup-rs/config.toml", home)
+        }
+    }
+}
+
+
+/// Parse a quoted string starting at the beginning of `text`, returning the
+/// string and the rest of the text
+fn parse_string(text: &str) -> Result<(String, &str), String> {
+    let quote = text.chars().next().unwrap();
+    let mut value = String::new();
+    let mut chars = text[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == quote {
+            return Ok((value, &text[i + 2..]));
+        }
+        if c == '\\' && quote == '"' {
+            match chars.next() {
+                Some((_, 'n')) => value.push('\n'),
+                Some((_, 't')) => value.push('\t'),
+                Some((_, '\\')) => value.push('\\'),
+                Some((_, '"')) => value.push('"'),
+                Some((_, c)) => return Err(format!("invalid escape sequence '\\{}'", c)),
+                None => break,
+            }
+        } else {
+            value.push(c);
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+
+const UNTERMINATED_ARRAY: &str = "unterminated array";
+
+
+/// Parse a value starting at the beginning of `text`, returning the value
+/// and the rest of the text
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    let text = text.trim_start();
+    if text.starts_with('"') || text.starts_with('\'') {
+        let (s, rest) = parse_string(text)?;
+        return Ok((Value::String(s), rest));
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            // Comments may follow the elements of a multi-line array
+            if rest.starts_with('#') {
+                rest = rest.find('\n').map_or("", |i| &rest[i..]);
+                continue;
+            }
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            if rest.is_empty() {
+                return Err(UNTERMINATED_ARRAY.to_string());
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') && !rest.starts_with('#') && !rest.is_empty() {
+                return Err("expected ',' or ']' in array".to_string());
+            }
+        }
+    }
+    let end = text
+        .find(|c: char| c == '#' || c.is_whitespace())
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    match word {
+        "true" => Ok((Value::Boolean(true), rest)),
+        "false" => Ok((Value::Boolean(false), rest)),
+        _ => match word.replace('_', "").parse::<i64>() {
+            Ok(n) if !word.starts_with('_') && !word.ends_with('_') => Ok((Value::Integer(n), rest)),
+            _ => Err(format!("invalid value '{}'", word)),
+        },
+    }
+}
+
+
+/// Parse the contents of a configuration file (a subset of TOML) into its
+/// sections
+pub fn parse(content: &str) -> Result<BTreeMap<String, Table>, String> {
+    let mut sections: BTreeMap<String, Table> = BTreeMap::new();
+    let mut current = String::new();
+    sections.insert(current.clone(), Table::new());
+    let mut lines = content.lines().enumerate();
+    while let Some((number, line)) = lines.next() {
+        let error = |message: String| format!("line {}: {}", number + 1, message);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            let header = match header.split_once(']') {
+                Some((name, rest)) if rest.trim().is_empty() || rest.trim().starts_with('#') => name,
+                _ => return Err(error("invalid section header".to_string())),
+            };
+            current = header.trim().to_string();
+            if sections.contains_key(&current) {
+                return Err(error(format!("duplicate section [{}]", current)));
+            }
+            sections.insert(current.clone(), Table::new());
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some(kv) => kv,
+            None => return Err(error("expected 'key = value'".to_string())),
+        };
+        let key = key.trim().trim_matches('"').to_string();
+        // Arrays may span several lines
+        let mut value = value.to_string();
+        let (value, rest) = loop {
+            match parse_value(&value) {
+                Err(e) if e == UNTERMINATED_ARRAY => match lines.next() {
+                    Some((_, next)) => value = format!("{}\n{}", value, next),
+                    None => return Err(error(e)),
+                },
+                result => break result.map_err(error)?,
+            }
+        };
+        let rest = rest.trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(error(format!("unexpected text after value: '{}'", rest)));
+        }
+        let table = sections.get_mut(&current).unwrap();
+        if table.insert(key.clone(), value).is_some() {
+            return Err(error(format!("duplicate key '{}'", key)));
+        }
+    }
+    Ok(sections)
+}
+
+
+fn get_string(table: &Table, section: &str, key: &str) -> Result<Option<String>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(v) => Err(format!("[{}] {} must be a string, not {}", section, key, v.type_name())),
+    }
+}
+
+
+fn get_strings(table: &Table, section: &str, key: &str) -> Result<Vec<String>, String> {
+    match table.get(key) {
+        None => Ok(Vec::new()),
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|v| match v {
+                Value::String(s) => Ok(s.clone()),
+                v => Err(format!("[{}] {} must hold strings, not {}", section, key, v.type_name())),
+            })
+            .collect(),
+        Some(v) => Err(format!("[{}] {} must be an array, not {}", section, key, v.type_name())),
+    }
+}
+
+
+fn get_bool(table: &Table, section: &str, key: &str) -> Result<Option<bool>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::Boolean(b)) => Ok(Some(*b)),
+        Some(v) => Err(format!("[{}] {} must be a boolean, not {}", section, key, v.type_name())),
+    }
+}
+
+
+fn get_integer(table: &Table, section: &str, key: &str) -> Result<Option<i64>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::Integer(n)) => Ok(Some(*n)),
+        Some(v) => Err(format!("[{}] {} must be an integer, not {}", section, key, v.type_name())),
+    }
+}
+
+
+impl Config {
+    /// Load the configuration from a file
+    pub fn load(path: &str) -> Result<Config, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path, e))?;
+        Config::from_content(&content).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn from_content(content: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        for (section, table) in parse(content)? {
+            let name = match section.strip_prefix("job.") {
+                Some(name) => name.to_string(),
+                None if section.is_empty() && table.is_empty() => continue,
+                None => return Err(format!("unknown section [{}]", section)),
+            };
+            for key in table.keys() {
+                match key.as_str() {
+                    "source" | "destination" | "schedule" | "dry" | "manifest" | "snapshot"
+                    | "encrypt" | "encrypt_names" | "key_file" | "policies" | "prewarm"
+                    | "full_scan_interval" | "compress" | "batch_small_files" | "delete_after_days" | "versions"
+                    | "change_budget" | "timeout" | "bwlimit" | "buffer_size" | "engine" | "order" | "error_report" | "retries" | "retry_delay" | "compare"
+                    | "detect_renames" | "trust_manifest" | "require" | "exclude" | "itemize_changes" | "one_file_system" | "nfs" | "android" | "modify_window" | "atomic"
+                    | "allow_empty_source" | "transform" | "ignore_space" | "verify_writes" | "fsync" | "fsync_at_end" | "sorted" | "emergency_prune"
+                    | "wait_for_space" | "symlinks" | "devices" | "specials" | "scan_jobs" | "copy_jobs" | "sanitize_names"
+                    | "normalize" | "case_collisions" | "pre_hook" | "post_hook" | "notify_url" | "notify_desktop"
+                    | "mail_to" | "mail_on" | "mail_server" | "metrics_file"
+                    | "delta" => (),
+                    _ => return Err(format!("[{}] unknown key '{}'", section, key)),
+                }
+            }
+            let required = |key: &str| match get_string(&table, &section, key) {
+                Ok(Some(s)) => Ok(s),
+                Ok(None) => Err(format!("[{}] missing '{}'", section, key)),
+                Err(e) => Err(e),
+            };
+            if get_bool(&table, &section, "detect_renames")? == Some(true)
+                && get_bool(&table, &section, "manifest")? != Some(true)
+            {
+                return Err(format!("[{}] detect_renames needs manifest = true", section));
+            }
+            if get_bool(&table, &section, "trust_manifest")? == Some(true)
+                && get_bool(&table, &section, "manifest")? != Some(true)
+            {
+                return Err(format!("[{}] trust_manifest needs manifest = true", section));
+            }
+            config.jobs.push(Job {
+                source: required("source")?,
+                destination: required("destination")?,
+                schedule: get_string(&table, &section, "schedule")?,
+                dry_run: get_bool(&table, &section, "dry")?.unwrap_or(false),
+                manifest: get_bool(&table, &section, "manifest")?.unwrap_or(false),
+                snapshot: match get_string(&table, &section, "snapshot")? {
+                    Some(kind) => Some(SnapshotKind::parse(&kind)
+                        .map_err(|e| format!("[{}] {}", section, e))?),
+                    None => None,
+                },
+                encrypt: get_bool(&table, &section, "encrypt")?.unwrap_or(false),
+                encrypt_names: get_bool(&table, &section, "encrypt_names")?.unwrap_or(false),
+                key_file: get_string(&table, &section, "key_file")?,
+                policy: {
+                    let mut policy = Policy::default();
+                    for rule in get_strings(&table, &section, "policies")? {
+                        policy.add(&rule).map_err(|e| format!("[{}] {}", section, e))?;
+                    }
+                    for pattern in get_strings(&table, &section, "exclude")? {
+                        policy.exclude(&pattern);
+                    }
+                    for rule in get_strings(&table, &section, "transform")? {
+                        policy.add_transform(&rule).map_err(|e| format!("[{}] {}", section, e))?;
+                    }
+                    policy
+                },
+                preconditions: {
+                    let mut preconditions = get_strings(&table, &section, "require")?
+                        .iter()
+                        .map(|text| Precondition::parse(text).map_err(|e| format!("[{}] {}", section, e)))
+                        .collect::<Result<Vec<Precondition>, String>>()?;
+                    if !get_bool(&table, &section, "allow_empty_source")?.unwrap_or(false) {
+                        preconditions.push(Precondition::NotEmpty);
+                    }
+                    preconditions
+                },
+                delta: get_bool(&table, &section, "delta")?.unwrap_or(false),
+                detect_renames: get_bool(&table, &section, "detect_renames")?.unwrap_or(false),
+                trust_manifest: get_bool(&table, &section, "trust_manifest")?.unwrap_or(false),
+                itemize_changes: get_bool(&table, &section, "itemize_changes")?.unwrap_or(false),
+                one_file_system: get_bool(&table, &section, "one_file_system")?.unwrap_or(false),
+                nfs: get_bool(&table, &section, "nfs")?.unwrap_or(false),
+                modify_window: match get_integer(&table, &section, "modify_window")? {
+                    Some(seconds) if seconds < 0 => {
+                        return Err(format!("[{}] modify_window must not be negative", section))
+                    }
+                    seconds => seconds.map(|s| s as u64),
+                },
+                android: get_bool(&table, &section, "android")?.unwrap_or(false),
+                atomic: get_bool(&table, &section, "atomic")?.unwrap_or(false),
+                sanitize_names: get_bool(&table, &section, "sanitize_names")?.unwrap_or(false),
+                normalize: match get_string(&table, &section, "normalize")? {
+                    Some(text) => Some(Form::parse(&text).map_err(|e| format!("[{}] {}", section, e))?),
+                    None => None,
+                },
+                case_collisions: match get_string(&table, &section, "case_collisions")? {
+                    Some(text) => Collisions::parse(&text).map_err(|e| format!("[{}] {}", section, e))?,
+                    None => Collisions::default(),
+                },
+                ignore_space: get_bool(&table, &section, "ignore_space")?.unwrap_or(false),
+                verify_writes: get_bool(&table, &section, "verify_writes")?.unwrap_or(false),
+                fsync: get_bool(&table, &section, "fsync")?.unwrap_or(false),
+                fsync_at_end: get_bool(&table, &section, "fsync_at_end")?.unwrap_or(false),
+                sorted: get_bool(&table, &section, "sorted")?.unwrap_or(false),
+                emergency_prune: get_bool(&table, &section, "emergency_prune")?.unwrap_or(false),
+                wait_for_space: match get_string(&table, &section, "wait_for_space")? {
+                    Some(text) => match schedule::parse_duration(&text).map_err(|e| format!("[{}] {}", section, e))? {
+                        0 => return Err(format!("[{}] wait_for_space must be positive", section)),
+                        seconds => Some(seconds),
+                    },
+                    None => None,
+                },
+                symlinks: match get_string(&table, &section, "symlinks")? {
+                    Some(text) => SymlinkPolicy::parse(&text).map_err(|e| format!("[{}] {}", section, e))?,
+                    None => SymlinkPolicy::default(),
+                },
+                devices: get_bool(&table, &section, "devices")?.unwrap_or(false),
+                specials: get_bool(&table, &section, "specials")?.unwrap_or(false),
+                compress: match get_string(&table, &section, "compress")? {
+                    Some(text) => Some(Compression::parse(&text)
+                        .map_err(|e| format!("[{}] {}", section, e))?),
+                    None => None,
+                },
+                batch_small_files: match get_string(&table, &section, "batch_small_files")? {
+                    Some(text) => Some(
+                        budget::parse_size(&text).ok_or_else(|| format!("[{}] invalid batch_small_files '{}'", section, text))?,
+                    ),
+                    None => None,
+                },
+                delete_after_days: match get_integer(&table, &section, "delete_after_days")? {
+                    Some(days) if days < 0 => {
+                        return Err(format!("[{}] delete_after_days must not be negative", section))
+                    }
+                    days => days.map(|d| d as u64),
+                },
+                versions: match get_integer(&table, &section, "versions")? {
+                    Some(count) if count < 1 => {
+                        return Err(format!("[{}] versions must be at least 1", section))
+                    }
+                    count => count.map(|c| c as usize),
+                },
+                change_budget: match get_string(&table, &section, "change_budget")? {
+                    Some(text) => Some(Budget::parse(&text).map_err(|e| format!("[{}] {}", section, e))?),
+                    None => None,
+                },
+                timeout: match get_string(&table, &section, "timeout")? {
+                    Some(text) => match schedule::parse_duration(&text).map_err(|e| format!("[{}] {}", section, e))? {
+                        0 => return Err(format!("[{}] timeout must be positive", section)),
+                        seconds => Some(seconds),
+                    },
+                    None => None,
+                },
+                bwlimit: match get_string(&table, &section, "bwlimit")? {
+                    Some(text) => Some(throttle::parse_rate(&text).map_err(|e| format!("[{}] {}", section, e))?),
+                    None => None,
+                },
+                buffer_size: match get_string(&table, &section, "buffer_size")? {
+                    Some(text) => Some(stream::parse_buffer_size(&text).map_err(|e| format!("[{}] {}", section, e))?),
+                    None => None,
+                },
+                uring: match get_string(&table, &section, "engine")? {
+                    Some(text) => uring::parse_engine(&text).map_err(|e| format!("[{}] {}", section, e))?,
+                    None => false,
+                },
+                order: match get_string(&table, &section, "order")? {
+                    Some(text) => Some(Order::parse(&text).map_err(|e| format!("[{}] {}", section, e))?),
+                    None => None,
+                },
+                error_report: get_string(&table, &section, "error_report")?,
+                retries: match get_integer(&table, &section, "retries")? {
+                    Some(retries) if !(0..=u32::MAX as i64).contains(&retries) => {
+                        return Err(format!("[{}] retries must not be negative", section))
+                    }
+                    retries => retries.unwrap_or(0) as u32,
+                },
+                retry_delay: match get_integer(&table, &section, "retry_delay")? {
+                    Some(ms) if ms < 0 => return Err(format!("[{}] retry_delay must not be negative", section)),
+                    ms => ms.map(|ms| ms as u64),
+                },
+                scan_jobs: match get_integer(&table, &section, "scan_jobs")? {
+                    Some(jobs) if !(1..=MAX_JOBS as i64).contains(&jobs) => {
+                        return Err(format!("[{}] scan_jobs must be from 1 to {}", section, MAX_JOBS))
+                    }
+                    jobs => jobs.unwrap_or(1) as usize,
+                },
+                copy_jobs: match get_integer(&table, &section, "copy_jobs")? {
+                    Some(jobs) if !(1..=MAX_JOBS as i64).contains(&jobs) => {
+                        return Err(format!("[{}] copy_jobs must be from 1 to {}", section, MAX_JOBS))
+                    }
+                    jobs => jobs.unwrap_or(1) as usize,
+                },
+                compare: match get_string(&table, &section, "compare")? {
+                    Some(name) => {
+                        let comparator = compare::parse(&name).map_err(|e| format!("[{}] {}", section, e))?;
+                        if comparator.needs_manifest() && get_bool(&table, &section, "manifest")? != Some(true) {
+                            return Err(format!("[{}] compare = \"manifest\" needs manifest = true", section));
+                        }
+                        Some(name)
+                    }
+                    None => None,
+                },
+                prewarm: get_bool(&table, &section, "prewarm")?.unwrap_or(false),
+                full_scan_interval: match get_string(&table, &section, "full_scan_interval")? {
+                    Some(text) => schedule::parse_duration(&text)
+                        .map_err(|e| format!("[{}] {}", section, e))?,
+                    None => 24 * 3600,
+                },
+                pre_hook: get_string(&table, &section, "pre_hook")?,
+                post_hook: get_string(&table, &section, "post_hook")?,
+                notify_url: get_string(&table, &section, "notify_url")?,
+                notify_desktop: get_bool(&table, &section, "notify_desktop")?.unwrap_or(false),
+                metrics_file: get_string(&table, &section, "metrics_file")?,
+                mail: match get_string(&table, &section, "mail_to")? {
+                    Some(to) => Some(Mail {
+                        to,
+                        on: match get_string(&table, &section, "mail_on")? {
+                            Some(text) => MailOn::parse(&text).map_err(|e| format!("[{}] {}", section, e))?,
+                            None => MailOn::default(),
+                        },
+                        server: get_string(&table, &section, "mail_server")?
+                            .unwrap_or_else(|| mail::DEFAULT_SERVER.to_string()),
+                    }),
+                    None => None,
+                },
+                name,
+            });
+        }
+        Ok(config)
+    }
+}
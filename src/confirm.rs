@@ -0,0 +1,82 @@
+use std::fmt;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+
+
+/// An entry of the destination about to be removed
+#[derive(Clone)]
+pub struct Removal {
+    pub path: String,
+    pub is_directory: bool,
+}
+
+
+impl fmt::Display for Removal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.path, if self.is_directory { "/" } else { "" })
+    }
+}
+
+
+/// Ask a question on the terminal, returning the answer, or None once the
+/// input is closed
+fn prompt(question: &str) -> Option<String> {
+    print!("{} ", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    match io::stdin().lock().read_line(&mut answer) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(answer.trim().to_lowercase()),
+    }
+}
+
+
+/// Go through entries one by one, asking which ones to remove
+fn ask_each(entries: Vec<Removal>) -> Vec<Removal> {
+    let mut approved = Vec::new();
+    let mut entries = entries.into_iter();
+    while let Some(entry) = entries.next() {
+        let question = format!("Remove {}? [y]es, [n]o, [a]ll the rest, [q]uit:", entry);
+        loop {
+            match prompt(&question).as_deref() {
+                Some("y") | Some("yes") => approved.push(entry.clone()),
+                Some("n") | Some("no") => (),
+                Some("a") | Some("all") => {
+                    approved.push(entry.clone());
+                    approved.extend(entries.by_ref());
+                }
+                Some("q") | Some("quit") | None => return approved,
+                Some(_) => continue,
+            }
+            break;
+        }
+    }
+    approved
+}
+
+
+/// List the entries of the destination about to be removed and ask
+/// whether to go ahead, returning those to remove. Nothing is removed
+/// without a terminal to ask on.
+pub fn ask(mut entries: Vec<Removal>) -> Vec<Removal> {
+    if entries.is_empty() {
+        return entries;
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    info!("{} entries of the destination are missing from the source:", entries.len());
+    for entry in &entries {
+        info!("  {}", entry);
+    }
+    if !io::stdin().is_terminal() {
+        warning!("no terminal to confirm the removals on; not removing anything");
+        return Vec::new();
+    }
+    loop {
+        match prompt("Remove them? [y]es, [n]o, [i]ndividually:").as_deref() {
+            Some("y") | Some("yes") => return entries,
+            Some("n") | Some("no") | Some("") | None => return Vec::new(),
+            Some("i") | Some("individually") => return ask_each(entries),
+            Some(_) => (),
+        }
+    }
+}
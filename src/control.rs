@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::pause;
+use crate::progress;
+use crate::report::json_string;
+
+
+
+/// How often the progress is streamed to the clients asking for events
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+
+/// Whether a control socket is served
+static SERVING: AtomicBool = AtomicBool::new(false);
+
+
+/// Connections the events are streamed to
+static SUBSCRIBERS: Mutex<Vec<UnixStream>> = Mutex::new(Vec::new());
+
+
+/// Jobs asked to run now, by name
+static TRIGGERED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+
+/// Listen on a control socket, replacing a stale one (left by a process
+/// that is gone), only the owner being allowed to connect
+pub fn bind(path: &str) -> Result<UnixListener, String> {
+    if fs::symlink_metadata(path).is_ok() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!("another backup-rs is listening on {}", path));
+        }
+        fs::remove_file(path).map_err(|e| format!("cannot remove {}: {}", path, e))?;
+    }
+    let listener = UnixListener::bind(path).map_err(|e| format!("cannot listen on {}: {}", path, e))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("cannot set the permissions of {}: {}", path, e))?;
+    Ok(listener)
+}
+
+
+/// Send an event (a JSON object) to the connections streaming them
+pub fn event(json: &str) {
+    if !SERVING.load(Ordering::SeqCst) {
+        return;
+    }
+    let line = format!("{}\n", json);
+    SUBSCRIBERS.lock().unwrap().retain_mut(|stream| stream.write_all(line.as_bytes()).is_ok());
+}
+
+
+/// The jobs asked to run now (by the `trigger` command) since the last
+/// call
+pub fn take_triggered() -> Vec<String> {
+    std::mem::take(&mut *TRIGGERED.lock().unwrap())
+}
+
+
+/// Whether a control socket is served, so that triggered jobs are to be
+/// looked for
+pub fn serving() -> bool {
+    SERVING.load(Ordering::SeqCst)
+}
+
+
+/// A request: a JSON object of strings, numbers and booleans (kept as
+/// their text), on a line
+fn parse(line: &str) -> Result<BTreeMap<String, String>, String> {
+    let invalid = || format!("invalid request (expected a JSON object): {}", line);
+    let mut chars = line.trim().chars().peekable();
+    let mut fields = BTreeMap::new();
+    let skip_spaces = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    let string = |chars: &mut std::iter::Peekable<std::str::Chars>| -> Option<String> {
+        let mut text = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(text),
+                '\\' => match chars.next()? {
+                    'n' => text.push('\n'),
+                    't' => text.push('\t'),
+                    'u' => {
+                        let code: String = chars.by_ref().take(4).collect();
+                        text.push(char::from_u32(u32::from_str_radix(&code, 16).ok()?)?);
+                    }
+                    c => text.push(c),
+                },
+                c => text.push(c),
+            }
+        }
+    };
+    if chars.next() != Some('{') {
+        return Err(invalid());
+    }
+    skip_spaces(&mut chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return Ok(fields);
+    }
+    loop {
+        skip_spaces(&mut chars);
+        if chars.next() != Some('"') {
+            return Err(invalid());
+        }
+        let key = string(&mut chars).ok_or_else(invalid)?;
+        skip_spaces(&mut chars);
+        if chars.next() != Some(':') {
+            return Err(invalid());
+        }
+        skip_spaces(&mut chars);
+        let value = match chars.next_if_eq(&'"') {
+            Some(_) => string(&mut chars).ok_or_else(invalid)?,
+            None => {
+                let mut text = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || "+-.".contains(*c)) {
+                    text.push(c);
+                }
+                text
+            }
+        };
+        fields.insert(key, value);
+        skip_spaces(&mut chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some('}') => return Ok(fields),
+            _ => return Err(invalid()),
+        }
+    }
+}
+
+
+/// The answer to a request, as a JSON object
+fn answer(request: &BTreeMap<String, String>, jobs: &[String], stream: &UnixStream) -> Result<String, String> {
+    match request.get("command").map(String::as_str) {
+        Some("status") => Ok(match progress::snapshot() {
+            Some(snapshot) => format!("{{\"ok\": true, \"running\": true, {}}}", snapshot.json_fields()),
+            None => "{\"ok\": true, \"running\": false}".to_string(),
+        }),
+        Some("pause") => {
+            pause::set(true);
+            Ok("{\"ok\": true}".to_string())
+        }
+        Some("resume") => {
+            pause::set(false);
+            Ok("{\"ok\": true}".to_string())
+        }
+        Some("cancel") => {
+            if progress::snapshot().is_none() {
+                return Err("no run in progress".to_string());
+            }
+            warning!("the run was cancelled through the control socket");
+            progress::cancel();
+            Ok("{\"ok\": true}".to_string())
+        }
+        Some("trigger") => {
+            if jobs.is_empty() {
+                return Err("only the daemon runs jobs".to_string());
+            }
+            let job = request.get("job").ok_or("missing \"job\"")?;
+            if !jobs.contains(job) {
+                return Err(format!("no scheduled job named '{}'", job));
+            }
+            info!("Job '{}' triggered through the control socket", job);
+            TRIGGERED.lock().unwrap().push(job.clone());
+            Ok("{\"ok\": true}".to_string())
+        }
+        Some("events") => {
+            let subscriber = stream.try_clone().map_err(|e| e.to_string())?;
+            SUBSCRIBERS.lock().unwrap().push(subscriber);
+            Ok("{\"ok\": true}".to_string())
+        }
+        Some(command) => Err(format!("unknown command '{}'", command)),
+        None => Err("missing \"command\"".to_string()),
+    }
+}
+
+
+/// Answer the requests of a connection, one per line, until it is closed
+fn handle(stream: UnixStream, jobs: &[String]) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream.try_clone()?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match parse(&line).and_then(|request| answer(&request, jobs, &stream)) {
+            Ok(response) => response,
+            Err(e) => format!("{{\"ok\": false, \"error\": {}}}", json_string(&e)),
+        };
+        writeln!(writer, "{}", response)?;
+    }
+    Ok(())
+}
+
+
+/// Serve the control socket in threads of their own: the status of the
+/// run in progress, pausing, resuming and cancelling it, running one of
+/// `jobs` (those of the daemon) now, and streaming its progress
+pub fn serve(listener: UnixListener, jobs: Vec<String>) {
+    SERVING.store(true, Ordering::SeqCst);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let jobs = jobs.clone();
+            std::thread::spawn(move || {
+                let _ = handle(stream, &jobs);
+            });
+        }
+    });
+    std::thread::spawn(|| loop {
+        std::thread::sleep(EVENT_INTERVAL);
+        if let Some(snapshot) = progress::snapshot() {
+            event(&format!("{{\"event\": \"progress\", {}}}", snapshot.json_fields()));
+        }
+    });
+}
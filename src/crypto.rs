@@ -0,0 +1,582 @@
+use std::io::{self, Read, Write};
+
+use crate::hash::{Digest, Sha256};
+
+
+
+/// Plaintext bytes per chunk of an encrypted file
+const CHUNK: usize = 64 * 1024;
+const TAG: usize = 16;
+const MAGIC: &[u8; 16] = b"backup-rs enc 1\n";
+const SALT: usize = 16;
+
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+
+/// One 64-byte block of the ChaCha20 keystream (RFC 8439)
+fn chacha20_block(key: &[u8; 32], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for i in 0..8 {
+        state[4 + i] = le32(&key[4 * i..]);
+    }
+    state[12] = counter;
+    for i in 0..3 {
+        state[13 + i] = le32(&nonce[4 * i..]);
+    }
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+    let mut block = [0u8; 64];
+    for i in 0..16 {
+        let word = working[i].wrapping_add(state[i]);
+        block[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    block
+}
+
+
+/// Encrypt or decrypt `data` in place with ChaCha20
+fn chacha20_xor(key: &[u8; 32], counter: u32, nonce: &[u8; 12], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (byte, k) in chunk.iter_mut().zip(block) {
+            *byte ^= k;
+        }
+    }
+}
+
+
+/// Poly1305 one-time authenticator (RFC 8439), with 26-bit limbs
+fn poly1305(key: &[u8; 32], message: &[u8]) -> [u8; 16] {
+    const MASK: u32 = 0x3ffffff;
+    let r0 = le32(&key[0..]) & 0x3ffffff;
+    let r1 = (le32(&key[3..]) >> 2) & 0x3ffff03;
+    let r2 = (le32(&key[6..]) >> 4) & 0x3ffc0ff;
+    let r3 = (le32(&key[9..]) >> 6) & 0x3f03fff;
+    let r4 = (le32(&key[12..]) >> 8) & 0x00fffff;
+    let (s1, s2, s3, s4) = (r1 * 5, r2 * 5, r3 * 5, r4 * 5);
+    let (mut h0, mut h1, mut h2, mut h3, mut h4) = (0u32, 0u32, 0u32, 0u32, 0u32);
+
+    for chunk in message.chunks(16) {
+        let mut block = [0u8; 17];
+        block[..chunk.len()].copy_from_slice(chunk);
+        // Full blocks get the 2^128 bit; partial ones are padded with a 1
+        let high = if chunk.len() == 16 {
+            1 << 24
+        } else {
+            block[chunk.len()] = 1;
+            0
+        };
+        h0 += le32(&block[0..]) & MASK;
+        h1 += (le32(&block[3..]) >> 2) & MASK;
+        h2 += (le32(&block[6..]) >> 4) & MASK;
+        h3 += (le32(&block[9..]) >> 6) & MASK;
+        h4 += (le32(&block[12..]) >> 8) | high;
+
+        let m = |a: u32, b: u32| a as u64 * b as u64;
+        let d0 = m(h0, r0) + m(h1, s4) + m(h2, s3) + m(h3, s2) + m(h4, s1);
+        let mut d1 = m(h0, r1) + m(h1, r0) + m(h2, s4) + m(h3, s3) + m(h4, s2);
+        let mut d2 = m(h0, r2) + m(h1, r1) + m(h2, r0) + m(h3, s4) + m(h4, s3);
+        let mut d3 = m(h0, r3) + m(h1, r2) + m(h2, r1) + m(h3, r0) + m(h4, s4);
+        let mut d4 = m(h0, r4) + m(h1, r3) + m(h2, r2) + m(h3, r1) + m(h4, r0);
+        h0 = d0 as u32 & MASK;
+        d1 += d0 >> 26;
+        h1 = d1 as u32 & MASK;
+        d2 += d1 >> 26;
+        h2 = d2 as u32 & MASK;
+        d3 += d2 >> 26;
+        h3 = d3 as u32 & MASK;
+        d4 += d3 >> 26;
+        h4 = d4 as u32 & MASK;
+        h0 += (d4 >> 26) as u32 * 5;
+        h1 += h0 >> 26;
+        h0 &= MASK;
+    }
+
+    // Fully carry h, then compute h - p and keep it if it isn't negative
+    h2 += h1 >> 26;
+    h1 &= MASK;
+    h3 += h2 >> 26;
+    h2 &= MASK;
+    h4 += h3 >> 26;
+    h3 &= MASK;
+    h0 += (h4 >> 26) * 5;
+    h4 &= MASK;
+    h1 += h0 >> 26;
+    h0 &= MASK;
+    let mut g0 = h0.wrapping_add(5);
+    let mut g1 = h1.wrapping_add(g0 >> 26);
+    g0 &= MASK;
+    let mut g2 = h2.wrapping_add(g1 >> 26);
+    g1 &= MASK;
+    let mut g3 = h3.wrapping_add(g2 >> 26);
+    g2 &= MASK;
+    let g4 = h4.wrapping_add(g3 >> 26).wrapping_sub(1 << 26);
+    g3 &= MASK;
+    let select = (g4 >> 31).wrapping_sub(1);
+    let keep = !select;
+    h0 = (h0 & keep) | (g0 & select);
+    h1 = (h1 & keep) | (g1 & select);
+    h2 = (h2 & keep) | (g2 & select);
+    h3 = (h3 & keep) | (g3 & select);
+    h4 = (h4 & keep) | (g4 & select);
+
+    let words = [
+        h0 | (h1 << 26),
+        (h1 >> 6) | (h2 << 20),
+        (h2 >> 12) | (h3 << 14),
+        (h3 >> 18) | (h4 << 8),
+    ];
+    let mut tag = [0u8; 16];
+    let mut carry = 0u64;
+    for i in 0..4 {
+        let sum = words[i] as u64 + le32(&key[16 + 4 * i..]) as u64 + carry;
+        tag[4 * i..4 * i + 4].copy_from_slice(&(sum as u32).to_le_bytes());
+        carry = sum >> 32;
+    }
+    tag
+}
+
+
+/// Tag of ChaCha20-Poly1305 over additional data (none, but for the test
+/// vectors) and a ciphertext
+fn aead_tag(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let block = chacha20_block(key, 0, nonce);
+    let one_time_key: [u8; 32] = block[..32].try_into().unwrap();
+    let mut data = aad.to_vec();
+    data.resize(aad.len().div_ceil(16) * 16, 0);
+    data.extend_from_slice(ciphertext);
+    data.resize(data.len() + ciphertext.len().div_ceil(16) * 16 - ciphertext.len(), 0);
+    data.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+    data.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    poly1305(&one_time_key, &data)
+}
+
+
+/// Encrypt `data` in place with ChaCha20-Poly1305, appending the tag
+fn seal(key: &[u8; 32], nonce: &[u8; 12], data: &mut Vec<u8>) {
+    chacha20_xor(key, 1, nonce, data);
+    let tag = aead_tag(key, nonce, &[], data);
+    data.extend_from_slice(&tag);
+}
+
+
+/// Check the tag of `data` and decrypt it in place, returning false if it
+/// was tampered with or the key is wrong
+fn open(key: &[u8; 32], nonce: &[u8; 12], data: &mut Vec<u8>) -> bool {
+    if data.len() < TAG {
+        return false;
+    }
+    let tag = data.split_off(data.len() - TAG);
+    let expected = aead_tag(key, nonce, &[], data);
+    // Compare in constant time
+    if tag.iter().zip(expected).fold(0, |acc, (a, b)| acc | (a ^ b)) != 0 {
+        return false;
+    }
+    chacha20_xor(key, 1, nonce, data);
+    true
+}
+
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Digest {
+    let mut block = [0u8; 64];
+    if key.len() > 64 {
+        let mut hasher = Sha256::new();
+        hasher.update(key);
+        block[..32].copy_from_slice(&hasher.finish());
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(&block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(&block.map(|b| b ^ 0x5c));
+    outer.update(&inner.finish());
+    outer.finish()
+}
+
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+
+/// URL-safe base64 without padding, which is also safe in file names
+fn base64_encode(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..chunk.len() + 1 {
+            text.push(BASE64[(n >> (18 - 6 * i)) as usize & 63] as char);
+        }
+    }
+    text
+}
+
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.as_bytes().chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let value = BASE64.iter().position(|b| b == c)? as u32;
+            n |= value << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            data.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(data)
+}
+
+
+/// Read random bytes from the kernel
+pub fn random_bytes(buffer: &mut [u8]) -> io::Result<()> {
+    std::fs::File::open("/dev/urandom")?.read_exact(buffer)
+}
+
+
+/// Fill `buffer` from `input` up to `size` bytes, stopping early at the end
+/// of the input
+fn fill(input: &mut dyn Read, buffer: &mut Vec<u8>, size: usize) -> io::Result<()> {
+    buffer.clear();
+    input.take(size as u64).read_to_end(buffer)?;
+    Ok(())
+}
+
+
+/// Nonce of a chunk: its big-endian index, and whether it is the last one,
+/// so that chunks can be neither reordered nor dropped (as in age's STREAM)
+fn chunk_nonce(index: u64, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[3..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+
+/// Keys derived from the key file
+#[derive(Clone)]
+pub struct Keys {
+    content: [u8; 32],
+    name_mac: [u8; 32],
+    name: [u8; 32],
+    link: [u8; 32],
+    /// Stored in the destination to detect a wrong key
+    pub check: Digest,
+}
+
+
+impl Keys {
+    /// Derive the keys from the contents of a key file
+    pub fn from_secret(secret: &[u8]) -> Keys {
+        let mut hasher = Sha256::new();
+        hasher.update(secret);
+        let master = hasher.finish();
+        Keys {
+            content: hmac_sha256(&master, b"backup-rs content"),
+            name_mac: hmac_sha256(&master, b"backup-rs name mac"),
+            name: hmac_sha256(&master, b"backup-rs name"),
+            link: hmac_sha256(&master, b"backup-rs link"),
+            check: hmac_sha256(&master, b"backup-rs check"),
+        }
+    }
+
+    /// Encrypt a stream: a random salt, from which the key of the file is
+    /// derived, followed by chunks of ChaCha20-Poly1305
+    pub fn encrypt(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let mut salt = [0u8; SALT];
+        random_bytes(&mut salt)?;
+        let key = hmac_sha256(&self.content, &salt);
+        output.write_all(MAGIC)?;
+        output.write_all(&salt)?;
+        let (mut current, mut next) = (Vec::new(), Vec::new());
+        fill(input, &mut current, CHUNK)?;
+        let mut index = 0;
+        loop {
+            fill(input, &mut next, CHUNK)?;
+            let last = next.is_empty();
+            seal(&key, &chunk_nonce(index, last), &mut current);
+            output.write_all(&current)?;
+            if last {
+                return Ok(());
+            }
+            std::mem::swap(&mut current, &mut next);
+            index += 1;
+        }
+    }
+
+    pub fn decrypt(&self, input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+        let mut header = [0u8; 16 + SALT];
+        input.read_exact(&mut header).map_err(|_| invalid("not an encrypted file"))?;
+        if &header[..16] != MAGIC {
+            return Err(invalid("not an encrypted file"));
+        }
+        let key = hmac_sha256(&self.content, &header[16..]);
+        let (mut current, mut next) = (Vec::new(), Vec::new());
+        fill(input, &mut current, CHUNK + TAG)?;
+        let mut index = 0;
+        loop {
+            fill(input, &mut next, CHUNK + TAG)?;
+            let last = next.is_empty();
+            if !open(&key, &chunk_nonce(index, last), &mut current) {
+                return Err(invalid("corrupted file or wrong key"));
+            }
+            output.write_all(&current)?;
+            if last {
+                return Ok(());
+            }
+            std::mem::swap(&mut current, &mut next);
+            index += 1;
+        }
+    }
+
+    pub fn encrypt_bytes(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        self.encrypt(&mut &data[..], &mut output)?;
+        Ok(output)
+    }
+
+    pub fn decrypt_bytes(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        self.decrypt(&mut &data[..], &mut output)?;
+        Ok(output)
+    }
+
+    /// Encrypt a file name. The encryption is deterministic (the nonce is
+    /// derived from the name), so that the same name always maps to the
+    /// same encrypted name.
+    pub fn encrypt_name(&self, name: &str) -> String {
+        let siv = hmac_sha256(&self.name_mac, name.as_bytes());
+        let nonce: [u8; 12] = siv[..12].try_into().unwrap();
+        let mut data = name.as_bytes().to_vec();
+        chacha20_xor(&self.name, 0, &nonce, &mut data);
+        let mut encrypted = siv[..16].to_vec();
+        encrypted.extend_from_slice(&data);
+        base64_encode(&encrypted)
+    }
+
+    pub fn decrypt_name(&self, encrypted: &str) -> Option<String> {
+        let data = base64_decode(encrypted)?;
+        if data.len() < 16 {
+            return None;
+        }
+        let nonce: [u8; 12] = data[..12].try_into().unwrap();
+        let mut name = data[16..].to_vec();
+        chacha20_xor(&self.name, 0, &nonce, &mut name);
+        let name = String::from_utf8(name).ok()?;
+        let siv = hmac_sha256(&self.name_mac, name.as_bytes());
+        if siv[..16] != data[..16] {
+            return None;
+        }
+        Some(name)
+    }
+
+    /// Encrypt the target of a symlink, which is stored as the target of
+    /// the symlink in the destination
+    pub fn encrypt_link(&self, target: &str) -> io::Result<String> {
+        let mut nonce = [0u8; 12];
+        random_bytes(&mut nonce)?;
+        let mut data = target.as_bytes().to_vec();
+        seal(&self.link, &nonce, &mut data);
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend_from_slice(&data);
+        Ok(base64_encode(&encrypted))
+    }
+
+    pub fn decrypt_link(&self, encrypted: &str) -> Option<String> {
+        let data = base64_decode(encrypted)?;
+        if data.len() < 12 {
+            return None;
+        }
+        let nonce: [u8; 12] = data[..12].try_into().unwrap();
+        let mut target = data[12..].to_vec();
+        if !open(&self.link, &nonce, &mut target) {
+            return None;
+        }
+        String::from_utf8(target).ok()
+    }
+}
+
+
+/// Size of a file once decrypted, given its encrypted size
+pub fn plaintext_size(encrypted: u64) -> u64 {
+    let body = encrypted.saturating_sub((MAGIC.len() + SALT) as u64);
+    let chunks = body.div_ceil((CHUNK + TAG) as u64).max(1);
+    body.saturating_sub(chunks * TAG as u64)
+}
+
+
+/// Longest file name that still fits in 255 bytes once encrypted
+pub const MAX_NAME: usize = 255 * 3 / 4 - 16;
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        let text: String = text.split_whitespace().collect();
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn array<const N: usize>(text: &str) -> [u8; N] {
+        hex(text).try_into().unwrap()
+    }
+
+    /// Key 00 01 02 ... 1f
+    fn sequence_key() -> [u8; 32] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    #[test]
+    fn chacha20_block_rfc8439() {
+        // Section 2.3.2
+        let nonce = array("00000009 0000004a 00000000");
+        assert_eq!(
+            chacha20_block(&sequence_key(), 1, &nonce).to_vec(),
+            hex("10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e
+                 d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e")
+        );
+        // Appendix A.1, test vectors 1 and 3
+        assert_eq!(
+            chacha20_block(&[0; 32], 0, &[0; 12]).to_vec(),
+            hex("76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7
+                 da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586")
+        );
+        let mut key = [0; 32];
+        key[31] = 1;
+        assert_eq!(
+            chacha20_block(&key, 1, &[0; 12]).to_vec(),
+            hex("3aeb5224ecf849929b9d828db1ced4dd832025e8018b8160b82284f3c949aa5a
+                 8eca00bbb4a73bdad192b5c42f73f2fd4e273644c8b36125a64addeb006c13a0")
+        );
+    }
+
+    #[test]
+    fn poly1305_rfc8439() {
+        // Section 2.5.2
+        let key = array("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+        assert_eq!(
+            poly1305(&key, b"Cryptographic Forum Research Group").to_vec(),
+            hex("a8061dc1305136c6c22b8baf0c0127a9")
+        );
+        // Appendix A.3, test vectors 1 and 5 to 9 (the limbs' carries)
+        let cases = [
+            ("00".repeat(32), "00".repeat(64), "00".repeat(16)),
+            (format!("02{}", "00".repeat(31)), "ff".repeat(16), format!("03{}", "00".repeat(15))),
+            (
+                format!("02{}{}", "00".repeat(15), "ff".repeat(16)),
+                format!("02{}", "00".repeat(15)),
+                format!("03{}", "00".repeat(15)),
+            ),
+            (
+                format!("01{}", "00".repeat(31)),
+                format!("{}f0{}11{}", "ff".repeat(16), "ff".repeat(15), "00".repeat(15)),
+                format!("05{}", "00".repeat(15)),
+            ),
+            (
+                format!("01{}", "00".repeat(31)),
+                format!("{}fb{}{}", "ff".repeat(16), "fe".repeat(15), "01".repeat(16)),
+                "00".repeat(16),
+            ),
+            (format!("02{}", "00".repeat(31)), format!("fd{}", "ff".repeat(15)), format!("fa{}", "ff".repeat(15))),
+        ];
+        for (key, message, tag) in cases {
+            assert_eq!(poly1305(&array(&key), &hex(&message)).to_vec(), hex(&tag), "key {}", key);
+        }
+    }
+
+    #[test]
+    fn aead_rfc8439() {
+        // Section 2.8.2
+        let key: [u8; 32] = std::array::from_fn(|i| 0x80 + i as u8);
+        let nonce = array("07000000 4041424344454647");
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let mut data = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+        chacha20_xor(&key, 1, &nonce, &mut data);
+        assert_eq!(
+            data,
+            hex("d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6
+                 3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36
+                 92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc
+                 3ff4def08e4b7a9de576d26586cec64b6116")
+        );
+        assert_eq!(aead_tag(&key, &nonce, &aad, &data).to_vec(), hex("1ae10b594f09e26a7e902ecbd0600691"));
+        // Appendix A.4, test vector 1: the one-time Poly1305 key
+        assert_eq!(
+            chacha20_block(&[0; 32], 0, &[0; 12])[..32].to_vec(),
+            hex("76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7")
+        );
+    }
+
+    #[test]
+    fn round_trip_at_chunk_boundaries() {
+        let keys = Keys::from_secret(b"secret");
+        for size in [0, CHUNK - 1, CHUNK, CHUNK + 1] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let encrypted = keys.encrypt_bytes(&data).unwrap();
+            assert_eq!(plaintext_size(encrypted.len() as u64), size as u64, "size {}", size);
+            assert_eq!(keys.decrypt_bytes(&encrypted).unwrap(), data, "size {}", size);
+        }
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let keys = Keys::from_secret(b"secret");
+        let encrypted = keys.encrypt_bytes(&vec![7; 2 * CHUNK + 1]).unwrap();
+        // Whole chunks dropped, a chunk cut short, and the header alone
+        let header = MAGIC.len() + SALT;
+        for length in [header + CHUNK + TAG, header + 2 * (CHUNK + TAG), encrypted.len() - 1, header, 0] {
+            assert!(keys.decrypt_bytes(&encrypted[..length]).is_err(), "length {}", length);
+        }
+    }
+
+    #[test]
+    fn reordered_input_is_rejected() {
+        let keys = Keys::from_secret(b"secret");
+        let data: Vec<u8> = (0..3 * CHUNK).map(|i| (i / CHUNK) as u8).collect();
+        let encrypted = keys.encrypt_bytes(&data).unwrap();
+        let header = MAGIC.len() + SALT;
+        let (first, second) = (header..header + CHUNK + TAG, header + CHUNK + TAG..header + 2 * (CHUNK + TAG));
+        let mut swapped = encrypted[..header].to_vec();
+        swapped.extend_from_slice(&encrypted[second]);
+        swapped.extend_from_slice(&encrypted[first]);
+        swapped.extend_from_slice(&encrypted[header + 2 * (CHUNK + TAG)..]);
+        assert_eq!(swapped.len(), encrypted.len());
+        assert!(keys.decrypt_bytes(&swapped).is_err());
+        assert!(Keys::from_secret(b"other").decrypt_bytes(&encrypted).is_err());
+    }
+}
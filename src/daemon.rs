@@ -0,0 +1,366 @@
+use std::fs;
+use std::net::TcpListener;
+use std::os::raw::c_int;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::android;
+use crate::compare;
+use crate::config::{Config, Job};
+use crate::control;
+use crate::hooks;
+use crate::mail;
+use crate::metrics;
+use crate::nfs;
+use crate::pause;
+use crate::progress;
+use crate::notify;
+use crate::schedule::{self, Schedule};
+use crate::status;
+use crate::stats::Stats;
+use crate::systemd;
+#[cfg(target_os = "linux")]
+use crate::run_incremental;
+#[cfg(target_os = "linux")]
+use crate::watch::{self, Watcher};
+use crate::{log, run_atomic, run_backup, Options};
+
+
+
+extern "C" {
+    fn fork() -> c_int;
+    fn setsid() -> c_int;
+    fn dup2(oldfd: c_int, newfd: c_int) -> c_int;
+}
+
+
+/// Detach from the terminal and keep running in the background
+fn detach() {
+    match unsafe { fork() } {
+        -1 => {
+            error!("cannot fork: {}", std::io::Error::last_os_error());
+            std::process::exit(1);
+        }
+        0 => (),
+        // The parent returns to the shell
+        _ => std::process::exit(0),
+    }
+    unsafe { setsid() };
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .unwrap();
+    for fd in 0..3 {
+        unsafe { dup2(null.as_raw_fd(), fd) };
+    }
+}
+
+
+/// Changes seen in the source of a job since its last run
+#[cfg(target_os = "linux")]
+struct Prewarm {
+    watcher: Watcher,
+    dirty: BTreeSet<String>,
+    /// Whether `dirty` holds every change since the last run
+    complete: bool,
+    /// When the last full run started
+    last_full: Option<i64>,
+}
+
+
+#[cfg(target_os = "linux")]
+impl Prewarm {
+    fn new(job: &Job) -> Option<Prewarm> {
+        match Watcher::new(&job.source, job.one_file_system) {
+            Ok(watcher) => Some(Prewarm {
+                watcher,
+                dirty: BTreeSet::new(),
+                complete: true,
+                last_full: None,
+            }),
+            Err(e) => {
+                warning!("job '{}': cannot watch {}: {}", job.name, job.source, e);
+                None
+            }
+        }
+    }
+
+    fn drain(&mut self) {
+        self.complete &= self.watcher.read_events(&mut self.dirty);
+    }
+
+    /// The paths that changed since the last run, or None if a full run is
+    /// needed (first run, lost events, or full scan due)
+    fn take_hints(&mut self, now: i64, full_scan_interval: i64) -> Option<BTreeSet<String>> {
+        self.drain();
+        let dirty = std::mem::take(&mut self.dirty);
+        let complete = std::mem::replace(&mut self.complete, true);
+        if !complete {
+            self.watcher.reset();
+        }
+        match self.last_full {
+            Some(last) if complete && now - last < full_scan_interval => Some(dirty),
+            _ => {
+                self.last_full = Some(now);
+                None
+            }
+        }
+    }
+}
+
+
+/// Run one job, logging its outcome instead of dying if it panics
+fn run_job(job: &Job, hints: Option<&BTreeSet<String>>) -> bool {
+    info!("Starting job '{}'", job.name);
+    let (errors, _) = log::counts();
+    systemd::status(&format!("Running job '{}'", job.name));
+    let start = std::time::Instant::now();
+    let result = std::panic::catch_unwind(|| {
+        let opts = Options {
+            dry_run: job.dry_run,
+            manifest: job.manifest,
+            snapshot: job.snapshot,
+            encrypt: job.encrypt,
+            encrypt_names: job.encrypt_names,
+            key_file: job.key_file.clone(),
+            policy: job.policy.clone(),
+            delta: job.delta,
+            detect_renames: job.detect_renames,
+            trust_manifest: job.trust_manifest,
+            itemize: job.itemize_changes,
+            one_file_system: job.one_file_system,
+            nfs: job.nfs,
+            android: job.android,
+            atomic: job.atomic,
+            sanitize_names: job.sanitize_names,
+            normalize: job.normalize,
+            case_collisions: job.case_collisions,
+            ignore_space: job.ignore_space,
+            verify_writes: job.verify_writes,
+            fsync: job.fsync,
+            fsync_at_end: job.fsync_at_end,
+            sorted: job.sorted,
+            emergency_prune: job.emergency_prune,
+            wait_for_space: job.wait_for_space.map(|seconds| Duration::from_secs(seconds as u64)),
+            symlinks: job.symlinks,
+            devices: job.devices,
+            specials: job.specials,
+            modify_window: job.modify_window.unwrap_or(match (job.android, job.nfs) {
+                (true, _) => android::MODIFY_WINDOW,
+                (false, true) => nfs::MODIFY_WINDOW,
+                (false, false) => 0,
+            }),
+            preconditions: job.preconditions.clone(),
+            compress: job.compress,
+            batch_small_files: job.batch_small_files,
+            delete_after_days: job.delete_after_days,
+            keep_versions: job.versions,
+            budget: job.change_budget,
+            timeout: job.timeout.map(|seconds| Duration::from_secs(seconds as u64)),
+            bwlimit: job.bwlimit,
+            buffer_size: job.buffer_size,
+            uring: job.uring,
+            order: job.order,
+            error_report: job.error_report.as_ref().map(PathBuf::from),
+            retries: job.retries,
+            retry_delay: job.retry_delay.map(Duration::from_millis),
+            scan_jobs: job.scan_jobs,
+            copy_jobs: job.copy_jobs,
+            // Checked when the configuration was read
+            comparator: job.compare.as_deref().and_then(|name| compare::parse(name).ok()),
+            ..Options::default()
+        };
+        let context = hooks::Context {
+            source: &job.source,
+            destination: &job.destination,
+            job: Some(&job.name),
+            dry_run: job.dry_run,
+        };
+        let run = || match hints {
+            Some(dirty) if dirty.is_empty() => {
+                info!("Nothing changed since the last run");
+                true
+            }
+            // The changes are staged in a copy of the whole destination
+            // anyway, so the hints don't help
+            _ if opts.atomic => {
+                let mut stats = Stats::new();
+                run_atomic(&job.source, &job.destination, &opts, &mut stats)
+            }
+            #[cfg(target_os = "linux")]
+            Some(dirty) => run_incremental(&job.source, &job.destination, dirty, &opts),
+            _ => {
+                let mut stats = Stats::new();
+                run_backup(&job.source, &job.destination, &opts, &mut stats)
+            }
+        };
+        metrics::around(job.metrics_file.as_deref(), &context, || {
+            mail::around(job.mail.as_ref(), &context, || {
+                notify::around(job.notify_url.as_deref(), &context, || {
+                    notify::around_desktop(job.notify_desktop, &context, || {
+                        hooks::around(job.pre_hook.as_deref(), job.post_hook.as_deref(), &context, run)
+                    })
+                })
+            })
+        })
+    });
+    match result {
+        Ok(false) => error!("job '{}' finished with problems", job.name),
+        Ok(true) => info!(
+            "Job '{}' finished in {:.1}s",
+            job.name,
+            start.elapsed().as_secs_f64()
+        ),
+        Err(_) => error!("job '{}' failed", job.name),
+    }
+    let ok = matches!(result, Ok(true));
+    let outcome = if ok { "succeeded" } else { "had problems" };
+    progress::finish(status::result(ok, log::counts().0 - errors));
+    systemd::status(&format!("Waiting for the next job (job '{}' {})", job.name, outcome));
+    ok
+}
+
+
+/// Run the scheduled jobs of the configuration file forever
+pub fn daemon(config_path: &str, foreground: bool, metrics_listen: Option<&str>, control_socket: Option<&str>) {
+    let config = match Config::load(config_path) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut jobs: Vec<(&Job, Schedule)> = Vec::new();
+    for job in &config.jobs {
+        let text = match &job.schedule {
+            Some(s) => s,
+            None => {
+                warning!("job '{}' has no schedule and will not run", job.name);
+                continue;
+            }
+        };
+        match Schedule::parse(text) {
+            Ok(schedule) => jobs.push((job, schedule)),
+            Err(e) => {
+                error!("job '{}': {}", job.name, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    if jobs.is_empty() {
+        error!("no scheduled jobs in {}", config_path);
+        std::process::exit(1);
+    }
+
+    // Bound before detaching, for the errors to show on the terminal
+    let listener = metrics_listen.map(|address| {
+        let address = if address.contains(':') { address.to_string() } else { format!("0.0.0.0:{}", address) };
+        match TcpListener::bind(&address) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("cannot listen on {}: {}", address, e);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    let control = control_socket.map(|path| match control::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    });
+
+    if !foreground {
+        detach();
+        log::use_syslog();
+    }
+    std::panic::set_hook(Box::new(|panic| error!("{}", panic)));
+    info!("Daemon started with {} scheduled job(s)", jobs.len());
+    progress::install();
+    pause::install();
+    if let Some(listener) = listener {
+        if let Ok(address) = listener.local_addr() {
+            info!("Serving the metrics at http://{}/metrics", address);
+        }
+        metrics::serve(listener);
+    }
+    if let Some(listener) = control {
+        info!("Listening for commands on {}", control_socket.unwrap_or_default());
+        control::serve(listener, jobs.iter().map(|(job, _)| job.name.clone()).collect());
+    }
+    systemd::ready();
+    systemd::status("Waiting for the next job");
+
+    // Watch the sources of the jobs that ask for it, so that their runs
+    // only sync what changed
+    #[cfg(target_os = "linux")]
+    let mut prewarm: Vec<Option<Prewarm>> = jobs
+        .iter()
+        .map(|(job, _)| if job.prewarm { Prewarm::new(job) } else { None })
+        .collect();
+    #[cfg(not(target_os = "linux"))]
+    if jobs.iter().any(|(job, _)| job.prewarm) {
+        warning!("prewarm is only supported on Linux");
+    }
+
+    let mut next: Vec<Option<i64>> = jobs
+        .iter()
+        .map(|(_, schedule)| schedule.next_after(schedule::now()))
+        .collect();
+    loop {
+        for name in control::take_triggered() {
+            if let Some((job, _)) = jobs.iter().find(|(job, _)| job.name == name) {
+                run_job(job, None);
+            }
+        }
+        let (index, when) = match next
+            .iter()
+            .enumerate()
+            .filter_map(|(i, t)| t.map(|t| (i, t)))
+            .min_by_key(|&(_, t)| t)
+        {
+            Some(n) => n,
+            None => {
+                error!("no job will ever run again");
+                std::process::exit(1);
+            }
+        };
+        let now = schedule::now();
+        if now < when {
+            // Sleep in short steps, so that suspend or clock changes don't
+            // delay the job for long (and triggered jobs don't wait)
+            let timeout = (when - now).min(if control::serving() { 1 } else { 60 });
+            #[cfg(target_os = "linux")]
+            {
+                let fds: Vec<c_int> = prewarm.iter().flatten().map(|p| p.watcher.fd()).collect();
+                if !fds.is_empty() {
+                    watch::wait_any(&fds, (timeout * 1000) as c_int);
+                    prewarm.iter_mut().flatten().for_each(Prewarm::drain);
+                    continue;
+                }
+            }
+            std::thread::sleep(Duration::from_secs(timeout as u64));
+            continue;
+        }
+        let (job, schedule) = &jobs[index];
+        #[cfg(target_os = "linux")]
+        {
+            let state = prewarm[index].as_mut();
+            let hints = state.and_then(|p| p.take_hints(now, job.full_scan_interval));
+            if !run_job(job, hints.as_ref()) {
+                // Something may not have been synced, so rescan everything
+                if let Some(state) = prewarm[index].as_mut() {
+                    state.last_full = None;
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        run_job(job, None);
+        next[index] = schedule.next_after(schedule::now().max(when));
+    }
+}
@@ -0,0 +1,92 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use crate::backend::Backend;
+use crate::manifest::{self, META_DIR};
+use crate::schedule;
+
+
+
+/// File, inside META_DIR, with the entries of the destination that are
+/// missing from the source but still kept, and since when
+const PENDING: &str = "pending-deletions";
+const PENDING_HEADER: &str = "backup-rs pending deletions 1";
+
+
+fn pending_path(destination: &str) -> String {
+    format!("{}/{}/{}", destination, META_DIR, PENDING)
+}
+
+
+/// Deletions held back for a grace period, so that a subdirectory of the
+/// source that is missing for a while (e.g. an unmounted disk) isn't wiped
+/// from the destination
+pub struct Deletions {
+    destination: String,
+    grace: i64,
+    now: i64,
+    /// Relative path -> when it was first found missing, as of the last run
+    previous: BTreeMap<String, i64>,
+    /// The entries found missing (and kept) by this run
+    missing: RefCell<BTreeMap<String, i64>>,
+}
+
+
+impl Deletions {
+    pub fn load(target: &dyn Backend, destination: &str, days: u64) -> Deletions {
+        let mut previous = BTreeMap::new();
+        if let Ok(content) = target.read(&pending_path(destination)) {
+            let content = String::from_utf8_lossy(&content);
+            let mut lines = content.lines();
+            if lines.next() == Some(PENDING_HEADER) {
+                for line in lines {
+                    if let Some((since, relative)) = line.split_once('\t') {
+                        if let Ok(since) = since.parse() {
+                            previous.insert(manifest::unescape(relative), since);
+                        }
+                    }
+                }
+            }
+        }
+        Deletions {
+            destination: destination.to_string(),
+            grace: days as i64 * 24 * 3600,
+            now: schedule::now(),
+            previous,
+            missing: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Record that a path of the destination is missing from the source.
+    /// Returns since when it has been missing if it is still to be kept.
+    pub fn keep(&self, path: &str) -> Option<i64> {
+        let relative = path.strip_prefix(&self.destination)?.trim_start_matches('/');
+        let since = self.previous.get(relative).copied().unwrap_or(self.now);
+        if self.now - since >= self.grace {
+            return None;
+        }
+        self.missing.borrow_mut().insert(relative.to_string(), since);
+        Some(since)
+    }
+
+    /// Paths (relative to the destination) kept by the last run, which
+    /// this run has to look at again even if their directory didn't change
+    pub fn pending(&self) -> impl Iterator<Item = &str> {
+        self.previous.keys().map(|k| k.as_str())
+    }
+
+    /// Write the entries still kept. Those that came back to the source are
+    /// forgotten, so that they get a full grace period if they go again.
+    pub fn save(&self, target: &dyn Backend) -> std::io::Result<()> {
+        let missing = self.missing.borrow();
+        if missing.is_empty() && self.previous.is_empty() {
+            return Ok(());
+        }
+        let mut content = format!("{}\n", PENDING_HEADER);
+        for (relative, since) in missing.iter() {
+            writeln!(content, "{}\t{}", since, manifest::escape(relative)).unwrap();
+        }
+        target.write(&pending_path(&self.destination), content.as_bytes())
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::hash::{Digest, Sha256};
+
+
+
+/// Files smaller than this are simply copied again
+pub const MIN_SIZE: u64 = 4 * 1024 * 1024;
+
+
+/// Read until `buffer` is full or the end of the input, returning the number
+/// of bytes read
+pub fn read_full(input: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+
+/// Polynomial of the CRC of POSIX `cksum`, the weak sum of the blocks
+const POLYNOMIAL: u32 = 0x04c1_1db7;
+
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ POLYNOMIAL } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+
+/// The CRC of what `crc` is the CRC of, followed by `byte`
+fn crc_push(crc: u32, byte: u8) -> u32 {
+    (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+}
+
+
+/// Product of two CRCs, as polynomials modulo that of the CRC
+fn crc_multiply(a: u32, b: u32) -> u32 {
+    let mut product = 0u32;
+    for bit in (0..32).rev() {
+        product = if product & 0x8000_0000 != 0 { (product << 1) ^ POLYNOMIAL } else { product << 1 };
+        if b & (1 << bit) != 0 {
+            product ^= a;
+        }
+    }
+    product
+}
+
+
+/// What `cksum` prints for the contents whose CRC is `crc`: their length is
+/// added to the CRC, which is then inverted
+fn cksum(mut crc: u32, length: u64) -> u32 {
+    let mut length = length;
+    while length > 0 {
+        crc = crc_push(crc, length as u8);
+        length >>= 8;
+    }
+    !crc
+}
+
+
+/// The CRC of a window of a file, rolled along it a byte at a time
+struct Rolling {
+    crc: u32,
+    /// The part of the CRC due to each byte leaving the window
+    leaving: [u32; 256],
+}
+
+
+impl Rolling {
+    fn new(window: usize) -> Rolling {
+        // Each byte leaving the window is followed by as many as are in it:
+        // its CRC times x^(8 * window)
+        let (mut shift, mut power, mut exponent) = (1u32, 0x100u32, window);
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                shift = crc_multiply(shift, power);
+            }
+            power = crc_multiply(power, power);
+            exponent >>= 1;
+        }
+        Rolling { crc: 0, leaving: std::array::from_fn(|b| crc_multiply(CRC_TABLE[b], shift)) }
+    }
+
+    fn start(&mut self, window: &[u8]) {
+        self.crc = window.iter().fold(0, |crc, &b| crc_push(crc, b));
+    }
+
+    fn roll(&mut self, leaving: u8, entering: u8) {
+        self.crc = crc_push(self.crc, entering) ^ self.leaving[leaving as usize];
+    }
+}
+
+
+/// Sums of a block of the old contents, to find it wherever it is in the
+/// new ones: the weak one as `cksum` prints it, and SHA-256
+pub struct Sums {
+    pub weak: u32,
+    pub strong: Digest,
+    pub size: u64,
+}
+
+
+/// Where each part of the new contents comes from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Run {
+    /// `count` blocks of the old contents, from block `start`
+    Old { start: u64, count: u64 },
+    /// `length` bytes of the new contents that aren't in the old ones,
+    /// from `offset`
+    Changed { offset: u64, length: u64 },
+}
+
+
+fn sha256(data: &[u8]) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+
+/// Add a run, joining it to the last one if it goes on from it
+fn push(runs: &mut Vec<Run>, run: Run) {
+    match (runs.last_mut(), run) {
+        (Some(Run::Old { start, count }), Run::Old { start: next, count: more }) if *start + *count == next => {
+            *count += more
+        }
+        _ => runs.push(run),
+    }
+}
+
+
+/// The new contents, as runs of blocks of the old ones and the data that
+/// changed in between. The blocks of the old contents (`old`, of
+/// `block_size` bytes but the last one) are found at any offset in the new
+/// ones, as rsync does: a rolling checksum of each window of `block_size`
+/// bytes is looked up among the weak sums of the old blocks, and only
+/// confirmed with SHA-256, so that data inserted or removed in the middle
+/// of a file only costs what changed.
+pub fn changed_runs(new: &mut dyn Read, old: &[Sums], block_size: usize) -> io::Result<Vec<Run>> {
+    let mut by_weak: HashMap<u32, Vec<u64>> = HashMap::new();
+    // Most windows match no block: a bit per weak sum (modulo its size) tells
+    // without a lookup
+    const FILTER_WORDS: usize = 1 << 14;
+    let bit = |weak: u32| ((weak >> 6) as usize % FILTER_WORDS, 1u64 << (weak & 63));
+    let mut filter = vec![0u64; FILTER_WORDS];
+    for (i, sums) in old.iter().enumerate().filter(|(_, sums)| sums.size == block_size as u64) {
+        by_weak.entry(sums.weak).or_default().push(i as u64);
+        let (word, mask) = bit(sums.weak);
+        filter[word] |= mask;
+    }
+    let tail = old.last().filter(|sums| sums.size < block_size as u64);
+    let mut runs: Vec<Run> = Vec::new();
+    let mut rolling = Rolling::new(block_size);
+    let mut data = Vec::with_capacity(2 * block_size);
+    // Where `data` starts in the file, where the window starts in `data`,
+    // and where the changed data not in a run yet starts in the file
+    let (mut base, mut window, mut changed) = (0u64, 0usize, 0u64);
+    let (mut rolled, mut read_all) = (false, false);
+    loop {
+        if data.len() <= window + block_size && !read_all {
+            data.drain(..window);
+            base += window as u64;
+            window = 0;
+            let filled = data.len();
+            data.resize(2 * block_size, 0);
+            let n = read_full(new, &mut data[filled..])?;
+            data.truncate(filled + n);
+            read_all = filled + n < 2 * block_size;
+        }
+        if data.len() < window + block_size {
+            break;
+        }
+        let offset = base + window as u64;
+        if !rolled {
+            rolling.start(&data[window..window + block_size]);
+            rolled = true;
+        }
+        let weak = cksum(rolling.crc, block_size as u64);
+        let (word, mask) = bit(weak);
+        if filter[word] & mask != 0 {
+            if let Some(candidates) = by_weak.get(&weak) {
+                let strong = sha256(&data[window..window + block_size]);
+                // Of the same blocks (as of zeros), the one going on from the
+                // last run, or else the one at the same offset, spares runs
+                // and moving data
+                let next = match runs.last() {
+                    Some(Run::Old { start, count }) => Some(start + count),
+                    _ => None,
+                };
+                let same = offset.is_multiple_of(block_size as u64).then_some(offset / block_size as u64);
+                let found = candidates
+                    .iter()
+                    .filter(|&&i| old[i as usize].strong == strong)
+                    .min_by_key(|&&i| (Some(i) != next, Some(i) != same, i));
+                if let Some(&i) = found {
+                    if offset > changed {
+                        push(&mut runs, Run::Changed { offset: changed, length: offset - changed });
+                    }
+                    push(&mut runs, Run::Old { start: i, count: 1 });
+                    window += block_size;
+                    changed = offset + block_size as u64;
+                    rolled = false;
+                    continue;
+                }
+            }
+        }
+        if window + block_size == data.len() {
+            break;
+        }
+        rolling.roll(data[window], data[window + block_size]);
+        window += 1;
+    }
+    // What is left is shorter than a block, unless the end was reached
+    // while rolling: the last block of the old contents may be at the end
+    let end = base + data.len() as u64;
+    if let Some(sums) = tail {
+        let rest = &data[data.len() - (sums.size as usize).min(data.len())..];
+        if end - changed >= sums.size && sha256(rest) == sums.strong {
+            let offset = end - sums.size;
+            if offset > changed {
+                push(&mut runs, Run::Changed { offset: changed, length: offset - changed });
+            }
+            push(&mut runs, Run::Old { start: old.len() as u64 - 1, count: 1 });
+            changed = end;
+        }
+    }
+    if end > changed {
+        push(&mut runs, Run::Changed { offset: changed, length: end - changed });
+    }
+    Ok(runs)
+}
+
+
+/// Reads the given ranges (offset, length) of a file, one after the other
+pub struct Ranges {
+    file: fs::File,
+    ranges: VecDeque<(u64, u64)>,
+    /// Bytes left in the current range
+    remaining: u64,
+}
+
+
+impl Ranges {
+    pub fn new(file: fs::File, ranges: impl IntoIterator<Item = (u64, u64)>) -> Ranges {
+        Ranges { file, ranges: ranges.into_iter().collect(), remaining: 0 }
+    }
+}
+
+
+impl Read for Ranges {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let (offset, length) = match self.ranges.pop_front() {
+                Some(range) => range,
+                None => return Ok(0),
+            };
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.remaining = length;
+        }
+        let length = buffer.len().min(self.remaining as usize);
+        let n = self.file.read(&mut buffer[..length])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the file shrank while being read"));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
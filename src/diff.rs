@@ -0,0 +1,197 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::backend::{self, Backend, Kind, Metadata};
+use crate::compare::{Checksum, Comparator, SizeMtime};
+use crate::log;
+use crate::manifest::{self, Manifest};
+use crate::schedule;
+use crate::Options;
+
+
+
+/// How an entry differs between the two trees
+enum Difference {
+    OnlyInA,
+    OnlyInB,
+    Contents(String),
+    /// The same contents, but modified in A after it was copied to B
+    Metadata(String),
+}
+
+
+impl Difference {
+    fn code(&self) -> char {
+        match self {
+            Difference::OnlyInA => '<',
+            Difference::OnlyInB => '>',
+            Difference::Contents(_) => 'C',
+            Difference::Metadata(_) => 'M',
+        }
+    }
+}
+
+
+/// The two trees compared, and what was found so far
+struct Trees<'a> {
+    a: &'a str,
+    b: &'a str,
+    target: &'a dyn Backend,
+    opts: &'a Options,
+    /// Entries found differing, by code
+    counts: [usize; 4],
+}
+
+
+fn kind_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::File => "file",
+        Kind::Directory => "directory",
+        Kind::Symlink => "symlink",
+        Kind::Other => "special file",
+    }
+}
+
+
+fn format_time(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    schedule::local_time(seconds).format()
+}
+
+
+/// Two modification times, to the nanosecond if they are the same to the
+/// second
+fn format_times(a: SystemTime, b: SystemTime) -> (String, String) {
+    let since = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    if since(a).as_secs() != since(b).as_secs() {
+        return (format_time(a), format_time(b));
+    }
+    let precise = |time: SystemTime| format!("{}.{:09}", format_time(time), since(time).subsec_nanos());
+    (precise(a), precise(b))
+}
+
+
+/// How the entry at `a` differs from the one at `b`, if it does. Files
+/// differ when the copy is out of date as a backup tells, by `--compare`
+/// (by size, and A modified after B, by default); those are then read to
+/// tell the files whose contents differ from those with only a later
+/// modification time. Directories are compared entry by entry instead.
+fn compare(a: &str, metadata: &fs::Metadata, entry: &Metadata, b: &str, copy: &Metadata, trees: &Trees) -> Option<Difference> {
+    let opts = trees.opts;
+    if entry.kind != copy.kind {
+        return Some(Difference::Contents(format!("a {} in A, a {} in B", kind_name(entry.kind), kind_name(copy.kind))));
+    }
+    match entry.kind {
+        Kind::Symlink if entry.target != copy.target => {
+            let target = |metadata: &Metadata| metadata.target.as_ref().map(|t| t.display().to_string()).unwrap_or_default();
+            Some(Difference::Contents(format!("points to {} in A, {} in B", target(entry), target(copy))))
+        }
+        Kind::File => {
+            if entry.size != copy.size {
+                return Some(Difference::Contents(format!("{} bytes in A, {} in B", entry.size, copy.size)));
+            }
+            let comparator = opts.comparator.as_deref().unwrap_or(&SizeMtime);
+            if !comparator.changed(a, metadata, b, copy, opts, trees.target) {
+                return None;
+            }
+            if comparator.reads_contents() || Checksum.changed(a, metadata, b, copy, opts, trees.target) {
+                return Some(Difference::Contents(Checksum.reason(a, metadata, copy, opts)));
+            }
+            let (modified, copied) = format_times(entry.modified, copy.modified);
+            Some(Difference::Metadata(format!("modified at {} in A, {} in B", modified, copied)))
+        }
+        _ => None,
+    }
+}
+
+
+/// Print an entry that differs
+fn report(relative: &str, kind: Kind, difference: Difference, trees: &mut Trees) {
+    let slash = if kind == Kind::Directory { "/" } else { "" };
+    let code = difference.code();
+    match difference {
+        Difference::Contents(why) | Difference::Metadata(why) => info!("{} {}{}: {}", code, relative, slash, why),
+        Difference::OnlyInA | Difference::OnlyInB => info!("{} {}{}", code, relative, slash),
+    }
+    trees.counts["<>CM".find(code).unwrap()] += 1;
+}
+
+
+/// Compare a directory of both trees (`relative` to their roots), in the
+/// order of the names of its entries, going into the subdirectories both
+/// have. The entries only one of them has are reported, without what they
+/// hold.
+fn diff_dir(relative: &str, trees: &mut Trees) {
+    let join = |root: &str, name: &str| match relative {
+        "" => format!("{}/{}", root, name),
+        _ => format!("{}/{}/{}", root, relative, name),
+    };
+    let directory = |root: &str| if relative.is_empty() { root.to_string() } else { format!("{}/{}", root, relative) };
+    let names_a: BTreeSet<String> = match fs::read_dir(directory(trees.a)) {
+        Ok(dir) => dir.filter_map(|entry| entry.ok()?.file_name().into_string().ok()).collect(),
+        Err(e) => return warning!("cannot read {}: {}", directory(trees.a), e),
+    };
+    let names_b: BTreeSet<String> = match trees.target.list(&directory(trees.b)) {
+        Ok(names) => names.into_iter().collect(),
+        Err(e) => return warning!("cannot read {}: {}", directory(trees.b), e),
+    };
+    for name in names_a.union(&names_b) {
+        // What backup-rs keeps in a destination isn't part of the tree
+        if relative.is_empty() && manifest::is_meta(name) {
+            continue;
+        }
+        let (a, b) = (join(trees.a, name), join(trees.b, name));
+        if trees.opts.policy.is_excluded(&a) {
+            continue;
+        }
+        let path = if relative.is_empty() { name.clone() } else { format!("{}/{}", relative, name) };
+        let metadata = fs::symlink_metadata(&a).ok();
+        let entry = metadata.as_ref().and_then(|m| backend::local_metadata(&a, m));
+        match (entry, trees.target.metadata(&b)) {
+            (None, None) => (),
+            (Some(entry), None) => report(&path, entry.kind, Difference::OnlyInA, trees),
+            (None, Some(copy)) => report(&path, copy.kind, Difference::OnlyInB, trees),
+            (Some(entry), Some(copy)) if entry.kind == Kind::Directory && copy.kind == Kind::Directory => {
+                diff_dir(&path, trees);
+            }
+            (Some(entry), Some(copy)) => {
+                if let Some(difference) = compare(&a, metadata.as_ref().unwrap(), &entry, &b, &copy, trees) {
+                    report(&path, entry.kind, difference, trees);
+                }
+            }
+        }
+    }
+}
+
+
+/// Compare two trees, the local directory A and B (a directory, or any
+/// destination), leaving out what the exclusions of A leave out: print the
+/// entries only in A (`<`) or only in B (`>`), those whose contents differ
+/// (`C`, including those of another type) and those with the same contents
+/// but modified after they were copied (`M`), directories ending in `/`. Returns
+/// whether the trees are the same.
+pub fn diff(a: &str, b: &str, opts: &Options) -> Result<bool, String> {
+    if !fs::metadata(a).is_ok_and(|m| m.is_dir()) {
+        return Err(format!("{} isn't a directory", a));
+    }
+    let (target, root) = backend::open(b, opts)?;
+    if target.metadata(&root).map(|m| m.kind) != Some(Kind::Directory) {
+        return Err(format!("{} isn't a directory", b));
+    }
+    let mut opts = opts.clone();
+    opts.policy.root = a.to_string();
+    if opts.comparator.as_ref().is_some_and(|c| c.needs_manifest()) {
+        opts.previous = Manifest::load(target.as_ref(), &root).map(Rc::new);
+    }
+    let mut trees = Trees { a, b: &root, target: target.as_ref(), opts: &opts, counts: [0; 4] };
+    diff_dir("", &mut trees);
+    let [only_a, only_b, contents, metadata] = trees.counts;
+    log::separator();
+    info!(
+        "{} only in A, {} only in B, {} with other contents, {} modified since they were copied",
+        only_a, only_b, contents, metadata
+    );
+    Ok(trees.counts == [0; 4])
+}
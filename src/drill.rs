@@ -0,0 +1,421 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::backend::{self, Backend, Kind};
+use crate::hash::{self, Digest};
+use crate::log;
+use crate::manifest::{self, Entry, Manifest, META_DIR};
+use crate::schedule;
+use crate::Options;
+
+
+
+/// Collect the relative paths of the regular files of the destination
+fn collect(target: &dyn Backend, path: &str, relative: &str, files: &mut Vec<String>) {
+    let mut names = match target.list(path) {
+        Ok(names) => names,
+        Err(_) => return,
+    };
+    names.sort();
+    for name in names {
+        if relative.is_empty() && manifest::is_meta(&name) {
+            continue;
+        }
+        let child = format!("{}/{}", path, name);
+        let child_relative = if relative.is_empty() {
+            name
+        } else {
+            format!("{}/{}", relative, name)
+        };
+        match target.metadata(&child).map(|m| m.kind) {
+            Some(Kind::Directory) => collect(target, &child, &child_relative, files),
+            Some(Kind::File) => files.push(child_relative),
+            _ => (),
+        }
+    }
+}
+
+
+/// Pick `count` random elements (reservoir sampling with a xorshift
+/// generator seeded from the clock and the pid)
+fn sample(items: Vec<String>, count: usize) -> Vec<String> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let mut state = nanos ^ ((std::process::id() as u64) << 32) | 1;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut chosen: Vec<String> = Vec::with_capacity(count);
+    for (i, item) in items.into_iter().enumerate() {
+        if chosen.len() < count {
+            chosen.push(item);
+        } else {
+            let j = (next() % (i as u64 + 1)) as usize;
+            if j < count {
+                chosen[j] = item;
+            }
+        }
+    }
+    chosen.sort();
+    chosen
+}
+
+
+enum Outcome {
+    Verified,
+    /// The file was restored but there's nothing to check it against
+    Unverified,
+    /// The source changed since the backup, so it can't be compared
+    Skipped(&'static str),
+    Failed(String),
+}
+
+
+/// Restore one file of the destination to `restored` and check it
+fn check(
+    target: &dyn Backend,
+    destination: &str,
+    source: Option<&str>,
+    manifest: Option<&Manifest>,
+    relative: &str,
+    restored: &str,
+) -> Outcome {
+    let path = format!("{}/{}", destination, relative);
+    let stored = match target.metadata(&path) {
+        Some(metadata) => metadata,
+        None => return Outcome::Failed("disappeared from the destination".to_string()),
+    };
+    if let Err(e) = target.fetch(&path, restored) {
+        return Outcome::Failed(format!("cannot restore: {}", e));
+    }
+    let restored_size = fs::metadata(restored).map(|m| m.len()).unwrap_or(0);
+    if restored_size != stored.size {
+        return Outcome::Failed(format!(
+            "restored {} bytes instead of {}",
+            restored_size, stored.size
+        ));
+    }
+    let digest = match hash::hash_file(restored) {
+        Ok(digest) => digest,
+        Err(e) => return Outcome::Failed(format!("cannot read the restored copy: {}", e)),
+    };
+
+    if manifest.is_some_and(|m| m.transforms.contains_key(relative)) {
+        return Outcome::Skipped("transformed when backed up");
+    }
+    if let Some(source) = source {
+        let source_file = format!("{}/{}", source, relative);
+        let metadata = match fs::metadata(&source_file) {
+            Ok(metadata) => metadata,
+            Err(_) => return Outcome::Skipped("no longer in the source"),
+        };
+        if metadata.len() != stored.size
+            || metadata.modified().map_or(true, |m| m > stored.modified)
+        {
+            return Outcome::Skipped("changed since the backup");
+        }
+        return match hash::hash_file(&source_file) {
+            Ok(source_digest) if source_digest == digest => Outcome::Verified,
+            Ok(_) => Outcome::Failed("restored copy differs from the source".to_string()),
+            Err(_) => Outcome::Skipped("source is unreadable"),
+        };
+    }
+    match manifest.and_then(|m| m.entries.get(relative)) {
+        Some(Entry::File { size, .. }) if *size == restored_size => Outcome::Verified,
+        Some(Entry::File { size, .. }) => Outcome::Failed(format!(
+            "restored {} bytes but the manifest records {}",
+            restored_size, size
+        )),
+        _ => Outcome::Unverified,
+    }
+}
+
+
+/// File, inside META_DIR, with how far the verification of every file of
+/// the destination got, so that an interrupted one can be resumed
+const PROGRESS: &str = "verify-progress";
+const PROGRESS_HEADER: &str = "backup-rs verify progress 1";
+
+/// How often the progress is recorded, in seconds
+const CHECKPOINT_INTERVAL: i64 = 10;
+
+
+fn progress_path(destination: &str) -> String {
+    format!("{}/{}/{}", destination, META_DIR, PROGRESS)
+}
+
+
+/// File, inside META_DIR, with the rollup hash (see manifest.rs) each
+/// directory of the source had when every file below it was last verified
+const VERIFIED: &str = "verified";
+const VERIFIED_HEADER: &str = "backup-rs verified 1";
+
+
+fn verified_path(destination: &str) -> String {
+    format!("{}/{}/{}", destination, META_DIR, VERIFIED)
+}
+
+
+fn load_verified(target: &dyn Backend, destination: &str) -> BTreeMap<String, Digest> {
+    let Ok(content) = target.read(&verified_path(destination)) else {
+        return BTreeMap::new();
+    };
+    let content = String::from_utf8_lossy(&content);
+    let mut lines = content.lines();
+    if lines.next() != Some(VERIFIED_HEADER) {
+        return BTreeMap::new();
+    }
+    lines
+        .filter_map(|line| {
+            let (rollup, path) = line.split_once('\t')?;
+            Some((manifest::unescape(path), hash::from_hex(rollup)?))
+        })
+        .collect()
+}
+
+
+fn save_verified(target: &dyn Backend, destination: &str, verified: &BTreeMap<&str, &Digest>) -> std::io::Result<()> {
+    let mut content = format!("{}\n", VERIFIED_HEADER);
+    for (path, rollup) in verified {
+        writeln!(content, "{}\t{}", hash::to_hex(rollup), manifest::escape(path)).unwrap();
+    }
+    target.write(&verified_path(destination), content.as_bytes())
+}
+
+
+/// The directories holding a file, relative to the root ("." for the root)
+fn parents(relative: &str) -> impl Iterator<Item = String> + '_ {
+    Path::new(relative).ancestors().skip(1).map(|ancestor| match ancestor.to_string_lossy().as_ref() {
+        "" => ".".to_string(),
+        ancestor => ancestor.to_string(),
+    })
+}
+
+
+/// How far a verification got: when it started, the last file it checked
+/// (files are checked in order) and what it found so far
+#[derive(Default)]
+struct Progress {
+    started: i64,
+    last: Option<String>,
+    verified: usize,
+    unverified: usize,
+    skipped: usize,
+    failed: usize,
+}
+
+
+impl Progress {
+    fn load(target: &dyn Backend, destination: &str) -> Option<Progress> {
+        let content = target.read(&progress_path(destination)).ok()?;
+        let content = String::from_utf8_lossy(&content);
+        let mut lines = content.lines();
+        if lines.next() != Some(PROGRESS_HEADER) {
+            return None;
+        }
+        let counts: Vec<i64> = lines.next()?.split('\t').map(|n| n.parse().ok()).collect::<Option<_>>()?;
+        let [started, verified, unverified, skipped, failed] = counts[..] else {
+            return None;
+        };
+        Some(Progress {
+            started,
+            last: Some(manifest::unescape(lines.next()?)),
+            verified: verified as usize,
+            unverified: unverified as usize,
+            skipped: skipped as usize,
+            failed: failed as usize,
+        })
+    }
+
+    fn save(&self, target: &dyn Backend, destination: &str) -> std::io::Result<()> {
+        let content = format!(
+            "{}\n{}\t{}\t{}\t{}\t{}\n{}\n",
+            PROGRESS_HEADER,
+            self.started,
+            self.verified,
+            self.unverified,
+            self.skipped,
+            self.failed,
+            manifest::escape(self.last.as_deref().unwrap_or_default())
+        );
+        target.write(&progress_path(destination), content.as_bytes())
+    }
+
+    fn checked(&self) -> usize {
+        self.verified + self.unverified + self.skipped + self.failed
+    }
+}
+
+
+/// Restore a random sample of `count` files of the destination (or all of
+/// them) to a temporary directory and check them against the source (or,
+/// without a source, against the manifest). Returns false if any file
+/// failed to restore.
+///
+/// Checking all of them records its progress in the destination as it
+/// goes, and resumes from there if it was interrupted, unless `restart`.
+/// Checking all of them against the source also records the rollup of the
+/// directories whose files were all verified, and then leaves out those
+/// still with the same rollup in the source and in the manifest, unless
+/// `restart`.
+pub fn drill(source: Option<&str>, destination: &str, count: Option<usize>, restart: bool, opts: &Options) -> bool {
+    log::separator();
+    match count {
+        Some(_) => info!("Restore drill of {}", destination),
+        None => info!("Verifying every file of {}", destination),
+    }
+    log::separator();
+    let (target, destination) = match backend::open(destination, opts) {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    let target = target.as_ref();
+    let mut files = Vec::new();
+    collect(target, &destination, "", &mut files);
+    if files.is_empty() {
+        error!("there are no files to restore in {}", destination);
+        return false;
+    }
+    let total = files.len();
+    let files = match count {
+        Some(count) => sample(files, count),
+        None => files,
+    };
+    let manifest = Manifest::load(target, &destination);
+
+    // Verifying against the source leaves out, with all they hold, the
+    // directories whose rollup is the same in the source, in the manifest
+    // and in the record of the last verifications, as unchanged since every
+    // file below them was verified
+    let scan = match (count, source, &manifest) {
+        (None, Some(source), Some(_)) => Some(Manifest::scan(source, opts.scan_jobs, opts.one_file_system)),
+        _ => None,
+    };
+    let verified = match (&scan, restart) {
+        (Some(_), false) => load_verified(target, &destination),
+        _ => BTreeMap::new(),
+    };
+    let unchanged: BTreeSet<&str> = match (&scan, &manifest) {
+        (Some(scan), Some(manifest)) => scan
+            .unchanged_dirs(manifest)
+            .filter(|path| verified.get(*path).is_some_and(|rollup| scan.rollup(path) == Some(rollup)))
+            .collect(),
+        _ => BTreeSet::new(),
+    };
+    let (files, already): (Vec<String>, Vec<String>) = files
+        .into_iter()
+        .partition(|relative| !parents(relative).any(|parent| unchanged.contains(parent.as_str())));
+    if !already.is_empty() {
+        info!("Skipping {} files in directories unchanged since they were verified", already.len());
+    }
+
+    let directory = std::env::temp_dir().join(format!("backup-rs-drill-{}", std::process::id()));
+    if let Err(e) = fs::create_dir_all(&directory) {
+        error!("cannot create {}: {}", directory.display(), e);
+        return false;
+    }
+    let resumed = match (count, restart) {
+        (None, false) => Progress::load(target, &destination),
+        _ => None,
+    };
+    let mut progress = match resumed {
+        Some(progress) => {
+            info!(
+                "Resuming the verification started on {} ({} files already checked)",
+                schedule::local_time(progress.started).format(),
+                progress.checked()
+            );
+            progress
+        }
+        None => Progress { started: schedule::now(), ..Progress::default() },
+    };
+    // Which files of the part already checked weren't verified isn't known
+    let resumed_clean = progress.checked() == progress.verified;
+    // Directories holding a file that wasn't verified
+    let mut doubtful = BTreeSet::new();
+    let mut checkpoint = schedule::now();
+    let mut recording = count.is_none();
+    for (i, relative) in files.iter().enumerate() {
+        // Paths compare component by component, in the order they are listed
+        if progress.last.as_ref().is_some_and(|last| Path::new(relative) <= Path::new(last)) {
+            continue;
+        }
+        let restored = directory.join(i.to_string());
+        let restored = restored.to_str().unwrap();
+        let outcome = check(target, &destination, source, manifest.as_ref(), relative, restored);
+        if !matches!(outcome, Outcome::Verified) {
+            doubtful.extend(parents(relative));
+        }
+        match outcome {
+            Outcome::Verified => {
+                action!("Verified {}", relative);
+                progress.verified += 1;
+            }
+            Outcome::Unverified => {
+                action!("Restored {} (nothing to verify it against)", relative);
+                progress.unverified += 1;
+            }
+            Outcome::Skipped(reason) => {
+                action!("Restored {} ({}, not verified)", relative, reason);
+                progress.skipped += 1;
+            }
+            Outcome::Failed(reason) => {
+                error!("{}: {}", relative, reason);
+                progress.failed += 1;
+            }
+        }
+        let _ = fs::remove_file(restored);
+        progress.last = Some(relative.clone());
+        if recording && schedule::now() - checkpoint >= CHECKPOINT_INTERVAL {
+            checkpoint = schedule::now();
+            if let Err(e) = progress.save(target, &destination) {
+                warning!("cannot record the progress of the verification: {}; it won't be resumable", e);
+                recording = false;
+            }
+        }
+    }
+    let _ = fs::remove_dir_all(Path::new(&directory));
+    if count.is_none() {
+        let _ = target.remove_file(&progress_path(&destination));
+    }
+    if let (Some(scan), Some(manifest), true) = (&scan, &manifest, resumed_clean) {
+        let verified: BTreeMap<&str, &Digest> = scan
+            .unchanged_dirs(manifest)
+            .filter(|path| !doubtful.contains(*path))
+            .filter_map(|path| Some((path, scan.rollup(path)?)))
+            .collect();
+        if let Err(e) = save_verified(target, &destination, &verified) {
+            warning!("cannot record which directories were verified: {}", e);
+        }
+    }
+
+    log::separator();
+    let Progress { verified, unverified, skipped, failed, .. } = progress;
+    info!(
+        "Restored {} of {} files: {} verified, {} unverified, {} skipped, {} failed",
+        verified + unverified + skipped + failed,
+        total,
+        verified,
+        unverified,
+        skipped,
+        failed
+    );
+    match (count, failed) {
+        (Some(_), 0) => info!("Restore drill passed"),
+        (Some(_), _) => error!("restore drill failed"),
+        (None, 0) => info!("Verification passed"),
+        (None, _) => error!("verification failed"),
+    }
+    failed == 0
+}
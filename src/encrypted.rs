@@ -0,0 +1,284 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::backend::{Backend, Kind, Metadata};
+use crate::crypto::{self, Keys};
+use crate::hash;
+use crate::manifest::{self, META_DIR};
+use crate::temporary;
+use crate::throttle::Throttled;
+use crate::versions;
+use crate::Options;
+
+
+
+/// File, inside META_DIR, marking an encrypted destination. It is the only
+/// file written in the clear, and holds a check value of the key.
+const MARKER: &str = "encryption";
+const MARKER_HEADER: &str = "backup-rs encryption 1";
+
+
+fn marker_path(root: &str) -> String {
+    format!("{}/{}/{}", root, META_DIR, MARKER)
+}
+
+
+fn parse_marker(content: &str) -> Option<(hash::Digest, bool)> {
+    let mut lines = content.lines();
+    if lines.next() != Some(MARKER_HEADER) {
+        return None;
+    }
+    let (mut check, mut names) = (None, false);
+    for line in lines {
+        match line.split_once(' ') {
+            Some(("check", hex)) => check = hash::from_hex(hex),
+            Some(("names", value)) => names = value == "yes",
+            _ => (),
+        }
+    }
+    Some((check?, names))
+}
+
+
+fn read_key(path: &str) -> Result<Keys, String> {
+    let secret = fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    if secret.len() < 16 {
+        return Err(format!(
+            "the key file {} is too short (create one with: head -c 32 /dev/urandom > {})",
+            path, path
+        ));
+    }
+    Ok(Keys::from_secret(&secret))
+}
+
+
+/// Encrypt everything written to a destination: the contents of files, the
+/// targets of symlinks and, optionally, the names of files and directories.
+/// The files of backup-rs itself keep their names but are encrypted too.
+pub struct EncryptedBackend {
+    inner: Box<dyn Backend>,
+    root: String,
+    keys: Keys,
+    names: bool,
+}
+
+
+/// Wrap the storage of a destination if it is (or is to be) encrypted
+pub fn wrap(inner: Box<dyn Backend>, root: &str, opts: &Options) -> Result<Box<dyn Backend>, String> {
+    let marker = inner
+        .read(&marker_path(root))
+        .ok()
+        .map(|data| String::from_utf8_lossy(&data).to_string());
+    let keys = match &opts.key_file {
+        Some(path) => Some(read_key(path)?),
+        None => None,
+    };
+    let names = match marker {
+        Some(content) => {
+            let (check, names) = parse_marker(&content)
+                .ok_or_else(|| format!("invalid {}", marker_path(root)))?;
+            match &keys {
+                Some(keys) if keys.check == check => names,
+                Some(_) => return Err(format!("wrong key for {}", root)),
+                None => return Err(format!("{} is encrypted; use --key-file", root)),
+            }
+        }
+        None if !opts.encrypt => return Ok(inner),
+        None => {
+            let keys = keys.as_ref().ok_or("--encrypt needs --key-file")?;
+            // Mixing files in the clear and encrypted ones would defeat the
+            // purpose, so only empty destinations can become encrypted
+            let existing = inner.list(root).unwrap_or_default();
+            if existing.iter().any(|name| !manifest::is_meta(name)) {
+                return Err(format!(
+                    "{} already has unencrypted files; encrypted backups need an empty destination",
+                    root
+                ));
+            }
+            if !opts.dry_run {
+                let content = format!(
+                    "{}\nnames {}\ncheck {}\n",
+                    MARKER_HEADER,
+                    if opts.encrypt_names { "yes" } else { "no" },
+                    hash::to_hex(&keys.check)
+                );
+                inner
+                    .write(&marker_path(root), content.as_bytes())
+                    .map_err(|e| format!("cannot write {}: {}", marker_path(root), e))?;
+            }
+            opts.encrypt_names
+        }
+    };
+    Ok(Box::new(EncryptedBackend {
+        inner,
+        root: root.to_string(),
+        keys: keys.unwrap(),
+        names,
+    }))
+}
+
+
+impl EncryptedBackend {
+    /// The tree a path is in and its components below the root of the tree,
+    /// or None if the path is the root itself, isn't below it, or belongs to
+    /// backup-rs. The previous versions of files are kept in a tree of
+    /// their own, with names encrypted like those of the destination.
+    fn components<'a>(&self, path: &'a str) -> Option<(&'a str, std::str::Split<'a, char>)> {
+        let rest = path.strip_prefix(&self.root)?.strip_prefix('/')?;
+        if let Some(kept) = path.strip_prefix(&versions::root(&self.root)).and_then(|p| p.strip_prefix('/')) {
+            return Some((&path[..path.len() - kept.len() - 1], kept.split('/')));
+        }
+        if rest.split('/').next().is_some_and(manifest::is_meta) {
+            return None;
+        }
+        Some((&path[..self.root.len()], rest.split('/')))
+    }
+
+    /// Path of an entry inside the underlying storage
+    fn translate(&self, path: &str) -> io::Result<String> {
+        let (tree, components) = match self.components(path) {
+            Some(components) if self.names => components,
+            _ => return Ok(path.to_string()),
+        };
+        let mut translated = tree.to_string();
+        for name in components {
+            // Temporary files are named after the encrypted name
+            if temporary::is_temporary(name) {
+                translated.push('/');
+                translated.push_str(name);
+                continue;
+            }
+            if name.len() > crypto::MAX_NAME {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("name too long to be encrypted: {}", name),
+                ));
+            }
+            translated.push('/');
+            translated.push_str(&self.keys.encrypt_name(name));
+        }
+        Ok(translated)
+    }
+
+    fn encrypt_file(&self, source: &str, encrypted: &str) -> io::Result<()> {
+        let mut input = io::BufReader::new(Throttled(fs::File::open(source)?));
+        let mut output = io::BufWriter::new(fs::File::create(encrypted)?);
+        self.keys.encrypt(&mut input, &mut output)?;
+        io::Write::flush(&mut output)
+    }
+}
+
+
+impl Backend for EncryptedBackend {
+    fn metadata(&self, path: &str) -> Option<Metadata> {
+        let mut metadata = self.inner.metadata(&self.translate(path).ok()?)?;
+        match metadata.kind {
+            Kind::File => metadata.size = crypto::plaintext_size(metadata.size),
+            Kind::Symlink => {
+                let target = metadata.target.as_ref()?.to_string_lossy().to_string();
+                metadata.target = self.keys.decrypt_link(&target).map(PathBuf::from);
+            }
+            _ => (),
+        }
+        Some(metadata)
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let names = self.inner.list(&self.translate(path)?)?;
+        if !self.names || (path != self.root && self.components(path).is_none()) {
+            return Ok(names);
+        }
+        // Entries that can't be decrypted are skipped
+        Ok(names
+            .into_iter()
+            .filter_map(|name| {
+                if (path == self.root && manifest::is_meta(&name)) || temporary::is_temporary(&name) {
+                    Some(name)
+                } else {
+                    self.keys.decrypt_name(&name)
+                }
+            })
+            .collect())
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.inner.create_dir(&self.translate(path)?)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        self.inner.remove_file(&self.translate(path)?)
+    }
+
+    fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        self.inner.remove_dir_all(&self.translate(path)?)
+    }
+
+    fn copy_file(&self, source: &str, path: &str) -> io::Result<()> {
+        let translated = self.translate(path)?;
+        if self.inner.is_local() {
+            let temporary = temporary::beside(&translated);
+            self.encrypt_file(source, &temporary)?;
+            return fs::rename(temporary, translated);
+        }
+        let temporary = temporary::local("encrypted");
+        self.encrypt_file(source, &temporary)?;
+        let result = self.inner.copy_file(&temporary, &translated);
+        let _ = fs::remove_file(&temporary);
+        result
+    }
+
+    fn symlink(&self, target: &Path, path: &str) -> io::Result<()> {
+        let encrypted = self.keys.encrypt_link(&target.to_string_lossy())?;
+        self.inner.symlink(Path::new(&encrypted), &self.translate(path)?)
+    }
+
+    fn mknod(&self, path: &str, mode: u32, device: u64) -> io::Result<()> {
+        self.inner.mknod(&self.translate(path)?, mode, device)
+    }
+
+    fn set_directory_metadata(&self, path: &str, source: &fs::Metadata) -> io::Result<()> {
+        self.inner.set_directory_metadata(&self.translate(path)?, source)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.inner.rename(&self.translate(from)?, &self.translate(to)?)
+    }
+
+    fn sync(&self, path: &str) -> io::Result<()> {
+        self.inner.sync(&self.translate(path)?)
+    }
+
+    fn fetch(&self, path: &str, local: &str) -> io::Result<()> {
+        let temporary = temporary::beside(local);
+        self.inner.fetch(&self.translate(path)?, &temporary)?;
+        let result = (|| {
+            let mut input = io::BufReader::new(fs::File::open(&temporary)?);
+            let mut output = io::BufWriter::new(fs::File::create(local)?);
+            self.keys.decrypt(&mut input, &mut output)?;
+            io::Write::flush(&mut output)
+        })();
+        let _ = fs::remove_file(&temporary);
+        result
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.keys.decrypt_bytes(&self.inner.read(&self.translate(path)?)?)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.inner.write(&self.translate(path)?, &self.keys.encrypt_bytes(data)?)
+    }
+
+    fn is_local(&self) -> bool {
+        self.inner.is_local()
+    }
+
+    fn small_file_size(&self) -> Option<u64> {
+        self.inner.small_file_size()
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        self.inner.finish()
+    }
+}
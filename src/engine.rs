@@ -0,0 +1,1261 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::backend::{Backend, Kind, Metadata};
+use crate::capabilities;
+use crate::case;
+use crate::compare::SizeMtime;
+use crate::confirm::Removal;
+use crate::delta;
+use crate::hash;
+use crate::manifest::{Entry, Manifest};
+use crate::order::Action;
+use crate::pause;
+use crate::pool::{Done, Job};
+use crate::progress;
+use crate::readahead::ReadAhead;
+use crate::retry::{self, retrying};
+use crate::schedule;
+use crate::space;
+use crate::special::Special;
+use crate::stats::human_bytes;
+use crate::stream;
+use crate::symlinks::{Ancestors, SymlinkPolicy};
+use crate::systemd;
+use crate::temporary;
+use crate::timeout;
+use crate::unicode::{self, Names};
+use crate::uring;
+use crate::Options;
+
+
+
+/// Get the size of a file (0 if it vanished)
+fn size(file: &str) -> u64 {
+    fs::metadata(file).map(|m| m.len()).unwrap_or(0)
+}
+
+
+/// Get the last modified time of a file (the epoch if it vanished)
+fn modified_time(file: &str) -> std::time::SystemTime {
+    fs::metadata(file).and_then(|m| m.modified()).unwrap_or(std::time::UNIX_EPOCH)
+}
+
+
+/// Check if a file is a symlink
+fn is_symlink(file: &str) -> i32 {
+    match fs::symlink_metadata(file) {
+        Ok(metadata) => if metadata.file_type().is_symlink() {
+            0
+        } else {
+            1
+        },
+        Err(_) => 2,
+    }
+}
+
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn posix_fadvise(fd: std::os::raw::c_int, offset: i64, len: i64, advice: std::os::raw::c_int) -> std::os::raw::c_int;
+    fn syncfs(fd: std::os::raw::c_int) -> std::os::raw::c_int;
+}
+
+
+#[cfg(not(target_os = "linux"))]
+extern "C" {
+    fn sync();
+}
+
+
+/// Flush a local file to the disk and drop its pages from the cache, so
+/// that reading it reads the disk
+pub fn drop_cache(path: &str) -> io::Result<()> {
+    let file = fs::File::open(path)?;
+    file.sync_all()?;
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        // POSIX_FADV_DONTNEED
+        unsafe { posix_fadvise(file.as_raw_fd(), 0, 0, 4) };
+    }
+    Ok(())
+}
+
+
+/// With `--fsync`, flush a copy to the disk, along with the directory
+/// holding it (its entry in it)
+fn sync_copy(path: &str, opts: &Options, target: &dyn Backend) -> io::Result<()> {
+    if !opts.fsync {
+        return Ok(());
+    }
+    target.sync(path)?;
+    sync_parent(path, opts, target);
+    Ok(())
+}
+
+
+/// With `--fsync`, flush the directory holding a path of the destination
+/// to the disk, once the path was added to it, renamed or removed
+pub fn sync_parent(path: &str, opts: &Options, target: &dyn Backend) {
+    let Some(parent) = Path::new(path).parent().and_then(|p| p.to_str()).filter(|p| !p.is_empty()) else {
+        return;
+    };
+    if opts.fsync && !opts.dry_run {
+        if let Err(e) = target.sync(parent) {
+            warning!("cannot flush {} to the disk: {}", parent, e);
+        }
+    }
+}
+
+
+/// Flush the filesystem holding a local path to the disk (and the others
+/// as well, on other systems than Linux)
+pub fn sync_filesystem(path: &str) -> io::Result<()> {
+    let directory = fs::File::open(path)?;
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        if unsafe { syncfs(directory.as_raw_fd()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        drop(directory);
+        unsafe { sync() };
+    }
+    Ok(())
+}
+
+
+/// Read a copied file back from the destination (from the disk itself, for
+/// a local one) and compare its hash with `digest`, that of what was copied
+fn verify_copy(destination: &str, digest: Option<hash::Digest>, target: &dyn Backend) -> io::Result<()> {
+    let Some(digest) = digest else {
+        return Err(io::Error::other("what was copied couldn't be read"));
+    };
+    let read = match target.local_file(destination) {
+        Some(path) => drop_cache(&path).and_then(|_| hash::hash_file(&path))?,
+        None => {
+            let restored = temporary::local("verify");
+            let read = target.fetch(destination, &restored).and_then(|_| hash::hash_file(&restored));
+            let _ = fs::remove_file(&restored);
+            read?
+        }
+    };
+    if read != digest {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the copy read back doesn't match what was copied"));
+    }
+    Ok(())
+}
+
+
+/// Remove a file, symlink or directory from the destination directory
+pub fn remove_path(path: &str, opts: &Options, target: &dyn Backend) {
+    let kind = match target.metadata(path) {
+        Some(metadata) => metadata.kind,
+        None => return,
+    };
+    if let Some(removals) = &opts.removals {
+        removals.borrow_mut().push(Removal { path: path.to_string(), is_directory: kind == Kind::Directory });
+        return;
+    }
+    if opts.itemize {
+        info!("{:11} {}", "*deleting", path);
+    } else if kind == Kind::Directory {
+        action!("Removing directory: {}", path);
+    } else if kind == Kind::Symlink {
+        action!("Removing symlink: {}", path);
+    } else {
+        action!("Removing file: {}", path);
+    }
+    let removed = match kind {
+        _ if opts.dry_run => Ok(()),
+        Kind::Directory => target.remove_dir_all(path),
+        _ => target.remove_file(path),
+    };
+    if let Err(e) = removed {
+        return failed(path, &format!("cannot remove {}", path), &e, opts);
+    }
+    if let Some(summary) = &opts.summary {
+        summary.removed(path, kind == Kind::Directory);
+    }
+    if opts.dry_run {
+        return;
+    }
+    forget_checksum(path, opts);
+    sync_parent(path, opts, target);
+}
+
+
+/// Remove an entry of the destination that is missing from the source,
+/// unless it is kept for a grace period
+pub fn remove_missing(path: &str, opts: &Options, target: &dyn Backend) {
+    if let Some(since) = opts.deletions.as_ref().and_then(|d| d.keep(path)) {
+        detail!(
+            "Keeping {} (missing from the source since {})",
+            path,
+            schedule::local_time(since).format()
+        );
+        return;
+    }
+    detail!("{}: missing from the source", path);
+    remove_path(path, opts, target);
+}
+
+
+/// Remove the copies of the entries that were in the source as of the
+/// manifest of the last run (or kept by `delete_after_days`) and are
+/// missing from it now, as found by `scan`, without reading the destination
+pub fn remove_vanished(previous: &Manifest, scan: &Manifest, source: &str, destination: &str, opts: &Options, target: &dyn Backend) {
+    let pending = opts.deletions.iter().flat_map(|d| d.pending()).map(|p| p.to_string());
+    let missing: BTreeSet<String> = previous
+        .entries
+        .keys()
+        .cloned()
+        .chain(pending)
+        .filter(|relative| relative != "." && !scan.entries.contains_key(relative))
+        .collect();
+    // Entries below one handled already go (or stay) with it
+    let mut handled = HashSet::new();
+    for relative in &missing {
+        if Path::new(relative).ancestors().skip(1).any(|a| handled.contains(a)) {
+            continue;
+        }
+        handled.insert(Path::new(relative));
+        let source = format!("{}/{}", source, relative);
+        // The copies of excluded and skipped paths are left as they are
+        if opts.policy.is_excluded(&source) || skipped(&source, opts) {
+            continue;
+        }
+        // E.g. moved already, along with a renamed directory
+        let path = format!("{}/{}", destination, unicode::normalize(relative, opts.normalize));
+        if target.metadata(&path).is_some() {
+            remove_missing(&path, opts, target);
+        }
+    }
+}
+
+
+/// Recursively iterate through the destination directory to remove the files
+/// that are not in the source directory
+pub fn remove_removed(source: &str, destination: &str, opts: &Options, target: &dyn Backend) {
+    remove_below(source, destination, &Ancestors::default(), opts, target)
+}
+
+
+/// Remove the copies of the entries missing from a directory of the source,
+/// reached through `ancestors`
+fn remove_below(source: &str, destination: &str, ancestors: &Ancestors, opts: &Options, target: &dyn Backend) {
+    // Names of the source directory, read if normalizing calls for it
+    let mut source_names = None;
+    // The entries of the source stored under other names, by those names
+    let renamed: HashMap<String, String> = match opts.case_insensitive || opts.normalize.is_some() {
+        true => {
+            let names: Vec<String> = fs::read_dir(source).into_iter().flatten().filter_map(|e| e.ok()?.file_name().into_string().ok()).collect();
+            case::collisions(&names, opts).into_iter().filter_map(|(name, collision)| Some((collision.stored?, name))).collect()
+        }
+        false => HashMap::new(),
+    };
+    let directory = fs::metadata(source).ok();
+    let ancestors = ancestors.enter(directory.as_ref());
+    let device = directory.filter(|_| opts.one_file_system).map(|m| m.dev());
+    let mut names = match target.list(destination) {
+        Ok(names) => names,
+        Err(e) => return failed(destination, &format!("cannot read {}", destination), &e, opts),
+    };
+    if opts.sorted {
+        names.sort();
+    }
+    for name in names {
+        let path = format!("{}/{}", destination, name);
+        if opts.protected.contains(Path::new(&path)) {
+            continue;
+        }
+        // Destinations are locked while a run writes to them, so the
+        // temporary files found are those of a run that was interrupted
+        if temporary::is_temporary(&name) {
+            action!("Removing the leftover temporary file {}", path);
+            if !opts.dry_run {
+                if let Err(e) = target.remove_file(&path) {
+                    warning!("cannot remove {}: {}", path, e);
+                }
+            }
+            continue;
+        }
+        let kind = match target.metadata(&path) {
+            Some(metadata) => metadata.kind,
+            None => continue,
+        };
+        // A copy under another form of a name, next to the one in the form
+        // asked for, is one left by a run that didn't normalize names
+        let normalized = unicode::normalize(&name, opts.normalize);
+        if normalized != name.as_str() && target.metadata(&format!("{}/{}", destination, normalized)).is_some() {
+            detail!("{}: left by a run that didn't normalize names, next to {}", path, normalized);
+            remove_path(&path, opts, target);
+            continue;
+        }
+        // The entry of the source may be under another form of the name
+        let source = match format!("{}/{}", source, name) {
+            _ if renamed.contains_key(&name) => format!("{}/{}", source, renamed[&name]),
+            path if opts.normalize.is_none() || name.is_ascii() || fs::symlink_metadata(&path).is_ok() => path,
+            path => {
+                let names = source_names.get_or_insert_with(|| {
+                    let names = fs::read_dir(source).into_iter().flatten().filter_map(|e| e.ok()?.file_name().into_string().ok());
+                    Names::new(names, opts.normalize)
+                });
+                match names.find(&name) {
+                    Some(actual) => format!("{}/{}", source, actual),
+                    None => path,
+                }
+            }
+        };
+        // The copies of excluded and skipped paths are left as they are
+        if opts.policy.is_excluded(&source) || skipped(&source, opts) {
+            continue;
+        }
+        // A path that doesn't answer isn't taken as missing
+        if let Err(e) = timeout::responds(&source, opts.timeout) {
+            warning!("cannot check {}: {}; keeping its copy", source, e);
+            continue;
+        }
+        // The copy of an entry that changed type is replaced by the copy pass
+        if stored_kind(&source, opts).is_some_and(|stored| stored != kind) {
+            detail!("{}: {} changed type", path, source);
+            remove_path(&path, opts, target);
+            continue;
+        }
+        if kind == Kind::Directory {
+            // Recursively call remove_removed() for subdirectories
+            // If the subdirectory doesn't exist in the source directory,
+            // remove it from the destination directory
+            if !Path::new(&source).exists() {
+                remove_missing(&path, opts, target);
+            } else if fs::metadata(&source).is_ok_and(|m| ancestors.contains(&m)) {
+                // A symlink loop, which isn't backed up
+                detail!("{}: {} leads back to a directory it is in", path, source);
+                remove_path(&path, opts, target);
+            } else if device.is_some_and(|device| fs::metadata(&source).is_ok_and(|m| m.dev() != device)) {
+                // Another file system is mounted on it, which isn't backed
+                // up: what was copied from it goes
+                detail!("{}: another file system is mounted on {}", path, source);
+                for name in target.list(&path).unwrap_or_default() {
+                    remove_missing(&format!("{}/{}", path, name), opts, target);
+                }
+            } else if !opts.unchanged.contains(Path::new(&source)) {
+                remove_below(&source, &path, &ancestors, opts, target);
+            }
+        } else if kind == Kind::Symlink {
+            // If the file doesn't exist in the source directory,
+            // remove it from the destination directory
+            if fs::read_link(&source).is_err() {
+                remove_missing(&path, opts, target);
+            }
+        } else if fs::symlink_metadata(&source).is_err() {
+            // Placeholders of broken symlinks are kept too
+            remove_missing(&path, opts, target);
+        }
+    }
+}
+
+
+/// Type of the copy of an entry of the source, as `backup()` stores it, or
+/// None if it isn't stored
+fn stored_kind(source: &str, opts: &Options) -> Option<Kind> {
+    let kind = |metadata: fs::Metadata| match metadata.file_type() {
+        file_type if file_type.is_dir() => Some(Kind::Directory),
+        file_type if file_type.is_file() => Some(Kind::File),
+        _ => Special::of(&metadata).filter(|special| special.recreated(opts)).map(|_| Kind::Other),
+    };
+    let metadata = fs::symlink_metadata(source).ok()?;
+    if !metadata.file_type().is_symlink() {
+        return kind(metadata);
+    }
+    let points_to = fs::metadata(source).ok();
+    match opts.symlinks {
+        SymlinkPolicy::Skip => None,
+        SymlinkPolicy::Placeholder => Some(Kind::File),
+        // Symlinks to directories are followed
+        _ if points_to.as_ref().is_some_and(|m| m.is_dir()) => Some(Kind::Directory),
+        SymlinkPolicy::Dereference => kind(points_to?),
+        SymlinkPolicy::Preserve | SymlinkPolicy::Relative if opts.android => None,
+        SymlinkPolicy::Preserve | SymlinkPolicy::Relative => Some(Kind::Symlink),
+    }
+}
+
+
+/// Give the copy of a file the capabilities of the source (see
+/// `capabilities`), if it has any, reporting those that can't be kept
+fn copy_capabilities(source: &str, destination: &str, target: &dyn Backend) {
+    let value = match capabilities::get(Path::new(source)) {
+        Ok(Some(value)) => value,
+        Ok(None) => return,
+        Err(e) => {
+            warning!("cannot read the capabilities of {}: {}", source, e);
+            return;
+        }
+    };
+    if let Err(e) = target.set_capabilities(destination, &value) {
+        warning!("cannot give {} the capabilities of {}: {}", destination, source, e);
+    }
+}
+
+
+/// Run the command transforming a file before it is stored, with the file
+/// on its standard input and its path in BACKUP_RS_SOURCE, returning the
+/// temporary file holding its output
+fn transform(source: &str, command: &str) -> io::Result<String> {
+    let output = temporary::local("transform");
+    let status = Command::new("sh")
+        .args(["-c", command])
+        .env("BACKUP_RS_SOURCE", source)
+        .stdin(fs::File::open(source)?)
+        .stdout(fs::File::create(&output)?)
+        .status()?;
+    if !status.success() {
+        let _ = fs::remove_file(&output);
+        return Err(io::Error::other(format!("'{}' failed ({})", command, status)));
+    }
+    Ok(output)
+}
+
+
+/// Report that a path of the source couldn't be backed up (`message`, e.g.
+/// `cannot read PATH`, being what was done), for the error report of the
+/// run, and so that it is skipped once it fails in enough runs in a row.
+/// A path that vanished since its directory was read is only warned about.
+fn failed(source: &str, message: &str, e: &io::Error, opts: &Options) {
+    let vanished = e.kind() == io::ErrorKind::NotFound
+        && fs::symlink_metadata(source).is_err_and(|e| e.kind() == io::ErrorKind::NotFound);
+    let message = format!("{}: {}", message, e);
+    if let Some(errors) = &opts.errors {
+        errors.add(source, e, vanished, &message);
+    }
+    if vanished {
+        warning!("{} (it vanished during the run)", message);
+        return;
+    }
+    error!("{}", message);
+    if let Some(skip_list) = &opts.skip_list {
+        skip_list.failed(source);
+    }
+}
+
+
+/// What the manifest of the last run has for a path of the source, when
+/// it is trusted to describe the destination too
+fn previous<'a>(source: &str, opts: &'a Options) -> Option<&'a Entry> {
+    if !opts.trust_manifest {
+        return None;
+    }
+    let relative = source.strip_prefix(&opts.policy.root)?.trim_start_matches('/');
+    opts.previous.as_ref()?.entries.get(relative)
+}
+
+
+/// Whether a file of the source is as the manifest of the last run has it
+/// (stored the same way), and so is its copy, when the manifest is trusted
+fn unchanged(source: &str, opts: &Options) -> bool {
+    let Some(Entry::File { size, mtime, mtime_nsec }) = previous(source, opts) else {
+        return false;
+    };
+    let relative = source.strip_prefix(&opts.policy.root).unwrap_or(source).trim_start_matches('/');
+    let transform = opts.previous.as_ref().and_then(|p| p.transforms.get(relative)).map(|c| c.as_str());
+    fs::symlink_metadata(source).is_ok_and(|m| {
+        m.is_file() && m.len() == *size && m.mtime() == *mtime && m.mtime_nsec() == *mtime_nsec
+    }) && transform == opts.policy.transform(source)
+}
+
+
+/// Record the hash of what was just stored at `destination`, for restores
+/// to check the copy against, or forget the one it had if it can't be told
+fn record_checksum(destination: &str, digest: io::Result<hash::Digest>, opts: &Options, target: &dyn Backend) {
+    let Some(checksums) = &opts.checksums else {
+        return;
+    };
+    match (digest, target.metadata(destination)) {
+        (Ok(digest), Some(copy)) => {
+            let modified = copy.modified.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+            checksums.record(destination, copy.size, modified, digest)
+        }
+        _ => checksums.forget(destination),
+    }
+}
+
+
+/// Forget the hash of a path of the destination about to be replaced or
+/// removed
+fn forget_checksum(path: &str, opts: &Options) {
+    if let Some(checksums) = &opts.checksums {
+        checksums.forget(path);
+    }
+}
+
+
+/// Whether a path of the source is in the skip-list
+fn skipped(source: &str, opts: &Options) -> bool {
+    opts.skip_list.as_ref().is_some_and(|s| s.skips(source))
+}
+
+
+/// The target the copy of a symlink of the source points to, made relative
+/// with `--symlinks relative` if it points inside the source
+pub fn stored_link(link: PathBuf, source: &str, opts: &Options) -> PathBuf {
+    opts.symlinks.target(link, source, &opts.policy.root)
+}
+
+
+fn copy_file(source: &str, destination: &str, opts: &Options, target: &dyn Backend) {
+    if !opts.itemize {
+        action!("Copying {} to {}", source, destination);
+    }
+    let link = is_symlink(source) == 0 && opts.symlinks.preserves();
+    let record = |bytes: u64| {
+        if let Some(summary) = &opts.summary {
+            summary.copied(destination, bytes);
+        }
+    };
+    if opts.dry_run {
+        record(if link { 0 } else { size(source) });
+    } else {
+        forget_checksum(destination, opts);
+        if link {
+            // Create a symlink in the destination directory
+            // pointing to the source file
+            // This is a workaround for the fs::copy() function
+            // not working with symlinks
+            let points_to = match fs::read_link(source) {
+                Ok(points_to) => stored_link(points_to, source, opts),
+                Err(e) => return failed(source, &format!("cannot read {}", source), &e, opts),
+            };
+            match retrying("create", destination, opts, || target.symlink(&points_to, destination)) {
+                Ok(()) => {
+                    sync_parent(destination, opts, target);
+                    record(0)
+                }
+                Err(e) => failed(source, &format!("cannot create {}", destination), &e, opts),
+            }
+            return;
+        }
+        if let Err(e) = timeout::probe(source, opts.timeout) {
+            return failed(source, &format!("cannot read {}", source), &e, opts);
+        }
+        // What is stored is the output of the transform of the file, if any
+        let transformed = match opts.policy.transform(source).map(|command| transform(source, command)) {
+            Some(Ok(output)) => Some(output),
+            Some(Err(e)) => return failed(source, &format!("cannot transform {}", source), &e, opts),
+            None => None,
+        };
+        let copied = transformed.as_deref().unwrap_or(source);
+        if opts.atomic {
+            // Replace the staged file instead of writing to it, as it is
+            // a hard link to the one in the destination
+            let _ = target.remove_file(destination);
+        }
+        if let Some(pool) = &opts.copies {
+            let job = Job { source: source.to_string(), destination: destination.to_string(), transformed };
+            return collect(pool.submit(job), opts, target);
+        }
+        let (result, digest) = stream::hashing(copied, || retrying("copy", source, opts, || target.copy_file(copied, destination)));
+        stored(source, destination, transformed.as_deref(), result, digest.map(Ok), opts, target);
+    }
+}
+
+
+/// Deal with the outcome of the copy of a file (or of the output of its
+/// transform, `transformed`, removed then) to the destination: record it
+/// (along with `digest`, the hash of what was copied, if known already)
+/// and check it, or report it as failed
+fn stored(
+    source: &str,
+    destination: &str,
+    transformed: Option<&str>,
+    mut result: io::Result<()>,
+    digest: Option<io::Result<hash::Digest>>,
+    opts: &Options,
+    target: &dyn Backend,
+) {
+    let copied = transformed.unwrap_or(source);
+    let bytes = size(copied);
+    if result.as_ref().is_err_and(space::is_full) {
+        // Leave no truncated copy behind
+        let _ = target.remove_file(destination);
+        if opts.out_of_space.as_ref().is_some_and(|room| room.make_room(source, bytes)) {
+            result = target.copy_file(copied, destination);
+            if result.is_err() {
+                let _ = target.remove_file(destination);
+            }
+        }
+    }
+    let digest = result.is_ok().then(|| digest.unwrap_or_else(|| hash::hash_file(copied)));
+    if let Some(output) = transformed {
+        let _ = fs::remove_file(output);
+    }
+    if let Err(e) = result {
+        return failed(source, &format!("cannot copy {}", source), &e, opts);
+    }
+    if let Err(e) = sync_copy(destination, opts, target) {
+        // Left there, it would look up to date to the next run
+        let _ = target.remove_file(destination);
+        return failed(source, &format!("cannot flush {} to the disk", destination), &e, opts);
+    }
+    let written = digest.as_ref().and_then(|digest| digest.as_ref().ok()).copied();
+    if let Some(digest) = digest {
+        record_checksum(destination, digest, opts, target);
+    }
+    if !checked(source, destination, written, opts, target) {
+        return;
+    }
+    copy_capabilities(source, destination, target);
+    if let Some(summary) = &opts.summary {
+        summary.copied(destination, bytes);
+    }
+}
+
+
+/// Read a copy back with `--verify-writes` (or the verify attribute of its
+/// file), removing it and reporting it as failed if it doesn't match
+/// `digest`, so that the next run copies it again. Returns whether it is
+/// good.
+fn checked(source: &str, destination: &str, digest: Option<hash::Digest>, opts: &Options, target: &dyn Backend) -> bool {
+    if !opts.verify_writes && !opts.policy.attributes(source).verify {
+        return true;
+    }
+    let Err(e) = verify_copy(destination, digest, target) else {
+        return true;
+    };
+    forget_checksum(destination, opts);
+    let _ = target.remove_file(destination);
+    failed(source, &format!("cannot verify {}", destination), &e, opts);
+    false
+}
+
+
+/// Deal with the copies made by the `--copy-jobs` workers, trying those
+/// that failed with a transient error again here
+fn collect(done: Vec<Done>, opts: &Options, target: &dyn Backend) {
+    for Done { job, mut result, digest } in done {
+        let copied = job.transformed.as_deref().unwrap_or(&job.source);
+        if opts.retries > 0 && result.as_ref().is_err_and(retry::transient) {
+            result = retrying("copy", &job.source, opts, || target.copy_file(copied, &job.destination));
+        }
+        stored(&job.source, &job.destination, job.transformed.as_deref(), result, digest, opts, target);
+    }
+}
+
+
+/// Make the copies held for `--order`, and wait for those handed to the
+/// `--copy-jobs` workers, once the run is done going through the source
+pub fn wait_copies(opts: &Options, target: &dyn Backend) {
+    if let Some(queue) = &opts.queued {
+        queue.run(|source, destination, action| {
+            if progress::cancelled() {
+                return;
+            }
+            progress::at(source);
+            pause::wait();
+            action(source, destination, opts, target);
+        });
+    }
+    if let Some(pool) = &opts.copies {
+        collect(pool.wait(), opts, target);
+    }
+    // Those of the deepest directories come first
+    for (source, destination) in opts.directories.take() {
+        set_directory_metadata(&source, &destination, target);
+    }
+}
+
+
+/// Bring the copy of a file that changed up to date, rewriting only the
+/// blocks that differ if possible
+fn update_file(source: &str, destination: &str, opts: &Options, target: &dyn Backend) {
+    let size = size(source);
+    if !opts.delta || size < delta::MIN_SIZE || opts.policy.transform(source).is_some() {
+        return copy_file(source, destination, opts, target);
+    }
+    if opts.dry_run {
+        if !opts.itemize {
+            action!("Updating {}", destination);
+        }
+        if let Some(summary) = &opts.summary {
+            summary.copied(destination, size);
+        }
+        return;
+    }
+    if let Err(e) = timeout::probe(source, opts.timeout) {
+        return failed(source, &format!("cannot read {}", source), &e, opts);
+    }
+    forget_checksum(destination, opts);
+    let (result, streamed) = stream::hashing(source, || retrying("update", destination, opts, || target.update_file(source, destination)));
+    match result {
+        Ok(Some(written)) => {
+            let digest = streamed.map(Ok).unwrap_or_else(|| hash::hash_file(source));
+            let updated = digest.as_ref().ok().copied();
+            record_checksum(destination, digest, opts, target);
+            if !checked(source, destination, updated, opts, target) {
+                return;
+            }
+            copy_capabilities(source, destination, target);
+            if let Some(summary) = &opts.summary {
+                summary.copied(destination, written);
+            }
+            if !opts.itemize {
+                action!(
+                    "Updated {} from {} ({} of {} rewritten)",
+                    destination,
+                    source,
+                    human_bytes(written),
+                    human_bytes(size)
+                );
+            }
+        }
+        Ok(None) => copy_file(source, destination, opts, target),
+        Err(e) => {
+            warning!("cannot update {} in place: {}; copying it", destination, e);
+            copy_file(source, destination, opts, target);
+        }
+    }
+}
+
+
+/// Print the rsync-style change code of a file or symlink about to be
+/// copied: what is done (`>` sent, `c` created), its type (`f` or `L`) and
+/// whether its value (a symlink target), size or time differ (`c`, `s`,
+/// `t`), or `+` for a new entry
+fn itemize(source: &str, destination: &str, existing: Option<&Metadata>, opts: &Options) {
+    let link = fs::read_link(source).ok().filter(|_| opts.symlinks.preserves()).map(|link| stored_link(link, source, opts));
+    let (update, kind) = if link.is_some() { ('c', 'L') } else { ('>', 'f') };
+    let code = match existing {
+        Some(metadata) if (metadata.kind == Kind::Symlink) == link.is_some() && metadata.kind != Kind::Directory => {
+            let flag = |differs: bool, c: char| if differs { c } else { '.' };
+            let value = link.is_some() && link != metadata.target;
+            let size = link.is_none() && self::size(source) != metadata.size;
+            let time = link.is_none() && modified_time(source) != metadata.modified;
+            format!("{}{}{}{}{}......", update, kind, flag(value, 'c'), flag(size, 's'), flag(time, 't'))
+        }
+        _ => format!("{}{}+++++++++", update, kind),
+    };
+    match link {
+        Some(link) => info!("{} {} -> {}", code, destination, link.display()),
+        None => info!("{} {}", code, destination),
+    }
+}
+
+
+/// Create the copy of a directory of the source in the destination if it
+/// is missing. Returns false if it couldn't be created.
+pub fn create_directory(source: &str, path: &str, opts: &Options, target: &dyn Backend) -> bool {
+    match target.metadata(path) {
+        Some(Metadata { kind: Kind::Directory, .. }) => return true,
+        // The entry was something else (e.g. a file) in the last run
+        Some(_) => remove_path(path, opts, target),
+        None => (),
+    }
+    if opts.itemize {
+        info!("cd+++++++++ {}/", path);
+    }
+    if !opts.dry_run {
+        if let Err(e) = target.create_dir(path) {
+            failed(source, &format!("cannot create {}", path), &e, opts);
+            return false;
+        }
+        sync_parent(path, opts, target);
+    }
+    true
+}
+
+
+/// Give the copy of a directory the permissions, owner and modification
+/// time of the directory of the source, once what is in it is copied
+/// (with `--copy-jobs`, once the copies are done, by `wait_copies`)
+pub fn copy_directory_metadata(source: &str, destination: &str, opts: &Options, target: &dyn Backend) {
+    if opts.dry_run {
+        return;
+    }
+    if opts.copies.is_some() || opts.queued.is_some() {
+        opts.directories.borrow_mut().push((source.to_string(), destination.to_string()));
+        return;
+    }
+    set_directory_metadata(source, destination, target);
+}
+
+
+fn set_directory_metadata(source: &str, destination: &str, target: &dyn Backend) {
+    let Ok(metadata) = fs::metadata(source) else {
+        return;
+    };
+    match target.set_directory_metadata(destination, &metadata) {
+        Err(e) if e.kind() != io::ErrorKind::Unsupported => {
+            warning!("cannot give {} the metadata of {}: {}", destination, source, e)
+        }
+        _ => (),
+    }
+}
+
+
+/// Store a symlink as a placeholder: a file holding its target
+fn backup_placeholder(source: &str, destination: &str, opts: &Options, target: &dyn Backend) {
+    let link = match fs::read_link(source) {
+        Ok(link) => link,
+        Err(e) => return failed(source, &format!("cannot read {}", source), &e, opts),
+    };
+    let content = link.as_os_str().as_bytes();
+    let existing = target.metadata(destination);
+    if let Some(Metadata { kind: Kind::File, size, .. }) = existing {
+        if size == content.len() as u64 && target.read(destination).is_ok_and(|c| c == content) {
+            return;
+        }
+    }
+    if opts.itemize {
+        info!(">f{} {}", if existing.is_some() { "c........." } else { "+++++++++" }, destination);
+    } else {
+        action!("Storing symlink {} as a placeholder in {}", source, destination);
+    }
+    if !opts.dry_run {
+        forget_checksum(destination, opts);
+        if let Err(e) = target.write(destination, content) {
+            return failed(source, &format!("cannot write {}", destination), &e, opts);
+        }
+        let mut hasher = hash::Sha256::new();
+        hasher.update(content);
+        record_checksum(destination, Ok(hasher.finish()), opts, target);
+    }
+    if let Some(summary) = &opts.summary {
+        summary.copied(destination, content.len() as u64);
+    }
+}
+
+
+/// Recreate a special file of the source in the destination, with
+/// `--devices` or `--specials`, or skip it with a warning
+fn backup_special(source: &str, destination: &str, special: Special, metadata: &fs::Metadata, opts: &Options, target: &dyn Backend) {
+    if !special.recreated(opts) {
+        warning!("Skipping {}: it is {} (use {} to recreate it)", source, special.describe(), special.option());
+        return;
+    }
+    let existing = target.metadata(destination);
+    if let Some(Metadata { kind: Kind::Other, .. }) = existing {
+        // Only local copies tell what they are
+        let same = match target.local_file(destination).map(fs::symlink_metadata) {
+            Some(Ok(copy)) => Special::of(&copy) == Some(special) && copy.rdev() == metadata.rdev(),
+            Some(Err(_)) => false,
+            None => true,
+        };
+        if same {
+            return;
+        }
+    }
+    if opts.itemize {
+        info!("c{}+++++++++ {}", if special.is_device() { 'D' } else { 'S' }, destination);
+    } else {
+        action!("Creating {} {}", special.describe().trim_start_matches("a "), destination);
+    }
+    if opts.dry_run {
+        return;
+    }
+    if existing.is_some() {
+        remove_path(destination, opts, target);
+    }
+    if let Err(e) = target.mknod(destination, metadata.mode(), metadata.rdev()) {
+        return failed(source, &format!("cannot create {}", destination), &e, opts);
+    }
+    if let Some(summary) = &opts.summary {
+        summary.copied(destination, 0);
+    }
+}
+
+
+/// Move the copy of a file about to be overwritten to its previous versions,
+/// if they are kept. Returns whether to go ahead and overwrite it.
+fn keep_version(path: &str, opts: &Options, target: &dyn Backend) -> bool {
+    let Some(versions) = &opts.versions else {
+        return true;
+    };
+    if !opts.itemize {
+        action!("Keeping the previous version of {}", path);
+    }
+    if opts.dry_run {
+        return true;
+    }
+    forget_checksum(path, opts);
+    match versions.keep(path, target) {
+        Ok(()) => {
+            sync_parent(path, opts, target);
+            true
+        }
+        Err(e) => {
+            error!("cannot keep the previous version of {}: {}; not overwriting it", path, e);
+            false
+        }
+    }
+}
+
+
+/// Copy a single file (or symlink) to the destination if it is missing or
+/// out of date
+pub fn backup_file(source_file: &str, destination_file: &str, opts: &Options, target: &dyn Backend) {
+    if progress::cancelled() {
+        return;
+    }
+    if let Some(summary) = &opts.summary {
+        summary.examined();
+        systemd::progress(source_file, summary.files());
+    }
+    progress::at(source_file);
+    pause::wait();
+    if let Err(e) = timeout::responds(source_file, opts.timeout) {
+        return failed(source_file, &format!("cannot read {}", source_file), &e, opts);
+    }
+    if unchanged(source_file, opts) {
+        detail!("Skipping {} (unchanged since the last run, as the manifest tells)", source_file);
+        return;
+    }
+    // FIFOs, sockets and device nodes have no contents to copy
+    let metadata = match opts.symlinks {
+        SymlinkPolicy::Dereference => fs::metadata(source_file),
+        _ => fs::symlink_metadata(source_file),
+    };
+    if let Some((special, metadata)) = metadata.ok().and_then(|m| Some((Special::of(&m)?, m))) {
+        return backup_special(source_file, destination_file, special, &metadata, opts, target);
+    }
+    if is_symlink(source_file) == 0 {
+        match opts.symlinks {
+            SymlinkPolicy::Preserve | SymlinkPolicy::Relative if opts.android => {
+                detail!("Skipping symlink {} (Android storage can't hold symlinks)", source_file);
+                return;
+            }
+            SymlinkPolicy::Skip => {
+                detail!("Skipping symlink {}", source_file);
+                return;
+            }
+            SymlinkPolicy::Placeholder => return backup_placeholder(source_file, destination_file, opts, target),
+            SymlinkPolicy::Dereference if fs::metadata(source_file).is_err() => {
+                warning!("Skipping symlink {}: what it points to doesn't exist", source_file);
+                return;
+            }
+            _ => (),
+        }
+    }
+    // A copy of another type (the entry changed type since the last run)
+    // goes first: copying over it would write through a symlink or to a
+    // FIFO, or fail for a directory
+    let link = is_symlink(source_file) == 0 && opts.symlinks.preserves();
+    let destination = match target.metadata(destination_file) {
+        Some(existing) if existing.kind != if link { Kind::Symlink } else { Kind::File } => {
+            detail!("{}: its copy is of another type", source_file);
+            if existing.kind == Kind::File && opts.versions.is_some() {
+                if !keep_version(destination_file, opts, target) {
+                    return;
+                }
+            } else {
+                remove_path(destination_file, opts, target);
+            }
+            None
+        }
+        existing => existing,
+    };
+    let copy = |action: Action| {
+        if opts.itemize {
+            itemize(source_file, destination_file, destination.as_ref(), opts);
+        }
+        match &opts.queued {
+            Some(queue) => queue.push(source_file, destination_file, action),
+            None => action(source_file, destination_file, opts, target),
+        }
+    };
+    if link {
+        match &destination {
+            Some(Metadata { target: link, .. }) => {
+                // If the symlink in the source directory points to a different
+                // file than the symlink in the destination directory, overwrite
+                // the destination symlink
+                let source = match fs::read_link(source_file) {
+                    Ok(source) => stored_link(source, source_file, opts),
+                    Err(e) => return failed(source_file, &format!("cannot read {}", source_file), &e, opts),
+                };
+                if Some(&source) != link.as_ref() {
+                    detail!("{}: now points to {}", source_file, source.display());
+                    copy(copy_file);
+                } else {
+                    detail!("Skipping {} (up to date)", source_file);
+                }
+            }
+            None => {
+                detail!("{}: not in the destination", source_file);
+                copy(copy_file)
+            }
+        }
+    } else if let Some(existing) = &destination {
+        // Overwrite the destination file if it is out of date, as the
+        // comparator tells
+        let metadata = match fs::metadata(source_file) {
+            Ok(metadata) => metadata,
+            Err(e) => return failed(source_file, &format!("cannot read {}", source_file), &e, opts),
+        };
+        let comparator = opts.comparator.as_deref().unwrap_or(&SizeMtime);
+        if comparator.changed(source_file, &metadata, destination_file, existing, opts, target) {
+            detail!("{}: {}", source_file, comparator.reason(source_file, &metadata, existing, opts));
+            if !keep_version(destination_file, opts, target) {
+                return;
+            }
+            // The previous copy was moved away, leaving nothing to update
+            copy(if opts.versions.is_some() { copy_file } else { update_file });
+        } else {
+            detail!("Skipping {} (up to date)", source_file);
+        }
+    } else {
+        detail!("{}: not in the destination", source_file);
+        copy(copy_file);
+    }
+}
+
+
+/// Path in a directory of the destination of the copy of an entry of the
+/// source (`name`), normalized with `--normalize`, moving the copy found
+/// under another form of the name, if any, to it
+fn destination_path(destination: &str, name: &str, equivalents: &mut Option<Names>, opts: &Options, target: &dyn Backend) -> String {
+    let normalized = unicode::normalize(name, opts.normalize);
+    let path = format!("{}/{}", destination, normalized);
+    if opts.normalize.is_none() || name.is_ascii() || target.metadata(&path).is_some() {
+        return path;
+    }
+    let names = equivalents.get_or_insert_with(|| Names::new(target.list(destination).unwrap_or_default(), opts.normalize));
+    if let Some(existing) = names.find(&normalized) {
+        let from = format!("{}/{}", destination, existing);
+        action!("Renaming {} to {} (the same name, normalized)", from, path);
+        if !opts.dry_run {
+            match target.rename(&from, &path) {
+                Ok(()) => {
+                    if let Some(checksums) = &opts.checksums {
+                        checksums.rename(&from, &path);
+                    }
+                    sync_parent(&path, opts, target);
+                }
+                Err(e) => warning!("cannot rename {} to {}: {}", from, path, e),
+            }
+        }
+    }
+    path
+}
+
+
+/// Backup the source directory to the destination directory
+pub fn backup(source: &str, destination: &str, opts: &Options, target: &dyn Backend) {
+    let read_ahead = ReadAhead::start(source, opts);
+    backup_dir(source, destination, &Ancestors::default(), opts, read_ahead.as_ref(), target)
+}
+
+
+/// The entries of a directory of the source, with their metadata if the
+/// scanners reading ahead looked them up already
+fn read_dir(
+    source: &str,
+    opts: &Options,
+    read_ahead: Option<&ReadAhead>,
+) -> io::Result<Vec<(fs::DirEntry, Option<io::Result<fs::Metadata>>)>> {
+    if let Some(listing) = read_ahead.and_then(|r| r.take(source, opts.timeout)) {
+        let mut listing = listing?;
+        if opts.sorted {
+            listing.sort_by_key(|(entry, _)| entry.file_name());
+        }
+        return Ok(listing.into_iter().map(|(entry, metadata)| (entry, Some(metadata))).collect());
+    }
+    let mut dir = timeout::read_dir(source, opts.timeout)?;
+    if opts.sorted {
+        dir.sort_by_key(|entry| entry.file_name());
+    }
+    if let Some(read_ahead) = read_ahead {
+        read_ahead.found(dir.iter());
+    }
+    Ok(dir.into_iter().map(|entry| (entry, None)).collect())
+}
+
+
+/// Back up a directory of the source, reached through `ancestors`
+fn backup_dir(
+    source: &str,
+    destination: &str,
+    ancestors: &Ancestors,
+    opts: &Options,
+    read_ahead: Option<&ReadAhead>,
+    target: &dyn Backend,
+) {
+    backup_entries(source, destination, ancestors, opts, read_ahead, target);
+    if let Some(read_ahead) = read_ahead {
+        read_ahead.done(source);
+    }
+}
+
+
+/// Back up the entries of a directory of the source
+fn backup_entries(
+    source: &str,
+    destination: &str,
+    ancestors: &Ancestors,
+    opts: &Options,
+    read_ahead: Option<&ReadAhead>,
+    target: &dyn Backend,
+) {
+    // Get a list (recursively) of the files in the source directory
+    // and copy them to the destination directory, preserving the
+    // directory structure
+    let dir = match read_dir(source, opts, read_ahead) {
+        Ok(d) => d,
+        Err(e) => return failed(source, &format!("cannot read {}", source), &e, opts),
+    };
+    // Entries whose names would be the same as others' in the destination
+    let names: Vec<String> = dir
+        .iter()
+        .map(|(entry, _)| entry)
+        .filter(|entry| !opts.policy.is_excluded(entry.path().to_str().unwrap_or_default()))
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    let collisions = case::collisions(&names, opts);
+    if opts.uring {
+        // The entries and their copies are looked at one at a time below:
+        // look them all up at once first
+        let mut paths: Vec<String> =
+            dir.iter().filter_map(|(entry, _)| Some(entry.path().to_str()?.to_string())).collect();
+        paths.extend(names.iter().filter_map(|name| target.local_file(&format!("{}/{}", destination, name))));
+        uring::prefetch(&paths);
+    }
+    let directory = fs::metadata(source).ok();
+    let ancestors = ancestors.enter(directory.as_ref());
+    // Device of the directory, not to leave with --one-file-system
+    let device = directory.filter(|_| opts.one_file_system).map(|m| m.dev());
+    // Symlinks to directories are followed, unless symlinks are skipped or
+    // stored as placeholders
+    let followed = opts.symlinks.preserves() || opts.symlinks == SymlinkPolicy::Dereference;
+    // Names of the destination directory, read if normalizing calls for it
+    let mut equivalents = None;
+    for (entry, metadata) in dir {
+        let path = entry.path();
+        // Paths are handled as text from here on
+        let Some(source_path) = path.to_str() else {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "its name isn't valid UTF-8");
+            failed(&path.to_string_lossy(), &format!("cannot back up {}", path.display()), &e, opts);
+            continue;
+        };
+        if let Some(pattern) = opts.policy.exclusion(source_path) {
+            detail!("Skipping {} (excluded by pattern '{}')", path.display(), pattern);
+            continue;
+        }
+        if skipped(source_path, opts) {
+            detail!("Skipping {} (in the skip-list)", path.display());
+            continue;
+        }
+        let mut name = entry.file_name().to_string_lossy().to_string();
+        if let Some(collision) = collisions.get(&name) {
+            let storage = match (opts.android, opts.case_insensitive) {
+                (true, _) => "on Android storage",
+                (false, true) => "in the destination, which doesn't tell case apart,",
+                (false, false) => "once normalized",
+            };
+            let Some(stored) = &collision.stored else {
+                warning!("Skipping {}: {} it would be the same as {}", path.display(), storage, collision.with);
+                continue;
+            };
+            warning!("Storing {} as {}: {} it would be the same as {}", path.display(), stored, storage, collision.with);
+            name = stored.clone();
+        }
+        let metadata = metadata.unwrap_or_else(|| timeout::metadata(source_path, opts.timeout));
+        let (is_dir, mounted, looping) = match metadata {
+            Ok(metadata) => (
+                metadata.is_dir(),
+                device.is_some_and(|device| metadata.dev() != device),
+                metadata.is_dir() && ancestors.contains(&metadata),
+            ),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                failed(source_path, &format!("cannot read {}", path.display()), &e, opts);
+                continue;
+            }
+            Err(_) => (false, false, false),
+        };
+        if is_dir && (followed || !entry.file_type().is_ok_and(|t| t.is_symlink())) {
+            if looping {
+                warning!("Skipping {}: it leads back to a directory it is in (a symlink loop)", path.display());
+                continue;
+            }
+            // Recursively call backup() for subdirectories
+            // Create the subdirectory in the destination directory
+            // if it doesn't exist
+            let destination = destination_path(destination, &name, &mut equivalents, opts, target);
+            if opts.unchanged.contains(&path)
+                || opts.protected.contains(Path::new(&destination))
+            {
+                continue;
+            }
+            // A directory of the last run is still there
+            if !matches!(previous(source_path, opts), Some(Entry::Directory { .. }))
+                && !create_directory(source_path, &destination, opts, target)
+            {
+                continue;
+            }
+            // Only the directory another file system is mounted on is kept
+            if mounted {
+                detail!("Not crossing into {} (another file system is mounted on it)", path.display());
+                copy_directory_metadata(source_path, &destination, opts, target);
+                continue;
+            }
+            backup_dir(source_path, &destination, &ancestors, opts, read_ahead, target);
+            copy_directory_metadata(source_path, &destination, opts, target);
+        } else {
+            // Copy the file to the destination directory
+            let destination_file = destination_path(destination, &name, &mut equivalents, opts, target);
+            if opts.protected.contains(Path::new(&destination_file)) {
+                continue;
+            }
+            backup_file(source_path, &destination_file, opts, target);
+        }
+    }
+}
+
+
+/// Copy only the given paths (relative to the source root), as listed with
+/// --files-from: their missing parent directories are created, listed
+/// directories aren't recursed into and nothing is removed
+pub fn backup_listed(source: &str, destination: &str, paths: &[String], opts: &Options, target: &dyn Backend) {
+    for relative in paths {
+        let source_path = format!("{}/{}", source, relative);
+        let destination_path = format!("{}/{}", destination, unicode::normalize(relative, opts.normalize));
+        if opts.policy.is_excluded(&source_path) || opts.protected.contains(Path::new(&destination_path)) {
+            continue;
+        }
+        if skipped(&source_path, opts) {
+            detail!("Skipping {} (in the skip-list)", source_path);
+            continue;
+        }
+        if let Err(e) = fs::symlink_metadata(&source_path) {
+            warning!("cannot access {}: {}", source_path, e);
+            continue;
+        }
+        // Symlinks to directories are followed, as in backup()
+        let is_dir = Path::new(&source_path).is_dir();
+        let mut directories: Vec<&str> = relative.match_indices('/').map(|(i, _)| &relative[..i]).collect();
+        if is_dir {
+            directories.push(relative);
+        }
+        let created = directories.into_iter().all(|directory| {
+            let path = format!("{}/{}", destination, unicode::normalize(directory, opts.normalize));
+            match target.metadata(&path) {
+                Some(Metadata { kind: Kind::Directory, .. }) => return true,
+                Some(_) => remove_path(&path, opts, target),
+                None => (),
+            }
+            create_directory(&format!("{}/{}", source, directory), &path, opts, target)
+        });
+        if created && !is_dir {
+            if target.metadata(&destination_path).map(|m| m.kind) == Some(Kind::Directory) {
+                remove_path(&destination_path, opts, target);
+            }
+            backup_file(&source_path, &destination_path, opts, target);
+        }
+    }
+}
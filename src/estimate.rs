@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use crate::backend;
+use crate::log;
+use crate::stats::{human_bytes, Stats};
+use crate::status;
+use crate::tui::elapsed;
+use crate::Options;
+
+
+
+/// Tell what backing the source up to the destination would cost: go
+/// through the source and compare it with the destination as a dry run
+/// does, without its output (but for errors, and the entries with -v), and
+/// print how many files and bytes would be copied, how many entries
+/// removed, and how long the copies would take at the pace of the last
+/// run. Returns false if the comparison failed.
+pub fn estimate(source: &str, destination: &str, opts: &Options) -> bool {
+    let mut opts = opts.clone();
+    opts.dry_run = true;
+    info!("Estimating a backup of {} to {}...", source, destination);
+    let pace = backend::open(destination, &opts).ok().and_then(|(target, root)| status::pace(target.as_ref(), &root));
+    let verbosity = log::verbosity();
+    if verbosity == log::NORMAL {
+        log::set_verbosity(log::QUIET);
+    }
+    let (_, warnings) = log::counts();
+    let started = Instant::now();
+    let ok = crate::run_backup(source, destination, &opts, &mut Stats::new());
+    let took = started.elapsed();
+    log::set_verbosity(verbosity);
+    if !ok {
+        error!("cannot estimate the backup");
+        return false;
+    }
+    let (_, totals) = status::last_run().unwrap_or_default();
+    log::separator();
+    info!(
+        "A backup would copy {} file(s) ({}) and remove {} entries",
+        totals.copied,
+        human_bytes(totals.bytes),
+        totals.removed
+    );
+    info!("Going through the source and comparing it took {}", elapsed(took));
+    match pace {
+        Some((bytes, seconds)) if bytes > 0 && seconds > 0 => {
+            let rate = (bytes / seconds).max(1);
+            info!(
+                "At the pace of the last run ({}/s), the copies would take about {}",
+                human_bytes(rate),
+                elapsed(Duration::from_secs(totals.bytes / rate))
+            );
+        }
+        _ => info!("No earlier run copied enough to tell how long the copies would take"),
+    }
+    let (_, now) = log::counts();
+    if now > warnings {
+        warning!("the comparison printed {} warning(s), which a dry run (--dry) lists", now - warnings);
+    }
+    true
+}
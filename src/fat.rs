@@ -0,0 +1,56 @@
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_long};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::Options;
+
+
+
+/// Seconds apart timestamps can be and still be taken as equal: FAT keeps
+/// modification times to 2 seconds, and exFAT as reported by most drivers
+pub const MODIFY_WINDOW: u64 = 2;
+
+
+/// `f_type` of the FAT (msdos, vfat) and exFAT filesystems
+const MSDOS_SUPER_MAGIC: c_long = 0x4d44;
+const EXFAT_SUPER_MAGIC: c_long = 0x2011_bab0;
+
+
+/// `struct statfs`, of which only the type of the filesystem (the first
+/// field) is read, with room for the rest
+#[repr(C)]
+struct Statfs {
+    f_type: c_long,
+    rest: [c_long; 31],
+}
+
+
+extern "C" {
+    fn statfs(path: *const c_char, buf: *mut Statfs) -> c_int;
+}
+
+
+/// Whether a path is (or would be, if it doesn't exist yet) on a FAT or
+/// exFAT filesystem, e.g. a USB stick or an SD card
+fn is_fat(path: &Path) -> bool {
+    let path = path.ancestors().find(|a| a.exists()).unwrap_or(Path::new("/"));
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut buf: Statfs = unsafe { std::mem::zeroed() };
+    if unsafe { statfs(path.as_ptr(), &mut buf) } != 0 {
+        return false;
+    }
+    matches!(buf.f_type, MSDOS_SUPER_MAGIC | EXFAT_SUPER_MAGIC)
+}
+
+
+/// Take timestamps 2 seconds apart as equal when a local destination is on
+/// FAT or exFAT, so that every run doesn't copy everything again
+pub fn detect(destination: &str, opts: &mut Options) {
+    if opts.modify_window < MODIFY_WINDOW && is_fat(Path::new(destination)) {
+        info!("{} is on FAT or exFAT, which keeps times to 2 seconds: taking those as equal", destination);
+        opts.modify_window = MODIFY_WINDOW;
+    }
+}
@@ -0,0 +1,77 @@
+use std::fs;
+use std::io::{self, Read};
+
+
+
+/// Read the entries of a list given with --exclude-from or --files-from,
+/// from a file or from the standard input (`-`): one per line, or
+/// NUL-delimited with `from0` (as printed by `find -print0`). Empty entries
+/// are skipped.
+pub fn read(path: &str, from0: bool) -> io::Result<Vec<String>> {
+    let mut content = Vec::new();
+    if path == "-" {
+        io::stdin().lock().read_to_end(&mut content)?;
+    } else {
+        content = fs::read(path)?;
+    }
+    let separator = if from0 { b'\0' } else { b'\n' };
+    let mut entries = Vec::new();
+    for entry in content.split(|&b| b == separator) {
+        let entry = match from0 {
+            true => entry,
+            false => entry.strip_suffix(b"\r").unwrap_or(entry),
+        };
+        if entry.is_empty() {
+            continue;
+        }
+        match std::str::from_utf8(entry) {
+            Ok(entry) => entries.push(entry.to_string()),
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not valid UTF-8", String::from_utf8_lossy(entry)),
+                ))
+            }
+        }
+    }
+    Ok(entries)
+}
+
+
+/// Exclusion patterns of a list, without its comments (lines starting
+/// with `#` or `;`)
+pub fn patterns(entries: Vec<String>) -> Vec<String> {
+    entries
+        .into_iter()
+        .map(|e| e.trim().to_string())
+        .filter(|e| !e.is_empty() && !e.starts_with('#') && !e.starts_with(';'))
+        .collect()
+}
+
+
+/// Components of a path, without the empty and `.` ones
+fn components(path: &str) -> Vec<&str> {
+    path.split('/').filter(|c| !c.is_empty() && *c != ".").collect()
+}
+
+
+/// Turn an entry of a --files-from list into a path relative to the source
+/// root. Entries may start with the source, as given or absolute, like
+/// `find SOURCE` prints them; other entries are relative to the source,
+/// even absolute ones, as with rsync. Returns None for the root itself.
+pub fn relative(entry: &str, source: &str) -> Result<Option<String>, String> {
+    let mut path = components(entry);
+    let absolute = std::path::absolute(source).ok();
+    let prefixes = [Some(source), absolute.as_ref().and_then(|p| p.to_str())];
+    for prefix in prefixes.into_iter().flatten() {
+        let prefix_components = components(prefix);
+        if entry.starts_with('/') == prefix.starts_with('/') && path.starts_with(&prefix_components) {
+            path.drain(..prefix_components.len());
+            break;
+        }
+    }
+    if path.contains(&"..") {
+        return Err(format!("{} is outside the source", entry));
+    }
+    Ok(Some(path.join("/")).filter(|p| !p.is_empty()))
+}
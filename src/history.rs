@@ -0,0 +1,134 @@
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+use crate::backend::{self, Backend, Kind};
+use crate::hash;
+use crate::schedule;
+use crate::snapshot;
+use crate::stats::human_bytes;
+use crate::temporary;
+use crate::versions;
+use crate::Options;
+
+
+
+/// A copy of the file kept by a destination
+struct Version {
+    /// The snapshot it is in, `~N` for the previous versions kept by
+    /// --versions, or `current`
+    label: String,
+    target: Rc<dyn Backend>,
+    path: String,
+    size: u64,
+    modified: i64,
+}
+
+
+/// The copies of a file kept by a destination: in its snapshots, in its
+/// previous versions and the current one, by modification time
+fn versions(destination: &str, relative: &str, opts: &Options) -> Result<Vec<Version>, String> {
+    let mut found = Vec::new();
+    let mut add = |label: String, target: Rc<dyn Backend>, path: String| {
+        if let Some(metadata) = target.metadata(&path).filter(|m| m.kind == Kind::File) {
+            let modified = metadata.modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+            found.push(Version { label, target, path, size: metadata.size, modified });
+        }
+    };
+    if snapshot::check_local(destination).is_ok() {
+        let kind = snapshot::detect(destination, opts.snapshot);
+        // A destination without snapshots may not be on ZFS either
+        for snapshot in snapshot::list(kind, destination).unwrap_or_default() {
+            let directory = snapshot::directory(kind, destination, &snapshot);
+            match backend::open(&directory, opts) {
+                Ok((target, root)) => add(snapshot.name(), Rc::from(target), format!("{}/{}", root, relative)),
+                Err(e) => warning!("cannot open snapshot {}: {}", snapshot.name(), e),
+            }
+        }
+    }
+    let (target, root) = backend::open(destination, opts)?;
+    let target: Rc<dyn Backend> = Rc::from(target);
+    let kept = format!("{}/{}", versions::root(&root), relative);
+    for n in 1.. {
+        let path = format!("{}~{}", kept, n);
+        if target.metadata(&path).is_none() {
+            break;
+        }
+        add(format!("~{}", n), target.clone(), path);
+    }
+    add("current".to_string(), target, format!("{}/{}", root, relative));
+    found.sort_by_key(|v| v.modified);
+    Ok(found)
+}
+
+
+/// Hash a copy, fetching it if it isn't local
+fn digest(version: &Version) -> Result<String, String> {
+    let local = temporary::local("history");
+    let result = version
+        .target
+        .fetch(&version.path, &local)
+        .and_then(|_| hash::hash_file(&local))
+        .map_err(|e| format!("cannot read {} of {}: {}", version.label, version.path, e));
+    let _ = fs::remove_file(&local);
+    result.map(|digest| hash::to_hex(&digest))
+}
+
+
+/// List the versions of a file of the source kept by a destination, in its
+/// snapshots, in its previous versions and as its current copy, leaving
+/// out those that didn't change since the one before
+pub fn history(destination: &str, path: &str, opts: &Options) -> Result<(), String> {
+    let relative = path.trim_matches('/');
+    let found = versions(destination, relative, opts)?;
+    if found.is_empty() {
+        return Err(format!("{} has no copy of {}", destination, relative));
+    }
+    let mut last = None;
+    for version in &found {
+        let digest = digest(version)?;
+        if last.as_ref() == Some(&digest) {
+            continue;
+        }
+        info!(
+            "{:19}  {:>10}  {}  {}",
+            version.label,
+            human_bytes(version.size),
+            schedule::local_time(version.modified).format(),
+            &digest[..16]
+        );
+        last = Some(digest);
+    }
+    Ok(())
+}
+
+
+/// Copy a version of a file listed by `history()` to `output` (by default
+/// the name of the file, in the current directory)
+pub fn restore_version(
+    destination: &str,
+    path: &str,
+    label: &str,
+    output: Option<&str>,
+    opts: &Options,
+) -> Result<(), String> {
+    let relative = path.trim_matches('/');
+    let version = versions(destination, relative, opts)?
+        .into_iter()
+        .find(|v| v.label == label)
+        .ok_or_else(|| format!("{} has no version '{}' of {}", destination, label, relative))?;
+    let name = Path::new(relative).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let output = output.unwrap_or(&name);
+    if Path::new(output).exists() && !opts.force {
+        return Err(format!("{} already exists; use --force to replace it", output));
+    }
+    let temporary = temporary::beside(output);
+    let result = version.target.fetch(&version.path, &temporary).and_then(|_| fs::rename(&temporary, output));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temporary);
+        return Err(format!("cannot restore {} of {} to {}: {}", label, relative, output, e));
+    }
+    info!("Restored {} of {} to {}", label, relative, output);
+    Ok(())
+}
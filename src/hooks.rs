@@ -0,0 +1,68 @@
+use std::process::Command;
+
+use crate::budget;
+use crate::log;
+use crate::status;
+
+
+
+/// The backup a hook runs around, as told to it in the environment
+pub struct Context<'a> {
+    pub source: &'a str,
+    pub destination: &'a str,
+    /// Name of the job of the configuration file, for the daemon
+    pub job: Option<&'a str>,
+    pub dry_run: bool,
+}
+
+
+/// Run a hook command with sh, with the backup described in BACKUP_RS_*
+/// environment variables (and, after it, its outcome), returning whether
+/// it succeeded
+fn run(command: &str, hook: &str, context: &Context, outcome: Option<(&str, i32)>) -> bool {
+    info!("Running the {}-backup hook: {}", hook, command);
+    let mut process = Command::new("sh");
+    process
+        .args(["-c", command])
+        .env("BACKUP_RS_HOOK", hook)
+        .env("BACKUP_RS_SOURCE", context.source)
+        .env("BACKUP_RS_DESTINATION", context.destination)
+        .env("BACKUP_RS_JOB", context.job.unwrap_or_default())
+        .env("BACKUP_RS_DRY_RUN", if context.dry_run { "1" } else { "0" });
+    if let Some((result, exit_status)) = outcome {
+        process.env("BACKUP_RS_RESULT", result).env("BACKUP_RS_EXIT_STATUS", exit_status.to_string());
+    }
+    match process.status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            error!("the {}-backup hook '{}' failed ({})", hook, command, status);
+            false
+        }
+        Err(e) => {
+            error!("cannot run the {}-backup hook '{}': {}", hook, command, e);
+            false
+        }
+    }
+}
+
+
+/// Run a backup between its pre-hook, which it doesn't start without
+/// (e.g. one dumping a database), and its post-hook, which runs whatever
+/// the backup's outcome (e.g. one unmounting the destination), once the
+/// destination is unlocked. Returns false if the backup or a hook failed.
+pub fn around(pre: Option<&str>, post: Option<&str>, context: &Context, backup: impl FnOnce() -> bool) -> bool {
+    let (errors, _) = log::counts();
+    let ok = match pre {
+        Some(command) if !run(command, "pre", context, None) => {
+            error!("not backing up {}: its pre-backup hook failed", context.source);
+            false
+        }
+        _ => backup(),
+    };
+    let Some(command) = post else {
+        return ok;
+    };
+    let result = status::result(ok, log::counts().0 - errors);
+    let exit_status = status::exit_status(result, !budget::exceeded());
+    run(command, "post", context, Some((result, exit_status))) && ok
+}
@@ -0,0 +1,222 @@
+use std::fs;
+use std::path::Path;
+
+use crate::backend;
+use crate::manifest::{self, Entry, Manifest};
+use crate::policy;
+use crate::regex::Regex;
+use crate::schedule;
+use crate::snapshot;
+use crate::stats::human_bytes;
+use crate::Options;
+
+
+
+/// Total size of the files below a directory of the manifest
+fn directory_size(manifest: &Manifest, relative: &str) -> u64 {
+    let prefix = if relative == "." { String::new() } else { format!("{}/", relative) };
+    manifest
+        .entries
+        .range(prefix.clone()..)
+        .take_while(|(path, _)| path.starts_with(&prefix))
+        .map(|(_, entry)| match entry {
+            Entry::File { size, .. } => *size,
+            _ => 0,
+        })
+        .sum()
+}
+
+
+/// Print one entry like `ls -l` does: type, size, modification time, name
+fn print_entry(manifest: &Manifest, relative: &str, name: &str, entry: &Entry) {
+    match entry {
+        Entry::Directory { .. } => {
+            info!("d  {:>10}  {:19}  {}/", human_bytes(directory_size(manifest, relative)), "", name)
+        }
+        Entry::File { size, mtime, .. } => info!(
+            "-  {:>10}  {}  {}",
+            human_bytes(*size),
+            schedule::local_time(*mtime).format(),
+            name
+        ),
+        Entry::Symlink { target } => info!("l  {:>10}  {:19}  {} -> {}", "", "", name, target),
+    }
+}
+
+
+/// The directory of a snapshot, for a mirror given as
+/// `DESTINATION::SNAPSHOT`, where SNAPSHOT is a name listed by
+/// `snapshots()` or `latest`
+pub fn resolve(mirror: &str, opts: &Options) -> Result<String, String> {
+    let (destination, name) = match mirror.rsplit_once("::") {
+        Some((destination, name)) if !destination.is_empty() && !name.contains('/') => (destination, name),
+        _ => return Ok(mirror.to_string()),
+    };
+    snapshot::check_local(destination)?;
+    let kind = snapshot::detect(destination, opts.snapshot);
+    let snapshots = snapshot::list(kind, destination)?;
+    let found = match name {
+        "latest" => snapshots.last(),
+        _ => snapshots.iter().find(|s| s.name() == name),
+    };
+    found
+        .map(|s| snapshot::directory(kind, destination, s))
+        .ok_or_else(|| format!("{} has no snapshot named '{}'", destination, name))
+}
+
+
+/// Number and total size of the files below a local directory
+fn count_files(directory: &Path, skip_meta: bool) -> (u64, u64) {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return (0, 0);
+    };
+    let (mut count, mut size) = (0, 0);
+    for entry in entries.filter_map(|e| e.ok()) {
+        if skip_meta && manifest::is_meta(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => {
+                let (c, s) = count_files(&entry.path(), false);
+                count += c;
+                size += s;
+            }
+            Ok(metadata) if metadata.is_file() => {
+                count += 1;
+                size += metadata.len();
+            }
+            _ => (),
+        }
+    }
+    (count, size)
+}
+
+
+/// List the snapshots of a local destination, with the number and size of
+/// the files they hold (from their manifest, or else from their files)
+pub fn snapshots(destination: &str, opts: &Options) -> Result<(), String> {
+    snapshot::check_local(destination)?;
+    let kind = snapshot::detect(destination, opts.snapshot);
+    let snapshots = snapshot::list(kind, destination)?;
+    if snapshots.is_empty() {
+        info!("{} has no snapshots", destination);
+    }
+    for snapshot in &snapshots {
+        let directory = snapshot::directory(kind, destination, snapshot);
+        let manifest = backend::open(&directory, opts)
+            .ok()
+            .and_then(|(target, root)| Manifest::load(target.as_ref(), &root));
+        let (count, size) = match manifest {
+            Some(manifest) => manifest.entries.values().fold((0, 0), |(count, size), entry| match entry {
+                Entry::File { size: file, .. } => (count + 1, size + file),
+                _ => (count, size),
+            }),
+            None => count_files(Path::new(&directory), true),
+        };
+        info!("{}  {} files, {}", snapshot.name(), count, human_bytes(size));
+    }
+    Ok(())
+}
+
+
+/// List a directory (or show a file) of a mirror, as of its last run,
+/// from its manifest rather than from the files themselves. `mirror` is a
+/// destination, a snapshot of one given by its directory or as
+/// `DESTINATION::SNAPSHOT`, and `path` is relative to the root of the
+/// source.
+pub fn ls(mirror: &str, path: Option<&str>, opts: &Options) -> Result<(), String> {
+    let mirror = &resolve(mirror, opts)?;
+    let (target, root) = backend::open(mirror, opts)?;
+    let manifest = Manifest::load(target.as_ref(), &root)
+        .ok_or_else(|| format!("{} has no manifest (backups keep one with --manifest)", mirror))?;
+    let relative = path
+        .map(|p| p.split('/').filter(|c| !c.is_empty() && *c != ".").collect::<Vec<_>>().join("/"))
+        .filter(|p| !p.is_empty())
+        .unwrap_or_else(|| ".".to_string());
+    let entry = manifest
+        .entries
+        .get(&relative)
+        .ok_or_else(|| format!("{} isn't in the backup", relative))?;
+    if !matches!(entry, Entry::Directory { .. }) {
+        print_entry(&manifest, &relative, &relative, entry);
+        return Ok(());
+    }
+    let prefix = if relative == "." { String::new() } else { format!("{}/", relative) };
+    for (path, entry) in manifest.entries.range(prefix.clone()..).take_while(|(p, _)| p.starts_with(&prefix)) {
+        let name = &path[prefix.len()..];
+        if name.contains('/') || path == "." {
+            continue;
+        }
+        print_entry(&manifest, path, name, entry);
+    }
+    Ok(())
+}
+
+
+/// What `find` looks for, besides the pattern: the size and modification
+/// time filters only let files through
+#[derive(Debug, Default)]
+pub struct Query {
+    /// The pattern is a regular expression, matched anywhere in the path,
+    /// rather than a glob
+    pub regex: bool,
+    /// Files of this size or more
+    pub larger: Option<u64>,
+    /// Files smaller than this
+    pub smaller: Option<u64>,
+    /// Files modified at this time or later
+    pub newer: Option<i64>,
+    /// Files modified before this time
+    pub older: Option<i64>,
+}
+
+
+impl Query {
+    fn filters(&self) -> bool {
+        self.larger.is_some() || self.smaller.is_some() || self.newer.is_some() || self.older.is_some()
+    }
+
+    fn admits(&self, entry: &Entry) -> bool {
+        match entry {
+            Entry::File { size, mtime, .. } => {
+                self.larger.is_none_or(|larger| *size >= larger)
+                    && self.smaller.is_none_or(|smaller| *size < smaller)
+                    && self.newer.is_none_or(|newer| *mtime >= newer)
+                    && self.older.is_none_or(|older| *mtime < older)
+            }
+            _ => !self.filters(),
+        }
+    }
+}
+
+
+/// List the entries of a mirror (as for `ls`) whose path, relative to the
+/// root of the source, matches a pattern, from its manifest rather than
+/// from the files themselves, with their sizes and modification times.
+/// A glob without a slash matches the names of the entries, as in
+/// `--exclude-from`. Finding nothing is an error.
+pub fn find(mirror: &str, pattern: &str, query: &Query, opts: &Options) -> Result<(), String> {
+    let mirror = &resolve(mirror, opts)?;
+    let (target, root) = backend::open(mirror, opts)?;
+    let manifest = Manifest::load(target.as_ref(), &root)
+        .ok_or_else(|| format!("{} has no manifest (backups keep one with --manifest)", mirror))?;
+    let regex = match query.regex {
+        true => Some(Regex::new(pattern)?),
+        false => None,
+    };
+    let mut found = 0;
+    for (path, entry) in &manifest.entries {
+        let matches = match &regex {
+            Some(regex) => regex.is_match(path),
+            None => policy::matches(pattern, path),
+        };
+        if path != "." && matches && query.admits(entry) {
+            print_entry(&manifest, path, path, entry);
+            found += 1;
+        }
+    }
+    match found {
+        0 => Err(format!("nothing in {} matches '{}'", mirror, pattern)),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::raw::c_int;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::hash::{self, Sha256};
+use crate::manifest::META_DIR;
+
+
+
+extern "C" {
+    fn flock(fd: c_int, operation: c_int) -> c_int;
+}
+
+
+const LOCK_EX: c_int = 2;
+const LOCK_NB: c_int = 4;
+
+
+/// Exclusive right to modify a destination, held until dropped
+pub struct Lock {
+    _file: fs::File,
+}
+
+
+/// Where the lock of a destination is: inside it if it is local, or else on
+/// this machine, named after the destination
+fn lock_path(destination: &str, local_path: Option<&str>) -> PathBuf {
+    match local_path {
+        Some(path) => Path::new(path).join(META_DIR).join("lock"),
+        None => {
+            let mut hasher = Sha256::new();
+            hasher.update(destination.as_bytes());
+            let id = hash::to_hex(&hasher.finish());
+            std::env::temp_dir().join(format!("backup-rs-{}.lock", &id[..16]))
+        }
+    }
+}
+
+
+/// Lock a destination so that no other run modifies it at the same time,
+/// failing right away if another one holds the lock. `local_path` is the
+/// path of a local destination; the lock of a remote one only keeps out
+/// the runs of this machine.
+pub fn acquire(destination: &str, local_path: Option<&str>) -> Result<Lock, String> {
+    let path = lock_path(destination, local_path);
+    let mut file = fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| fs::File::options().read(true).write(true).create(true).truncate(false).open(&path))
+        .map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+    if unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) } != 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::WouldBlock {
+            return Err(format!("cannot lock {}: {}", path.display(), e));
+        }
+        let mut pid = String::new();
+        let _ = file.read_to_string(&mut pid);
+        return Err(match pid.trim() {
+            "" => format!("another backup-rs is already running on {}", destination),
+            pid => format!("another backup-rs (pid {}) is already running on {}", pid, destination),
+        });
+    }
+    // Tell whoever finds the destination locked who holds it
+    let _ = file
+        .set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| writeln!(file, "{}", std::process::id()));
+    Ok(Lock { _file: file })
+}
+
+
+/// The process ID of the run holding the lock of a destination, if one
+/// does (`local_path` as for `acquire`)
+pub fn holder(destination: &str, local_path: Option<&str>) -> Result<Option<u32>, String> {
+    let path = lock_path(destination, local_path);
+    let mut file = match fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("cannot open {}: {}", path.display(), e)),
+    };
+    // Getting the lock means that nobody holds it; it is let go with the file
+    if unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) } == 0 {
+        return Ok(None);
+    }
+    let mut pid = String::new();
+    file.read_to_string(&mut pid).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    pid.trim().parse().map(Some).map_err(|_| format!("{} doesn't tell which process holds it", path.display()))
+}
@@ -0,0 +1,354 @@
+use std::ffi::CString;
+use std::io::{self, IsTerminal};
+use std::os::raw::{c_char, c_int};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::Mutex;
+
+
+
+extern "C" {
+    fn openlog(ident: *const c_char, option: c_int, facility: c_int);
+    fn syslog(priority: c_int, format: *const c_char, ...);
+}
+
+
+const LOG_PID: c_int = 0x01;
+const LOG_DAEMON: c_int = 3 << 3;
+
+
+/// Send messages to syslog (and thus to journald) instead of the terminal
+static SYSLOG: AtomicBool = AtomicBool::new(false);
+
+
+/// Print everything to stderr, stdout carrying data (e.g. an archive)
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+
+/// Verbosity levels: errors only (-q), the summary and problems (the
+/// default), each action on an entry (-v), and why entries are skipped
+/// (-vv)
+pub const QUIET: u8 = 0;
+pub const NORMAL: u8 = 1;
+pub const VERBOSE: u8 = 2;
+pub const DETAILED: u8 = 3;
+
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(NORMAL);
+
+
+/// Color the messages printed to stdout and to stderr
+static COLOR_STDOUT: AtomicBool = AtomicBool::new(false);
+static COLOR_STDERR: AtomicBool = AtomicBool::new(false);
+
+
+/// Errors and warnings printed since `collect_problems()` was called, if
+/// it was (for the mails sent after runs)
+static PROBLEMS: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+
+/// Messages kept instead of printed while the dashboard (`--tui`) is
+/// shown, those of the verbosity to be printed once it is gone, and the
+/// errors and warnings whatever the verbosity
+static CAPTURED: Mutex<Option<Vec<(Level, String)>>> = Mutex::new(None);
+
+
+/// Number of errors and warnings printed so far
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+static WARNINGS: AtomicU64 = AtomicU64::new(0);
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+    Info,
+    /// Something done to an entry (copying, removing...), printed with -v
+    Action,
+    /// Why an entry is left alone, printed with -vv
+    Detail,
+}
+
+
+impl Level {
+    fn priority(self) -> c_int {
+        match self {
+            Level::Error => 3,
+            Level::Warning => 4,
+            Level::Info | Level::Action => 6,
+            Level::Detail => 7,
+        }
+    }
+
+    /// The verbosity messages of this severity are printed from
+    fn verbosity(self) -> u8 {
+        match self {
+            Level::Error => QUIET,
+            Level::Warning | Level::Info => NORMAL,
+            Level::Action => VERBOSE,
+            Level::Detail => DETAILED,
+        }
+    }
+}
+
+
+/// When to color messages by their severity and by what they report
+/// (`--color`)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Color {
+    /// On a terminal, unless the NO_COLOR environment variable is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+
+impl Color {
+    pub fn parse(text: &str) -> Result<Color, String> {
+        match text {
+            "auto" => Ok(Color::Auto),
+            "always" => Ok(Color::Always),
+            "never" => Ok(Color::Never),
+            _ => Err(format!("invalid color mode '{}' (expected always, never or auto)", text)),
+        }
+    }
+}
+
+
+/// Route all further messages to syslog
+pub fn use_syslog() {
+    // openlog() keeps the pointer, so the identifier must live forever
+    static IDENT: &std::ffi::CStr = c"backup-rs";
+    unsafe { openlog(IDENT.as_ptr(), LOG_PID, LOG_DAEMON) };
+    SYSLOG.store(true, Ordering::SeqCst);
+}
+
+
+/// Print all further messages to stderr, leaving stdout to data
+pub fn use_stderr() {
+    STDERR.store(true, Ordering::SeqCst);
+}
+
+
+/// Color all further messages printed to the terminal as chosen
+pub fn use_color(color: Color) {
+    let colored = |terminal: bool| match color {
+        Color::Always => true,
+        Color::Never => false,
+        Color::Auto => {
+            terminal
+                && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && std::env::var_os("TERM").is_none_or(|term| term != "dumb")
+        }
+    };
+    COLOR_STDOUT.store(colored(io::stdout().is_terminal()), Ordering::SeqCst);
+    COLOR_STDERR.store(colored(io::stderr().is_terminal()), Ordering::SeqCst);
+}
+
+
+/// The escape sequence coloring a message: errors in bold red, warnings in
+/// yellow, and by their first word (or change code, with
+/// --itemize-changes) removals in red, copies in green and entries left
+/// alone in cyan
+fn color(level: Level, text: &str) -> Option<&'static str> {
+    match level {
+        Level::Error => return Some("\x1b[1;31m"),
+        Level::Warning => return Some("\x1b[33m"),
+        Level::Info | Level::Action | Level::Detail => (),
+    }
+    // Change codes take 11 columns, padded with spaces
+    let itemized = text.as_bytes().get(11) == Some(&b' ');
+    match text.chars().next().filter(|_| itemized) {
+        Some('*') => return Some("\x1b[31m"),
+        Some('>' | '<' | 'c') => return Some("\x1b[32m"),
+        _ => (),
+    }
+    match text.split(' ').next()? {
+        "Removing" | "Pruning" | "Forgetting" => Some("\x1b[31m"),
+        "Copying" | "Creating" | "Updating" | "Restoring" | "Storing" | "Adding" => Some("\x1b[32m"),
+        "Skipping" | "Keeping" | "Not" => Some("\x1b[36m"),
+        _ => None,
+    }
+}
+
+
+/// Print only the messages of the given verbosity and below
+pub fn set_verbosity(verbosity: u8) {
+    VERBOSITY.store(verbosity, Ordering::SeqCst);
+}
+
+
+pub fn verbosity() -> u8 {
+    VERBOSITY.load(Ordering::SeqCst)
+}
+
+
+/// Keep the errors and warnings printed from now on, whatever the
+/// verbosity, until `take_problems()` is called
+pub fn collect_problems() {
+    *PROBLEMS.lock().unwrap() = Some(Vec::new());
+}
+
+
+/// The errors and warnings printed since `collect_problems()`, no longer
+/// keeping them
+pub fn take_problems() -> Vec<String> {
+    PROBLEMS.lock().unwrap().take().unwrap_or_default()
+}
+
+
+/// Keep the messages from now on instead of printing them, until
+/// `release()` is called
+pub fn capture() {
+    *CAPTURED.lock().unwrap() = Some(Vec::new());
+}
+
+
+/// Print the messages kept since `capture()`, printing the next ones
+/// again
+pub fn release() {
+    let Some(captured) = CAPTURED.lock().unwrap().take() else {
+        return;
+    };
+    for (level, text) in captured {
+        if level.verbosity() <= VERBOSITY.load(Ordering::SeqCst) {
+            print(level, &text);
+        }
+    }
+}
+
+
+/// The last `count` errors and warnings kept since `capture()`, the last
+/// one last
+pub fn captured_problems(count: usize) -> Vec<String> {
+    let captured = CAPTURED.lock().unwrap();
+    let mut problems: Vec<String> = captured
+        .iter()
+        .flatten()
+        .rev()
+        .filter(|(level, _)| matches!(level, Level::Error | Level::Warning))
+        .take(count)
+        .map(|(_, text)| text.clone())
+        .collect();
+    problems.reverse();
+    problems
+}
+
+
+/// Number of errors and warnings printed so far
+pub fn counts() -> (u64, u64) {
+    (ERRORS.load(Ordering::SeqCst), WARNINGS.load(Ordering::SeqCst))
+}
+
+
+/// Print a message with the given severity
+pub fn message(level: Level, text: &str) {
+    match level {
+        Level::Error => ERRORS.fetch_add(1, Ordering::SeqCst),
+        Level::Warning => WARNINGS.fetch_add(1, Ordering::SeqCst),
+        _ => 0,
+    };
+    if let (Level::Error | Level::Warning, Some(problems)) = (level, PROBLEMS.lock().unwrap().as_mut()) {
+        problems.push(text.to_string());
+    }
+    let printed = level.verbosity() <= VERBOSITY.load(Ordering::SeqCst);
+    if let Some(captured) = CAPTURED.lock().unwrap().as_mut() {
+        if printed || matches!(level, Level::Error | Level::Warning) {
+            captured.push((level, text.to_string()));
+        }
+        return;
+    }
+    if printed {
+        print(level, text);
+    }
+}
+
+
+fn print(level: Level, text: &str) {
+    if SYSLOG.load(Ordering::SeqCst) {
+        let text = CString::new(text.replace('\0', "")).unwrap();
+        unsafe {
+            syslog(
+                level.priority(),
+                c"%s".as_ptr(),
+                text.as_ptr(),
+            )
+        };
+        return;
+    }
+    let stdout = !matches!(level, Level::Error | Level::Warning) && !STDERR.load(Ordering::SeqCst);
+    let colored = match stdout {
+        true => COLOR_STDOUT.load(Ordering::SeqCst),
+        false => COLOR_STDERR.load(Ordering::SeqCst),
+    };
+    let text = match color(level, text).filter(|_| colored) {
+        Some(escape) => format!("{}{}\x1b[0m", escape, text),
+        None => text.to_string(),
+    };
+    match stdout {
+        true => println!("{}", text),
+        false => eprintln!("{}", text),
+    }
+}
+
+
+/// Print a horizontal rule separating sections of terminal output
+pub fn separator() {
+    if SYSLOG.load(Ordering::SeqCst) || VERBOSITY.load(Ordering::SeqCst) == QUIET {
+        return;
+    }
+    if let Some(captured) = CAPTURED.lock().unwrap().as_mut() {
+        captured.push((Level::Info, "-".repeat(80)));
+        return;
+    }
+    match STDERR.load(Ordering::SeqCst) {
+        true => eprintln!("{}", "-".repeat(80)),
+        false => println!("{}", "-".repeat(80)),
+    }
+}
+
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::message($crate::log::Level::Info, &format!($($arg)*))
+    };
+}
+
+
+#[macro_export]
+macro_rules! action {
+    ($($arg:tt)*) => {
+        $crate::log::message($crate::log::Level::Action, &format!($($arg)*))
+    };
+}
+
+
+#[macro_export]
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        $crate::log::message($crate::log::Level::Detail, &format!($($arg)*))
+    };
+}
+
+
+#[macro_export]
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::log::message(
+            $crate::log::Level::Warning,
+            &format!("Warning: {}", format!($($arg)*)),
+        )
+    };
+}
+
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::message(
+            $crate::log::Level::Error,
+            &format!("Error: {}", format!($($arg)*)),
+        )
+    };
+}
@@ -0,0 +1,199 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::hooks::Context;
+use crate::log;
+use crate::schedule;
+use crate::stats::human_bytes;
+use crate::status::{self, Outcome};
+
+
+
+/// SMTP server mails are sent through unless told otherwise: a relay on
+/// this machine (as postfix, exim or msmtpd)
+pub const DEFAULT_SERVER: &str = "localhost:25";
+
+
+/// Give up on a server that doesn't answer in this long
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+
+/// Which runs are mailed about (`--mail-on`)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MailOn {
+    /// Those that didn't succeed
+    #[default]
+    Failure,
+    Always,
+}
+
+
+impl MailOn {
+    pub fn parse(text: &str) -> Result<MailOn, String> {
+        match text {
+            "failure" => Ok(MailOn::Failure),
+            "always" => Ok(MailOn::Always),
+            _ => Err(format!("invalid mail condition '{}' (expected failure or always)", text)),
+        }
+    }
+}
+
+
+/// Where and when to mail the outcome of runs
+#[derive(Clone, Debug)]
+pub struct Mail {
+    /// Addresses, separated by commas
+    pub to: String,
+    pub on: MailOn,
+    /// HOST[:PORT] of the SMTP server
+    pub server: String,
+}
+
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .map(|name| name.trim().to_string())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+
+/// The current time as the Date header of a mail (RFC 5322), e.g.
+/// `Wed, 14 Oct 2026 09:16:33 +0200`
+fn date() -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let now = schedule::now();
+    let time = schedule::local_time(now);
+    let offset = schedule::utc_offset(now);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} {}{:02}{:02}",
+        DAYS[time.weekday as usize],
+        time.day,
+        MONTHS[(time.month as usize + 11) % 12],
+        time.year,
+        time.hour,
+        time.minute,
+        time.second,
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 3600,
+        offset.abs() % 3600 / 60
+    )
+}
+
+
+/// Read a reply of the server (the last line of a multiline one holding a
+/// space after the code), failing unless its code is the one expected
+fn expect(reader: &mut impl BufRead, code: &str) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection"));
+        }
+        if !line.starts_with(code) {
+            return Err(io::Error::other(format!("the server replied {}", line.trim_end())));
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+
+/// Send a mail over plain SMTP (without STARTTLS or authentication, as
+/// relays on this machine or the local network take them), to each of the
+/// addresses of `to`, separated by commas
+fn send(server: &str, from: &str, to: &str, subject: &str, body: &str) -> io::Result<()> {
+    let recipients: Vec<&str> = to.split(',').map(str::trim).filter(|address| !address.is_empty()).collect();
+    let server = if server.contains(':') { server.to_string() } else { format!("{}:25", server) };
+    let stream = TcpStream::connect(&server)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    expect(&mut reader, "220")?;
+    write!(writer, "EHLO {}\r\n", hostname())?;
+    expect(&mut reader, "250")?;
+    write!(writer, "MAIL FROM:<{}>\r\n", from)?;
+    expect(&mut reader, "250")?;
+    for recipient in &recipients {
+        write!(writer, "RCPT TO:<{}>\r\n", recipient)?;
+        expect(&mut reader, "25")?;
+    }
+    write!(writer, "DATA\r\n")?;
+    expect(&mut reader, "354")?;
+    let mut message = format!(
+        "Date: {}\r\nFrom: backup-rs <{}>\r\nTo: {}\r\nSubject: {}\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        date(),
+        from,
+        recipients.join(", "),
+        subject
+    );
+    for line in body.lines() {
+        // A line holding a dot alone would end the mail
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".\r\n");
+    writer.write_all(message.as_bytes())?;
+    expect(&mut reader, "250")?;
+    write!(writer, "QUIT\r\n")?;
+    Ok(())
+}
+
+
+/// Run a backup and mail how it went (what it changed and the errors and
+/// warnings it printed) if it didn't succeed, or always with
+/// `MailOn::Always`. Dry runs aren't mailed about. Returns whether the
+/// backup succeeded.
+pub fn around(mail: Option<&Mail>, context: &Context, backup: impl FnOnce() -> bool) -> bool {
+    let Some(mail) = mail.filter(|_| !context.dry_run) else {
+        return backup();
+    };
+    log::collect_problems();
+    status::around(context, backup, |outcome| {
+        let problems = log::take_problems();
+        if outcome.exit_status != 0 || mail.on == MailOn::Always {
+            send_outcome(mail, context, outcome, &problems);
+        }
+    })
+}
+
+
+/// Mail how a run went
+fn send_outcome(mail: &Mail, context: &Context, outcome: &Outcome, problems: &[String]) {
+    let Outcome { result, exit_status, ref run, ref totals, .. } = *outcome;
+    let what = match context.job {
+        Some(job) => format!("job '{}'", job),
+        None => format!("{} to {}", context.source, context.destination),
+    };
+    let subject = format!("backup-rs on {}: {} ({})", hostname(), what, result);
+    let mut body = String::new();
+    writeln!(body, "Source: {}", context.source).unwrap();
+    writeln!(body, "Destination: {}", context.destination).unwrap();
+    writeln!(body, "Run: {}", if run.is_empty() { "none (it didn't start)" } else { run }).unwrap();
+    writeln!(body, "Result: {} (exit status {})", result, exit_status).unwrap();
+    writeln!(body, "Duration: {:.1}s", outcome.duration.as_secs_f64()).unwrap();
+    writeln!(body, "Copied: {} ({})", totals.copied, human_bytes(totals.bytes)).unwrap();
+    writeln!(body, "Removed: {}", totals.removed).unwrap();
+    if !problems.is_empty() {
+        writeln!(body, "\nErrors and warnings:").unwrap();
+        for problem in problems {
+            writeln!(body, "  {}", problem).unwrap();
+        }
+    }
+    let from = format!("backup-rs@{}", hostname());
+    match send(&mail.server, &from, &mail.to, &subject, &body) {
+        Ok(()) => info!("Mailed the outcome of the run to {}", mail.to),
+        Err(e) => warning!("cannot mail {} through {}: {}", mail.to, mail.server, e),
+    }
+}
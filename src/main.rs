@@ -1,252 +1,2408 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
 
+#[macro_use]
+mod log;
+mod adopt;
+mod android;
+mod archive;
+mod backend;
+mod batched;
+mod budget;
+mod bundle;
+mod capabilities;
+mod case;
+mod changes;
+mod check;
+mod checksums;
+mod chunker;
+mod compare;
+mod compressed;
+mod config;
+mod confirm;
+mod control;
+mod crypto;
+mod daemon;
+mod deletions;
+mod delta;
+mod diff;
+mod drill;
+mod encrypted;
+mod engine;
+mod estimate;
+mod fat;
+mod filelist;
+mod history;
+mod hooks;
+mod listing;
+mod lock;
+mod mail;
+mod hash;
+mod manifest;
+mod metrics;
+#[cfg(target_os = "linux")]
+mod mount;
+mod nfs;
+mod order;
+mod pause;
+mod notify;
+mod policy;
+mod pool;
+mod preconditions;
+mod priority;
+mod progress;
+mod prune;
+mod readahead;
+mod regex;
+mod renames;
+mod repair;
+mod report;
+mod repository;
+mod restore;
+mod retry;
+mod sanitized;
+mod schedule;
+mod skiplist;
+mod smb;
+mod snapshot;
+mod special;
+mod space;
+mod staging;
+mod ssh;
+mod stats;
+mod status;
+mod stream;
+mod summary;
+mod symlinks;
+mod systemd;
+mod tar;
+mod temporary;
+mod throttle;
+mod timeout;
+mod tui;
+mod unicode;
+mod unicode_tables;
+mod uring;
+mod versions;
+#[cfg(target_os = "linux")]
+mod watch;
+mod zip;
 
+use budget::Budget;
+use case::Collisions;
+use checksums::Checksums;
+use compare::Comparator;
+use compressed::Compression;
+use confirm::Removal;
+use deletions::Deletions;
+use engine::{backup, backup_listed, remove_path, remove_removed, remove_vanished, wait_copies};
+use manifest::Manifest;
+use order::{Order, Queue};
+use policy::Policy;
+use pool::CopyPool;
+use preconditions::Precondition;
+use report::ErrorReport;
+use skiplist::SkipList;
+use snapshot::SnapshotKind;
+use space::OutOfSpace;
+use stats::{human_bytes, Stats};
+use status::Run;
+use summary::Summary;
+use symlinks::SymlinkPolicy;
+use unicode::Form;
+use versions::Versions;
 
-/// Get the size of a file
-fn size(file: &str) -> u64 {
-    let file = fs::File::open(file).unwrap();
-    let metadata = file.metadata().unwrap();
-    metadata.len()
+
+
+/// Settings of a backup run
+#[derive(Clone, Default)]
+pub struct Options {
+    /// Only report what would be done
+    pub dry_run: bool,
+    /// Keep a manifest of the source in the destination, used to skip
+    /// unchanged subtrees in later runs
+    pub manifest: bool,
+    /// Source directories known to be unchanged since the last run
+    pub unchanged: HashSet<PathBuf>,
+    /// Destination paths that belong to backup-rs itself
+    pub protected: HashSet<PathBuf>,
+    /// Snapshot the destination after a successful run
+    pub snapshot: Option<SnapshotKind>,
+    /// Encrypt what is written to a new destination
+    pub encrypt: bool,
+    /// Also encrypt the names of files and directories
+    pub encrypt_names: bool,
+    /// File holding the secret the encryption keys are derived from
+    pub key_file: Option<String>,
+    /// Per-path attributes (e.g. verifying the copies of some files)
+    pub policy: Policy,
+    /// Store the files written to the destination compressed
+    pub compress: Option<Compression>,
+    /// Batch the files up to this size into packs
+    pub batch_small_files: Option<u64>,
+    /// Store the entries whose names the destination can't hold under
+    /// sanitized names
+    pub sanitize_names: bool,
+    /// Store names in this Unicode normalization form, taking those that
+    /// are the same once normalized as the same
+    pub normalize: Option<Form>,
+    /// The destination doesn't tell names apart by case (found out when
+    /// the run starts)
+    pub case_insensitive: bool,
+    /// What to do with the entries whose names would be the same as
+    /// others' in the destination
+    pub case_collisions: Collisions,
+    /// Update large files that changed by writing only the data that
+    /// differs
+    pub delta: bool,
+    /// With a manifest, move the copies of the entries moved in the source
+    /// instead of copying them again
+    pub detect_renames: bool,
+    /// Take the manifest of the last run as what the destination holds,
+    /// instead of reading it
+    pub trust_manifest: bool,
+    /// The manifest of the last run, when it is trusted or the comparator
+    /// needs it
+    pub previous: Option<Rc<Manifest>>,
+    /// How to tell whether the copy of a file is out of date (by size and
+    /// modification time, if not set)
+    pub comparator: Option<Rc<dyn Comparator>>,
+    /// Look for and correct the damage done to the destination by other
+    /// means than backups, before bringing it up to date
+    pub repair: bool,
+    /// Keep the entries missing from the source for this many days before
+    /// removing them from the destination
+    pub delete_after_days: Option<u64>,
+    /// Deletions held back by `delete_after_days`, during a run
+    pub deletions: Option<Rc<Deletions>>,
+    /// Keep this many previous versions of the files overwritten
+    pub keep_versions: Option<usize>,
+    /// The previous versions kept by `keep_versions`, during a run
+    pub versions: Option<Rc<Versions>>,
+    /// What must hold for the source to be backed up at all
+    pub preconditions: Vec<Precondition>,
+    /// What the run changed, during a run
+    pub summary: Option<Rc<Summary>>,
+    /// The paths of the source that couldn't be backed up, during a run
+    pub errors: Option<Rc<ErrorReport>>,
+    /// Write them to this file too, as JSON
+    pub error_report: Option<PathBuf>,
+    /// The only paths to copy, as listed (relative to the source root)
+    pub files_from: Option<Vec<String>>,
+    /// Don't go into the directories of the source other file systems are
+    /// mounted on
+    pub one_file_system: bool,
+    /// Print an rsync-style change code for each change instead of the
+    /// usual messages
+    pub itemize: bool,
+    /// Back up to a destination that isn't marked as one, or import a bundle
+    /// into a replica that doesn't match it
+    pub force: bool,
+    /// Make the changes of a run in a staging directory, put in place of
+    /// the destination at the end
+    pub atomic: bool,
+    /// Only warn when the destination seems too small for the copies
+    pub ignore_space: bool,
+    /// Read each copied file back from the destination and check it
+    pub verify_writes: bool,
+    /// Flush each copy to the disk, and the directories whose entries
+    /// change
+    pub fsync: bool,
+    /// Flush the filesystem of the destination once the run is over
+    pub fsync_at_end: bool,
+    /// Remove old snapshots and versions when a local destination fills up
+    pub emergency_prune: bool,
+    /// How long to wait for space when a local destination fills up
+    pub wait_for_space: Option<Duration>,
+    /// What to do about it, during a run
+    pub out_of_space: Option<Rc<OutOfSpace>>,
+    /// Ask before removing the entries missing from the source
+    pub confirm_delete: bool,
+    /// Entries to remove, collected instead of removed until they are
+    /// confirmed
+    pub removals: Option<Rc<RefCell<Vec<Removal>>>>,
+    /// The destination is on an NFS mount
+    pub nfs: bool,
+    /// The destination is on the shared storage of an Android device
+    pub android: bool,
+    /// Timestamps this many seconds apart are taken as equal
+    pub modify_window: u64,
+    /// Go through the entries of each directory in the order of their names,
+    /// rather than in the order the file system lists them in
+    pub sorted: bool,
+    /// What to do with the symlinks of the source
+    pub symlinks: SymlinkPolicy,
+    /// Recreate the device nodes of the source in the destination
+    pub devices: bool,
+    /// Recreate the FIFOs and sockets of the source in the destination
+    pub specials: bool,
+    /// Flag the runs that change more than this
+    pub budget: Option<Budget>,
+    /// The paths of the source to leave alone and those that failed,
+    /// during a run
+    pub skip_list: Option<Rc<SkipList>>,
+    /// The hashes of the files of the destination, during a run
+    pub checksums: Option<Rc<Checksums>>,
+    /// Restore the files that don't match their hash anyway
+    pub best_effort: bool,
+    /// Give up on the paths of the source that don't answer in this long
+    pub timeout: Option<Duration>,
+    /// Bytes per second the copies are limited to
+    pub bwlimit: Option<u64>,
+    /// Size of the buffer the copies to local destinations go through
+    pub buffer_size: Option<usize>,
+    /// Copy and look up the local files through io_uring (--engine uring)
+    pub uring: bool,
+    /// Try the copies that fail with a transient error again this many
+    /// times, waiting this long (`retry::DEFAULT_DELAY` by default)
+    pub retries: u32,
+    pub retry_delay: Option<Duration>,
+    /// Threads scanning the source (for the manifest, and ahead of the
+    /// run), and threads copying files to a local destination (one of each
+    /// if 0)
+    pub scan_jobs: usize,
+    pub copy_jobs: usize,
+    /// The workers copying files, during a run with `--copy-jobs`
+    pub copies: Option<Rc<CopyPool>>,
+    /// Order to make the copies in, once the run went through the source,
+    /// and the copies held until then
+    pub order: Option<Order>,
+    pub queued: Option<Rc<Queue>>,
+    /// Copies of directories (and the directories of the source they are
+    /// of) to give their metadata once the copies into them are done
+    pub directories: Rc<RefCell<Vec<(String, String)>>>,
 }
 
 
-/// Get the last modified time of a file
-fn modified_time(file: &str) -> std::time::SystemTime {
-    let metadata = fs::metadata(file).unwrap();
-    metadata.modified().unwrap()
+
+/// Most threads `--scan-jobs` and `--copy-jobs` (and the matching keys of
+/// the configuration) may start
+pub const MAX_JOBS: usize = 1024;
+
+
+const USAGE: &str = "\
+    Usage: backup-rs [OPTION]... SOURCE DESTINATION
+      or:  backup-rs backup [OPTION]... SOURCE DESTINATION
+      or:  backup-rs watch [OPTION]... SOURCE DESTINATION
+      or:  backup-rs daemon [OPTION]...
+      or:  backup-rs drill [OPTION]... [SOURCE] DESTINATION
+      or:  backup-rs verify [OPTION]... [SOURCE] DESTINATION
+      or:  backup-rs check [--percent N] DESTINATION
+      or:  backup-rs repair [OPTION]... SOURCE DESTINATION
+      or:  backup-rs adopt [OPTION]... SOURCE DESTINATION
+      or:  backup-rs restore [OPTION]... DESTINATION DIRECTORY
+      or:  backup-rs ls [OPTION]... MIRROR [PATH]
+      or:  backup-rs find [OPTION]... MIRROR PATTERN
+      or:  backup-rs mount [OPTION]... MIRROR MOUNTPOINT
+      or:  backup-rs history [OPTION]... DESTINATION PATH [FILE]
+      or:  backup-rs snapshots [OPTION]... DESTINATION
+      or:  backup-rs prune [OPTION]... DESTINATION
+      or:  backup-rs changes [--since SNAPSHOT] SOURCE DESTINATION
+      or:  backup-rs diff [OPTION]... A B
+      or:  backup-rs estimate [OPTION]... SOURCE DESTINATION
+      or:  backup-rs export [--since SNAPSHOT] DESTINATION BUNDLE
+      or:  backup-rs import [OPTION]... BUNDLE REPLICA
+      or:  backup-rs repo init|snapshots|gc [OPTION]... REPO
+      or:  backup-rs repo backup [OPTION]... SOURCE REPO
+      or:  backup-rs repo restore [OPTION]... REPO SNAPSHOT DIRECTORY
+      or:  backup-rs repo forget [OPTION]... REPO SNAPSHOT...
+      or:  backup-rs ctl pause|resume DESTINATION
+
+    DESTINATION may be a remote directory, [USER@]HOST:PATH, which is
+    accessed by running commands over ssh, or a directory of an SMB/CIFS
+    share, smb://[USER@]HOST/SHARE/PATH, which is accessed with smbclient
+    (from Samba), the password of USER being taken from the PASSWD
+    environment variable (without USER, as a guest). Names holding ; or \"
+    can't be stored on a share, and neither can symlinks. A local
+    DESTINATION can't be SOURCE itself, hold it, or be inside it unless it
+    is excluded from the backup (with --exclude-from).
+
+    Each run (but dry ones) records when it ended, its outcome (result ok,
+    errors or failed, and exit_status), the number of errors and warnings
+    and what it changed in DESTINATION/.backup-rs/status, as KEY VALUE
+    lines. It also locks DESTINATION while it runs (with
+    DESTINATION/.backup-rs/lock, or a lock file in the temporary directory
+    of this machine for remote destinations), failing right away if
+    another run holds the lock.
+
+    The paths of SOURCE listed in DESTINATION/.backup-rs/skip-list (one per
+    line, relative to SOURCE, # starting a comment) are left alone, their
+    copies being kept as they are, so that paths known to be bad (e.g. a
+    broken NFS export) don't stall or fail every run. Paths that fail in 3
+    runs in a row are added to it; remove them once they are fixed.
+
+    File capabilities (as set with setcap, e.g. on ping) are copied along
+    with the files to local destinations, and restored with them, which
+    takes running as root; the files whose capabilities can't be kept
+    (e.g. on a remote or encrypted DESTINATION) are reported.
+
+    watch performs an initial backup and then keeps mirroring the changes
+    made in SOURCE as they happen (Linux only).
+
+    daemon runs the jobs of the configuration file on their schedules, in
+    the background, logging to syslog. Jobs are defined as:
+
+      [job.home]
+      source = \"/home/me\"
+      destination = \"/mnt/backup/home\"
+      schedule = \"0 3 * * *\"  # minute hour day-of-month month day-of-week
+      prewarm = true  # watch the source between runs, so that runs only
+                      # sync what changed (with a full run every
+      full_scan_interval = \"24h\"  # 24 hours by default)
+      delete_after_days = 7  # as --delete-after-days
+      versions = 3  # as --versions
+      batch_small_files = \"64K\"  # as --batch-small-files
+      change_budget = \"20%,50G\"  # as --change-budget
+      timeout = \"30s\"  # as --timeout
+      bwlimit = \"10M\"  # as --bwlimit
+      buffer_size = \"4M\"  # as --buffer-size
+      engine = \"uring\"  # as --engine
+      order = \"newest-first\"  # as --order
+      error_report = \"/var/log/backup-rs/home.json\"  # as --error-report
+      retries = 3  # as --retries
+      retry_delay = 500  # as --retry-delay
+      emergency_prune = true  # as --emergency-prune
+      one_file_system = true  # as --one-file-system
+      sanitize_names = true  # as --sanitize-names
+      normalize = \"nfc\"  # as --normalize
+      case_collisions = \"rename\"  # as --case-collisions
+      verify_writes = true  # as --verify-writes
+      fsync = true  # as --fsync
+      fsync_at_end = true  # as --fsync-at-end
+      sorted = true  # as --sorted
+      scan_jobs = 8  # as --scan-jobs
+      copy_jobs = 4  # as --copy-jobs
+      modify_window = 2  # as --modify-window
+      wait_for_space = \"1h\"  # as --wait-for-space
+      compare = \"checksum\"  # as --compare
+      require = [\"mounted:/home\", \"min-files:1000\"]  # as --require
+      allow_empty_source = false  # as --allow-empty-source
+      exclude = [\".cache\", \"*.tmp\"]  # as the patterns of --exclude-from
+      symlinks = \"placeholder\"  # as --symlinks
+      devices = true  # as --devices
+      specials = true  # as --specials
+      transform = [\"*.db sqlite3 \\\"$BACKUP_RS_SOURCE\\\" .dump\"]  # as --transform
+      pre_hook = \"pg_dump mydb > /home/me/mydb.sql\"  # as --pre-hook
+      post_hook = \"umount /mnt/backup\"  # as --post-hook
+      notify_url = \"https://hc-ping.com/UUID\"  # as --notify-url
+      notify_desktop = true  # as --notify-desktop
+      mail_to = \"me@example.com\"  # as --mail-to
+      mail_on = \"always\"  # as --mail-on
+      mail_server = \"relay.example.com:25\"  # as --mail-server
+      metrics_file = \"/var/lib/node_exporter/backup.prom\"  # as --metrics-file
+
+    drill restores a random sample of the files of DESTINATION to a
+    temporary directory and checks that they match SOURCE (or, without
+    SOURCE, the manifest), as proof that the backup can be restored. verify
+    does the same with every file, recording how far it got in
+    DESTINATION/.backup-rs as it goes, so that an interrupted verify resumes
+    from there. Against SOURCE, with a manifest, verify also records the
+    rollup hash of the directories whose files were all verified, and then
+    leaves out those whose rollup is still the same in SOURCE and in the
+    manifest.
+
+    check reads every file of DESTINATION back (from the disk itself, for
+    a local one) and compares it with the size and hash it had when it was
+    copied, which every run records in DESTINATION/.backup-rs/checksums,
+    along with the modification time it left the copy with, to find the
+    copies that rotted since (their contents changed, but not their
+    modification time), were modified since by other means or went
+    missing, and fails if any did. With --percent, it only checks that
+    part of the files, starting after the last one the previous check
+    with --percent got to (as recorded in DESTINATION/.backup-rs/scrub),
+    and starting over once it went through all of them, so that a large
+    DESTINATION is scrubbed a part at a time, e.g. by a daily check
+    --percent 10 going through all of it every 10 days.
+
+    repair compares DESTINATION, a mirror of SOURCE made with --manifest,
+    with SOURCE and the manifest of the last run, and corrects the damage
+    done to it by other means than backups, reporting every entry it
+    corrects: entries that aren't in SOURCE are removed, the permissions
+    of the copies (on a local DESTINATION) are set back, and the copies
+    missing, of the wrong type, truncated or with other contents are made
+    again. It then backs SOURCE up as a run with --manifest does. Mirrors
+    made with --encrypt or --compress can't be repaired.
+
+    adopt takes over DESTINATION, a mirror of SOURCE made by another tool
+    (e.g. rsync -a, or restored by restic), without copying anything: it
+    marks it as a backup-rs destination and writes the manifest of SOURCE,
+    leaving out the copies that are missing or out of date (as --compare
+    tells), which the next run then copies. The entries of DESTINATION
+    that aren't in SOURCE are counted, and removed by the next run. Back
+    it up with --manifest from then on.
+
+    restore copies the whole of DESTINATION back into DIRECTORY, checking
+    each file against the hash its copy had when it was backed up: the
+    files that don't match are corrupt, and aren't restored (unless
+    --best-effort is given).
+
+    ls lists the directory PATH of SOURCE (its root by default), or shows
+    the file PATH, as of the last run that kept the manifest of MIRROR (a
+    DESTINATION or a snapshot of one, as DESTINATION::NAME with a NAME
+    listed by snapshots, or latest, or as its directory, e.g.
+    DESTINATION/.backup-rs/snapshots/NAME), with sizes and modification
+    times, reading only the manifest.
+
+    find lists the entries of SOURCE whose path matches PATTERN as of the
+    last run that kept the manifest of MIRROR (as for ls), with sizes and
+    modification times, reading only the manifest, and fails if none
+    does. PATTERN is a glob, which matches the names of the entries if it
+    has no slash (as for --exclude-from), or with --regex an extended
+    regular expression matched anywhere in their path.
+
+    mount serves MIRROR (as for ls) as a read-only FUSE filesystem on the
+    directory MOUNTPOINT, until it is unmounted or interrupted, so that
+    its files can be read with any tool (those of remote, encrypted or
+    compressed mirrors are fetched whole when opened). With --snapshots,
+    MIRROR is a DESTINATION, and each of its snapshots is a directory of
+    MOUNTPOINT, next to current, DESTINATION as it is.
+
+    history lists the versions of the file PATH of SOURCE that DESTINATION
+    keeps, in its snapshots, in the previous versions kept by --versions
+    and as its current copy, by modification time and leaving out those
+    that didn't change, with their size and hash. With --restore-version
+    VERSION (a name listed by history), that version is copied to FILE
+    (the name of PATH in the current directory by default).
+
+    snapshots lists the snapshots of DESTINATION (made by --snapshot) by
+    name, which is when they were taken, with the number and size of the
+    files in them.
+
+    prune removes the snapshots of DESTINATION (made by --snapshot) that
+    aren't kept by --keep-daily, --keep-weekly or --keep-monthly, and
+    reports the space that frees. The last snapshot is always kept.
+
+    changes lists what changed in SOURCE since SNAPSHOT of DESTINATION (a
+    name listed by snapshots, or latest) was taken, or since the last run
+    without --since, from the manifest kept then (with --manifest), without
+    backing anything up: the entries added (+), modified (M) and removed
+    (-), the directories ending in /.
+
+    diff compares the trees of the directory A and of B (a directory, or
+    any DESTINATION, whose .backup-rs is left out), leaving out the paths
+    of A excluded with --exclude-from, and lists the entries only in A
+    (<), only in B (>), with other contents (C, including those of another
+    type) or with the same contents but modified in A after they were
+    copied to B (M), the directories ending in /. Files of the same size
+    count as the same when a backup would take their copy as up to date,
+    as --compare tells (by default, when A wasn't modified after B), and
+    are read to tell C from M otherwise. The exit status is 0 if the trees
+    are the same, 1 if they differ and 2 on trouble.
+
+    estimate goes through SOURCE and compares it with DESTINATION as a dry
+    run does (with the same options), without listing every entry (unless
+    with -v), and prints how many files and bytes a backup would copy and
+    how many entries it would remove, with how long the copies would take
+    at the pace of the last run.
+
+    export writes the changes made in DESTINATION since SNAPSHOT (or all of
+    it) to the file BUNDLE, which can be carried to an offsite REPLICA and
+    applied there with import. SNAPSHOT is the name of a btrfs or ZFS
+    snapshot of DESTINATION, or of a previous export, as printed by it.
+
+    repo manages a deduplicating repository, where the contents of files
+    are split into chunks stored only once, and each backup is kept as a
+    snapshot made of chunks: init creates an empty REPO, backup adds a
+    snapshot of SOURCE to it, snapshots lists them, restore copies one
+    (given by a prefix of its ID, or latest) into DIRECTORY, forget removes
+    snapshots and gc removes the chunks no snapshot uses anymore. REPO can
+    be remote and encrypted like any DESTINATION.
+
+    ctl pause pauses the run backing up to DESTINATION (a local one, or a
+    remote one backed up from this machine), once it is done with the file
+    it is copying, until ctl resume; as do sending it SIGUSR2 and SIGCONT.
+
+    OPTIONS (given as --OPTION VALUE or --OPTION=VALUE; the arguments
+    after -- are paths, even if they start with a dash; the short options
+    go together, as in -nx):
+      -n, --dry  simulate the backup process, printing what it would do
+                 (as -v does)
+      -q, --quiet  print only errors, instead of the summary of the run
+                   and its errors and warnings
+      -v, --verbose  also print each entry copied, updated or removed;
+                     given twice (-vv), also why: that a file isn't in
+                     DESTINATION yet, its size differs (1024 vs 2048
+                     bytes), or it is missing from SOURCE, and why the
+                     others are left alone (up to date, excluded by
+                     pattern 'node_modules')
+      --explain  same as -vv
+      --stats  print a resource usage report (CPU, memory, I/O) at the end
+      --color WHEN  color the messages by severity and by what they report
+                    (errors, warnings, removals, copies and entries left
+                    alone): auto (the default) on a terminal unless the
+                    NO_COLOR environment variable is set, always or never
+      --low-priority  run with the lowest CPU priority (as nice -n 19) and
+                      the idle I/O scheduling class (as ionice -c 3), so
+                      that a backup (or the daemon) running in the
+                      background doesn't slow down anything else
+      --format FORMAT  write DESTINATION as a mirror (the default) or as an
+                       archive of SOURCE: with FORMAT=tar, a tar archive, -
+                       being the standard output (with the messages going
+                       to the standard error), e.g. to pipe it elsewhere,
+                       compressed with --compress or as its name implies
+                       (.tar.gz, .tgz, .tar.zst or .tzst); with FORMAT=zip,
+                       a zip archive (its entries stored uncompressed),
+                       keeping the modification times and symlinks
+      --manifest  keep a manifest of the source in DESTINATION/.backup-rs,
+                  so that later runs skip the subtrees that haven't changed
+      --delete-after-days N  only remove the entries missing from SOURCE
+                             from DESTINATION once they have been missing
+                             for N days, in case a part of SOURCE is only
+                             gone for a while (e.g. an unmounted disk);
+                             watch mirrors deletions right away
+      --versions N  before overwriting the copy of a file that changed,
+                    keep it as a previous version, up to the last N of
+                    them, in DESTINATION/.backup-rs/versions/PATH~1 (the
+                    last one), PATH~2 and so on (so that it is copied
+                    again rather than updated with --delta)
+      --timeout DURATION  give up on a path of SOURCE (a file or
+                    directory) that doesn't answer in DURATION (e.g. 30s or
+                    2m) when looked at, listed or first read, reporting it
+                    as an error and going on with the rest, so that a
+                    wedged network mount or a dying disk doesn't hang the
+                    run; copies that stall once started aren't interrupted
+      --compare METHOD  how to tell whether the copy of a file is out of
+                    date: size-mtime (its size differs, or the file was
+                    modified after it was copied; the default), checksum
+                    (its size or hash differs, reading every file and its
+                    copy), ctime (as size-mtime, with the time the file
+                    last changed in any way, so that modification times set
+                    back are noticed) or manifest (with --manifest, the
+                    file isn't as the manifest of the last run has it)
+      --bwlimit RATE  limit the copies to RATE bytes per second (e.g. 500K
+                    or 10M), on average over a second, so that a backup
+                    doesn't take all of the link or the disk; copies to
+                    SMB shares are paced a whole file at a time
+      --buffer-size SIZE  copy the files to a local DESTINATION through a
+                    buffer of SIZE bytes (e.g. 4M; 128K by default, and 1M
+                    or 4M for Android devices and NFS exports)
+      --engine ENGINE  how the local files are copied and looked up: sync
+                    (the default), a system call at a time, or uring (on
+                    Linux), through io_uring, reading and writing several
+                    chunks of a file at once and looking up the entries of
+                    each directory all together, so that fast storage
+                    (NVMe) with many small files isn't left mostly idle;
+                    falls back to sync where io_uring is disabled
+      --error-report FILE  write the paths that couldn't be backed up
+                    (listed at the end of the run) to FILE, as a JSON
+                    object with the ID of the run and its failures, each
+                    with its path, problem (permission denied, vanished,
+                    timed out, no space or i/o error) and message
+      --retries N  try the copies that fail with a transient error (a
+                    file in use, or a network file system or link that
+                    stalls or drops) up to N more times before reporting
+                    them as failed
+      --retry-delay MS  wait MS milliseconds before trying a copy again
+                    (1000 by default)
+      --scan-jobs N  scan the source with N threads, each going through
+                    directories of its own: for the manifest, and ahead of
+                    the run, listing the directories and looking up their
+                    entries before it gets to them, so that it (and the
+                    copies behind it) doesn't wait for each in turn; pays
+                    off on network file systems and on SSDs (1 by default,
+                    1024 at most)
+      --copy-jobs N  copy files to a local DESTINATION with N threads,
+                    while the run goes on through the source (1 by default,
+                    1024 at most)
+      --order ORDER  copy the files once the run went through the source,
+                    in ORDER rather than as it gets to them, so that what
+                    matters most is safe first if the run is cut short:
+                    small-first (the most files in the time given),
+                    large-first, newest-first (the files modified last,
+                    the most likely to be missing from the backups) or
+                    path
+      --change-budget LIMITS  flag a run that changes more than expected,
+                    as ransomware or a runaway process would: LIMITS is a
+                    percentage of the files copied or removed (e.g. 20%)
+                    and/or a size copied (e.g. 50G), separated by commas;
+                    going over one is an error and makes the exit status 2
+      --require CONDITION  only run if CONDITION holds, so that a source
+            that isn't there (e.g. a disk that isn't mounted) doesn't get
+            its backup wiped; may be repeated. CONDITION is one of:
+              exists:PATH  PATH exists
+              mounted:PATH  something is mounted on the directory PATH
+              marker:NAME  the file NAME is present in SOURCE
+              min-files:N  SOURCE holds at least N files and directories
+            where relative paths are relative to SOURCE
+      --allow-empty-source  back up SOURCE even if it is empty, which is
+                            otherwise taken as a disk that isn't mounted
+                            and refused, as it would empty DESTINATION
+      --atomic  make the changes in a staging copy of DESTINATION (made of
+                hard links, next to it) and put it in place of DESTINATION
+                at the end, so that readers never see a partly updated
+                DESTINATION (local ones only; not with --snapshot, and
+                files are copied again instead of updated with --delta)
+      --notify-url URL  POST a JSON summary of the run (run_id, status as
+                        ok, errors or failed, exit_status, duration in
+                        seconds, copied, bytes, removed, errors, warnings)
+                        to URL once it is over, or to URL/fail if it
+                        didn't succeed, as healthchecks.io checks take
+                        them (with curl; not for dry runs)
+      --notify-desktop  show a desktop notification once the run is over,
+                        with what it changed or how many errors it had
+                        (with notify-send, or osascript on macOS; not for
+                        dry runs)
+      --mail-to ADDRESS[,ADDRESS]...  mail the outcome of the run (what it
+                                      changed, and its errors and
+                                      warnings) to the ADDRESSes if it
+                                      didn't succeed (not for dry runs)
+      --mail-on WHEN  mail on failure (the default) or always
+      --mail-server HOST[:PORT]  SMTP server to send mails through, in
+                                 plain SMTP without authentication (as a
+                                 relay on this machine or the local
+                                 network), localhost:25 by default; only
+                                 relays taking mail without STARTTLS or
+                                 AUTH work, not submission servers (as
+                                 on port 587)
+      --metrics-file PATH  write the metrics of the run (when it ended and
+                           last succeeded, its duration, the files and
+                           bytes copied, the files removed, its errors,
+                           warnings and exit status) to PATH, a .prom file
+                           of the directory of the textfile collector of
+                           node_exporter, keeping those of other runs
+                           (not for dry runs)
+      --tui  show a dashboard of the run instead of its messages: what it
+             did so far, its throughput, the files the workers copy and
+             its errors and warnings, then a summary of the run until a
+             key is pressed, after which the messages are printed
+      --pre-hook COMMAND  run COMMAND with sh before the backup (e.g. to
+                          dump a database), which doesn't start if it fails
+      --post-hook COMMAND  run COMMAND with sh after the backup, whatever
+                           its outcome, once DESTINATION is unlocked (e.g.
+                           to unmount it). Both hooks get the backup in
+                           BACKUP_RS_SOURCE, BACKUP_RS_DESTINATION,
+                           BACKUP_RS_JOB (the name of the daemon job),
+                           BACKUP_RS_DRY_RUN (1 or 0) and BACKUP_RS_HOOK
+                           (pre or post), and the post-hook its outcome in
+                           BACKUP_RS_RESULT (ok, errors or failed) and
+                           BACKUP_RS_EXIT_STATUS; a hook that fails fails
+                           the run
+      --confirm-delete  list the entries of DESTINATION missing from
+                        SOURCE and ask whether to remove them, all at once
+                        or one by one, before removing any (nothing is
+                        removed without a terminal to ask on)
+      --verify-writes  read each file copied (or updated) back from
+                       DESTINATION, from the disk itself for a local one
+                       (its pages being dropped from the cache), and compare
+                       its hash with that of what was copied; a copy that
+                       doesn't match is removed and reported as failed, so
+                       that the next run copies it again
+      --fsync  flush each file copied to a local DESTINATION to the disk,
+               and the directories as entries are added to, renamed in or
+               removed from them, so that the copies made are there even
+               if the disk is unplugged or the power goes right after
+      --fsync-at-end  flush the filesystem of a local DESTINATION to the
+                      disk once the run is over, before it is reported
+                      done
+      --sorted  go through the entries of each directory (to copy them,
+                and to remove the copies of those that are gone) in the
+                order of their names rather than in the order the file
+                system lists them in, so that the output of two runs (dry
+                ones, say) over the same files is the same, on any machine
+      --ignore-space  only warn when the files to copy (as estimated before
+                      copying them) don't fit in the free space of a local
+                      DESTINATION, instead of not copying anything
+      --emergency-prune  when a local DESTINATION runs out of space in
+                    mid-run, remove its oldest snapshots (keeping the last
+                    one) and then the previous versions kept by --versions
+                    until the file being copied fits, and go on
+      --wait-for-space DURATION  when a local DESTINATION runs out of
+                    space in mid-run, pause for up to DURATION (e.g. 30m)
+                    until enough is freed, and go on; otherwise the files
+                    that don't fit are reported as failed
+      --delta  update the copies of large files (4 MiB or more) that
+               changed by writing only the data that differs instead of
+               copying them again (not with --encrypt or --compress).
+               Local copies are rewritten in place where their blocks
+               differ; for remote ones (which need GNU coreutils), the
+               blocks of the copy are found wherever they moved in the file,
+               with a rolling checksum, so data inserted or removed in the
+               middle of it doesn't send the rest again.
+      --detect-renames  with --manifest, recognize the files and directories
+                        moved or renamed in SOURCE since the last run (by
+                        their size and modification time, or for
+                        directories by those of everything in them) and
+                        move their copies instead of copying them again
+      --trust-manifest  with --manifest, take the manifest of the last run
+                        as what DESTINATION holds: the files of SOURCE that
+                        are as it has them aren't looked up in DESTINATION,
+                        and only the entries it has that are gone from
+                        SOURCE are removed. Changes made to DESTINATION by
+                        other means (and leftovers of interrupted copies)
+                        go unnoticed.
+      --exclude-from FILE  leave the paths of SOURCE matching the patterns
+            listed in FILE (one per line, as in --policy; lines starting
+            with # or ; are comments) out of the backup, along with
+            everything in them; their copies in DESTINATION are kept.
+            May be repeated; FILE can be - for the standard input
+      --files-from FILE  only copy the paths listed in FILE (relative to
+            SOURCE, or starting with it, such as the output of
+            find SOURCE -newer STAMP), creating the
+            directories they are in; listed directories aren't recursed
+            into and nothing is removed from DESTINATION. FILE can be -
+            for the standard input
+      --from0  the lists of --exclude-from and --files-from are separated
+               by NUL characters instead of newlines (as with find -print0)
+      -x, --one-file-system  don't go into the directories of SOURCE other
+            file systems are mounted on (as /proc, /sys, network mounts or
+            the disk of DESTINATION under /), backing them up as empty
+            directories
+      -i, --itemize-changes  print a change code for each change, as rsync -i
+            does, instead of the usual messages (dry runs too):
+              >f+++++++++ FILE  new file
+              >f.st...... FILE  file whose size (s) and/or time (t) changed
+              cL+++++++++ LINK -> TARGET  new symlink
+              cLc........ LINK -> TARGET  symlink pointing elsewhere
+              cd+++++++++ DIRECTORY/  new directory
+              *deleting   PATH  removal
+      --nfs  tune the access to a DESTINATION on an NFS mount: directories
+             are listed once with the metadata of all their entries, files
+             are copied with large buffers and without setting their
+             permissions (which servers mapping users reject), and
+             timestamps up to 2 seconds apart are taken as equal, as the
+             clocks of the server and of this machine may differ
+      --android  handle a DESTINATION on the shared storage of an Android
+                 device (/sdcard, /storage/..., e.g. backing up from
+                 Termux): symlinks are skipped (unless --symlinks
+                 dereferences them or stores placeholders), files are
+                 copied without setting their permissions and copied
+                 again rather than updated in place, timestamps up to 2
+                 seconds apart are taken as equal and, as names are
+                 case-insensitive there, only the first of the names of a
+                 directory that differ only by case is copied
+      --modify-window SECS  take the modification times of files and of
+                 their copies up to SECS seconds apart as equal; 2 for a
+                 local DESTINATION on FAT or exFAT (which keep times to 2
+                 seconds), found by itself
+      --symlinks POLICY  what to do with the symlinks of SOURCE: preserve
+                         them (the default; symlinks to directories are
+                         followed), dereference them (copy what they point
+                         to), skip them, or store each as a placeholder, a
+                         file holding its target (as git does where
+                         symlinks aren't supported), or preserve them with
+                         relative targets (\"relative\"): those pointing
+                         inside SOURCE by an absolute path are rewritten to
+                         point inside DESTINATION, so that they still work
+                         wherever it is mounted
+      -L, --copy-links  same as --symlinks dereference
+      --skip-links  same as --symlinks skip
+      --keep-relative  same as --symlinks relative
+      --devices  recreate the character and block devices of SOURCE in a
+                 local DESTINATION (which takes running as root), instead of
+                 skipping them with a warning
+      --specials  recreate the FIFOs and sockets of SOURCE in a local
+                  DESTINATION, instead of skipping them with a warning
+      --snapshot TYPE  after a successful run, create a read-only snapshot
+                       of DESTINATION, which must be a btrfs subvolume
+                       (TYPE=btrfs) or a ZFS dataset mountpoint (TYPE=zfs)
+      --policy 'PATTERN ATTRIBUTE:VALUE,...'  apply attributes to the paths
+            of SOURCE matching PATTERN (a glob, where ** matches any number
+            of directories, and a pattern without / matches file names);
+            may be repeated, later rules overriding earlier ones. The
+            attributes are:
+              verify:true  as --verify-writes, for these paths
+              compress:false  with --compress, store the files as they are
+                              (e.g. already compressed ones)
+      --transform 'PATTERN COMMAND'  store the output of COMMAND (run
+            with sh, the file on its standard input and its path in
+            BACKUP_RS_SOURCE) instead of the files of SOURCE matching
+            PATTERN (as in --policy), e.g. 'Photos/**/*.jpg exiftool
+            -gps:all= -o - -' or '*.db sqlite3 \"$BACKUP_RS_SOURCE\" .dump';
+            may be repeated, later rules overriding earlier ones. These
+            copies are only compared with SOURCE by date, and the manifest
+            records them
+      --compress ALGORITHM[:LEVEL]  store the files of DESTINATION
+            compressed with zstd (level 1-19, 3 by default) or gzip (level
+            1-9, 6 by default), adding a .zst or .gz suffix to their names;
+            restore, drill and verify decompress them
+      --batch-small-files SIZE  store the files of DESTINATION up to SIZE
+            (e.g. 64K) together, in packs of about 8 MiB written at once,
+            rather than each on its own, for storages where every file
+            written costs a request; an index in DESTINATION/.backup-rs
+            tells where each one is, so restore, drill and verify read them
+            as the others. With --encrypt the packs are encrypted; they
+            aren't compressed with --compress
+      --sanitize-names  store the entries whose names FAT, exFAT or NTFS
+            (e.g. a USB stick, or an SMB share) can't hold, with one of
+            < > : \" \\ | ? * or a control character, a trailing dot or
+            space, or a device name (CON, aux.txt), under names with
+            lookalike characters instead (\"a：b\" for \"a:b\"), recording the
+            original names in DESTINATION/.backup-rs/names, so that
+            restores give them back
+      --normalize FORM  store the names of DESTINATION in the Unicode
+            normalization form FORM, nfc (composed, as Linux and Windows
+            usually write names) or nfd (decomposed, as macOS does), and
+            take the names that are the same once normalized as the same,
+            so that the copies of files named on a Mac and on Linux aren't
+            removed and copied again; copies under the other form are
+            renamed, and two entries of SOURCE with the same normalized
+            name are handled as --case-collisions says
+      --case-collisions POLICY  what to do with the entries of a directory
+            of SOURCE whose names would be the same as another's in
+            DESTINATION, as on Android storage, on a destination that
+            doesn't tell case apart (found out on each run, e.g. FAT, exFAT,
+            NTFS or an SMB share; README and readme) or, with --normalize,
+            once normalized: skip (the default) warns and leaves out all but
+            the first one (in the order of names), rename warns and stores
+            them as \"NAME (2).EXT\", \"NAME (3).EXT\"...
+      --encrypt  encrypt the contents of the files (and the targets of the
+                 symlinks) written to DESTINATION, which must be empty on
+                 the first encrypted run, with ChaCha20-Poly1305
+      --encrypt-names  with --encrypt, also encrypt the names of files and
+                       directories (names are limited to 175 bytes)
+      --key-file PATH  file holding the secret the keys are derived from,
+                       needed for every command on an encrypted DESTINATION
+                       (create one with: head -c 32 /dev/urandom > PATH)
+      --debounce MS  (watch) wait until no changes have been seen for MS
+                     milliseconds before syncing a batch (default: 500)
+      --config PATH  (daemon) configuration file
+                     (default: ~/.config/backup-rs/config.toml)
+      --foreground  (daemon) stay in the foreground and log to the terminal
+      --control-socket PATH  (daemon, watch) listen for commands on the
+                             Unix socket PATH (see Control socket below)
+      --metrics-listen [HOST:]PORT  (daemon) serve the metrics of the runs
+                                    of the jobs over HTTP at /metrics, for
+                                    Prometheus to scrape them
+      --sample N  (drill) number of files to restore (default: 20)
+      --percent N  (check) only check N% of the files, those after the
+                   ones the last check with --percent checked
+      --rewrite-prefix /FROM=/TO  (restore) restore the entries whose path
+            in SOURCE (as recorded by the last run) is FROM or below it
+            as if they were in TO instead, and point the symlinks whose
+            target is there to TO, e.g. onto a machine where the home
+            directory has another name; may be repeated, the longest
+            matching FROM applying
+      --best-effort  (restore) restore the files that don't match their
+                     hash too, still reporting them as corrupt
+      --restore-version VERSION  (history) copy that version of PATH
+                                 instead of listing them; --force
+                                 replaces an existing FILE
+      --snapshots  (mount) mount every snapshot of DESTINATION
+      --keep-daily N  (prune) keep the last snapshot of each of the last N
+                      days with snapshots
+      --keep-weekly N  (prune) same for weeks, starting on Mondays
+      --keep-monthly N  (prune) same for months
+      --restart  (verify) check every file again instead of resuming an
+                 interrupted verification of DESTINATION from where it
+                 stopped, or leaving out the directories verified before
+      --since SNAPSHOT  (export) only export the changes made since SNAPSHOT;
+               (changes) list the changes made since SNAPSHOT
+      --regex  (find) PATTERN is a regular expression
+      --larger SIZE, --smaller SIZE  (find) only list the files of SIZE
+                                     (e.g. 10M or 2G) or more, or of less
+      --newer TIME, --older TIME  (find) only list the files modified at
+                                  TIME or later, or before it; TIME is a
+                                  local date (2024-05-01, with an optional
+                                  T12:30) or a duration before now (7d)
+      --force  back up to a DESTINATION that isn't empty but lacks the
+               .backup-rs-destination marker of backup-rs destinations
+               (written by the first run), which is otherwise refused in
+               case it is the wrong directory; (import) import even if
+               REPLICA doesn't match the state the bundle was exported
+               from
+      -h, --help  display this help and exit
+      -V, --version  output version information and exit
+
+    Exit status:
+      0  if OK,
+      1  if minor problems (e.g., cannot access subdirectory)
+      2  if the run went over its --change-budget
+      3  if the run failed as a whole (e.g., the source is unavailable,
+         the destination is locked or a hook failed)
+
+    Run as a systemd service of Type=notify (`backup-rs daemon --foreground`,
+    or a backup started by a timer), backup-rs tells systemd when it is up
+    and shows how far the run got in `systemctl status`.
+
+    Control socket: with --control-socket, the daemon and watch take
+    commands on a Unix socket, as JSON objects, one per line, each
+    answered by one with \"ok\" (and \"error\" if it is false):
+      {\"command\": \"status\"}  whether a run is in progress (\"running\")
+                             and how far it got
+      {\"command\": \"pause\"}, {\"command\": \"resume\"}  as ctl does
+      {\"command\": \"cancel\"}  stop the run in progress, which fails
+      {\"command\": \"trigger\", \"job\": \"NAME\"}  (daemon) run the job NAME now
+      {\"command\": \"events\"}  stream the events of the runs over the
+                             connection: started, progress (every second)
+                             and finished (with its result)
+    e.g.: echo '{\"command\": \"status\"}' | socat - UNIX-CONNECT:PATH
+
+    Sending SIGUSR1 to backup-rs (`kill -USR1 PID`) prints how far the run in
+    progress got (the file it is at, the files looked at, copied and removed,
+    and how fast it copies) without interrupting it.
+
+    Full documentation <https://github.com/j-morano/contemporary-z>
+    ";
+
+
+/// What the options of the command line set, besides `Options`
+struct Arguments {
+    opts: Options,
+    print_stats: bool,
+    debounce: u64,
+    config_path: String,
+    foreground: bool,
+    since: Option<String>,
+    sample: usize,
+    restart: bool,
+    percent: Option<usize>,
+    all_snapshots: bool,
+    format: Option<archive::Format>,
+    restore_version: Option<String>,
+    rewrites: Vec<(String, String)>,
+    retention: prune::Retention,
+    query: listing::Query,
+    exclude_from: Vec<String>,
+    files_from: Option<String>,
+    from0: bool,
+    allow_empty_source: bool,
+    low_priority: bool,
+    color: log::Color,
+    verbosity: Option<u8>,
+    pre_hook: Option<String>,
+    post_hook: Option<String>,
+    notify_url: Option<String>,
+    notify_desktop: bool,
+    metrics_file: Option<String>,
+    metrics_listen: Option<String>,
+    control_socket: Option<String>,
+    dashboard: bool,
+    mail_to: Option<String>,
+    mail_on: mail::MailOn,
+    mail_server: String,
 }
 
 
-/// Check if a file is a symlink
-fn is_symlink(file: &str) -> i32 {
-    match fs::symlink_metadata(file) {
-        Ok(metadata) => if metadata.file_type().is_symlink() {
-            return 0;
-        } else {
-            return 1;
-        },
-        Err(_) => return 2,
-    }
-}
-
-
-/// Recursively iterate through the destination directory to remove the files
-/// that are not in the source directory
-fn remove_removed(source: &str, destination: &str, dry_run: bool) {
-    for entry in fs::read_dir(destination).unwrap() {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_dir() {
-            // Recursively call remove_removed() for subdirectories
-            // If the subdirectory doesn't exist in the source directory,
-            // remove it from the destination directory
-            let subdirectory = path.file_name().unwrap().to_str().unwrap();
-            let source = format!("{}/{}", source, subdirectory);
-            if !Path::new(&source).exists() {
-                println!("Removing directory: {}", path.to_str().unwrap());
-                if !dry_run {
-                    fs::remove_dir_all(path).unwrap();
-                }
-            } else {
-                remove_removed(&source, path.to_str().unwrap(), dry_run);
-            }
-        } else {
-            // If the file doesn't exist in the source directory,
-            // remove it from the destination directory
-            let file_name = path.file_name().unwrap();
-            let file_name_str = match file_name.to_str() {
-                Some(s) => s,
-                None => continue,
+impl Default for Arguments {
+    fn default() -> Arguments {
+        Arguments {
+            opts: Options::default(),
+            print_stats: false,
+            debounce: 500,
+            config_path: config::default_path(),
+            foreground: false,
+            since: None,
+            sample: 20,
+            restart: false,
+            percent: None,
+            all_snapshots: false,
+            format: None,
+            restore_version: None,
+            rewrites: Vec::new(),
+            retention: prune::Retention::default(),
+            query: listing::Query::default(),
+            exclude_from: Vec::new(),
+            files_from: None,
+            from0: false,
+            allow_empty_source: false,
+            low_priority: false,
+            color: log::Color::default(),
+            verbosity: None,
+            pre_hook: None,
+            post_hook: None,
+            notify_url: None,
+            notify_desktop: false,
+            metrics_file: None,
+            metrics_listen: None,
+            control_socket: None,
+            dashboard: false,
+            mail_to: None,
+            mail_on: mail::MailOn::default(),
+            mail_server: mail::DEFAULT_SERVER.to_string(),
+        }
+    }
+}
+
+
+/// How an option is taken: alone, or with the value after it, which is
+/// refused with why
+enum Apply {
+    Flag(fn(&mut Arguments)),
+    Value(fn(&mut Arguments, &str) -> Result<(), String>),
+}
+
+
+/// An option of the command line
+struct Spec {
+    name: &'static str,
+    /// The short option standing for it, if any
+    short: Option<char>,
+    /// The commands it applies to, or all of them if empty
+    commands: &'static [&'static str],
+    apply: Apply,
+}
+
+
+impl Spec {
+    const fn flag(name: &'static str, apply: fn(&mut Arguments)) -> Spec {
+        Spec { name, short: None, commands: &[], apply: Apply::Flag(apply) }
+    }
+
+    const fn value(name: &'static str, apply: fn(&mut Arguments, &str) -> Result<(), String>) -> Spec {
+        Spec { name, short: None, commands: &[], apply: Apply::Value(apply) }
+    }
+
+    const fn short(self, letter: char) -> Spec {
+        Spec { short: Some(letter), ..self }
+    }
+
+    const fn only(self, commands: &'static [&'static str]) -> Spec {
+        Spec { commands, ..self }
+    }
+}
+
+
+const BACKUP: &[&str] = &["backup"];
+
+
+/// A number given as the value of an option, in the range it takes
+fn number<T: std::str::FromStr + PartialOrd>(value: &str, range: impl std::ops::RangeBounds<T>) -> Result<T, String> {
+    value.parse().ok().filter(|n| range.contains(n)).ok_or_else(|| format!("invalid number '{}'", value))
+}
+
+
+/// A number of threads given as the value of an option, up to MAX_JOBS
+fn jobs(value: &str) -> Result<usize, String> {
+    number(value, 1..=MAX_JOBS).map_err(|_| format!("invalid number of threads '{}' (1-{})", value, MAX_JOBS))
+}
+
+
+/// A duration given as the value of an option (e.g. 30m), of more than 0
+fn duration(value: &str) -> Result<Duration, String> {
+    match schedule::parse_duration(value)? {
+        seconds if seconds > 0 => Ok(Duration::from_secs(seconds as u64)),
+        _ => Err(format!("invalid duration '{}' (it must be more than 0)", value)),
+    }
+}
+
+
+/// The options of the command line. Several short options can be given
+/// together (`-nx` for `-n -x`).
+const OPTIONS: &[Spec] = &[
+    Spec::flag("--help", |_| print_usage_and_exit(0)).short('h'),
+    Spec::flag("--version", |_| {
+        // Print the version of the program from the Cargo.toml file
+        println!("backup-rs {}", env!("CARGO_PKG_VERSION"));
+        std::process::exit(0);
+    })
+    .short('V'),
+    Spec::flag("--dry", |a| a.opts.dry_run = true).short('n'),
+    Spec::flag("--manifest", |a| a.opts.manifest = true),
+    Spec::flag("--detect-renames", |a| a.opts.detect_renames = true),
+    Spec::flag("--trust-manifest", |a| a.opts.trust_manifest = true),
+    Spec::flag("--delta", |a| a.opts.delta = true),
+    Spec::flag("--atomic", |a| a.opts.atomic = true).only(BACKUP),
+    Spec::value("--notify-url", |a, v| {
+        a.notify_url = Some(v.to_string());
+        Ok(())
+    })
+    .only(BACKUP),
+    Spec::flag("--notify-desktop", |a| a.notify_desktop = true).only(BACKUP),
+    Spec::flag("--tui", |a| a.dashboard = true).only(BACKUP),
+    Spec::value("--metrics-file", |a, v| {
+        a.metrics_file = Some(v.to_string());
+        Ok(())
+    })
+    .only(BACKUP),
+    Spec::value("--control-socket", |a, v| {
+        a.control_socket = Some(v.to_string());
+        Ok(())
+    })
+    .only(&["daemon", "watch"]),
+    Spec::value("--metrics-listen", |a, v| {
+        a.metrics_listen = Some(v.to_string());
+        Ok(())
+    })
+    .only(&["daemon"]),
+    Spec::value("--mail-to", |a, v| {
+        a.mail_to = Some(v.to_string());
+        Ok(())
+    })
+    .only(BACKUP),
+    Spec::value("--mail-on", |a, v| {
+        a.mail_on = mail::MailOn::parse(v)?;
+        Ok(())
+    }),
+    Spec::value("--mail-server", |a, v| {
+        a.mail_server = v.to_string();
+        Ok(())
+    }),
+    Spec::value("--pre-hook", |a, v| {
+        a.pre_hook = Some(v.to_string());
+        Ok(())
+    })
+    .only(BACKUP),
+    Spec::value("--post-hook", |a, v| {
+        a.post_hook = Some(v.to_string());
+        Ok(())
+    })
+    .only(BACKUP),
+    Spec::flag("--sanitize-names", |a| a.opts.sanitize_names = true),
+    Spec::value("--normalize", |a, v| {
+        a.opts.normalize = Some(Form::parse(v)?);
+        Ok(())
+    }),
+    Spec::value("--case-collisions", |a, v| {
+        a.opts.case_collisions = Collisions::parse(v)?;
+        Ok(())
+    }),
+    Spec::flag("--ignore-space", |a| a.opts.ignore_space = true),
+    Spec::flag("--verify-writes", |a| a.opts.verify_writes = true),
+    Spec::flag("--fsync", |a| a.opts.fsync = true),
+    Spec::flag("--fsync-at-end", |a| a.opts.fsync_at_end = true),
+    Spec::flag("--sorted", |a| a.opts.sorted = true),
+    Spec::flag("--emergency-prune", |a| a.opts.emergency_prune = true),
+    Spec::value("--wait-for-space", |a, v| {
+        a.opts.wait_for_space = Some(duration(v)?);
+        Ok(())
+    }),
+    Spec::flag("--confirm-delete", |a| a.opts.confirm_delete = true).only(BACKUP),
+    Spec::flag("--allow-empty-source", |a| a.allow_empty_source = true),
+    Spec::flag("--itemize-changes", |a| a.opts.itemize = true).short('i'),
+    Spec::flag("--android", |a| {
+        a.opts.android = true;
+        a.opts.modify_window = a.opts.modify_window.max(android::MODIFY_WINDOW);
+    }),
+    Spec::flag("--nfs", |a| {
+        a.opts.nfs = true;
+        a.opts.modify_window = a.opts.modify_window.max(nfs::MODIFY_WINDOW);
+    }),
+    Spec::value("--modify-window", |a, v| {
+        a.opts.modify_window = number(v, ..)?;
+        Ok(())
+    }),
+    Spec::value("--snapshot", |a, v| {
+        a.opts.snapshot = Some(SnapshotKind::parse(v)?);
+        Ok(())
+    }),
+    Spec::flag("--stats", |a| a.print_stats = true),
+    Spec::flag("--low-priority", |a| a.low_priority = true),
+    Spec::flag("--quiet", |a| a.verbosity = Some(log::QUIET)).short('q'),
+    Spec::flag("--explain", |a| a.verbosity = Some(log::DETAILED)),
+    Spec::flag("--verbose", |a| {
+        a.verbosity = Some(if a.verbosity >= Some(log::VERBOSE) { log::DETAILED } else { log::VERBOSE });
+    })
+    .short('v'),
+    Spec::value("--color", |a, v| {
+        a.color = log::Color::parse(v)?;
+        Ok(())
+    }),
+    Spec::value("--policy", |a, v| a.opts.policy.add(v)),
+    Spec::value("--transform", |a, v| a.opts.policy.add_transform(v)),
+    Spec::value("--format", |a, v| {
+        a.format = match v {
+            "mirror" => None,
+            "tar" => Some(archive::Format::Tar),
+            "zip" => Some(archive::Format::Zip),
+            _ => return Err(format!("invalid format '{}' (expected mirror, tar or zip)", v)),
+        };
+        Ok(())
+    })
+    .only(BACKUP),
+    Spec::value("--timeout", |a, v| {
+        a.opts.timeout = Some(duration(v)?);
+        Ok(())
+    }),
+    Spec::value("--compare", |a, v| {
+        a.opts.comparator = Some(compare::parse(v)?);
+        Ok(())
+    }),
+    Spec::value("--order", |a, v| {
+        a.opts.order = Some(Order::parse(v)?);
+        Ok(())
+    }),
+    Spec::value("--engine", |a, v| {
+        a.opts.uring = uring::parse_engine(v)?;
+        Ok(())
+    }),
+    Spec::value("--buffer-size", |a, v| {
+        a.opts.buffer_size = Some(stream::parse_buffer_size(v)?);
+        Ok(())
+    }),
+    Spec::value("--bwlimit", |a, v| {
+        a.opts.bwlimit = Some(throttle::parse_rate(v)?);
+        Ok(())
+    }),
+    Spec::value("--error-report", |a, v| {
+        a.opts.error_report = Some(PathBuf::from(v));
+        Ok(())
+    }),
+    Spec::value("--retries", |a, v| {
+        a.opts.retries = number(v, ..)?;
+        Ok(())
+    }),
+    Spec::value("--retry-delay", |a, v| {
+        a.opts.retry_delay = Some(Duration::from_millis(number(v, ..)?));
+        Ok(())
+    }),
+    Spec::value("--scan-jobs", |a, v| {
+        a.opts.scan_jobs = jobs(v)?;
+        Ok(())
+    }),
+    Spec::value("--copy-jobs", |a, v| {
+        a.opts.copy_jobs = jobs(v)?;
+        Ok(())
+    }),
+    Spec::value("--change-budget", |a, v| {
+        a.opts.budget = Some(Budget::parse(v)?);
+        Ok(())
+    }),
+    Spec::value("--symlinks", |a, v| {
+        a.opts.symlinks = SymlinkPolicy::parse(v)?;
+        Ok(())
+    }),
+    Spec::flag("--copy-links", |a| a.opts.symlinks = SymlinkPolicy::Dereference).short('L'),
+    Spec::flag("--skip-links", |a| a.opts.symlinks = SymlinkPolicy::Skip),
+    Spec::flag("--keep-relative", |a| a.opts.symlinks = SymlinkPolicy::Relative),
+    Spec::flag("--devices", |a| a.opts.devices = true),
+    Spec::flag("--specials", |a| a.opts.specials = true),
+    Spec::value("--compress", |a, v| {
+        a.opts.compress = Some(Compression::parse(v)?);
+        Ok(())
+    }),
+    Spec::value("--batch-small-files", |a, v| {
+        a.opts.batch_small_files = Some(budget::parse_size(v).ok_or_else(|| format!("invalid size '{}'", v))?);
+        Ok(())
+    }),
+    Spec::value("--versions", |a, v| {
+        a.opts.keep_versions = Some(number(v, 1..)?);
+        Ok(())
+    }),
+    Spec::value("--delete-after-days", |a, v| {
+        a.opts.delete_after_days = Some(number(v, ..)?);
+        Ok(())
+    }),
+    Spec::value("--require", |a, v| {
+        a.opts.preconditions.push(Precondition::parse(v)?);
+        Ok(())
+    }),
+    Spec::value("--exclude-from", |a, v| {
+        a.exclude_from.push(v.to_string());
+        Ok(())
+    }),
+    Spec::value("--files-from", |a, v| {
+        a.files_from = Some(v.to_string());
+        Ok(())
+    })
+    .only(BACKUP),
+    Spec::flag("--from0", |a| a.from0 = true),
+    Spec::flag("--one-file-system", |a| a.opts.one_file_system = true).short('x'),
+    Spec::flag("--encrypt", |a| a.opts.encrypt = true),
+    Spec::flag("--encrypt-names", |a| a.opts.encrypt_names = true),
+    Spec::value("--key-file", |a, v| {
+        a.opts.key_file = Some(v.to_string());
+        Ok(())
+    }),
+    Spec::value("--debounce", |a, v| {
+        a.debounce = number(v, ..)?;
+        Ok(())
+    }),
+    Spec::value("--config", |a, v| {
+        a.config_path = v.to_string();
+        Ok(())
+    }),
+    Spec::flag("--foreground", |a| a.foreground = true),
+    Spec::value("--sample", |a, v| {
+        a.sample = number(v, 1..)?;
+        Ok(())
+    }),
+    Spec::value("--since", |a, v| {
+        a.since = Some(v.to_string());
+        Ok(())
+    }),
+    Spec::flag("--force", |a| a.opts.force = true),
+    Spec::flag("--restart", |a| a.restart = true),
+    Spec::value("--percent", |a, v| {
+        a.percent = Some(number(v, 1..=100)?);
+        Ok(())
+    }),
+    Spec::flag("--regex", |a| a.query.regex = true),
+    Spec::value("--larger", |a, v| {
+        a.query.larger = Some(budget::parse_size(v).ok_or_else(|| format!("invalid size '{}'", v))?);
+        Ok(())
+    }),
+    Spec::value("--smaller", |a, v| {
+        a.query.smaller = Some(budget::parse_size(v).ok_or_else(|| format!("invalid size '{}'", v))?);
+        Ok(())
+    }),
+    Spec::value("--newer", |a, v| {
+        a.query.newer = Some(schedule::parse_time(v)?);
+        Ok(())
+    }),
+    Spec::value("--older", |a, v| {
+        a.query.older = Some(schedule::parse_time(v)?);
+        Ok(())
+    }),
+    Spec::flag("--snapshots", |a| a.all_snapshots = true),
+    Spec::value("--rewrite-prefix", |a, v| {
+        a.rewrites.push(restore::parse_rewrite(v)?);
+        Ok(())
+    }),
+    Spec::flag("--best-effort", |a| a.opts.best_effort = true),
+    Spec::value("--restore-version", |a, v| {
+        a.restore_version = Some(v.to_string());
+        Ok(())
+    }),
+    Spec::value("--keep-daily", |a, v| {
+        a.retention.daily = number(v, ..)?;
+        Ok(())
+    }),
+    Spec::value("--keep-weekly", |a, v| {
+        a.retention.weekly = number(v, ..)?;
+        Ok(())
+    }),
+    Spec::value("--keep-monthly", |a, v| {
+        a.retention.monthly = number(v, ..)?;
+        Ok(())
+    }),
+];
+
+
+fn print_usage_and_exit(code: i32) -> ! {
+    println!("{}", USAGE);
+    std::process::exit(code);
+}
+
+
+/// Report a mistake in the command line and exit
+fn usage_error(message: &str) -> ! {
+    error!("{}", message);
+    eprintln!("Try 'backup-rs --help' for more information.");
+    std::process::exit(1);
+}
+
+
+/// Report an option given without a value, or with one it doesn't take
+fn invalid_value(option: &str) -> ! {
+    usage_error(&format!("missing or invalid value for {}", option));
+}
+
+
+/// Take an option found in the command line of `command`, with the value
+/// after it if it takes one, reporting it if it doesn't apply to the
+/// command or its value is missing or invalid
+fn take_option<'a>(spec: &Spec, command: &str, values: &mut impl Iterator<Item = &'a String>, arguments: &mut Arguments) {
+    if !spec.commands.is_empty() && !spec.commands.contains(&command) {
+        let commands: Vec<&str> = spec
+            .commands
+            .iter()
+            .map(|command| match *command {
+                "backup" => "backups",
+                "daemon" => "the daemon",
+                command => command,
+            })
+            .collect();
+        usage_error(&format!("{} only applies to {}", spec.name, commands.join(" and to ")));
+    }
+    match spec.apply {
+        Apply::Flag(apply) => apply(arguments),
+        Apply::Value(apply) => {
+            let Some(value) = values.next() else {
+                invalid_value(spec.name);
             };
-            let source_file = format!("{}/{}", source, file_name_str);
-            if is_symlink(path.to_str().unwrap()) == 0 {
-                match fs::read_link(source_file) {
-                    Ok(_) => (),
-                    Err(_) => {
-                        println!("Removing symlink: {}", path.to_str().unwrap());
-                        if !dry_run {
-                            fs::remove_dir_all(path.clone()).unwrap();
-                        }
-                    }
-                }
-            } else {
-                if !Path::new(&source_file).exists() {
-                    println!("Removing file: {}", path.to_str().unwrap());
-                    if !dry_run {
-                        fs::remove_file(path).unwrap();
+            if let Err(e) = apply(arguments, value) {
+                usage_error(&format!("{}: {}", spec.name, e));
+            }
+        }
+    }
+}
+
+
+/// Report a command given the wrong number of paths, with the ways of
+/// giving them from the usage
+fn wrong_arguments(command: &str) -> ! {
+    let prefix = format!("backup-rs {} ", command);
+    let synopsis: Vec<&str> = USAGE
+        .lines()
+        .map(|line| line.trim().trim_start_matches("Usage:").trim_start_matches("or:").trim())
+        .take_while(|line| !line.is_empty())
+        .filter(|line| line.starts_with(&prefix) || (command == "backup" && line.starts_with("backup-rs [")))
+        .collect();
+    usage_error(&format!("wrong arguments for {}; usage:\n  {}", command, synopsis.join("\n  ")));
+}
+
+
+/// The arguments of the command line with short options and
+/// `--OPTION=VALUE` spelled out as long options and their values, and
+/// apart from them those after `--`, which are paths even if they start
+/// with a dash
+fn expand_arguments(args: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut expanded = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "--" {
+            return (expanded, args[i + 1..].to_vec());
+        }
+        if let Some((option, value)) = arg.split_once('=').filter(|_| arg.starts_with("--")) {
+            expanded.push(option.to_string());
+            expanded.push(value.to_string());
+            continue;
+        }
+        match arg.strip_prefix('-') {
+            Some(letters) if !letters.is_empty() && !letters.starts_with('-') => {
+                for letter in letters.chars() {
+                    match OPTIONS.iter().find(|spec| spec.short == Some(letter)) {
+                        Some(spec) => expanded.push(spec.name.to_string()),
+                        None => usage_error(&format!("unknown option '-{}'", letter)),
                     }
                 }
             }
+            _ => expanded.push(arg.clone()),
         }
     }
+    (expanded, Vec::new())
 }
 
 
-fn copy_file(source: &str, destination: &str, dry_run: bool) {
-    println!("Copying {} to {}", source, destination);
-    if !dry_run {
-        if is_symlink(source) == 0 {
-            // Create a symlink in the destination directory
-            // pointing to the source file
-            // This is a workaround for the fs::copy() function
-            // not working with symlinks
-            let source = fs::read_link(source).unwrap();
-            std::os::unix::fs::symlink(source, destination.clone()).unwrap();
+/// Mirror the source directory to the destination directory. Returns false
+/// if the run completed with problems.
+fn run_backup(source: &str, destination: &str, opts: &Options, stats: &mut Stats) -> bool {
+    let run = Run::start();
+    progress::start(source, destination);
+    temporary::clean_local();
+    systemd::expect_files(0);
+    systemd::status(&format!("Backing up {} to {}", source, destination));
+    log::separator();
+    info!("Source: {}", source);
+    info!("Destination: {}", destination);
+    info!("Run: {}", run.id);
+    log::separator();
+
+    if !opts.dry_run {
+        info!("Backup in progress...");
+    } else {
+        info!("Dry run: Backup simulation in progress...");
+    }
+    if let Err(e) = preconditions::check_all(&opts.preconditions, source) {
+        error!("the source isn't available: {}; not backing it up", e);
+        return false;
+    }
+    let mut opts = opts.clone();
+    opts.policy.root = source.to_string();
+    let location = destination;
+    let (target, destination) = match backend::open(destination, &opts) {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    let target = target.as_ref();
+    let destination = destination.as_str();
+    if target.is_local() {
+        fat::detect(destination, &mut opts);
+    }
+    if !check_nesting(source, destination, &opts, target) {
+        return false;
+    }
+    if !opts.dry_run {
+        // Create the destination directory if it doesn't exist
+        if target.metadata(destination).is_none() {
+            if let Err(e) = target.create_dir(destination) {
+                error!("cannot create the destination {}: {}", destination, e);
+                return false;
+            }
+        }
+    }
+    if !check_marker(destination, &opts, target) {
+        return false;
+    }
+    case::detect(destination, &mut opts, target);
+    let _lock = match lock_destination(location, destination, &opts, target) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+
+    opts.protected.insert(Path::new(destination).join(manifest::META_DIR));
+    opts.protected.insert(Path::new(destination).join(manifest::MARKER));
+    opts.summary = Some(Rc::new(Summary::new(destination)));
+    opts.errors = Some(Rc::new(ErrorReport::default()));
+    opts.versions = opts.keep_versions.map(|count| Rc::new(Versions::new(destination, count)));
+    opts.out_of_space = target.is_local().then(|| Rc::new(OutOfSpace::new(destination, &opts)));
+    opts.skip_list = Some(Rc::new(SkipList::load(target, destination, source)));
+    opts.checksums = Some(Rc::new(Checksums::load(target, destination)));
+    opts.copies = copy_pool(&opts, target);
+    opts.queued = copy_queue(&opts);
+    if let Some(entries) = &opts.files_from {
+        // Nothing else is looked at, so the manifest isn't updated either
+        let mut paths = Vec::new();
+        for entry in entries {
+            match filelist::relative(entry, source) {
+                Ok(Some(path)) => paths.push(path),
+                Ok(None) => (),
+                Err(e) => warning!("ignoring a listed path: {}", e),
+            }
+        }
+        stats.start_phase("Copy pass");
+        info!("Copying {} listed path(s)...", paths.len());
+        backup_listed(source, destination, &paths, &opts, target);
+        wait_copies(&opts, target);
+        stats.finish_phase();
+        if let Some(summary) = &opts.summary {
+            summary.print();
+        }
+        return conclude(source, destination, &run, &opts, target, None);
+    }
+    let scan = if opts.manifest {
+        // Compare the source with the manifest of the last run, to skip the
+        // subtrees that haven't changed since then
+        stats.start_phase("Scan");
+        let scan = Manifest::scan(source, opts.scan_jobs, opts.one_file_system);
+        systemd::expect_files(scan.files());
+        if let Some(previous) = Manifest::load(target, destination) {
+            // Repairs look at everything
+            for relative in scan.unchanged_dirs(&previous).filter(|_| !opts.repair) {
+                opts.unchanged.insert(Path::new(source).join(relative));
+            }
+            if opts.detect_renames {
+                let moves = renames::detect(&previous, &scan);
+                renames::apply(&moves, source, destination, &mut opts, target);
+            }
+            if opts.trust_manifest || opts.comparator.as_ref().is_some_and(|c| c.needs_manifest()) {
+                opts.previous = Some(Rc::new(previous));
+            }
+        }
+        Some(scan)
+    } else {
+        None
+    };
+    if let Some(days) = opts.delete_after_days {
+        let deletions = Deletions::load(target, destination, days);
+        // The directories holding the entries kept by the last run are looked
+        // at again, to remove those whose grace period is over
+        for pending in deletions.pending() {
+            for ancestor in Path::new(pending).ancestors() {
+                opts.unchanged.remove(&Path::new(source).join(ancestor));
+            }
+        }
+        opts.deletions = Some(Rc::new(deletions));
+    }
+    if opts.unchanged.contains(Path::new(source)) {
+        info!("Nothing changed since the last backup");
+        stats.finish_phase();
+        return conclude(source, destination, &run, &opts, target, None);
+    }
+
+    if opts.repair {
+        stats.start_phase("Repair");
+        if !repair::repair(source, destination, &opts, target) {
+            return false;
+        }
+    }
+
+    // Recursively iterate through the destination directory to remove the files
+    // that are not in the source directory
+    stats.start_phase("Deletion pass");
+    let pass = |opts: &Options| match (&opts.previous, &scan) {
+        (Some(previous), Some(scan)) if opts.trust_manifest => {
+            remove_vanished(previous, scan, source, destination, opts, target)
+        }
+        _ => remove_removed(source, destination, opts, target),
+    };
+    if target.metadata(destination).is_some() {
+        if opts.confirm_delete && !opts.dry_run {
+            confirm_removals(&opts, target, pass);
         } else {
-            fs::copy(source, destination).unwrap();
+            pass(&opts);
         }
     }
+    if let (Some(deletions), false) = (&opts.deletions, opts.dry_run) {
+        if let Err(e) = deletions.save(target) {
+            warning!("cannot record the pending deletions of {}: {}", destination, e);
+        }
+    }
+
+    stats.start_phase("Space check");
+    if !check_space(source, destination, &opts, target) {
+        return false;
+    }
+
+    log::separator();
+    // Backup the source to the destination
+    stats.start_phase("Copy pass");
+    backup(source, destination, &opts, target);
+    wait_copies(&opts, target);
+    stats.finish_phase();
+    if progress::cancelled() {
+        error!("the run was cancelled");
+        return false;
+    }
+    if let Some(summary) = &opts.summary {
+        summary.print();
+    }
+
+    // The subtrees skipped as unchanged are only in the scan
+    let files = match &scan {
+        Some(scan) => Some(scan.files()),
+        None => opts.summary.as_ref().map(|s| s.files()),
+    };
+    if let (Some(mut scan), false) = (scan, opts.dry_run) {
+        scan.note_transforms(&opts.policy);
+        scan.run = Some(run.id.clone());
+        // What couldn't be backed up is looked at again by the next run
+        for relative in opts.skip_list.iter().flat_map(|s| s.failures()) {
+            scan.forget(&relative);
+        }
+        if let Err(e) = scan.save(target, destination) {
+            warning!("cannot write {}: {}", manifest::path(destination), e);
+        }
+    }
+    conclude(source, destination, &run, &opts, target, files)
 }
 
 
-/// Backup the source directory to the destination directory
-fn backup(source: &str, destination: &str, dry_run: bool) {
-    // Get a list (recursively) of the files in the source directory
-    // and copy them to the destination directory, preserving the
-    // directory structure
-    let dir = match fs::read_dir(source) {
-        Ok(d) => d,
-        Err(_) => {
-            return;
+/// Back up to a staging copy of a local destination and put it in place of
+/// the destination once the run is over, so that readers never see a
+/// destination that is only partly updated. A run that fails leaves the
+/// destination as it was.
+fn run_atomic(source: &str, destination: &str, opts: &Options, stats: &mut Stats) -> bool {
+    if opts.dry_run {
+        return run_backup(source, destination, opts, stats);
+    }
+    if backend::parse_remote(destination).is_some() || destination.starts_with("smb://") {
+        error!("--atomic needs a local destination");
+        return false;
+    }
+    if opts.snapshot.is_some() {
+        error!("--atomic can't be combined with --snapshot");
+        return false;
+    }
+    // Held until the staged tree is in place
+    let _lock = match lock::acquire(destination, Some(destination)) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{}", e);
+            return false;
         }
     };
-    for entry in dir {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.is_dir() {
-            // Recursively call backup() for subdirectories
-            // Create the subdirectory in the destination directory
-            // if it doesn't exist
-            let subdirectory = path.file_name().unwrap().to_str().unwrap();
-            let destination = format!("{}/{}", destination, subdirectory);
-            if !Path::new(&destination).exists() {
-                if !dry_run {
-                    fs::create_dir(&destination).unwrap();
-                }
-            }
-            backup(path.to_str().unwrap(), &destination, dry_run);
-        } else {
-            // Copy the file to the destination directory
-            let file_name = path.file_name().unwrap();
-            let file_name_str = match file_name.to_str() {
-                Some(s) => s,
-                None => continue,
-            };
-            let destination_file = format!("{}/{}", destination, file_name_str);
-            let source_file = path.to_str().unwrap();
-            if is_symlink(source_file) == 0 {
-                if is_symlink(&destination_file) == 0 {
-                    // If the symlink in the source directory points to a different
-                    // file than the symlink in the destination directory, overwrite
-                    // the destination symlink
-                    let source = fs::read_link(source_file).unwrap();
-                    let destination = fs::read_link(&destination_file).unwrap();
-                    if source != destination {
-                        copy_file(source_file, &destination_file, dry_run);
-                    }
-                } else {
-                    // If the destination file is not a symlink, overwrite it
-                    copy_file(source_file, &destination_file, dry_run);
-                }
-            } else if Path::new(&destination_file).exists() {
-                // Get size of both files, and if they are different, overwrite
-                // the destination file
-                if size(source_file) != size(&destination_file) {
-                    copy_file(source_file, &destination_file, dry_run);
-                } else {
-                    if modified_time(source_file) > modified_time(&destination_file) {
-                        copy_file(source_file, &destination_file, dry_run);
-                    }
-                }
-            } else {
-                copy_file(source_file, &destination_file, dry_run);
-            }
+    let staging = match staging::prepare(destination) {
+        Ok(staging) => staging,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    // Files are shared with the destination until replaced, so they can't
+    // be updated in place
+    let mut opts = opts.clone();
+    opts.delta = false;
+    if !run_backup(source, &staging, &opts, stats) {
+        let _ = fs::remove_dir_all(&staging);
+        error!("not updating {}", destination);
+        return false;
+    }
+    match staging::publish(destination, &staging) {
+        Ok(()) => {
+            // The swap is a change of the directory holding the destination
+            engine::sync_parent(destination, &opts, &backend::LocalBackend);
+            flush(&opts, &backend::LocalBackend, destination);
+            info!("Published the changes to {}", destination);
+            true
+        }
+        Err(e) => {
+            error!("cannot put {} in place of {}: {}", staging, destination, e);
+            false
         }
     }
 }
 
 
-fn print_usage_and_exit(code: i32) {
-    const USAGE: &str = "\
-    Usage: backup-rs [OPTION]... SOURCE DESTINATION
+/// Remove the entries of the destination missing from the source, as
+/// found by a deletion pass, once they are confirmed
+fn confirm_removals(opts: &Options, target: &dyn backend::Backend, pass: impl Fn(&Options)) {
+    let removals = Rc::new(RefCell::new(Vec::new()));
+    let mut collecting = opts.clone();
+    collecting.removals = Some(removals.clone());
+    pass(&collecting);
+    for removal in confirm::ask(removals.take()) {
+        remove_path(&removal.path, opts, target);
+    }
+}
 
-    OPTIONS:
-      --dry  simulate the backup process
-      --help  display this help and exit
-      --version  output version information and exit
 
-    Exit status:
-      0  if OK,
-      1  if minor problems (e.g., cannot access subdirectory)
+/// Log the error of a command, if any, returning whether it succeeded
+fn report(result: Result<(), String>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            error!("{}", e);
+            false
+        }
+    }
+}
 
-    Full documentation <https://github.com/j-morano/contemporary-z>
-    ";
-    println!("{}", USAGE);
-    std::process::exit(code);
+
+/// Make sure that the copies fit in a local destination before starting
+/// them, rather than running out of space halfway through. Returns false
+/// if they don't.
+fn check_space(source: &str, destination: &str, opts: &Options, target: &dyn backend::Backend) -> bool {
+    if !target.is_local() {
+        return true;
+    }
+    let space = match space::space(Path::new(destination)) {
+        Ok(space) => space,
+        Err(e) => {
+            warning!("cannot get the free space of {}: {}", destination, e);
+            return true;
+        }
+    };
+    let needed = space::estimate(source, destination, space.block_size, opts, target);
+    if needed <= space.available {
+        return true;
+    }
+    let message = format!(
+        "the copies need about {} but only {} is available on {}",
+        human_bytes(needed),
+        human_bytes(space.available),
+        destination
+    );
+    // Compressed copies take less space than the files
+    if opts.ignore_space || opts.dry_run || opts.compress.is_some() {
+        warning!("{}", message);
+        return true;
+    }
+    error!("{}; not copying anything (use --ignore-space to try anyway)", message);
+    false
+}
+
+
+/// Refuse to back up to a local destination that overlaps with the source,
+/// logging why. Returns false if it does.
+fn check_nesting(source: &str, destination: &str, opts: &Options, target: &dyn backend::Backend) -> bool {
+    if !target.is_local() {
+        return true;
+    }
+    // The backup doesn't go into a destination on another file system
+    // with --one-file-system
+    let excluded = |relative: &str| {
+        opts.policy.is_excluded(&format!("{}/{}", source, relative))
+            || (opts.one_file_system && preconditions::leaves_file_system(source, relative))
+    };
+    report(preconditions::check_nesting(source, destination, excluded))
+}
+
+
+/// Make sure that a destination is one to mirror to before removing
+/// anything from it, in case a wrong one was given: an empty destination
+/// is marked as one, and one that isn't marked is refused (unless forced),
+/// but for those that hold the state of earlier runs. Returns false if it
+/// is refused.
+pub fn check_marker(destination: &str, opts: &Options, target: &dyn backend::Backend) -> bool {
+    let marker = format!("{}/{}", destination, manifest::MARKER);
+    if target.metadata(&marker).is_some() {
+        return true;
+    }
+    let names = target.list(destination).unwrap_or_default();
+    if !opts.force && !names.is_empty() && !names.iter().any(|name| name == manifest::META_DIR) {
+        error!(
+            "{} isn't empty and has no {}, so it may not be a backup; not touching it (use --force if it is the right destination)",
+            destination,
+            manifest::MARKER
+        );
+        return false;
+    }
+    if !opts.dry_run {
+        let text = b"This directory is a mirror kept by backup-rs, which removes from it what isn't in its source.\n";
+        if let Err(e) = target.write(&marker, text) {
+            warning!("cannot write {}: {}", marker, e);
+        }
+    }
+    true
+}
+
+
+/// Lock the destination of a run, so that overlapping runs (e.g. from cron)
+/// don't undo each other's work. Dry runs don't modify it, so they don't
+/// lock it.
+pub fn lock_destination(
+    location: &str,
+    destination: &str,
+    opts: &Options,
+    target: &dyn backend::Backend,
+) -> Result<Option<lock::Lock>, String> {
+    if opts.dry_run {
+        return Ok(None);
+    }
+    lock::acquire(location, target.is_local().then_some(destination)).map(Some)
+}
+
+
+/// The workers copying files to the destination with `--copy-jobs`, if it
+/// takes copies made in parallel
+pub fn copy_pool(opts: &Options, target: &dyn backend::Backend) -> Option<Rc<CopyPool>> {
+    let copier = target.copier().filter(|_| opts.copy_jobs > 1 && !opts.dry_run)?;
+    match CopyPool::new(opts.copy_jobs, copier) {
+        Ok(pool) => Some(Rc::new(pool)),
+        Err(e) => {
+            warning!("cannot start the copy workers: {}; copying the files one at a time", e);
+            None
+        }
+    }
+}
+
+
+/// The copies held to be made in the order of `--order`, if given
+pub fn copy_queue(opts: &Options) -> Option<Rc<Queue>> {
+    opts.order.map(|order| Rc::new(Queue::new(order)))
+}
+
+
+/// Write what the storage of the destination keeps for the end of a run
+/// (e.g. the index of the compressed files)
+pub fn finish(target: &dyn backend::Backend, destination: &str) {
+    if let Err(e) = target.finish() {
+        warning!("cannot update the metadata of {}: {}", destination, e);
+    }
+}
+
+
+/// With `--fsync-at-end`, flush the filesystem of a local destination to
+/// the disk, once the run wrote all it had to
+pub fn flush(opts: &Options, target: &dyn backend::Backend, destination: &str) {
+    if !opts.fsync_at_end || opts.dry_run || !target.is_local() {
+        return;
+    }
+    action!("Flushing {} to the disk", destination);
+    if let Err(e) = engine::sync_filesystem(destination) {
+        error!("cannot flush {} to the disk: {}", destination, e);
+    }
+}
+
+
+/// Check what a run changed against the change budget, if any, `files`
+/// being the number of files in the source if known. Returns false if it
+/// went over it.
+pub fn check_budget(opts: &Options, files: Option<u64>) -> bool {
+    match (&opts.budget, &opts.summary) {
+        (Some(budget), Some(summary)) => budget.check(summary.total(), files),
+        _ => true,
+    }
+}
+
+
+/// Print the paths that a run couldn't back up, and write them to the
+/// `--error-report` file if asked to
+pub fn report_failures(run: &Run, opts: &Options) {
+    let Some(errors) = &opts.errors else {
+        return;
+    };
+    errors.print();
+    if let Some(path) = &opts.error_report {
+        if let Err(e) = errors.write_json(path, &run.id) {
+            warning!("cannot write {}: {}", path.display(), e);
+        }
+    }
+}
+
+
+/// End a run: save the state of the destination, snapshot it if asked to,
+/// check its changes against the budget and record how the run went in it.
+/// Returns false if the snapshot couldn't be created.
+fn conclude(
+    source: &str,
+    destination: &str,
+    run: &Run,
+    opts: &Options,
+    target: &dyn backend::Backend,
+    files: Option<u64>,
+) -> bool {
+    finish(target, destination);
+    report_failures(run, opts);
+    if let (Some(skip_list), false) = (&opts.skip_list, opts.dry_run) {
+        if let Err(e) = skip_list.save(target) {
+            warning!("cannot record the failures of {}: {}", destination, e);
+        }
+    }
+    if let (Some(checksums), false) = (&opts.checksums, opts.dry_run) {
+        if let Err(e) = checksums.save(target) {
+            warning!("cannot write {}: {}", checksums::path(destination), e);
+        }
+    }
+    let ok = take_snapshot(opts, target, destination);
+    let within_budget = check_budget(opts, files);
+    match opts.dry_run {
+        false => run.record(source, destination, ok, within_budget, opts.summary.as_deref(), target),
+        true => run.simulated(opts.summary.as_deref()),
+    }
+    flush(opts, target, destination);
+    ok
+}
+
+
+/// Snapshot the destination after a run, if asked to. Returns false if the
+/// snapshot couldn't be created.
+fn take_snapshot(opts: &Options, target: &dyn backend::Backend, destination: &str) -> bool {
+    if let (Some(kind), false) = (opts.snapshot, opts.dry_run) {
+        if !target.is_local() {
+            error!("snapshots are only supported for local destinations");
+            return false;
+        }
+        match snapshot::create(kind, destination) {
+            Ok(name) => info!("Created snapshot {}", name),
+            Err(e) => {
+                error!("cannot snapshot the destination: {}", e);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+
+/// Sync only the given paths (relative to the source root), known to be the
+/// only ones that changed since the last run. The manifest is left as it
+/// is: being older than the destination only makes the next full run
+/// rescan more.
+#[cfg(target_os = "linux")]
+fn run_incremental(
+    source: &str,
+    destination: &str,
+    dirty: &std::collections::BTreeSet<String>,
+    opts: &Options,
+) -> bool {
+    let run = Run::start();
+    progress::start(source, destination);
+    log::separator();
+    info!("Source: {}", source);
+    info!("Destination: {}", destination);
+    info!("Run: {}", run.id);
+    log::separator();
+    info!("Syncing {} changed path(s)...", dirty.len());
+    if let Err(e) = preconditions::check_all(&opts.preconditions, source) {
+        error!("the source isn't available: {}; not backing it up", e);
+        return false;
+    }
+    let mut opts = opts.clone();
+    opts.policy.root = source.to_string();
+    let location = destination;
+    let (target, destination) = match backend::open(destination, &opts) {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    let target = target.as_ref();
+    if target.is_local() {
+        fat::detect(&destination, &mut opts);
+    }
+    if !check_nesting(source, &destination, &opts, target) || !check_marker(&destination, &opts, target) {
+        return false;
+    }
+    case::detect(&destination, &mut opts, target);
+    let _lock = match lock_destination(location, &destination, &opts, target) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    opts.protected.insert(Path::new(&destination).join(manifest::META_DIR));
+    opts.protected.insert(Path::new(&destination).join(manifest::MARKER));
+    opts.summary = Some(Rc::new(Summary::new(&destination)));
+    opts.errors = Some(Rc::new(ErrorReport::default()));
+    opts.versions = opts.keep_versions.map(|count| Rc::new(Versions::new(&destination, count)));
+    opts.out_of_space = target.is_local().then(|| Rc::new(OutOfSpace::new(&destination, &opts)));
+    opts.skip_list = Some(Rc::new(SkipList::load(target, &destination, source)));
+    opts.checksums = Some(Rc::new(Checksums::load(target, &destination)));
+    opts.copies = copy_pool(&opts, target);
+    opts.queued = copy_queue(&opts);
+    let mut dirty = dirty.clone();
+    if let Some(days) = opts.delete_after_days {
+        let deletions = Deletions::load(target, &destination, days);
+        // The entries kept by the last run may be due for removal by now
+        dirty.extend(deletions.pending().map(|p| p.to_string()));
+        opts.deletions = Some(Rc::new(deletions));
+    }
+    watch::sync_dirty(source, &destination, &dirty, &opts, target);
+    wait_copies(&opts, target);
+    if progress::cancelled() {
+        error!("the run was cancelled");
+        return false;
+    }
+    if let Some(summary) = &opts.summary {
+        summary.print();
+    }
+    if let (Some(deletions), false) = (&opts.deletions, opts.dry_run) {
+        if let Err(e) = deletions.save(target) {
+            warning!("cannot record the pending deletions of {}: {}", destination, e);
+        }
+    }
+    // Only the changed paths were looked at, so the files of the source are
+    // those of the last full run
+    let files = match opts.budget {
+        Some(Budget { percent: Some(_), .. }) => Manifest::load(target, &destination).map(|m| m.files()),
+        _ => None,
+    };
+    conclude(source, &destination, &run, &opts, target, files)
 }
 
 
 
 fn main() {
     // Process command line arguments
-    let mut args: Vec<String> = std::env::args().collect();
-    if args.len() == 2 {
-        if args[1] == "--help" {
-            print_usage_and_exit(0);
-        } else if args[1] == "--version" {
-            // Print the version of the program from the Cargo.toml file
-            let version = env!("CARGO_PKG_VERSION");
-            println!("backup-rs {}", version);
-            std::process::exit(0);
-        } else {
-            print_usage_and_exit(1);
-        }
-    } else if args.len() == 3 || args.len() == 4 {
-        let mut dry_run = false;
-        if args.len() == 4 {
-            let mut i = 0;
-            let mut loc = 0;
-            for arg in &args {
-                if arg == "--dry" {
-                    dry_run = true;
-                    loc = i;
-                }
-                i += 1;
+    let args: Vec<String> = std::env::args().collect();
+    let mut command = "backup";
+    let mut arguments = Arguments::default();
+    let mut paths: Vec<&String> = Vec::new();
+    let (expanded, after_dashes) = expand_arguments(&args[1..]);
+    let mut args_iter = expanded.iter();
+    if let Some(c @ ("backup" | "watch" | "daemon" | "drill" | "verify" | "check" | "repair" | "adopt" | "restore" | "ls" | "find" | "mount" | "history" | "snapshots" | "prune" | "changes" | "diff" | "estimate" | "export" | "import" | "repo" | "ctl")) = expanded.first().map(|a| a.as_str()) {
+        command = c;
+        args_iter.next();
+    }
+    while let Some(arg) = args_iter.next() {
+        match OPTIONS.iter().find(|spec| spec.name == arg) {
+            Some(spec) => take_option(spec, command, &mut args_iter, &mut arguments),
+            None if arg.starts_with("--") => usage_error(&format!("unknown option '{}'", arg)),
+            None => paths.push(arg),
+        }
+    }
+    let Arguments {
+        mut opts,
+        print_stats,
+        debounce,
+        config_path,
+        foreground,
+        since,
+        sample,
+        restart,
+        percent,
+        all_snapshots,
+        format,
+        restore_version,
+        rewrites,
+        retention,
+        query,
+        exclude_from,
+        files_from,
+        from0,
+        allow_empty_source,
+        low_priority,
+        color,
+        verbosity,
+        pre_hook,
+        post_hook,
+        notify_url,
+        notify_desktop,
+        metrics_file,
+        metrics_listen,
+        control_socket,
+        dashboard,
+        mail_to,
+        mail_on,
+        mail_server,
+    } = arguments;
+    paths.extend(after_dashes.iter());
+    log::use_color(color);
+    // Dry runs are there to see what a run would do
+    log::set_verbosity(verbosity.unwrap_or(if opts.dry_run { log::VERBOSE } else { log::NORMAL }));
+    if low_priority {
+        if let Err(e) = priority::lower() {
+            warning!("cannot lower the priority of backup-rs: {}", e);
+        }
+    }
+    if !allow_empty_source {
+        opts.preconditions.push(Precondition::NotEmpty);
+    }
+    if opts.detect_renames && !opts.manifest {
+        error!("--detect-renames needs --manifest");
+        std::process::exit(1);
+    }
+    if command == "repair" {
+        // The manifest is what tells the damage apart from changes
+        opts.repair = true;
+        opts.manifest = true;
+        opts.trust_manifest = false;
+    }
+    if opts.trust_manifest && !opts.manifest {
+        error!("--trust-manifest needs --manifest");
+        std::process::exit(1);
+    }
+    if opts.comparator.as_ref().is_some_and(|c| c.needs_manifest()) && !opts.manifest {
+        error!("--compare manifest needs --manifest");
+        std::process::exit(1);
+    }
+    let lists = exclude_from.iter().chain(files_from.iter());
+    if lists.filter(|path| path.as_str() == "-").count() > 1 {
+        error!("only one list can be read from the standard input");
+        std::process::exit(1);
+    }
+    for path in exclude_from {
+        match filelist::read(&path, from0) {
+            Ok(entries) => filelist::patterns(entries).iter().for_each(|p| opts.policy.exclude(p)),
+            Err(e) => {
+                error!("cannot read {}: {}", path, e);
+                std::process::exit(1);
             }
-            if !dry_run {
-                print_usage_and_exit(1);
-            } else {
-                args.remove(loc);
+        }
+    }
+    if let Some(path) = files_from {
+        match filelist::read(&path, from0) {
+            Ok(entries) => opts.files_from = Some(entries),
+            Err(e) => {
+                error!("cannot read {}: {}", path, e);
+                std::process::exit(1);
             }
         }
-        let source = &args[1];
-        let destination = &args[2];
-        println!("{}", "-".repeat(80));
-        println!("Source: {}", source);
-        println!("Destination: {}", destination);
-        println!("{}", "-".repeat(80));
-
-        if !dry_run {
-            println!("Backup in progress...");
+    }
+    if command == "ctl" {
+        let paused = match (paths.first().map(|a| a.as_str()), &paths[..]) {
+            (Some("pause"), [_, _]) => true,
+            (Some("resume"), [_, _]) => false,
+            _ => wrong_arguments(command),
+        };
+        match pause::control(paths[1], paused) {
+            Ok(pid) => info!("{} backup-rs (pid {})", if paused { "Paused" } else { "Resumed" }, pid),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if command == "daemon" {
+        if !paths.is_empty() {
+            wrong_arguments(command);
+        }
+        daemon::daemon(&config_path, foreground, metrics_listen.as_deref(), control_socket.as_deref());
+        return;
+    }
+    if command == "drill" || command == "verify" {
+        let sample = if command == "drill" { Some(sample) } else { None };
+        let ok = match paths[..] {
+            [destination] => drill::drill(None, destination, sample, restart, &opts),
+            [source, destination] => drill::drill(Some(source), destination, sample, restart, &opts),
+            _ => wrong_arguments(command),
+        };
+        if !ok {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "check" {
+        let [destination] = paths[..] else {
+            wrong_arguments(command);
+        };
+        if !check::check(destination, percent, &opts) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "adopt" {
+        let [source, destination] = paths[..] else {
+            wrong_arguments(command);
+        };
+        if !adopt::adopt(source, destination, &opts) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "repo" {
+        let ok = match (paths.first().map(|a| a.as_str()), &paths[..]) {
+            (Some("init"), [_, location]) => report(repository::init(location, &opts)),
+            (Some("backup"), [_, source, location]) => repository::backup(source, location, &opts),
+            (Some("snapshots"), [_, location]) => report(repository::list(location, &opts)),
+            (Some("restore"), [_, location, snapshot, directory]) => {
+                repository::restore(location, snapshot, directory, &opts)
+            }
+            (Some("forget"), [_, location, snapshots @ ..]) if !snapshots.is_empty() => {
+                report(repository::forget(location, snapshots, &opts))
+            }
+            (Some("gc"), [_, location]) => report(repository::gc(location, &opts)),
+            _ => wrong_arguments(command),
+        };
+        if !ok {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "ls" {
+        let ok = match paths[..] {
+            [mirror] => report(listing::ls(mirror, None, &opts)),
+            [mirror, path] => report(listing::ls(mirror, Some(path), &opts)),
+            _ => wrong_arguments(command),
+        };
+        if !ok {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "find" {
+        let [mirror, pattern] = paths[..] else {
+            wrong_arguments(command);
+        };
+        if !report(listing::find(mirror, pattern, &query, &opts)) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "mount" {
+        let [mirror, mountpoint] = paths[..] else {
+            wrong_arguments(command);
+        };
+        #[cfg(target_os = "linux")]
+        let ok = report(mount::mount_mirror(mirror, mountpoint, all_snapshots, &opts));
+        #[cfg(not(target_os = "linux"))]
+        let ok = {
+            let _ = (mirror, mountpoint, all_snapshots);
+            error!("mounting is only supported on Linux");
+            false
+        };
+        if !ok {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "history" {
+        let ok = match (&paths[..], restore_version.as_deref()) {
+            ([destination, path], None) => report(history::history(destination, path, &opts)),
+            ([destination, path], Some(version)) => {
+                report(history::restore_version(destination, path, version, None, &opts))
+            }
+            ([destination, path, output], Some(version)) => {
+                report(history::restore_version(destination, path, version, Some(output), &opts))
+            }
+            _ => wrong_arguments(command),
+        };
+        if !ok {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "snapshots" {
+        let ok = match paths[..] {
+            [destination] => report(listing::snapshots(destination, &opts)),
+            _ => wrong_arguments(command),
+        };
+        if !ok {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "prune" {
+        let ok = match paths[..] {
+            [destination] => report(prune::prune(destination, retention, &opts)),
+            _ => wrong_arguments(command),
+        };
+        if !ok {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if paths.len() != 2 {
+        wrong_arguments(command);
+    }
+    if command == "estimate" {
+        if !estimate::estimate(paths[0], paths[1], &opts) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "diff" {
+        match diff::diff(paths[0], paths[1], &opts) {
+            Ok(true) => (),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+    if command == "changes" {
+        if !report(changes::changes(paths[0], paths[1], since.as_deref(), &opts)) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "restore" {
+        if !restore::restore(paths[0], paths[1], &rewrites, &opts) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    if command == "export" || command == "import" {
+        let result = if command == "export" {
+            bundle::export(paths[0], since.as_deref(), paths[1])
         } else {
-            println!("Dry run: Backup simulation in progress...");
+            bundle::import(paths[0], paths[1], opts.dry_run, opts.force)
+        };
+        if let Err(e) = result {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let source = paths[0];
+    let destination = paths[1];
+    if let Some(format) = format {
+        if !report(archive::archive(source, destination, format, &opts)) {
+            std::process::exit(1);
         }
-        if !dry_run {
-            // Create the destination directory if it doesn't exist
-            if !Path::new(destination).exists() {
-                fs::create_dir(destination).unwrap();
+        return;
+    }
+    let mut stats = Stats::new();
+    let (errors, _) = log::counts();
+    progress::install();
+    pause::install();
+    if let Some(path) = &control_socket {
+        match control::bind(path) {
+            Ok(listener) => control::serve(listener, Vec::new()),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
             }
         }
+    }
+    systemd::ready();
+    let start = std::time::Instant::now();
+    if dashboard {
+        if let Err(e) = tui::start() {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let context = hooks::Context { source, destination, job: None, dry_run: opts.dry_run };
+    let mail = mail_to.map(|to| mail::Mail { to, on: mail_on, server: mail_server });
+    let ok = metrics::around(metrics_file.as_deref(), &context, || {
+        mail::around(mail.as_ref(), &context, || {
+            notify::around(notify_url.as_deref(), &context, || {
+                notify::around_desktop(notify_desktop, &context, || {
+                    hooks::around(pre_hook.as_deref(), post_hook.as_deref(), &context, || match opts.atomic {
+                        true => run_atomic(source, destination, &opts, &mut stats),
+                        false => run_backup(source, destination, &opts, &mut stats),
+                    })
+                })
+            })
+        })
+    });
+    let result = status::result(ok, log::counts().0 - errors);
+    progress::finish(result);
+    tui::finish(result, start.elapsed());
+    systemd::status(&format!("Finished backing up {} to {} ({})", source, destination, result));
+    if print_stats {
+        stats.print();
+    }
 
-        // Recursively iterate through the destination directory to remove the files
-        // that are not in the source directory
-        remove_removed(source, destination, dry_run);
-
-        println!("{}", "-".repeat(80));
-        // Backup the source to the destination
-        backup(&args[1], &args[2], dry_run);
-    } else {
-        print_usage_and_exit(1);
+    if command == "watch" {
+        #[cfg(target_os = "linux")]
+        watch::watch(source, destination, &opts, debounce);
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = debounce;
+            error!("watch mode is only supported on Linux");
+            std::process::exit(1);
+        }
+    }
+    let exit_status = status::exit_status(result, !budget::exceeded());
+    if exit_status != 0 {
+        std::process::exit(exit_status);
     }
 }
@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::backend::Backend;
+use crate::hash::{self, Digest, Sha256};
+use crate::policy::Policy;
+use crate::symlinks::Ancestors;
+
+
+
+/// Directory, at the root of the destination, where backup-rs keeps its own
+/// files. It is never copied from the source nor removed from the destination.
+pub const META_DIR: &str = ".backup-rs";
+
+/// File, at the root of a destination, marking it as one backup-rs mirrors
+/// to (and so removes things from)
+pub const MARKER: &str = ".backup-rs-destination";
+
+const MANIFEST_FILE: &str = "manifest";
+const HEADER: &str = "backup-rs manifest 1";
+
+
+/// What the manifest knows about one path of the source
+#[derive(Clone, Debug, PartialEq)]
+pub enum Entry {
+    /// A directory, with the rollup hash of everything below it
+    Directory { rollup: Digest },
+    File { size: u64, mtime: i64, mtime_nsec: i64 },
+    Symlink { target: String },
+}
+
+
+/// The state of the source tree as of the last completed backup, keyed by
+/// path relative to the source root (`.` being the root itself)
+#[derive(Default)]
+pub struct Manifest {
+    pub entries: BTreeMap<String, Entry>,
+    /// Files whose copy is the output of a command rather than the file
+    /// itself, with the command
+    pub transforms: BTreeMap<String, String>,
+    /// ID of the run that wrote it
+    pub run: Option<String>,
+}
+
+
+fn join(relative: &str, name: &str) -> String {
+    if relative == "." {
+        name.to_string()
+    } else {
+        format!("{}/{}", relative, name)
+    }
+}
+
+
+/// Escape tabs, newlines and backslashes so that each entry fits in one line
+pub fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+
+pub fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => result.push('\t'),
+                Some('n') => result.push('\n'),
+                Some(c) => result.push(c),
+                None => (),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+
+/// Threads scanning subdirectories of the source on their own, up to
+/// `--scan-jobs`
+#[derive(Clone, Copy)]
+struct Scanners<'scope, 'env> {
+    scope: &'scope thread::Scope<'scope, 'env>,
+    /// Threads that may still be started
+    free: &'env AtomicUsize,
+}
+
+
+impl Scanners<'_, '_> {
+    fn take(&self) -> bool {
+        self.free.fetch_update(Ordering::AcqRel, Ordering::Acquire, |free| free.checked_sub(1)).is_ok()
+    }
+}
+
+
+/// An entry of a directory being scanned: one known already, or a
+/// subdirectory being scanned by another thread, into a manifest of its own
+enum Scanned<'scope> {
+    Entry(Entry),
+    Directory(thread::ScopedJoinHandle<'scope, (Digest, Manifest)>),
+}
+
+
+/// Record a directory and everything below it, returning its rollup hash.
+/// The rollup covers the name, type and metadata of every entry, so two
+/// directories with the same rollup can be considered identical.
+/// Subdirectories are scanned by threads of their own while `scanners`
+/// has some free. Those on another device than `device`, if given, are
+/// taken as empty, and those leading back to one of `ancestors` (the
+/// directories it is in) are left out.
+fn scan_dir<'scope>(
+    path: &Path,
+    relative: &str,
+    follow_links: bool,
+    device: Option<u64>,
+    ancestors: &Ancestors,
+    scanners: Scanners<'scope, '_>,
+    manifest: &mut Manifest,
+) -> Digest {
+    let ancestors = ancestors.enter(fs::metadata(path).ok().as_ref());
+    let mut hasher = Sha256::new();
+    let mut names: Vec<String> = match fs::read_dir(path) {
+        Ok(dir) => dir
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str().map(|s| s.to_string()))
+            .collect(),
+        Err(_) => {
+            hasher.update(b"unreadable");
+            Vec::new()
+        }
+    };
+    names.sort();
+    let mut scanned = Vec::with_capacity(names.len());
+    for name in names {
+        if relative == "." && is_meta(&name) {
+            continue;
+        }
+        let child = path.join(&name);
+        let child_relative = join(relative, &name);
+        let metadata = match fs::symlink_metadata(&child) {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        let followed = match follow_links {
+            true => fs::metadata(&child).ok(),
+            false => Some(metadata.clone()),
+        };
+        let is_dir = followed.as_ref().is_some_and(|m| m.is_dir());
+        if is_dir && followed.as_ref().is_some_and(|m| ancestors.contains(m)) {
+            continue;
+        }
+        let mounted = followed.is_some_and(|m| device.is_some_and(|device| m.dev() != device));
+        // A thread that can't be started is one fewer to scan with, and the
+        // directory is scanned by this one
+        let spawned = match is_dir && !mounted && scanners.take() {
+            true => {
+                let (child, subdirectory, ancestors) = (child.clone(), child_relative.clone(), ancestors.clone());
+                thread::Builder::new()
+                    .spawn_scoped(scanners.scope, move || {
+                        let mut manifest = Manifest::default();
+                        let rollup = scan_dir(&child, &subdirectory, follow_links, device, &ancestors, scanners, &mut manifest);
+                        scanners.free.fetch_add(1, Ordering::AcqRel);
+                        (rollup, manifest)
+                    })
+                    .ok()
+            }
+            false => None,
+        };
+        let entry = if is_dir && mounted {
+            Scanned::Entry(Entry::Directory { rollup: Sha256::new().finish() })
+        } else if let Some(scanning) = spawned {
+            Scanned::Directory(scanning)
+        } else if is_dir {
+            Scanned::Entry(Entry::Directory {
+                rollup: scan_dir(&child, &child_relative, follow_links, device, &ancestors, scanners, manifest),
+            })
+        } else if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&child).unwrap_or_default();
+            Scanned::Entry(Entry::Symlink {
+                target: target.to_string_lossy().to_string(),
+            })
+        } else {
+            Scanned::Entry(Entry::File {
+                size: metadata.len(),
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+            })
+        };
+        scanned.push((name, child_relative, entry));
+    }
+    for (name, child_relative, entry) in scanned {
+        let entry = match entry {
+            Scanned::Entry(entry) => entry,
+            Scanned::Directory(scanning) => {
+                let (rollup, mut below) = scanning.join().unwrap_or_else(|e| std::panic::resume_unwind(e));
+                manifest.entries.append(&mut below.entries);
+                Entry::Directory { rollup }
+            }
+        };
+        hasher.update(name.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(format_entry(&entry).as_bytes());
+        hasher.update(b"\n");
+        manifest.entries.insert(child_relative, entry);
+    }
+    hasher.finish()
+}
+
+
+/// Type and metadata of an entry, as written in the manifest
+fn format_entry(entry: &Entry) -> String {
+    match entry {
+        Entry::Directory { rollup } => format!("D\t{}", hash::to_hex(rollup)),
+        Entry::File { size, mtime, mtime_nsec } => {
+            format!("F\t{}\t{}.{:09}", size, mtime, mtime_nsec)
+        }
+        Entry::Symlink { target } => format!("L\t{}", escape(target)),
+    }
+}
+
+
+fn parse_entry(fields: &[&str]) -> Option<Entry> {
+    match fields {
+        ["D", rollup] => Some(Entry::Directory { rollup: hash::from_hex(rollup)? }),
+        ["F", size, mtime] => {
+            let (secs, nsecs) = mtime.split_once('.')?;
+            Some(Entry::File {
+                size: size.parse().ok()?,
+                mtime: secs.parse().ok()?,
+                mtime_nsec: nsecs.parse().ok()?,
+            })
+        }
+        ["L", target] => Some(Entry::Symlink { target: unescape(target) }),
+        _ => None,
+    }
+}
+
+
+/// Path of the manifest inside the destination directory
+pub fn path(destination: &str) -> String {
+    format!("{}/{}/{}", destination, META_DIR, MANIFEST_FILE)
+}
+
+
+/// Whether an entry of the root of a destination belongs to backup-rs
+/// itself
+pub fn is_meta(name: &str) -> bool {
+    name == META_DIR || name == MARKER
+}
+
+
+impl Manifest {
+    fn scan_with(root: &str, follow_links: bool, jobs: usize, one_file_system: bool) -> Manifest {
+        let mut manifest = Manifest::default();
+        let device = fs::metadata(root).ok().filter(|_| one_file_system).map(|m| m.dev());
+        // The calling thread is one of the jobs
+        let free = AtomicUsize::new(jobs.saturating_sub(1));
+        let rollup = thread::scope(|scope| {
+            let scanners = Scanners { scope, free: &free };
+            scan_dir(Path::new(root), ".", follow_links, device, &Ancestors::default(), scanners, &mut manifest)
+        });
+        manifest
+            .entries
+            .insert(".".to_string(), Entry::Directory { rollup });
+        manifest
+    }
+
+    /// Describe the current state of the source directory, scanning up to
+    /// `jobs` directories at a time. Symlinks to directories are followed,
+    /// as the backup itself does, and with `one_file_system` the
+    /// directories other file systems are mounted on are taken as empty.
+    pub fn scan(source: &str, jobs: usize, one_file_system: bool) -> Manifest {
+        Manifest::scan_with(source, true, jobs, one_file_system)
+    }
+
+    /// Number of files (and symlinks) in the tree
+    pub fn files(&self) -> u64 {
+        self.entries.values().filter(|entry| !matches!(entry, Entry::Directory { .. })).count() as u64
+    }
+
+    /// Describe a mirror (a destination or a snapshot of it) as it is on
+    /// disk, without following symlinks
+    pub fn scan_mirror(root: &str) -> Manifest {
+        Manifest::scan_with(root, false, 1, false)
+    }
+
+    /// Load the manifest stored in the destination directory, if any
+    pub fn load(target: &dyn Backend, destination: &str) -> Option<Manifest> {
+        Manifest::parse(&String::from_utf8(target.read(&path(destination)).ok()?).ok()?)
+    }
+
+    pub fn parse(content: &str) -> Option<Manifest> {
+        let mut lines = content.lines();
+        if lines.next() != Some(HEADER) {
+            return None;
+        }
+        let mut manifest = Manifest::default();
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            // The path comes last, after the type and metadata fields
+            let (path, fields) = fields.split_last()?;
+            if let ["T", command] = fields {
+                manifest.transforms.insert(unescape(path), unescape(command));
+                continue;
+            }
+            if let ["R"] = fields {
+                manifest.run = Some(path.to_string());
+                continue;
+            }
+            manifest.entries.insert(unescape(path), parse_entry(fields)?);
+        }
+        Some(manifest)
+    }
+
+    /// Write the manifest into the destination directory, atomically
+    /// replacing the previous one
+    pub fn save(&self, target: &dyn Backend, destination: &str) -> std::io::Result<()> {
+        target.write(&path(destination), self.to_text().as_bytes())
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut content = format!("{}\n", HEADER);
+        if let Some(run) = &self.run {
+            writeln!(content, "R\t{}", run).unwrap();
+        }
+        for (path, entry) in &self.entries {
+            writeln!(content, "{}\t{}", format_entry(entry), escape(path)).unwrap();
+        }
+        for (path, command) in &self.transforms {
+            writeln!(content, "T\t{}\t{}", escape(command), escape(path)).unwrap();
+        }
+        content
+    }
+
+    /// Record which files are transformed before they are stored, by the
+    /// rules of a policy
+    pub fn note_transforms(&mut self, policy: &Policy) {
+        for (path, entry) in &self.entries {
+            if let Entry::File { .. } = entry {
+                if let Some(command) = policy.transform(&format!("{}/{}", policy.root, path)) {
+                    self.transforms.insert(path.clone(), command.to_string());
+                }
+            }
+        }
+    }
+
+    /// Forget a path, and that the directories holding it are unchanged,
+    /// so that the next run looks at it again (e.g. when it couldn't be
+    /// backed up)
+    pub fn forget(&mut self, relative: &str) {
+        self.entries.remove(relative);
+        for ancestor in Path::new(relative).ancestors().skip(1) {
+            let ancestor = match ancestor.to_string_lossy().as_ref() {
+                "" => ".".to_string(),
+                ancestor => ancestor.to_string(),
+            };
+            if let Some(Entry::Directory { rollup }) = self.entries.get_mut(&ancestor) {
+                *rollup = Digest::default();
+            }
+        }
+    }
+
+    /// Rollup hash of a directory, relative to the source root
+    pub fn rollup(&self, relative: &str) -> Option<&Digest> {
+        match self.entries.get(relative) {
+            Some(Entry::Directory { rollup }) => Some(rollup),
+            _ => None,
+        }
+    }
+
+    /// Directories (relative to the source root) whose rollup is the same
+    /// in both manifests, i.e. whose whole subtree is unchanged
+    pub fn unchanged_dirs<'a>(&'a self, previous: &'a Manifest) -> impl Iterator<Item = &'a str> {
+        self.entries.iter().filter_map(move |(path, entry)| match entry {
+            Entry::Directory { rollup } if previous.rollup(path) == Some(rollup) => {
+                Some(path.as_str())
+            }
+            _ => None,
+        })
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::hooks::Context;
+use crate::status;
+
+
+
+/// The metrics of runs: name, type and help, in the order they are written
+const METRICS: [(&str, &str, &str); 9] = [
+    ("backup_rs_last_run_timestamp_seconds", "gauge", "When the last run ended, in seconds since the epoch"),
+    ("backup_rs_last_success_timestamp_seconds", "gauge", "When the last successful run ended, in seconds since the epoch"),
+    ("backup_rs_last_run_duration_seconds", "gauge", "How long the last run took"),
+    ("backup_rs_last_run_copied_files", "gauge", "Files the last run copied"),
+    ("backup_rs_last_run_copied_bytes", "gauge", "Bytes the last run copied"),
+    ("backup_rs_last_run_removed_files", "gauge", "Files the last run removed from the destination"),
+    ("backup_rs_last_run_errors", "gauge", "Errors the last run had"),
+    ("backup_rs_last_run_warnings", "gauge", "Warnings the last run had"),
+    ("backup_rs_last_run_exit_status", "gauge", "Exit status of the last run (0 if OK, 1 if minor problems, 2 if over its change budget, 3 if it failed)"),
+];
+
+
+/// Metric -> labels (as `{source="...",...}`) -> value, of the runs of
+/// this process
+type Samples = BTreeMap<String, BTreeMap<String, String>>;
+
+
+static SAMPLES: Mutex<Samples> = Mutex::new(BTreeMap::new());
+
+
+/// A label value, escaped as the exposition format wants it
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+fn labels(context: &Context) -> String {
+    let mut labels = String::from("{");
+    if let Some(job) = context.job {
+        write!(labels, "backup_job=\"{}\",", escape(job)).unwrap();
+    }
+    write!(labels, "source=\"{}\",destination=\"{}\"}}", escape(context.source), escape(context.destination)).unwrap();
+    labels
+}
+
+
+/// The samples of a file in the exposition format (as written by
+/// `write`), for those of other runs to be kept
+fn parse(content: &str) -> Samples {
+    let mut samples = Samples::new();
+    for line in content.lines().filter(|line| !line.starts_with('#')) {
+        let Some((series, value)) = line.rsplit_once(' ') else {
+            continue;
+        };
+        let (name, labels) = match series.find('{') {
+            Some(brace) => series.split_at(brace),
+            None => (series, ""),
+        };
+        samples.entry(name.to_string()).or_default().insert(labels.to_string(), value.to_string());
+    }
+    samples
+}
+
+
+/// Samples in the exposition format of Prometheus
+fn render(samples: &Samples) -> String {
+    let mut text = String::new();
+    for (name, kind, help) in METRICS {
+        let Some(series) = samples.get(name) else {
+            continue;
+        };
+        writeln!(text, "# HELP {} {}", name, help).unwrap();
+        writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+        for (labels, value) in series {
+            writeln!(text, "{}{} {}", name, labels, value).unwrap();
+        }
+    }
+    text
+}
+
+
+/// Write the samples of the runs of this process to a file for the
+/// textfile collector of node_exporter, keeping those of the runs of
+/// other processes (and when they last succeeded). The file is replaced
+/// at once, so the collector never reads half of it.
+fn write(path: &str) -> io::Result<()> {
+    let mut merged = fs::read_to_string(path).map(|content| parse(&content)).unwrap_or_default();
+    for (name, series) in SAMPLES.lock().unwrap().iter() {
+        merged.entry(name.clone()).or_default().extend(series.clone());
+    }
+    let temporary = format!("{}.tmp", path);
+    fs::write(&temporary, render(&merged))?;
+    fs::rename(&temporary, path)
+}
+
+
+/// Run a backup and record its metrics (when it ended, how long it took,
+/// what it changed, its errors and warnings and exit status), writing them
+/// to `path` if given, for node_exporter to collect, and serving them with
+/// `serve`. Dry runs aren't recorded. Returns whether the backup succeeded.
+pub fn around(path: Option<&str>, context: &Context, backup: impl FnOnce() -> bool) -> bool {
+    status::around(context, backup, |outcome| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut values = vec![
+            ("backup_rs_last_run_timestamp_seconds", now.to_string()),
+            ("backup_rs_last_run_duration_seconds", format!("{:.3}", outcome.duration.as_secs_f64())),
+            ("backup_rs_last_run_copied_files", outcome.totals.copied.to_string()),
+            ("backup_rs_last_run_copied_bytes", outcome.totals.bytes.to_string()),
+            ("backup_rs_last_run_removed_files", outcome.totals.removed.to_string()),
+            ("backup_rs_last_run_errors", outcome.errors.to_string()),
+            ("backup_rs_last_run_warnings", outcome.warnings.to_string()),
+            ("backup_rs_last_run_exit_status", outcome.exit_status.to_string()),
+        ];
+        if outcome.exit_status == 0 {
+            values.push(("backup_rs_last_success_timestamp_seconds", now.to_string()));
+        }
+        let labels = labels(context);
+        {
+            let mut samples = SAMPLES.lock().unwrap();
+            for (name, value) in values {
+                samples.entry(name.to_string()).or_default().insert(labels.clone(), value);
+            }
+        }
+        if let Some(path) = path {
+            if let Err(e) = write(path) {
+                warning!("cannot write the metrics to {}: {}", path, e);
+            }
+        }
+    })
+}
+
+
+/// Answer a scrape with the metrics of the runs so far
+fn answer(stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers aren't looked at
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = match path {
+        "/" | "/metrics" => ("200 OK", render(&SAMPLES.lock().unwrap())),
+        _ => ("404 Not Found", "Not found; the metrics are at /metrics\n".to_string()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+
+/// Serve the metrics of the runs of the daemon over HTTP, at /metrics, for
+/// Prometheus to scrape them, in a thread of its own
+pub fn serve(listener: TcpListener) {
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = answer(stream) {
+                warning!("cannot serve the metrics: {}", e);
+            }
+        }
+    });
+}
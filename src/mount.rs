@@ -0,0 +1,632 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::raw::{c_char, c_int, c_ulong, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::process::Command;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::UNIX_EPOCH;
+
+use crate::backend::{self, Backend, Kind, Metadata};
+use crate::listing;
+use crate::manifest;
+use crate::snapshot;
+use crate::temporary;
+use crate::{log, Options};
+
+
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: i16,
+    revents: i16,
+}
+
+
+#[repr(C)]
+struct IoVec {
+    base: *mut c_void,
+    len: usize,
+}
+
+
+#[repr(C)]
+struct MsgHdr {
+    name: *mut c_void,
+    name_len: u32,
+    iov: *mut IoVec,
+    iov_len: usize,
+    control: *mut c_void,
+    control_len: usize,
+    flags: c_int,
+}
+
+
+extern "C" {
+    fn mount(source: *const c_char, target: *const c_char, fstype: *const c_char, flags: c_ulong, data: *const c_void) -> c_int;
+    fn umount2(target: *const c_char, flags: c_int) -> c_int;
+    fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    fn socketpair(domain: c_int, kind: c_int, protocol: c_int, fds: *mut c_int) -> c_int;
+    fn recvmsg(fd: c_int, message: *mut MsgHdr, flags: c_int) -> isize;
+    fn getuid() -> u32;
+    fn getgid() -> u32;
+}
+
+
+const MS_RDONLY: c_ulong = 1;
+const MS_NOSUID: c_ulong = 2;
+const MS_NODEV: c_ulong = 4;
+const MNT_DETACH: c_int = 2;
+const POLLIN: i16 = 0x1;
+const SIGINT: c_int = 2;
+const SIGTERM: c_int = 15;
+const AF_UNIX: c_int = 1;
+const SOCK_STREAM: c_int = 1;
+const SCM_RIGHTS: c_int = 1;
+const SOL_SOCKET: c_int = 1;
+
+const ENOENT: i32 = 2;
+const EINTR: i32 = 4;
+const EIO: i32 = 5;
+const EAGAIN: i32 = 11;
+const ENODEV: i32 = 19;
+const EROFS: i32 = 30;
+const ENOSYS: i32 = 38;
+
+/// Opcodes of the requests of the FUSE protocol
+const LOOKUP: u32 = 1;
+const FORGET: u32 = 2;
+const GETATTR: u32 = 3;
+const READLINK: u32 = 5;
+const OPEN: u32 = 14;
+const READ: u32 = 15;
+const STATFS: u32 = 17;
+const RELEASE: u32 = 18;
+const FLUSH: u32 = 25;
+const INIT: u32 = 26;
+const OPENDIR: u32 = 27;
+const READDIR: u32 = 28;
+const RELEASEDIR: u32 = 29;
+const ACCESS: u32 = 34;
+const INTERRUPT: u32 = 36;
+const DESTROY: u32 = 38;
+const BATCH_FORGET: u32 = 42;
+
+/// Sizes of `struct fuse_in_header` and `struct fuse_out_header`
+const IN_HEADER: usize = 40;
+const OUT_HEADER: usize = 16;
+/// Largest write the kernel may send, which read requests never are
+const MAX_WRITE: u32 = 128 * 1024;
+/// How long (in seconds) the kernel may cache names and attributes
+const VALID: u64 = 10;
+
+/// Set by SIGINT and SIGTERM to unmount
+static STOP: AtomicBool = AtomicBool::new(false);
+
+
+extern "C" fn stop(_signal: c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+
+/// A tree of the mounted filesystem: a mirror or a snapshot of one
+struct Tree {
+    name: String,
+    target: Rc<dyn Backend>,
+    root: String,
+}
+
+
+enum Node {
+    /// The directory listing the trees, when there are several
+    Trees,
+    Entry { tree: usize, path: String },
+}
+
+
+/// An open file: a local copy of the file, removed once closed if it was
+/// fetched
+struct OpenFile {
+    file: fs::File,
+    fetched: Option<String>,
+}
+
+
+/// The kernel side of the FUSE protocol, serving the trees read-only
+struct Filesystem {
+    trees: Vec<Tree>,
+    /// Node of inode N at N - 1
+    nodes: Vec<Node>,
+    inodes: HashMap<(usize, String), u64>,
+    files: HashMap<u64, OpenFile>,
+    directories: HashMap<u64, Vec<(u64, u32, String)>>,
+    next_handle: u64,
+    uid: u32,
+    gid: u32,
+}
+
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4).map_or(0, |b| u32::from_ne_bytes(b.try_into().unwrap()))
+}
+
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    data.get(offset..offset + 8).map_or(0, |b| u64::from_ne_bytes(b.try_into().unwrap()))
+}
+
+
+/// Append the fields of a reply
+fn put32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_ne_bytes());
+}
+
+
+fn put64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_ne_bytes());
+}
+
+
+impl Filesystem {
+    fn new(trees: Vec<Tree>) -> Filesystem {
+        let root = match trees.len() {
+            1 => Node::Entry { tree: 0, path: trees[0].root.clone() },
+            _ => Node::Trees,
+        };
+        Filesystem {
+            trees,
+            nodes: vec![root],
+            inodes: HashMap::new(),
+            files: HashMap::new(),
+            directories: HashMap::new(),
+            next_handle: 1,
+            uid: unsafe { getuid() },
+            gid: unsafe { getgid() },
+        }
+    }
+
+    fn node(&self, inode: u64) -> Option<&Node> {
+        self.nodes.get((inode as usize).checked_sub(1)?)
+    }
+
+    fn inode(&mut self, tree: usize, path: String) -> u64 {
+        if let Some(inode) = self.inodes.get(&(tree, path.clone())) {
+            return *inode;
+        }
+        self.nodes.push(Node::Entry { tree, path: path.clone() });
+        let inode = self.nodes.len() as u64;
+        self.inodes.insert((tree, path), inode);
+        inode
+    }
+
+    fn metadata(&self, inode: u64) -> Option<Metadata> {
+        match self.node(inode)? {
+            Node::Trees => Some(Metadata { kind: Kind::Directory, size: 0, modified: UNIX_EPOCH, target: None }),
+            Node::Entry { tree, path } => self.trees[*tree].target.metadata(path),
+        }
+    }
+
+    /// `struct fuse_attr` of an entry
+    fn attr(&self, buffer: &mut Vec<u8>, inode: u64, metadata: &Metadata) {
+        let (mode, nlink) = match metadata.kind {
+            Kind::Directory => (0o040555, 2),
+            Kind::Symlink => (0o120777, 1),
+            _ => (0o100444, 1),
+        };
+        let modified = metadata.modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        put64(buffer, inode);
+        put64(buffer, metadata.size);
+        put64(buffer, metadata.size.div_ceil(512));
+        for _ in 0..3 {
+            put64(buffer, modified.as_secs());
+        }
+        for _ in 0..3 {
+            put32(buffer, modified.subsec_nanos());
+        }
+        for value in [mode, nlink, self.uid, self.gid, 0, 4096, 0] {
+            put32(buffer, value);
+        }
+    }
+
+    /// The entries of a directory, with their inode and type
+    fn list(&mut self, inode: u64) -> Result<Vec<(u64, u32, String)>, i32> {
+        let (tree, path) = match self.node(inode) {
+            Some(Node::Trees) => {
+                let names: Vec<String> = self.trees.iter().map(|t| t.name.clone()).collect();
+                let mut entries = Vec::new();
+                for (tree, name) in names.into_iter().enumerate() {
+                    let root = self.trees[tree].root.clone();
+                    entries.push((self.inode(tree, root), 4, name));
+                }
+                return Ok(entries);
+            }
+            Some(Node::Entry { tree, path }) => (*tree, path.clone()),
+            None => return Err(ENOENT),
+        };
+        let target = self.trees[tree].target.clone();
+        let at_root = path == self.trees[tree].root;
+        let mut names = target.list(&path).map_err(|_| EIO)?;
+        names.sort();
+        let mut entries = Vec::new();
+        for name in names {
+            if at_root && manifest::is_meta(&name) {
+                continue;
+            }
+            let child = format!("{}/{}", path, name);
+            let kind = match target.metadata(&child).map(|m| m.kind) {
+                Some(Kind::Directory) => 4,
+                Some(Kind::File) => 8,
+                Some(Kind::Symlink) => 10,
+                _ => continue,
+            };
+            entries.push((self.inode(tree, child), kind, name));
+        }
+        Ok(entries)
+    }
+
+    fn lookup(&mut self, parent: u64, name: &str) -> Result<u64, i32> {
+        match self.node(parent) {
+            Some(Node::Trees) => {
+                let tree = self.trees.iter().position(|t| t.name == name).ok_or(ENOENT)?;
+                let root = self.trees[tree].root.clone();
+                Ok(self.inode(tree, root))
+            }
+            Some(Node::Entry { tree, path }) => {
+                let (tree, path) = (*tree, path.clone());
+                if (path == self.trees[tree].root && manifest::is_meta(name)) || name.contains('/') {
+                    return Err(ENOENT);
+                }
+                Ok(self.inode(tree, format!("{}/{}", path, name)))
+            }
+            None => Err(ENOENT),
+        }
+    }
+
+    fn open(&mut self, inode: u64) -> Result<u64, i32> {
+        let Some(Node::Entry { tree, path }) = self.node(inode) else {
+            return Err(ENOENT);
+        };
+        let target = &self.trees[*tree].target;
+        let (local, fetched) = match target.local_file(path) {
+            Some(local) => (local, None),
+            None => {
+                let local = temporary::local("mount");
+                if target.fetch(path, &local).is_err() {
+                    let _ = fs::remove_file(&local);
+                    return Err(EIO);
+                }
+                (local.clone(), Some(local))
+            }
+        };
+        let file = fs::File::open(&local).map_err(|e| e.raw_os_error().unwrap_or(EIO))?;
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.files.insert(handle, OpenFile { file, fetched });
+        Ok(handle)
+    }
+
+    fn release(&mut self, handle: u64) {
+        if let Some(OpenFile { fetched: Some(local), .. }) = self.files.remove(&handle) {
+            let _ = fs::remove_file(local);
+        }
+    }
+
+    /// Answer a request, returning the reply (None for the requests that
+    /// don't have any) or an errno
+    fn handle(&mut self, opcode: u32, inode: u64, body: &[u8]) -> Option<Result<Vec<u8>, i32>> {
+        let mut reply = Vec::new();
+        let result = match opcode {
+            INIT => {
+                put32(&mut reply, 7);
+                put32(&mut reply, 31);
+                put32(&mut reply, u32_at(body, 8));
+                put32(&mut reply, 0);
+                // max_background and congestion_threshold
+                reply.extend_from_slice(&16u16.to_ne_bytes());
+                reply.extend_from_slice(&12u16.to_ne_bytes());
+                put32(&mut reply, MAX_WRITE);
+                put32(&mut reply, 1);
+                reply.resize(64, 0);
+                Ok(())
+            }
+            FORGET | BATCH_FORGET | INTERRUPT => return None,
+            LOOKUP => {
+                let name = body.split(|b| *b == 0).next().unwrap_or_default();
+                let name = String::from_utf8_lossy(name).to_string();
+                match self.lookup(inode, &name) {
+                    Ok(child) => match self.metadata(child) {
+                        Some(metadata) => {
+                            put64(&mut reply, child);
+                            put64(&mut reply, 0);
+                            put64(&mut reply, VALID);
+                            put64(&mut reply, VALID);
+                            put32(&mut reply, 0);
+                            put32(&mut reply, 0);
+                            self.attr(&mut reply, child, &metadata);
+                            Ok(())
+                        }
+                        None => Err(ENOENT),
+                    },
+                    Err(e) => Err(e),
+                }
+            }
+            GETATTR => match self.metadata(inode) {
+                Some(metadata) => {
+                    put64(&mut reply, VALID);
+                    put32(&mut reply, 0);
+                    put32(&mut reply, 0);
+                    self.attr(&mut reply, inode, &metadata);
+                    Ok(())
+                }
+                None => Err(ENOENT),
+            },
+            READLINK => match self.metadata(inode).and_then(|m| m.target) {
+                Some(link) => {
+                    reply.extend_from_slice(link.as_os_str().as_bytes());
+                    Ok(())
+                }
+                None => Err(ENOENT),
+            },
+            OPEN => match u32_at(body, 0) & 3 {
+                0 => self.open(inode).map(|handle| {
+                    put64(&mut reply, handle);
+                    put64(&mut reply, 0);
+                }),
+                _ => Err(EROFS),
+            },
+            READ => match self.files.get(&u64_at(body, 0)) {
+                Some(open) => {
+                    let (offset, size) = (u64_at(body, 8), u32_at(body, 16).min(MAX_WRITE) as usize);
+                    reply.resize(size, 0);
+                    let mut read = 0;
+                    while read < size {
+                        match open.file.read_at(&mut reply[read..], offset + read as u64) {
+                            Ok(0) => break,
+                            Ok(n) => read += n,
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                            Err(_) => return Some(Err(EIO)),
+                        }
+                    }
+                    reply.truncate(read);
+                    Ok(())
+                }
+                None => Err(EIO),
+            },
+            RELEASE => {
+                self.release(u64_at(body, 0));
+                Ok(())
+            }
+            OPENDIR => self.list(inode).map(|entries| {
+                let handle = self.next_handle;
+                self.next_handle += 1;
+                self.directories.insert(handle, entries);
+                put64(&mut reply, handle);
+                put64(&mut reply, 0);
+            }),
+            READDIR => match self.directories.get(&u64_at(body, 0)) {
+                Some(entries) => {
+                    let (offset, size) = (u64_at(body, 8) as usize, u32_at(body, 16) as usize);
+                    for (i, (inode, kind, name)) in entries.iter().enumerate().skip(offset) {
+                        // `struct fuse_dirent`, padded to 8 bytes
+                        let length = (24 + name.len()).div_ceil(8) * 8;
+                        if reply.len() + length > size {
+                            break;
+                        }
+                        put64(&mut reply, *inode);
+                        put64(&mut reply, i as u64 + 1);
+                        put32(&mut reply, name.len() as u32);
+                        put32(&mut reply, *kind);
+                        reply.extend_from_slice(name.as_bytes());
+                        reply.resize(reply.len().div_ceil(8) * 8, 0);
+                    }
+                    Ok(())
+                }
+                None => Err(EIO),
+            },
+            RELEASEDIR => {
+                self.directories.remove(&u64_at(body, 0));
+                Ok(())
+            }
+            STATFS => {
+                reply.resize(40, 0);
+                for value in [4096, 255, 4096] {
+                    put32(&mut reply, value);
+                }
+                reply.resize(80, 0);
+                Ok(())
+            }
+            // Nothing can be written
+            ACCESS if u32_at(body, 0) & 2 != 0 => Err(EROFS),
+            ACCESS | FLUSH | DESTROY => Ok(()),
+            _ => Err(ENOSYS),
+        };
+        Some(result.map(|_| reply))
+    }
+}
+
+
+/// Mount the FUSE filesystem of a device with mount(2), which needs
+/// privileges
+fn mount_directly(device: &fs::File, mountpoint: &CString) -> io::Result<()> {
+    let data = CString::new(format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        device.as_raw_fd(),
+        unsafe { getuid() },
+        unsafe { getgid() }
+    ))
+    .unwrap();
+    let (source, fstype) = (c"backup-rs", c"fuse.backup-rs");
+    let flags = MS_RDONLY | MS_NOSUID | MS_NODEV;
+    if unsafe { mount(source.as_ptr(), mountpoint.as_ptr(), fstype.as_ptr(), flags, data.as_ptr() as *const c_void) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+/// Mount with the setuid fusermount helper, as unprivileged users do: it
+/// opens the device, mounts it and passes it back over a socket
+fn mount_with_fusermount(mountpoint: &str) -> io::Result<fs::File> {
+    let mut sockets = [0; 2];
+    if unsafe { socketpair(AF_UNIX, SOCK_STREAM, 0, sockets.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (ours, theirs) = unsafe { (fs::File::from_raw_fd(sockets[0]), fs::File::from_raw_fd(sockets[1])) };
+    let options = "ro,nosuid,nodev,default_permissions,fsname=backup-rs,subtype=backup-rs";
+    let mut status = None;
+    for helper in ["fusermount3", "fusermount"] {
+        match Command::new(helper)
+            .args(["-o", options, "--", mountpoint])
+            .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+            .status()
+        {
+            Ok(result) => {
+                status = Some(result);
+                break;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    match status {
+        Some(status) if status.success() => (),
+        Some(status) => return Err(io::Error::other(format!("fusermount failed ({})", status))),
+        None => return Err(io::Error::other("fusermount isn't installed")),
+    }
+    drop(theirs);
+    // One byte of data, and the descriptor in a control message
+    let mut byte = 0u8;
+    let mut iov = IoVec { base: &mut byte as *mut u8 as *mut c_void, len: 1 };
+    let mut control = [0u64; 4];
+    let mut message = MsgHdr {
+        name: std::ptr::null_mut(),
+        name_len: 0,
+        iov: &mut iov,
+        iov_len: 1,
+        control: control.as_mut_ptr() as *mut c_void,
+        control_len: std::mem::size_of_val(&control),
+        flags: 0,
+    };
+    if unsafe { recvmsg(ours.as_raw_fd(), &mut message, 0) } <= 0 {
+        return Err(io::Error::other("fusermount didn't pass the device back"));
+    }
+    // struct cmsghdr: length, level and type, then the descriptor
+    let control: [u8; 32] = unsafe { std::mem::transmute(control) };
+    let (level, kind) = (u32_at(&control, 8) as c_int, u32_at(&control, 12) as c_int);
+    if message.control_len < 20 || level != SOL_SOCKET || kind != SCM_RIGHTS {
+        return Err(io::Error::other("fusermount didn't pass the device back"));
+    }
+    Ok(unsafe { fs::File::from_raw_fd(u32_at(&control, 16) as c_int) })
+}
+
+
+fn unmount(mountpoint: &CString, privileged: bool) {
+    let ok = match privileged {
+        true => (unsafe { umount2(mountpoint.as_ptr(), MNT_DETACH) }) == 0,
+        false => ["fusermount3", "fusermount"].iter().any(|helper| {
+            Command::new(helper)
+                .args(["-u", "-z", "--"])
+                .arg(std::ffi::OsStr::from_bytes(mountpoint.as_bytes()))
+                .status()
+                .is_ok_and(|s| s.success())
+        }),
+    };
+    if !ok {
+        warning!("cannot unmount {}", mountpoint.to_string_lossy());
+    }
+}
+
+
+/// Mount a mirror (or a snapshot of one, as `DESTINATION::SNAPSHOT`), or
+/// with `snapshots` all the snapshots of a destination and its current
+/// state side by side, as a read-only filesystem, serving it until it is
+/// unmounted or interrupted
+pub fn mount_mirror(mirror: &str, mountpoint: &str, snapshots: bool, opts: &Options) -> Result<(), String> {
+    let open = |name: String, location: &str| -> Result<Tree, String> {
+        let (target, root) = backend::open(location, opts)?;
+        Ok(Tree { name, target: Rc::from(target), root })
+    };
+    let mut trees = Vec::new();
+    if snapshots {
+        snapshot::check_local(mirror)?;
+        let kind = snapshot::detect(mirror, opts.snapshot);
+        for snapshot in snapshot::list(kind, mirror)? {
+            trees.push(open(snapshot.name(), &snapshot::directory(kind, mirror, &snapshot))?);
+        }
+        trees.push(open("current".to_string(), mirror)?);
+    } else {
+        trees.push(open(String::new(), &listing::resolve(mirror, opts)?)?);
+    }
+    let mountpoint_c = CString::new(mountpoint).map_err(|e| e.to_string())?;
+    let device = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")
+        .map_err(|e| format!("cannot open /dev/fuse: {}", e))?;
+    let (mut device, privileged) = match mount_directly(&device, &mountpoint_c) {
+        Ok(()) => (device, true),
+        Err(e) if e.raw_os_error() == Some(1) => {
+            let device = mount_with_fusermount(mountpoint).map_err(|e| format!("cannot mount {}: {}", mountpoint, e))?;
+            (device, false)
+        }
+        Err(e) => return Err(format!("cannot mount {}: {}", mountpoint, e)),
+    };
+    unsafe {
+        signal(SIGINT, stop);
+        signal(SIGTERM, stop);
+    }
+    log::separator();
+    info!("Mounted {} on {} (unmount it or interrupt to stop)", mirror, mountpoint);
+    log::separator();
+    let mut filesystem = Filesystem::new(trees);
+    let mut buffer = vec![0u8; MAX_WRITE as usize + 4096];
+    loop {
+        if STOP.swap(false, Ordering::SeqCst) {
+            unmount(&mountpoint_c, privileged);
+        }
+        let mut fds = PollFd { fd: device.as_raw_fd(), events: POLLIN, revents: 0 };
+        if unsafe { poll(&mut fds, 1, 1000) } <= 0 {
+            continue;
+        }
+        let length = match device.read(&mut buffer) {
+            Ok(length) => length,
+            Err(e) if e.raw_os_error() == Some(ENODEV) => break,
+            // Interrupted requests
+            Err(e) if matches!(e.raw_os_error(), Some(ENOENT | EINTR | EAGAIN)) => continue,
+            Err(e) => return Err(format!("cannot read from /dev/fuse: {}", e)),
+        };
+        let request = &buffer[..length];
+        if length < IN_HEADER {
+            continue;
+        }
+        let (opcode, unique, inode) = (u32_at(request, 4), u64_at(request, 8), u64_at(request, 16));
+        let Some(result) = filesystem.handle(opcode, inode, &request[IN_HEADER..]) else {
+            continue;
+        };
+        let (errno, data) = match result {
+            Ok(data) => (0, data),
+            Err(errno) => (-errno, Vec::new()),
+        };
+        let mut reply = Vec::with_capacity(OUT_HEADER + data.len());
+        put32(&mut reply, (OUT_HEADER + data.len()) as u32);
+        reply.extend_from_slice(&errno.to_ne_bytes());
+        put64(&mut reply, unique);
+        reply.extend_from_slice(&data);
+        // The request may have been interrupted in the meantime
+        let _ = device.write(&reply);
+        if opcode == DESTROY {
+            break;
+        }
+    }
+    for handle in filesystem.files.keys().copied().collect::<Vec<_>>() {
+        filesystem.release(handle);
+    }
+    info!("Unmounted {}", mountpoint);
+    Ok(())
+}
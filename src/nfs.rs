@@ -0,0 +1,197 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::backend::{self, Backend, LocalBackend, Metadata};
+
+
+
+/// Size of the reads and writes of copies, several of the usual NFS rsize
+/// and wsize so that the client can keep requests in flight
+const BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Seconds apart the timestamps of the source and of the copies can be (as
+/// the clocks of the server and of this machine may be) and still be taken
+/// as equal
+pub const MODIFY_WINDOW: u64 = 2;
+
+
+fn parent_and_name(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((parent, name)) => (parent, name),
+        None => (".", path),
+    }
+}
+
+
+/// A local destination on an NFS mount. Each directory is listed once, with
+/// the metadata of all its entries (which the client gets in one go with
+/// READDIRPLUS), instead of asking the server about every entry on its
+/// own. Copies use large buffers and leave the permissions alone, as
+/// servers that map users (e.g. with root_squash) reject chmod and chown.
+pub struct NfsBackend {
+    local: LocalBackend,
+    /// Directory -> metadata of its entries, by name
+    directories: RefCell<HashMap<String, HashMap<String, Metadata>>>,
+}
+
+
+impl NfsBackend {
+    pub fn new() -> NfsBackend {
+        NfsBackend { local: LocalBackend, directories: RefCell::new(HashMap::new()) }
+    }
+
+    /// List a directory into the cache, if it isn't already
+    fn load(&self, directory: &str) -> io::Result<()> {
+        if self.directories.borrow().contains_key(directory) {
+            return Ok(());
+        }
+        let mut entries = HashMap::new();
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            // Entries whose name isn't valid UTF-8 are skipped
+            let Some(name) = entry.file_name().to_str().map(|n| n.to_string()) else {
+                continue;
+            };
+            let path = format!("{}/{}", directory, name);
+            if let Some(metadata) = entry.metadata().ok().and_then(|m| backend::local_metadata(&path, &m)) {
+                entries.insert(name, metadata);
+            }
+        }
+        self.directories.borrow_mut().insert(directory.to_string(), entries);
+        Ok(())
+    }
+
+    /// Update the cached metadata of a path after changing it
+    fn refresh(&self, path: &str) {
+        let (parent, name) = parent_and_name(path);
+        let metadata = self.local.metadata(path);
+        if let Some(entries) = self.directories.borrow_mut().get_mut(parent) {
+            match metadata {
+                Some(metadata) => entries.insert(name.to_string(), metadata),
+                None => entries.remove(name),
+            };
+        }
+    }
+
+    /// Forget the listings of a directory and of everything below it
+    fn forget(&self, path: &str) {
+        let prefix = format!("{}/", path);
+        self.directories
+            .borrow_mut()
+            .retain(|p, _| p != path && !p.starts_with(&prefix));
+    }
+}
+
+
+impl Backend for NfsBackend {
+    fn metadata(&self, path: &str) -> Option<Metadata> {
+        let (parent, name) = parent_and_name(path);
+        if self.load(parent).is_err() {
+            return self.local.metadata(path);
+        }
+        self.directories.borrow().get(parent)?.get(name).cloned()
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        self.load(path)?;
+        Ok(self.directories.borrow()[path].keys().cloned().collect())
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        let result = self.local.create_dir(path);
+        self.refresh(path);
+        result
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        let result = self.local.remove_file(path);
+        self.refresh(path);
+        result
+    }
+
+    fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        let result = self.local.remove_dir_all(path);
+        self.forget(path);
+        self.refresh(path);
+        result
+    }
+
+    fn copy_file(&self, source: &str, path: &str) -> io::Result<()> {
+        let result = backend::copy_contents(source, path, BUFFER_SIZE);
+        self.refresh(path);
+        result
+    }
+
+    fn symlink(&self, target: &Path, path: &str) -> io::Result<()> {
+        let result = self.local.symlink(target, path);
+        self.refresh(path);
+        result
+    }
+
+    fn mknod(&self, path: &str, mode: u32, device: u64) -> io::Result<()> {
+        let result = self.local.mknod(path, mode, device);
+        self.refresh(path);
+        result
+    }
+
+    fn set_directory_metadata(&self, path: &str, source: &fs::Metadata) -> io::Result<()> {
+        let result = self.local.set_directory_metadata(path, source);
+        self.refresh(path);
+        result
+    }
+
+    fn update_file(&self, source: &str, path: &str) -> io::Result<Option<u64>> {
+        let result = self.local.update_file(source, path);
+        self.refresh(path);
+        result
+    }
+
+    fn capabilities(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        self.local.capabilities(path)
+    }
+
+    fn set_capabilities(&self, path: &str, value: &[u8]) -> io::Result<()> {
+        self.local.set_capabilities(path, value)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let result = self.local.rename(from, to);
+        self.forget(from);
+        self.refresh(from);
+        self.refresh(to);
+        result
+    }
+
+    fn sync(&self, path: &str) -> io::Result<()> {
+        self.local.sync(path)
+    }
+
+    fn fetch(&self, path: &str, local: &str) -> io::Result<()> {
+        self.local.fetch(path, local)
+    }
+
+    fn local_file(&self, path: &str) -> Option<String> {
+        self.local.local_file(path)
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.local.read(path)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let result = self.local.write(path, data);
+        // The parent directories may have been created too
+        for ancestor in Path::new(path).ancestors().filter_map(|a| a.to_str()) {
+            self.refresh(ancestor);
+        }
+        result
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use crate::hooks::Context;
+use crate::report::json_string;
+use crate::stats::human_bytes;
+use crate::status;
+
+
+
+/// POST a JSON document to a URL, with curl
+fn post(url: &str, body: &str) -> io::Result<()> {
+    let mut child = Command::new("curl")
+        .args(["-fsS", "--max-time", "30", "--retry", "3", "-X", "POST"])
+        .args(["-H", "Content-Type: application/json", "--data-binary", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(body.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("curl failed ({})", status)));
+    }
+    Ok(())
+}
+
+
+/// Run a backup and tell a monitoring endpoint (as a healthchecks.io check
+/// URL) how it went, by POSTing a JSON summary of it (its run ID, result,
+/// exit status, duration, what it changed and the errors and warnings it
+/// printed) to `url`, or to `url/fail` if it didn't succeed. Dry runs
+/// aren't reported. Returns whether the backup succeeded.
+pub fn around(url: Option<&str>, context: &Context, backup: impl FnOnce() -> bool) -> bool {
+    let Some(url) = url else {
+        return backup();
+    };
+    status::around(context, backup, |outcome| {
+        let body = format!(
+            "{{\"run_id\": {}, \"source\": {}, \"destination\": {}, \"job\": {}, \"status\": {}, \"exit_status\": {}, \
+             \"duration\": {:.1}, \"copied\": {}, \"bytes\": {}, \"removed\": {}, \"errors\": {}, \"warnings\": {}}}\n",
+            json_string(&outcome.run),
+            json_string(context.source),
+            json_string(context.destination),
+            context.job.map(json_string).unwrap_or_else(|| "null".to_string()),
+            json_string(outcome.result),
+            outcome.exit_status,
+            outcome.duration.as_secs_f64(),
+            outcome.totals.copied,
+            outcome.totals.bytes,
+            outcome.totals.removed,
+            outcome.errors,
+            outcome.warnings
+        );
+        let endpoint = match outcome.exit_status {
+            0 => url.to_string(),
+            _ => format!("{}/fail", url.trim_end_matches('/')),
+        };
+        if let Err(e) = post(&endpoint, &body) {
+            warning!("cannot notify {}: {}", endpoint, e);
+        }
+    })
+}
+
+
+/// Show a notification on the desktop: with notify-send (freedesktop
+/// notifications) or, on macOS, with osascript
+fn show(title: &str, body: &str, failed: bool) -> io::Result<()> {
+    let status = if cfg!(target_os = "macos") {
+        let quoted = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""));
+        let script = format!("display notification {} with title {}", quoted(body), quoted(title));
+        Command::new("osascript").args(["-e", &script]).status()?
+    } else {
+        let urgency = if failed { "--urgency=critical" } else { "--urgency=normal" };
+        Command::new("notify-send").args(["--app-name=backup-rs", urgency, title, body]).status()?
+    };
+    if !status.success() {
+        return Err(io::Error::other(format!("the notification failed ({})", status)));
+    }
+    Ok(())
+}
+
+
+/// Run a backup and tell how it went in a desktop notification (what it
+/// changed, or how many errors it had), for backups run by hand or by a
+/// user timer. Dry runs aren't notified. Returns whether the backup
+/// succeeded.
+pub fn around_desktop(enabled: bool, context: &Context, backup: impl FnOnce() -> bool) -> bool {
+    if !enabled {
+        return backup();
+    }
+    status::around(context, backup, |outcome| {
+        let what = match context.job {
+            Some(job) => format!("Job '{}'", job),
+            None => format!("{} to {}", context.source, context.destination),
+        };
+        let errors = outcome.errors;
+        let (title, body) = match outcome.result {
+            "ok" => (
+                "Backup finished",
+                format!(
+                    "{}: {} copied ({}), {} removed in {:.1}s",
+                    what,
+                    outcome.totals.copied,
+                    human_bytes(outcome.totals.bytes),
+                    outcome.totals.removed,
+                    outcome.duration.as_secs_f64()
+                ),
+            ),
+            "errors" => ("Backup finished with errors", format!("{}: {} error(s), see its output", what, errors)),
+            _ => ("Backup failed", format!("{}: {} error(s), see its output", what, errors)),
+        };
+        if let Err(e) = show(title, &body, outcome.result != "ok") {
+            warning!("cannot show a desktop notification: {}", e);
+        }
+    })
+}
@@ -0,0 +1,106 @@
+use std::cell::RefCell;
+use std::fs;
+use std::time::SystemTime;
+
+use crate::backend::Backend;
+use crate::Options;
+
+
+
+/// Order the copies of a run are made in (`--order`), rather than the order
+/// the run goes through the source in, so that what matters most is copied
+/// first if the run is cut short
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    /// The smallest files first: the most files copied in the time given
+    SmallFirst,
+    LargeFirst,
+    /// The files modified last first, those most likely missing from the
+    /// backups
+    NewestFirst,
+    /// In the order of their paths
+    Path,
+}
+
+
+impl Order {
+    pub fn parse(text: &str) -> Result<Order, String> {
+        match text {
+            "small-first" => Ok(Order::SmallFirst),
+            "large-first" => Ok(Order::LargeFirst),
+            "newest-first" => Ok(Order::NewestFirst),
+            "path" => Ok(Order::Path),
+            _ => Err(format!("invalid order '{}' (expected small-first, large-first, newest-first or path)", text)),
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Order::SmallFirst => "the smallest first",
+            Order::LargeFirst => "the largest first",
+            Order::NewestFirst => "the newest first",
+            Order::Path => "in the order of their paths",
+        }
+    }
+}
+
+
+/// How a file is brought to its copy: `engine::copy_file()` or
+/// `engine::update_file()`
+pub type Action = fn(&str, &str, &Options, &dyn Backend);
+
+
+/// A copy found out of date by the run, made once it went through the
+/// whole source
+struct Copy {
+    source: String,
+    destination: String,
+    size: u64,
+    modified: SystemTime,
+    action: Action,
+}
+
+
+/// The copies of a run with `--order`, held until it is done going through
+/// the source, then made in that order
+pub struct Queue {
+    order: Order,
+    copies: RefCell<Vec<Copy>>,
+}
+
+
+impl Queue {
+    pub fn new(order: Order) -> Queue {
+        Queue { order, copies: RefCell::default() }
+    }
+
+    pub fn push(&self, source: &str, destination: &str, action: Action) {
+        let metadata = fs::symlink_metadata(source).ok();
+        self.copies.borrow_mut().push(Copy {
+            source: source.to_string(),
+            destination: destination.to_string(),
+            size: metadata.as_ref().map_or(0, |m| m.len()),
+            modified: metadata.and_then(|m| m.modified().ok()).unwrap_or(SystemTime::UNIX_EPOCH),
+            action,
+        });
+    }
+
+    /// Make the copies held, in order, with `copy`
+    pub fn run(&self, mut copy: impl FnMut(&str, &str, Action)) {
+        let mut copies = self.copies.take();
+        if copies.is_empty() {
+            return;
+        }
+        // Those that compare the same stay in the order they were found in
+        match self.order {
+            Order::SmallFirst => copies.sort_by_key(|c| c.size),
+            Order::LargeFirst => copies.sort_by_key(|c| std::cmp::Reverse(c.size)),
+            Order::NewestFirst => copies.sort_by_key(|c| std::cmp::Reverse(c.modified)),
+            Order::Path => copies.sort_by(|a, b| a.source.cmp(&b.source)),
+        }
+        info!("Copying {} file(s), {}", copies.len(), self.order.describe());
+        for c in copies {
+            copy(&c.source, &c.destination, c.action);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::os::raw::c_int;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+use std::time::Duration;
+
+use crate::lock;
+use crate::systemd;
+
+
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    fn kill(pid: c_int, signal: c_int) -> c_int;
+}
+
+
+#[cfg(not(target_os = "macos"))]
+const SIGUSR2: c_int = 12;
+#[cfg(target_os = "macos")]
+const SIGUSR2: c_int = 31;
+#[cfg(not(target_os = "macos"))]
+const SIGCONT: c_int = 18;
+#[cfg(target_os = "macos")]
+const SIGCONT: c_int = 19;
+
+
+/// How often a paused run looks whether it was resumed
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+
+/// Set by SIGUSR2 and cleared by SIGCONT
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+
+/// Whether the pause was told about, so that it is only once
+static ANNOUNCED: AtomicBool = AtomicBool::new(false);
+
+
+extern "C" fn pause(_signal: c_int) {
+    PAUSED.store(true, Ordering::SeqCst);
+}
+
+
+extern "C" fn resume(_signal: c_int) {
+    PAUSED.store(false, Ordering::SeqCst);
+}
+
+
+/// Pause (or resume) the runs of this process
+pub fn set(paused: bool) {
+    PAUSED.store(paused, Ordering::SeqCst);
+}
+
+
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+
+/// Pause the runs of this process on SIGUSR2 and resume them on SIGCONT
+pub fn install() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| unsafe {
+        signal(SIGUSR2, pause);
+        signal(SIGCONT, resume);
+    });
+}
+
+
+/// Wait while the run is paused. Called before each file is copied, so
+/// that the copy in progress is finished first and nothing is left half
+/// done.
+pub fn wait() {
+    if !PAUSED.load(Ordering::SeqCst) {
+        return;
+    }
+    if !ANNOUNCED.swap(true, Ordering::SeqCst) {
+        info!("Paused (resume with backup-rs ctl resume, or kill -CONT {})", std::process::id());
+        systemd::status("Paused");
+    }
+    while PAUSED.load(Ordering::SeqCst) {
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    if ANNOUNCED.swap(false, Ordering::SeqCst) {
+        info!("Resumed");
+        systemd::status("Resumed");
+    }
+}
+
+
+/// Pause (or resume) the run holding the lock of a local DESTINATION, or
+/// of a remote one run from this machine, returning its process ID
+pub fn control(destination: &str, paused: bool) -> Result<u32, String> {
+    let local = Path::new(destination).is_dir().then_some(destination);
+    let Some(pid) = lock::holder(destination, local)? else {
+        return Err(format!("no backup-rs is running on {}", destination));
+    };
+    if unsafe { kill(pid as c_int, if paused { SIGUSR2 } else { SIGCONT }) } != 0 {
+        return Err(format!("cannot signal backup-rs (pid {}): {}", pid, std::io::Error::last_os_error()));
+    }
+    Ok(pid)
+}
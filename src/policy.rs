@@ -0,0 +1,187 @@
+/// Match one path component against a pattern with `*` and `?` wildcards
+fn match_name(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            match_name(&pattern[1..], name) || (!name.is_empty() && match_name(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => match_name(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => match_name(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+
+/// Match path components against pattern components, where `**` matches
+/// any number of components
+fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some(&"**") => (0..=path.len()).any(|i| match_components(&pattern[1..], &path[i..])),
+        Some(p) => {
+            !path.is_empty()
+                && match_name(p.as_bytes(), path[0].as_bytes())
+                && match_components(&pattern[1..], &path[1..])
+        }
+    }
+}
+
+
+/// Whether a path relative to the source root matches a glob pattern. A
+/// pattern without a slash matches the name of a file in any directory.
+pub fn matches(pattern: &str, relative: &str) -> bool {
+    let path: Vec<&str> = relative.split('/').collect();
+    if !pattern.contains('/') {
+        return match_name(pattern.as_bytes(), path.last().unwrap().as_bytes());
+    }
+    let pattern: Vec<&str> = pattern.trim_start_matches('/').split('/').collect();
+    match_components(&pattern, &path)
+}
+
+
+/// How the files a rule applies to are handled
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attributes {
+    /// Read back each copied file and compare it with the source
+    pub verify: bool,
+    /// Store the file compressed, when compression is enabled
+    pub compress: bool,
+}
+
+
+impl Default for Attributes {
+    fn default() -> Attributes {
+        Attributes { verify: false, compress: true }
+    }
+}
+
+
+/// A `PATTERN attribute:value,...` rule
+#[derive(Clone, Debug)]
+struct Rule {
+    pattern: String,
+    verify: Option<bool>,
+    compress: Option<bool>,
+}
+
+
+/// Attributes applied to subsets of the source, by rules in the order they
+/// were given (later rules override earlier ones)
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+    /// Patterns of the paths left out of the backup
+    excludes: Vec<String>,
+    /// Patterns of the files transformed before they are stored, with the
+    /// command that does it
+    transforms: Vec<(String, String)>,
+    /// Root of the source the patterns are relative to
+    pub root: String,
+}
+
+
+impl Policy {
+    /// Add a rule such as `Documents/** verify:true`
+    pub fn add(&mut self, text: &str) -> Result<(), String> {
+        let mut words = text.split_whitespace();
+        let pattern = match words.next() {
+            Some(pattern) => pattern.to_string(),
+            None => return Err("empty policy rule".to_string()),
+        };
+        let mut rule = Rule { pattern, verify: None, compress: None };
+        let attributes: Vec<&str> = words.flat_map(|w| w.split(',')).filter(|a| !a.is_empty()).collect();
+        if attributes.is_empty() {
+            return Err(format!("policy rule '{}' has no attributes", text));
+        }
+        for attribute in attributes {
+            let (name, value) = attribute.split_once(':').unwrap_or((attribute, "true"));
+            let value = match value {
+                "true" => true,
+                "false" => false,
+                _ => return Err(format!("invalid value '{}' in policy rule '{}'", value, text)),
+            };
+            match name {
+                "verify" => rule.verify = Some(value),
+                "compress" => rule.compress = Some(value),
+                _ => return Err(format!("unknown attribute '{}' in policy rule '{}'", name, text)),
+            }
+        }
+        self.rules.push(rule);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Leave the paths matching a pattern (and everything below them) out
+    /// of the backup
+    pub fn exclude(&mut self, pattern: &str) {
+        self.excludes.push(pattern.trim_end_matches('/').to_string());
+    }
+
+    /// Add a `PATTERN COMMAND` rule, transforming the files matching
+    /// PATTERN with COMMAND before they are stored
+    pub fn add_transform(&mut self, text: &str) -> Result<(), String> {
+        match text.trim().split_once(char::is_whitespace) {
+            Some((pattern, command)) if !command.trim().is_empty() => {
+                self.transforms.push((pattern.to_string(), command.trim().to_string()));
+                Ok(())
+            }
+            _ => Err(format!("transform rule '{}' has no command", text)),
+        }
+    }
+
+    /// Command transforming a file of the source before it is stored, if
+    /// any (the last matching rule wins)
+    pub fn transform(&self, source_path: &str) -> Option<&str> {
+        let relative = source_path.strip_prefix(&self.root)?.trim_start_matches('/');
+        self.transforms
+            .iter()
+            .rev()
+            .find(|(pattern, _)| matches(pattern, relative))
+            .map(|(_, command)| command.as_str())
+    }
+
+    /// Whether a path of the source, or one of the directories it is in,
+    /// is excluded
+    pub fn is_excluded(&self, source_path: &str) -> bool {
+        self.exclusion(source_path).is_some()
+    }
+
+    /// The pattern excluding a path of the source, or one of the
+    /// directories it is in, if any
+    pub fn exclusion(&self, source_path: &str) -> Option<&str> {
+        if self.excludes.is_empty() {
+            return None;
+        }
+        let relative = source_path.strip_prefix(&self.root)?.trim_start_matches('/');
+        let mut end = 0;
+        while end < relative.len() {
+            end = relative[end + 1..].find('/').map(|i| end + 1 + i).unwrap_or(relative.len());
+            let path = &relative[..end];
+            if let Some(pattern) = self.excludes.iter().find(|pattern| matches(pattern, path)) {
+                return Some(pattern);
+            }
+        }
+        None
+    }
+
+    /// Attributes of a path of the source
+    pub fn attributes(&self, source_path: &str) -> Attributes {
+        let mut attributes = Attributes::default();
+        let relative = match source_path.strip_prefix(&self.root) {
+            Some(relative) => relative.trim_start_matches('/'),
+            None => return attributes,
+        };
+        for rule in self.rules.iter().filter(|r| matches(&r.pattern, relative)) {
+            if let Some(verify) = rule.verify {
+                attributes.verify = verify;
+            }
+            if let Some(compress) = rule.compress {
+                attributes.compress = compress;
+            }
+        }
+        attributes
+    }
+}
@@ -0,0 +1,144 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::backend::Copier;
+use crate::hash::{self, Digest};
+use crate::pause;
+use crate::progress;
+use crate::stream;
+
+
+
+/// Copies queued per worker before `submit` waits for one to be done, so
+/// that the traversal doesn't get far ahead of the copies
+const QUEUED_PER_WORKER: usize = 4;
+
+
+/// A copy handed to the workers: `source` (or the output of its transform,
+/// `transformed`) to `destination`
+pub struct Job {
+    pub source: String,
+    pub destination: String,
+    pub transformed: Option<String>,
+}
+
+
+/// A copy made by a worker, with the hash of what was copied if it worked
+pub struct Done {
+    pub job: Job,
+    pub result: io::Result<()>,
+    pub digest: Option<io::Result<Digest>>,
+}
+
+
+/// Worker threads copying files to a local destination while the run goes
+/// on through the source (`--copy-jobs`). The workers only copy and hash;
+/// everything else about each copy (recording it, checking it, reporting
+/// it as failed) is left to the thread of the run, as `submit` and `wait`
+/// hand back the copies that are done.
+pub struct CopyPool {
+    jobs: Sender<Job>,
+    done: Receiver<Done>,
+    workers: Vec<JoinHandle<()>>,
+    /// Destinations of the copies handed to the workers and not handed
+    /// back yet
+    pending: RefCell<HashSet<String>>,
+    limit: usize,
+}
+
+
+impl CopyPool {
+    /// Start `count` workers, or as many as the system lets start (an
+    /// error if not even one)
+    pub fn new(count: usize, copier: Copier) -> io::Result<CopyPool> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        let (finished, done) = mpsc::channel();
+        let mut workers = Vec::with_capacity(count);
+        for index in 0..count {
+            let queue = queue.clone();
+            let finished = finished.clone();
+            let spawned = thread::Builder::new().spawn(move || loop {
+                let Ok(job) = queue.lock().unwrap_or_else(|e| e.into_inner()).recv() else {
+                    return;
+                };
+                pause::wait();
+                let copied = job.transformed.as_deref().unwrap_or(&job.source);
+                progress::worker(index, Some(&job.source));
+                let (result, streamed) = stream::hashing(copied, || copier(copied, &job.destination));
+                let digest = result.is_ok().then(|| streamed.map(Ok).unwrap_or_else(|| hash::hash_file(copied)));
+                progress::worker(index, None);
+                if finished.send(Done { job, result, digest }).is_err() {
+                    return;
+                }
+            });
+            match spawned {
+                Ok(worker) => workers.push(worker),
+                Err(e) if workers.is_empty() => return Err(e),
+                Err(e) => {
+                    warning!("cannot start more than {} of the {} copy workers: {}", workers.len(), count, e);
+                    break;
+                }
+            }
+        }
+        let limit = workers.len() * QUEUED_PER_WORKER;
+        Ok(CopyPool { jobs, done, workers, pending: RefCell::default(), limit })
+    }
+
+    /// Hand a copy to the workers, returning the copies done meanwhile
+    /// (waiting for one first if too many are queued, and for the one
+    /// to the same destination, if any, so that two don't write it at once)
+    pub fn submit(&self, job: Job) -> Vec<Done> {
+        let mut done = Vec::new();
+        loop {
+            let pending = self.pending.borrow();
+            let wait = pending.len() >= self.limit || pending.contains(&job.destination);
+            if pending.is_empty() {
+                break;
+            }
+            drop(pending);
+            let next = match wait {
+                true => self.done.recv().ok(),
+                false => self.done.try_recv().ok(),
+            };
+            let Some(next) = next else {
+                break;
+            };
+            self.pending.borrow_mut().remove(&next.job.destination);
+            done.push(next);
+        }
+        let destination = job.destination.clone();
+        match self.jobs.send(job) {
+            Ok(()) => {
+                self.pending.borrow_mut().insert(destination);
+            }
+            Err(mpsc::SendError(job)) => done.push(Done {
+                job,
+                result: Err(io::Error::other("the copy workers stopped")),
+                digest: None,
+            }),
+        }
+        done
+    }
+
+    /// Wait for all the copies handed to the workers, returning them
+    pub fn wait(&self) -> Vec<Done> {
+        let count = self.pending.take().len();
+        (0..count).filter_map(|_| self.done.recv().ok()).collect()
+    }
+}
+
+
+impl Drop for CopyPool {
+    fn drop(&mut self) {
+        // Closing the queue stops the workers once they are done
+        self.jobs = mpsc::channel().0;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
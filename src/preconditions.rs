@@ -0,0 +1,197 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+
+
+/// Something that must hold for the source to be considered available,
+/// checked before anything is removed from the destination
+#[derive(Clone, Debug, PartialEq)]
+pub enum Precondition {
+    /// A path exists
+    Exists(PathBuf),
+    /// A path is a mountpoint, i.e. something is mounted on it
+    Mounted(PathBuf),
+    /// A file is present at the root of the source
+    Marker(String),
+    /// The source holds at least this many entries
+    MinFiles(u64),
+    /// The source is a directory holding something, which is required
+    /// unless --allow-empty-source is given: an empty source is most
+    /// likely a disk that isn't mounted, and mirroring it would remove
+    /// everything from the destination
+    NotEmpty,
+}
+
+
+impl Precondition {
+    /// Parse `exists:PATH`, `mounted:PATH`, `marker:NAME` or `min-files:N`.
+    /// Relative paths are relative to the source.
+    pub fn parse(text: &str) -> Result<Precondition, String> {
+        let (kind, value) = text
+            .split_once(':')
+            .ok_or_else(|| format!("invalid precondition '{}' (expected KIND:VALUE)", text))?;
+        if value.is_empty() {
+            return Err(format!("invalid precondition '{}'", text));
+        }
+        match kind {
+            "exists" => Ok(Precondition::Exists(PathBuf::from(value))),
+            "mounted" => Ok(Precondition::Mounted(PathBuf::from(value))),
+            "marker" => Ok(Precondition::Marker(value.to_string())),
+            "min-files" => match value.parse() {
+                Ok(n) => Ok(Precondition::MinFiles(n)),
+                Err(_) => Err(format!("invalid number of files in precondition '{}'", text)),
+            },
+            _ => Err(format!(
+                "unknown precondition '{}' (expected exists, mounted, marker or min-files)",
+                kind
+            )),
+        }
+    }
+
+    /// Check the precondition for a source, explaining why it doesn't hold
+    pub fn check(&self, source: &str) -> Result<(), String> {
+        match self {
+            Precondition::Exists(path) => {
+                let path = Path::new(source).join(path);
+                if fs::symlink_metadata(&path).is_err() {
+                    return Err(format!("{} doesn't exist", path.display()));
+                }
+            }
+            Precondition::Mounted(path) => {
+                let path = Path::new(source).join(path);
+                if !is_mountpoint(&path) {
+                    return Err(format!("nothing is mounted on {}", path.display()));
+                }
+            }
+            Precondition::Marker(name) => {
+                let path = Path::new(source).join(name);
+                if !path.is_file() {
+                    return Err(format!("the marker file {} is missing", path.display()));
+                }
+            }
+            Precondition::MinFiles(min) => {
+                let found = count(Path::new(source), *min);
+                if found < *min {
+                    return Err(format!("{} has {} entries, fewer than the {} required", source, found, min));
+                }
+            }
+            Precondition::NotEmpty => {
+                let mut entries = fs::read_dir(source).map_err(|e| format!("cannot read {}: {}", source, e))?;
+                if entries.next().is_none() {
+                    return Err(format!(
+                        "{} is empty (is it mounted?); use --allow-empty-source if it should be",
+                        source
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Whether something is mounted on a directory: it is on another device
+/// than its parent, or it is its own parent (the root)
+fn is_mountpoint(path: &Path) -> bool {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_dir() => metadata,
+        _ => return false,
+    };
+    match fs::metadata(path.join("..")) {
+        Ok(parent) => parent.dev() != metadata.dev() || parent.ino() == metadata.ino(),
+        Err(_) => false,
+    }
+}
+
+
+/// Whether a path below a directory (given relative to it) is on another
+/// file system, or below a directory another file system is mounted on
+pub fn leaves_file_system(root: &str, relative: &str) -> bool {
+    let Ok(device) = fs::metadata(root).map(|m| m.dev()) else {
+        return false;
+    };
+    let mut path = Path::new(root).to_path_buf();
+    Path::new(relative).components().any(|component| {
+        path.push(component);
+        fs::metadata(&path).is_ok_and(|m| m.dev() != device)
+    })
+}
+
+
+/// Count the entries below a directory, stopping once `limit` is reached
+fn count(directory: &Path, limit: u64) -> u64 {
+    let mut found = 0;
+    let mut pending = vec![directory.to_path_buf()];
+    while let Some(directory) = pending.pop() {
+        let entries = match fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            found += 1;
+            if found >= limit {
+                return found;
+            }
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                pending.push(entry.path());
+            }
+        }
+    }
+    found
+}
+
+
+/// Check all the preconditions of a source, returning the first that fails
+pub fn check_all(preconditions: &[Precondition], source: &str) -> Result<(), String> {
+    preconditions.iter().try_for_each(|p| p.check(source))
+}
+
+
+/// Resolve a path that may not exist yet: its closest existing ancestor,
+/// canonicalized, followed by the rest of it
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let path = std::path::absolute(path).ok()?;
+    let mut rest = Vec::new();
+    for ancestor in path.ancestors() {
+        if let Ok(mut resolved) = ancestor.canonicalize() {
+            resolved.extend(rest.iter().rev());
+            return Some(resolved);
+        }
+        rest.push(ancestor.file_name()?);
+    }
+    None
+}
+
+
+/// Check that a local destination and the source don't overlap: backing up
+/// into the source would copy the backup into itself, and the deletion pass
+/// over a destination holding the source would remove it. A destination
+/// inside the source is fine if `excluded` (given its path relative to the
+/// source) says it is excluded from the backup.
+pub fn check_nesting<F: Fn(&str) -> bool>(source: &str, destination: &str, excluded: F) -> Result<(), String> {
+    let (Some(source_path), Some(destination_path)) = (resolve(Path::new(source)), resolve(Path::new(destination)))
+    else {
+        return Ok(());
+    };
+    if source_path == destination_path {
+        return Err(format!("the source and the destination are the same directory ({})", source_path.display()));
+    }
+    if let Ok(relative) = destination_path.strip_prefix(&source_path) {
+        if !excluded(relative.to_str().unwrap_or_default()) {
+            return Err(format!(
+                "the destination {} is inside the source {}; exclude it or choose another destination",
+                destination_path.display(),
+                source_path.display()
+            ));
+        }
+    }
+    if source_path.starts_with(&destination_path) {
+        return Err(format!(
+            "the source {} is inside the destination {}, whose deletion pass would remove it",
+            source_path.display(),
+            destination_path.display()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::io;
+use std::os::raw::c_int;
+
+
+
+extern "C" {
+    fn setpriority(which: c_int, who: c_int, prio: c_int) -> c_int;
+}
+
+
+const PRIO_PROCESS: c_int = 0;
+/// The lowest CPU priority
+const NICENESS: c_int = 19;
+
+
+/// Put this thread in the idle I/O scheduling class, in which the disk is
+/// only used when nothing else needs it
+#[cfg(target_os = "linux")]
+fn idle_io() -> io::Result<()> {
+    use std::os::raw::c_long;
+
+    extern "C" {
+        fn syscall(number: c_long, ...) -> c_long;
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const SYS_IOPRIO_SET: c_long = 251;
+    #[cfg(target_arch = "x86")]
+    const SYS_IOPRIO_SET: c_long = 289;
+    #[cfg(target_arch = "arm")]
+    const SYS_IOPRIO_SET: c_long = 314;
+    // aarch64, riscv64 and the other architectures of the generic table
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm")))]
+    const SYS_IOPRIO_SET: c_long = 30;
+    const IOPRIO_WHO_PROCESS: c_int = 1;
+    const IOPRIO_CLASS_IDLE: c_int = 3;
+    const IOPRIO_CLASS_SHIFT: c_int = 13;
+
+    let ioprio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    match unsafe { syscall(SYS_IOPRIO_SET, IOPRIO_WHO_PROCESS, 0 as c_int, ioprio) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+
+#[cfg(not(target_os = "linux"))]
+fn idle_io() -> io::Result<()> {
+    Ok(())
+}
+
+
+/// Give this process the lowest CPU priority and (on Linux) the idle I/O
+/// scheduling class, so that a backup running in the background doesn't
+/// slow down anything else. Both are per thread on Linux, the threads and
+/// the commands started afterwards inheriting them, so this is done before
+/// starting any.
+pub fn lower() -> io::Result<()> {
+    if unsafe { setpriority(PRIO_PROCESS, 0, NICENESS) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    idle_io()
+}
@@ -0,0 +1,269 @@
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+
+use crate::control;
+use crate::pause;
+use crate::report::json_string;
+use crate::stats::human_bytes;
+
+
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+}
+
+
+#[cfg(not(target_os = "macos"))]
+const SIGUSR1: c_int = 10;
+#[cfg(target_os = "macos")]
+const SIGUSR1: c_int = 30;
+
+
+/// How often the thread printing the progress looks for a request
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+
+/// Set by SIGUSR1 to print the progress
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+
+/// The run in progress: source, destination, when it started and the
+/// entry it is at
+struct Current {
+    source: String,
+    destination: String,
+    start: Instant,
+    path: Option<String>,
+}
+
+
+static CURRENT: Mutex<Option<Current>> = Mutex::new(None);
+
+
+/// Files looked at, copied (and bytes written for them) and entries
+/// removed by the run in progress
+static EXAMINED: AtomicU64 = AtomicU64::new(0);
+static COPIED: AtomicU64 = AtomicU64::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+static REMOVED: AtomicU64 = AtomicU64::new(0);
+
+
+/// Bytes written so far by the copies streamed through `stream::copy`, in
+/// progress or done, so that the copy of a large file is seen going on
+static STREAMED: AtomicU64 = AtomicU64::new(0);
+
+
+/// The file each worker copying files (`--copy-jobs`) is copying, if any
+static WORKERS: Mutex<Vec<Option<String>>> = Mutex::new(Vec::new());
+
+
+/// Set to stop the run in progress
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+
+extern "C" fn request(_signal: c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+
+/// Print how far the run in progress got when the process gets SIGUSR1
+/// (`kill -USR1 PID`), as dd does, without interrupting it
+pub fn install() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        unsafe {
+            signal(SIGUSR1, request);
+        }
+        std::thread::spawn(|| loop {
+            std::thread::sleep(POLL_INTERVAL);
+            if REQUESTED.swap(false, Ordering::SeqCst) {
+                report();
+            }
+        });
+    });
+}
+
+
+/// Start counting the progress of a run
+pub fn start(source: &str, destination: &str) {
+    for counter in [&EXAMINED, &COPIED, &BYTES, &REMOVED, &STREAMED] {
+        counter.store(0, Ordering::SeqCst);
+    }
+    CANCELLED.store(false, Ordering::SeqCst);
+    *CURRENT.lock().unwrap() = Some(Current {
+        source: source.to_string(),
+        destination: destination.to_string(),
+        start: Instant::now(),
+        path: None,
+    });
+    control::event(&format!(
+        "{{\"event\": \"started\", \"source\": {}, \"destination\": {}}}",
+        json_string(source),
+        json_string(destination)
+    ));
+}
+
+
+/// The run in progress is over, with this result (ok, errors or failed)
+pub fn finish(result: &str) {
+    if let Some(snapshot) = snapshot() {
+        control::event(&format!(
+            "{{\"event\": \"finished\", {}, \"result\": {}}}",
+            snapshot.json_fields(),
+            json_string(result)
+        ));
+    }
+    *CURRENT.lock().unwrap() = None;
+}
+
+
+/// Record that the run is at a file of the source
+pub fn at(path: &str) {
+    EXAMINED.fetch_add(1, Ordering::SeqCst);
+    if let Some(current) = CURRENT.lock().unwrap().as_mut() {
+        current.path = Some(path.to_string());
+    }
+}
+
+
+/// Record a file copied (or updated) into the destination
+pub fn copied(bytes: u64) {
+    COPIED.fetch_add(1, Ordering::SeqCst);
+    BYTES.fetch_add(bytes, Ordering::SeqCst);
+}
+
+
+/// Record a chunk of a copy written
+pub fn streamed(bytes: u64) {
+    STREAMED.fetch_add(bytes, Ordering::SeqCst);
+}
+
+
+/// Record the file a worker copying files started (or finished) copying
+pub fn worker(index: usize, path: Option<&str>) {
+    let mut workers = WORKERS.lock().unwrap();
+    if workers.len() <= index {
+        workers.resize(index + 1, None);
+    }
+    workers[index] = path.map(str::to_string);
+}
+
+
+/// The file each worker copying files is copying, if any
+pub fn workers() -> Vec<Option<String>> {
+    WORKERS.lock().unwrap().clone()
+}
+
+
+/// Record an entry removed from the destination
+pub fn removed() {
+    REMOVED.fetch_add(1, Ordering::SeqCst);
+}
+
+
+/// Stop the run in progress: it copies nothing more, and fails once it
+/// is done with the copies under way
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+    pause::set(false);
+}
+
+
+pub fn cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+
+/// How far the run in progress got
+pub struct Snapshot {
+    pub source: String,
+    pub destination: String,
+    pub elapsed: Duration,
+    /// The file it is at
+    pub path: Option<String>,
+    pub examined: u64,
+    pub copied: u64,
+    pub bytes: u64,
+    /// Bytes written so far, including those of the copies in progress
+    /// (only known for the copies to local destinations)
+    pub streamed: u64,
+    pub removed: u64,
+}
+
+
+impl Snapshot {
+    /// Bytes copied per second
+    pub fn rate(&self) -> u64 {
+        (self.written() as f64 / self.elapsed.as_secs_f64().max(0.001)) as u64
+    }
+
+    /// Bytes written so far, as far as known
+    pub fn written(&self) -> u64 {
+        self.bytes.max(self.streamed)
+    }
+
+    /// The fields of the snapshot as members of a JSON object (without
+    /// its braces), for the control socket
+    pub fn json_fields(&self) -> String {
+        format!(
+            "\"source\": {}, \"destination\": {}, \"elapsed\": {:.1}, \"path\": {}, \"examined\": {}, \
+             \"copied\": {}, \"bytes\": {}, \"written\": {}, \"rate\": {}, \"removed\": {}, \"paused\": {}",
+            json_string(&self.source),
+            json_string(&self.destination),
+            self.elapsed.as_secs_f64(),
+            self.path.as_deref().map(json_string).unwrap_or_else(|| "null".to_string()),
+            self.examined,
+            self.copied,
+            self.bytes,
+            self.written(),
+            self.rate(),
+            self.removed,
+            pause::paused()
+        )
+    }
+}
+
+
+/// How far the run in progress got, if one is
+pub fn snapshot() -> Option<Snapshot> {
+    let current = CURRENT.lock().unwrap();
+    let current = current.as_ref()?;
+    Some(Snapshot {
+        source: current.source.clone(),
+        destination: current.destination.clone(),
+        elapsed: current.start.elapsed(),
+        path: current.path.clone(),
+        examined: EXAMINED.load(Ordering::SeqCst),
+        copied: COPIED.load(Ordering::SeqCst),
+        bytes: BYTES.load(Ordering::SeqCst),
+        streamed: STREAMED.load(Ordering::SeqCst),
+        removed: REMOVED.load(Ordering::SeqCst),
+    })
+}
+
+
+/// Print the entry the run in progress is at, what it did so far and how
+/// fast it copies
+fn report() {
+    let Some(snapshot) = snapshot() else {
+        info!("Progress: no run in progress");
+        return;
+    };
+    info!(
+        "Progress: backing up {} to {} for {}s, at {}",
+        snapshot.source,
+        snapshot.destination,
+        snapshot.elapsed.as_secs(),
+        snapshot.path.as_deref().unwrap_or("the start")
+    );
+    info!(
+        "Progress: {} files looked at, {} copied ({}, {}/s), {} removed",
+        snapshot.examined,
+        snapshot.copied,
+        human_bytes(snapshot.bytes),
+        human_bytes(snapshot.rate()),
+        snapshot.removed
+    );
+}
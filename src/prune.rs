@@ -0,0 +1,113 @@
+use crate::log;
+use crate::schedule::LocalTime;
+use crate::snapshot::{self, Snapshot};
+use crate::stats::human_bytes;
+use crate::Options;
+
+
+
+/// Which snapshots to keep: the last one of each of this many days, weeks
+/// (starting on Mondays) and months with snapshots, counting back from the
+/// last one
+#[derive(Clone, Copy, Default)]
+pub struct Retention {
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+
+/// Days from 1970-01-01 to a date
+fn days(time: &LocalTime) -> i64 {
+    let year = time.year as i64 - (time.month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = (time.month as i64 + 9) % 12;
+    let day_of_year = (153 * month + 2) / 5 + time.day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+
+/// Number of the period (day, week or month) a time is in
+type Period = fn(&LocalTime) -> i64;
+
+
+/// Why each snapshot (oldest first) is kept, or None if it isn't
+fn select(snapshots: &[Snapshot], retention: Retention) -> Vec<Option<&'static str>> {
+    let mut reasons = vec![None; snapshots.len()];
+    // 1970-01-01 was a Thursday
+    let periods: [(&str, usize, Period); 3] = [
+        ("daily", retention.daily, days),
+        ("weekly", retention.weekly, |t| (days(t) + 3).div_euclid(7)),
+        ("monthly", retention.monthly, |t| t.year as i64 * 12 + t.month as i64),
+    ];
+    for (reason, count, period) in periods {
+        let mut last = None;
+        let mut kept = 0;
+        for (i, snapshot) in snapshots.iter().enumerate().rev() {
+            if kept == count {
+                break;
+            }
+            let current = period(&snapshot.time);
+            if last != Some(current) {
+                last = Some(current);
+                kept += 1;
+                reasons[i].get_or_insert(reason);
+            }
+        }
+    }
+    // The last snapshot is always kept
+    if let Some(reason) = reasons.last_mut() {
+        reason.get_or_insert("last");
+    }
+    reasons
+}
+
+
+/// Remove the snapshots of a local destination that a grandfather-father-son
+/// retention doesn't keep, reporting the space it frees
+pub fn prune(destination: &str, retention: Retention, opts: &Options) -> Result<(), String> {
+    snapshot::check_local(destination)?;
+    if retention.daily + retention.weekly + retention.monthly == 0 {
+        return Err("nothing to keep; use --keep-daily, --keep-weekly or --keep-monthly".to_string());
+    }
+    let kind = snapshot::detect(destination, opts.snapshot);
+    let snapshots = snapshot::list(kind, destination)?;
+    if snapshots.is_empty() {
+        info!("{} has no snapshots", destination);
+        return Ok(());
+    }
+    log::separator();
+    info!("Pruning the snapshots of {}", destination);
+    log::separator();
+    let (mut removed, mut reclaimed, mut failed) = (0, 0, 0);
+    for (snapshot, reason) in snapshots.iter().zip(select(&snapshots, retention)) {
+        if let Some(reason) = reason {
+            action!("Keeping {} ({})", snapshot.id, reason);
+            continue;
+        }
+        action!("Removing {}", snapshot.id);
+        let size = snapshot::exclusive_size(kind, snapshot).unwrap_or(0);
+        if !opts.dry_run {
+            if let Err(e) = snapshot::remove(kind, snapshot) {
+                error!("cannot remove {}: {}", snapshot.id, e);
+                failed += 1;
+                continue;
+            }
+        }
+        removed += 1;
+        reclaimed += size;
+    }
+    log::separator();
+    info!(
+        "Removed {} of {} snapshots, reclaiming {}",
+        removed,
+        snapshots.len(),
+        human_bytes(reclaimed)
+    );
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{} snapshots couldn't be removed", failed)),
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::policy::Policy;
+use crate::Options;
+
+
+
+/// Entries of a directory, with their metadata (following symlinks)
+pub type Listing = Vec<(fs::DirEntry, io::Result<fs::Metadata>)>;
+
+
+/// Entries listed ahead of the run at most, so that the scanners don't
+/// fill the memory with a tree far larger than what the run copies
+const HELD: usize = 100_000;
+
+
+/// Where a directory of the source is at
+enum State {
+    /// Waiting for a scanner
+    Queued,
+    Scanning,
+    Listed(io::Result<Listing>),
+}
+
+
+#[derive(Default)]
+struct Directories {
+    states: BTreeMap<String, State>,
+    /// The directories to list next, in the order the run goes through
+    /// them (depth first)
+    queue: VecDeque<String>,
+    /// Entries listed and not taken yet
+    held: usize,
+    stopped: bool,
+}
+
+
+/// What the scanners need to know to leave alone the directories the run
+/// doesn't go into
+struct Skipped {
+    policy: Policy,
+    unchanged: HashSet<PathBuf>,
+    device: Option<u64>,
+}
+
+
+struct Shared {
+    directories: Mutex<Directories>,
+    changed: Condvar,
+    skipped: Skipped,
+}
+
+
+/// Threads listing the directories of the source, and looking up their
+/// entries, ahead of the run going through them (`--scan-jobs`): a run
+/// through a deep tree on a network file system otherwise waits for each
+/// directory in turn, and the copies behind it too. The run takes each
+/// listing as it gets to its directory, and reads those no scanner got to
+/// itself.
+pub struct ReadAhead {
+    shared: Arc<Shared>,
+}
+
+
+impl ReadAhead {
+    /// Start the scanners on the source, unless a single thread scans it
+    pub fn start(source: &str, opts: &Options) -> Option<ReadAhead> {
+        if opts.scan_jobs < 2 {
+            return None;
+        }
+        let skipped = Skipped {
+            policy: opts.policy.clone(),
+            unchanged: opts.unchanged.clone(),
+            device: fs::metadata(source).ok().filter(|_| opts.one_file_system).map(|m| m.dev()),
+        };
+        let mut directories = Directories::default();
+        directories.states.insert(source.to_string(), State::Queued);
+        directories.queue.push_back(source.to_string());
+        let shared = Arc::new(Shared { directories: Mutex::new(directories), changed: Condvar::new(), skipped });
+        for started in 0..opts.scan_jobs {
+            let scanner = shared.clone();
+            // Scanners are left behind rather than waited for, as one may
+            // be stuck on a mount that stopped answering
+            if let Err(e) = thread::Builder::new().spawn(move || scan(&scanner)) {
+                if started == 0 {
+                    warning!("cannot start the scanners: {}; the run reads the source itself", e);
+                    return None;
+                }
+                warning!("cannot start more than {} of the {} scanners: {}", started, opts.scan_jobs, e);
+                break;
+            }
+        }
+        Some(ReadAhead { shared })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Directories> {
+        self.shared.directories.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The listing of a directory the run gets to, waiting (up to `limit`)
+    /// for the scanner listing it. None if no scanner got to it, for the
+    /// run to read it.
+    pub fn take(&self, path: &str, limit: Option<Duration>) -> Option<io::Result<Listing>> {
+        let mut directories = self.lock();
+        let scanning = |d: &mut Directories| matches!(d.states.get(path), Some(State::Scanning));
+        directories = match limit {
+            Some(limit) => {
+                let (directories, waited) =
+                    self.shared.changed.wait_timeout_while(directories, limit, scanning).unwrap_or_else(|e| e.into_inner());
+                if waited.timed_out() {
+                    let message = format!("no answer after {}s", limit.as_secs_f64());
+                    return Some(Err(io::Error::new(io::ErrorKind::TimedOut, message)));
+                }
+                directories
+            }
+            None => self.shared.changed.wait_while(directories, scanning).unwrap_or_else(|e| e.into_inner()),
+        };
+        match directories.states.remove(path)? {
+            State::Listed(listing) => {
+                directories.held -= listing.as_ref().map_or(0, |l| l.len());
+                self.shared.changed.notify_all();
+                Some(listing)
+            }
+            _ => None,
+        }
+    }
+
+    /// Queue the subdirectories of a directory the run read itself, for the
+    /// scanners to list ahead of it
+    pub fn found<'a>(&self, entries: impl Iterator<Item = &'a fs::DirEntry>) {
+        let subdirectories = subdirectories(entries, &self.shared.skipped);
+        queue(&mut self.lock(), subdirectories);
+        self.shared.changed.notify_all();
+    }
+
+    /// Forget what was listed below a directory the run is done with (the
+    /// subdirectories it skipped)
+    pub fn done(&self, path: &str) {
+        let mut directories = self.lock();
+        let below = format!("{}/", path);
+        // '0' comes right after '/'
+        let end = format!("{}0", path);
+        let forgotten: Vec<String> = directories.states.range(below..end).map(|(path, _)| path.clone()).collect();
+        for path in forgotten {
+            if let Some(State::Listed(Ok(listing))) = directories.states.remove(&path) {
+                directories.held -= listing.len();
+            }
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        self.lock().stopped = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+
+/// The subdirectories among the entries of a directory that the run goes
+/// into, in order
+fn subdirectories<'a>(entries: impl Iterator<Item = &'a fs::DirEntry>, skipped: &Skipped) -> Vec<String> {
+    entries
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|entry| entry.path())
+        .filter(|path| !skipped.unchanged.contains(path))
+        .filter_map(|path| path.to_str().map(str::to_string))
+        .filter(|path| !skipped.policy.is_excluded(path))
+        .filter(|path| {
+            let device = || fs::symlink_metadata(path).map(|m| m.dev()).ok();
+            skipped.device.is_none() || device() == skipped.device
+        })
+        .collect()
+}
+
+
+/// Queue directories to be listed before those queued already, as the run
+/// goes into them first
+fn queue(directories: &mut Directories, paths: Vec<String>) {
+    for path in paths.into_iter().rev() {
+        directories.states.insert(path.clone(), State::Queued);
+        directories.queue.push_front(path);
+    }
+}
+
+
+/// List the directories of the queue, until the run is over
+fn scan(shared: &Shared) {
+    loop {
+        let mut directories = shared
+            .changed
+            .wait_while(shared.directories.lock().unwrap_or_else(|e| e.into_inner()), |d| {
+                !d.stopped && (d.queue.is_empty() || d.held >= HELD)
+            })
+            .unwrap_or_else(|e| e.into_inner());
+        if directories.stopped {
+            return;
+        }
+        let path = directories.queue.pop_front().unwrap();
+        // The run may have read it itself, or skipped it
+        if !matches!(directories.states.get(&path), Some(State::Queued)) {
+            continue;
+        }
+        directories.states.insert(path.clone(), State::Scanning);
+        drop(directories);
+
+        let listing = list(&path);
+        let subdirectories = match &listing {
+            Ok(listing) => subdirectories(listing.iter().map(|(entry, _)| entry), &shared.skipped),
+            Err(_) => Vec::new(),
+        };
+        let mut directories = shared.directories.lock().unwrap_or_else(|e| e.into_inner());
+        // Unless the run went past it in the meantime
+        if matches!(directories.states.get(&path), Some(State::Scanning)) {
+            directories.held += listing.as_ref().map_or(0, |l| l.len());
+            directories.states.insert(path, State::Listed(listing));
+            queue(&mut directories, subdirectories);
+        }
+        shared.changed.notify_all();
+    }
+}
+
+
+/// Read the entries of a directory and look each up
+fn list(path: &str) -> io::Result<Listing> {
+    let entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| {
+            let metadata = fs::metadata(entry.path());
+            (entry, metadata)
+        })
+        .collect())
+}
@@ -0,0 +1,347 @@
+/// Part of a regular expression
+#[derive(Debug)]
+enum Node {
+    Char(char),
+    /// `.`, anything but a newline
+    Any,
+    /// `[...]`: ranges of characters, or anything else if negated
+    Class { ranges: Vec<(char, char)>, negated: bool },
+    /// `^`
+    Start,
+    /// `$`
+    End,
+    Concat(Vec<Node>),
+    Alternation(Vec<Node>),
+    /// `*`, `+`, `?` and `{m,n}`, which match as much as they can
+    Repeat { node: Box<Node>, min: usize, max: Option<usize> },
+}
+
+
+/// Regular expression, with the common syntax of the extended ones
+/// (`.`, `[...]`, `^`, `$`, `*`, `+`, `?`, `{m,n}`, `|` and groups), and
+/// `\d`, `\w` and `\s` (and their negations). Matches are found with
+/// backtracking, which is enough for the short texts (paths) it is used on.
+#[derive(Debug)]
+pub struct Regex {
+    root: Node,
+}
+
+
+const DIGITS: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+
+impl Parser<'_> {
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concat()?];
+        while self.chars.next_if_eq(&'|').is_some() {
+            branches.push(self.concat()?);
+        }
+        Ok(match branches.len() {
+            1 => branches.pop().unwrap(),
+            _ => Node::Alternation(branches),
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(&c) = self.chars.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.repeat(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn repeat(&mut self, mut node: Node) -> Result<Node, String> {
+        loop {
+            let (min, max) = match self.chars.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.chars.next();
+                    let mut bounds = String::new();
+                    for c in self.chars.by_ref() {
+                        if c == '}' {
+                            break;
+                        }
+                        bounds.push(c);
+                    }
+                    let invalid = || format!("invalid repetition {{{}}}", bounds);
+                    let parse = |n: &str| n.trim().parse::<usize>().map_err(|_| invalid());
+                    let (min, max) = match bounds.split_once(',') {
+                        Some((min, "")) => (parse(min)?, None),
+                        Some((min, max)) => (parse(min)?, Some(parse(max)?)),
+                        None => (parse(&bounds)?, Some(parse(&bounds)?)),
+                    };
+                    if max.is_some_and(|max| max < min) {
+                        return Err(invalid());
+                    }
+                    node = Node::Repeat { node: Box::new(node), min, max };
+                    continue;
+                }
+                _ => return Ok(node),
+            };
+            self.chars.next();
+            node = Node::Repeat { node: Box::new(node), min, max };
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.chars.next().unwrap() {
+            '(' => {
+                // Non-capturing groups are groups too
+                if self.chars.next_if_eq(&'?').is_some() && self.chars.next_if_eq(&':').is_none() {
+                    return Err("unsupported group (?".to_string());
+                }
+                let node = self.alternation()?;
+                match self.chars.next() {
+                    Some(')') => Ok(node),
+                    _ => Err("missing )".to_string()),
+                }
+            }
+            '[' => self.class(),
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '\\' => self.escape(),
+            c @ ('*' | '+' | '?') => Err(format!("nothing to repeat before {}", c)),
+            c => Ok(Node::Char(c)),
+        }
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        let class = |ranges: &[(char, char)], negated| Node::Class { ranges: ranges.to_vec(), negated };
+        Ok(match self.chars.next().ok_or("trailing \\")? {
+            'd' => class(DIGITS, false),
+            'D' => class(DIGITS, true),
+            'w' => class(WORD, false),
+            'W' => class(WORD, true),
+            's' => class(SPACE, false),
+            'S' => class(SPACE, true),
+            'n' => Node::Char('\n'),
+            't' => Node::Char('\t'),
+            c if c.is_ascii_alphanumeric() => return Err(format!("unsupported escape \\{}", c)),
+            c => Node::Char(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.chars.next_if_eq(&'^').is_some();
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let c = match self.chars.next() {
+                None => return Err("missing ]".to_string()),
+                // A ] first is one of the characters
+                Some(']') if !first => break,
+                Some('\\') => match self.escape()? {
+                    Node::Char(c) => c,
+                    Node::Class { ranges: escaped, negated: false } => {
+                        ranges.extend(escaped);
+                        first = false;
+                        continue;
+                    }
+                    _ => return Err("unsupported escape in [...]".to_string()),
+                },
+                Some(c) => c,
+            };
+            first = false;
+            let mut lookahead = self.chars.clone();
+            if lookahead.next() == Some('-') && lookahead.peek().is_some_and(|&n| n != ']') {
+                self.chars.next();
+                let end = self.chars.next().unwrap();
+                if end < c {
+                    return Err(format!("invalid range {}-{}", c, end));
+                }
+                ranges.push((c, end));
+            } else {
+                ranges.push((c, c));
+            }
+        }
+        Ok(Node::Class { ranges, negated })
+    }
+}
+
+
+/// Match `node` at `i`, calling `next` with where each way of matching it
+/// ends, until it returns true
+fn match_node(node: &Node, text: &[char], i: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+    match node {
+        Node::Char(c) => text.get(i) == Some(c) && next(i + 1),
+        Node::Any => text.get(i).is_some_and(|&c| c != '\n') && next(i + 1),
+        Node::Class { ranges, negated } => {
+            text.get(i).is_some_and(|c| ranges.iter().any(|(a, b)| (a..=b).contains(&c)) != *negated) && next(i + 1)
+        }
+        Node::Start => i == 0 && next(i),
+        Node::End => i == text.len() && next(i),
+        Node::Concat(nodes) => match_sequence(nodes, text, i, next),
+        Node::Alternation(branches) => branches.iter().any(|branch| match_node(branch, text, i, next)),
+        Node::Repeat { node, min, max } => match_repeat(node, *min, *max, 0, text, i, next),
+    }
+}
+
+
+fn match_sequence(nodes: &[Node], text: &[char], i: usize, next: &mut dyn FnMut(usize) -> bool) -> bool {
+    match nodes.split_first() {
+        None => next(i),
+        Some((first, rest)) => match_node(first, text, i, &mut |j| match_sequence(rest, text, j, next)),
+    }
+}
+
+
+fn match_repeat(
+    node: &Node,
+    min: usize,
+    max: Option<usize>,
+    count: usize,
+    text: &[char],
+    i: usize,
+    next: &mut dyn FnMut(usize) -> bool,
+) -> bool {
+    // Once the minimum is reached, a repetition that matches nothing
+    // would go on forever
+    if max.is_none_or(|max| count < max)
+        && match_node(node, text, i, &mut |j| {
+            (j != i || count < min) && match_repeat(node, min, max, count + 1, text, j, next)
+        })
+    {
+        return true;
+    }
+    count >= min && next(i)
+}
+
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut parser = Parser { chars: pattern.chars().peekable() };
+        let root = parser.alternation().and_then(|root| match parser.chars.next() {
+            None => Ok(root),
+            Some(_) => Err("unmatched )".to_string()),
+        });
+        root.map(|root| Regex { root })
+            .map_err(|e| format!("invalid regular expression '{}': {}", pattern, e))
+    }
+
+    /// Whether the expression matches anywhere in `text`
+    pub fn is_match(&self, text: &str) -> bool {
+        let text: Vec<char> = text.chars().collect();
+        (0..=text.len()).any(|start| match_node(&self.root, &text, start, &mut |_| true))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check each (pattern, text, whether it matches)
+    fn check(cases: &[(&str, &str, bool)]) {
+        for &(pattern, text, expected) in cases {
+            let regex = Regex::new(pattern).unwrap();
+            assert_eq!(regex.is_match(text), expected, "'{}' on '{}'", pattern, text);
+        }
+    }
+
+    #[test]
+    fn anchors() {
+        check(&[
+            ("abc", "xabcx", true),
+            ("^abc", "abcx", true),
+            ("^abc", "xabc", false),
+            ("abc$", "xabc", true),
+            ("abc$", "abcx", false),
+            ("^abc$", "abc", true),
+            ("^abc$", "abcabc", false),
+            ("^$", "", true),
+            ("^$", "a", false),
+            ("a^b", "ab", false),
+            ("(^a|b)c", "xac", false),
+            ("(^a|b)c", "xbc", true),
+        ]);
+    }
+
+    #[test]
+    fn alternation() {
+        check(&[
+            ("cat|dog", "hotdog", true),
+            ("cat|dog", "cow", false),
+            ("^(cat|dog)s?$", "cats", true),
+            ("^(cat|dog)s?$", "dogs", true),
+            ("^(cat|dog)s?$", "catdog", false),
+            ("^(a|ab)c$", "abc", true),
+            ("^(?:x|)y$", "y", true),
+            ("^(a|b|c)+$", "abcabc", true),
+            ("^(a|b|c)+$", "abd", false),
+            ("\\.(jpe?g|png)$", "photo.jpeg", true),
+            ("\\.(jpe?g|png)$", "photo.png.bak", false),
+        ]);
+    }
+
+    #[test]
+    fn classes() {
+        check(&[
+            ("^[abc]$", "b", true),
+            ("^[abc]$", "d", false),
+            ("^[a-z0-9]+$", "abc123", true),
+            ("^[a-z0-9]+$", "abC", false),
+            ("^[^a-z]$", "A", true),
+            ("^[^a-z]$", "a", false),
+            ("^[]a]$", "]", true),
+            ("^[a-]$", "-", true),
+            ("^[\\d_]+$", "1_2", true),
+            ("^.$", "é", true),
+            ("^.$", "\n", false),
+            ("^\\d+$", "2024", true),
+            ("^\\D+$", "20x", false),
+            ("^\\w+$", "file_name1", true),
+            ("^\\W$", "-", true),
+            ("a\\sb", "a\tb", true),
+            ("^\\S+$", "a b", false),
+            ("^a\\.b$", "a.b", true),
+            ("^a\\.b$", "axb", false),
+        ]);
+    }
+
+    #[test]
+    fn repetition() {
+        check(&[
+            ("^ab*c$", "ac", true),
+            ("^ab*c$", "abbbc", true),
+            ("^ab+c$", "ac", false),
+            ("^ab+c$", "abc", true),
+            ("^ab?c$", "abbc", false),
+            ("^a{3}$", "aaa", true),
+            ("^a{3}$", "aa", false),
+            ("^a{2,}$", "aaaaa", true),
+            ("^a{2,}$", "a", false),
+            ("^a{1,2}$", "aaa", false),
+            ("^a{0}b$", "b", true),
+            ("^(ab)*$", "ababab", true),
+            ("^(ab)*$", "aba", false),
+            // Backtracking out of a greedy repetition
+            ("^a*ab$", "aaab", true),
+            ("^.*\\.rs$", "src/main.rs", true),
+            // Repetitions of what can match nothing end
+            ("^(a*)*$", "aaa", true),
+            ("^(a*)*b$", "aaac", false),
+            ("^(a?){2}$", "", true),
+        ]);
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for pattern in ["(a", "a)", "[a", "*a", "a{2,1}", "a{x}", "[z-a]", "\\q", "(?=a)", "a\\"] {
+            assert!(Regex::new(pattern).is_err(), "'{}'", pattern);
+        }
+    }
+}
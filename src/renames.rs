@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::backend::{Backend, Kind};
+use crate::engine;
+use crate::hash::Digest;
+use crate::manifest::{Entry, Manifest};
+use crate::unicode;
+use crate::Options;
+
+
+
+/// Whether `path` is `ancestor` or below it
+fn is_within(path: &str, ancestor: &str) -> bool {
+    path == ancestor || path.strip_prefix(ancestor).is_some_and(|rest| rest.starts_with('/'))
+}
+
+
+/// Find the entries of the source that were moved since the last run, by
+/// matching the entries that appeared with the ones that disappeared: a
+/// directory with the same rollup (so the same names and metadata all the
+/// way down), or a file with the same size and modification time, as long
+/// as no other vanished file has them too. Returns (old, new) pairs of
+/// paths relative to the source root, parents before their children.
+pub fn detect(previous: &Manifest, current: &Manifest) -> Vec<(String, String)> {
+    let mut directories: HashMap<&Digest, Vec<&str>> = HashMap::new();
+    let mut files: HashMap<(u64, i64, i64), Vec<&str>> = HashMap::new();
+    for (path, entry) in &previous.entries {
+        if current.entries.contains_key(path) {
+            continue;
+        }
+        match entry {
+            Entry::Directory { rollup } => directories.entry(rollup).or_default().push(path),
+            Entry::File { size, mtime, mtime_nsec } if *size > 0 => {
+                files.entry((*size, *mtime, *mtime_nsec)).or_default().push(path)
+            }
+            _ => (),
+        }
+    }
+
+    let mut moves: Vec<(String, String)> = Vec::new();
+    for (path, entry) in &current.entries {
+        if previous.entries.contains_key(path) || moves.iter().any(|(_, to)| is_within(path, to)) {
+            continue;
+        }
+        // An entry can only be moved once, and not together with what
+        // contains it or what it contains
+        let available = |candidate: &str| {
+            !moves.iter().any(|(from, _)| is_within(candidate, from) || is_within(from, candidate))
+        };
+        let from = match entry {
+            Entry::Directory { rollup } => directories
+                .get(rollup)
+                .and_then(|candidates| candidates.iter().copied().find(|c| available(c))),
+            Entry::File { size, mtime, mtime_nsec } => match files.get(&(*size, *mtime, *mtime_nsec)) {
+                Some(candidates) if candidates.len() == 1 => candidates.iter().copied().find(|c| available(c)),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(from) = from {
+            moves.push((from.to_string(), path.clone()));
+        }
+    }
+    moves
+}
+
+
+/// Move the copies of the entries moved in the source, instead of removing
+/// and copying them again. Moved directories are marked as unchanged, since
+/// their copy is already up to date.
+pub fn apply(moves: &[(String, String)], source: &str, destination: &str, opts: &mut Options, target: &dyn Backend) {
+    for (from, to) in moves {
+        let from_path = format!("{}/{}", destination, unicode::normalize(from, opts.normalize));
+        let to_path = format!("{}/{}", destination, unicode::normalize(to, opts.normalize));
+        let kind = match target.metadata(&from_path) {
+            Some(metadata) if metadata.kind == Kind::Directory || metadata.kind == Kind::File => {
+                metadata.kind
+            }
+            // The destination doesn't hold what the manifest says, so leave
+            // it to the deletion and copy passes
+            _ => continue,
+        };
+        if target.metadata(&to_path).is_some() {
+            continue;
+        }
+        action!("Moving {} to {}", from_path, to_path);
+        if opts.dry_run {
+            // Leave the old copy out of the simulated deletions
+            opts.protected.insert(Path::new(&from_path).to_path_buf());
+        } else {
+            // The new parent directories would be created by the copy pass
+            let mut missing = Vec::new();
+            let mut parent = Path::new(&to_path).parent();
+            while let Some(directory) = parent.and_then(|p| p.to_str()) {
+                if directory.len() <= destination.len() || target.metadata(directory).is_some() {
+                    break;
+                }
+                missing.push(directory.to_string());
+                parent = Path::new(directory).parent();
+            }
+            let result = missing
+                .iter()
+                .rev()
+                .try_for_each(|directory| target.create_dir(directory))
+                .and_then(|_| target.rename(&from_path, &to_path));
+            if let Err(e) = result {
+                warning!("cannot move {}: {}; copying it instead", from_path, e);
+                continue;
+            }
+            if let Some(checksums) = &opts.checksums {
+                checksums.rename(&from_path, &to_path);
+            }
+            for changed in missing.iter().chain([&from_path, &to_path]) {
+                engine::sync_parent(changed, opts, target);
+            }
+        }
+        if kind == Kind::Directory {
+            opts.unchanged.insert(Path::new(source).join(to));
+        }
+    }
+}
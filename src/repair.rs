@@ -0,0 +1,231 @@
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+use crate::backend::{self, Backend, Kind};
+use crate::hash;
+use crate::manifest::{self, Entry, Manifest};
+use crate::Options;
+
+
+
+/// Looks for the damage done to a mirror by other means than backups:
+/// entries that were added to it, copies that were removed, replaced,
+/// truncated or changed, and (on a local destination) permissions that were
+/// changed. What the last run backed up is told apart from what changed in
+/// the source since then with its manifest, so that only the copies that
+/// should be as the source is now are compared with it.
+struct Repair<'a> {
+    source: &'a str,
+    destination: &'a str,
+    manifest: Manifest,
+    opts: &'a Options,
+    target: &'a dyn Backend,
+    /// Number of entries corrected so far
+    corrected: usize,
+}
+
+
+impl Repair<'_> {
+    fn correct(&mut self, path: &str, problem: &str) {
+        self.corrected += 1;
+        action!("Repairing {}: {}", path, problem);
+    }
+
+    /// Remove a damaged copy, for the copy pass to make it again
+    fn remove(&mut self, path: &str, problem: &str) {
+        self.correct(path, problem);
+        if !self.opts.dry_run {
+            let removed = match self.target.metadata(path).map(|m| m.kind) {
+                Some(Kind::Directory) => self.target.remove_dir_all(path),
+                _ => self.target.remove_file(path),
+            };
+            if let Err(e) = removed {
+                error!("cannot remove {}: {}", path, e);
+            }
+        }
+    }
+
+    /// Whether a file of the source is as the last run backed it up
+    fn backed_up(&self, relative: &str, metadata: &fs::Metadata) -> bool {
+        matches!(
+            self.manifest.entries.get(relative),
+            Some(Entry::File { size, mtime, mtime_nsec })
+                if *size == metadata.len() && *mtime == metadata.mtime() && *mtime_nsec == metadata.mtime_nsec()
+        )
+    }
+
+    /// Give the copy of a directory or file of a local destination the
+    /// permissions of the source
+    fn check_permissions(&mut self, path: &str, metadata: &fs::Metadata) {
+        if !self.target.is_local() || self.opts.android {
+            return;
+        }
+        let Ok(copy) = fs::symlink_metadata(path) else {
+            return;
+        };
+        let (mode, copy_mode) = (metadata.mode() & 0o7777, copy.mode() & 0o7777);
+        if mode == copy_mode {
+            return;
+        }
+        self.correct(path, &format!("permissions {:04o} instead of {:04o}", copy_mode, mode));
+        if !self.opts.dry_run {
+            if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+                error!("cannot change the permissions of {}: {}", path, e);
+            }
+        }
+    }
+
+    /// Compare the copy of a file that didn't change since the last run
+    /// with the file. Returns the problem, if any.
+    fn compare(&self, source: &str, path: &str, size: u64, copy_size: u64) -> Option<String> {
+        if size != copy_size {
+            return Some(match copy_size < size {
+                true => format!("truncated ({} of {} bytes)", copy_size, size),
+                false => format!("{} bytes instead of {}", copy_size, size),
+            });
+        }
+        let copy = match backend::hash_stored(self.target, path) {
+            Ok(copy) => copy,
+            Err(e) => {
+                warning!("cannot read {}: {}; not comparing it", path, e);
+                return None;
+            }
+        };
+        match hash::hash_file(source).map(|digest| digest == copy) {
+            Ok(true) => None,
+            Ok(false) => Some("the contents differ from the source".to_string()),
+            Err(e) => {
+                warning!("cannot compare {} with {}: {}", path, source, e);
+                None
+            }
+        }
+    }
+
+    /// Look at a directory of the mirror and at everything in it, along
+    /// with the directory of the source it is the copy of
+    fn directory(&mut self, relative: &str) {
+        let join = |root: &str| match relative {
+            "" => root.to_string(),
+            _ => format!("{}/{}", root, relative),
+        };
+        let (source_dir, destination_dir) = (join(self.source), join(self.destination));
+        let mut names = match self.target.list(&destination_dir) {
+            Ok(names) => names,
+            Err(e) => {
+                warning!("cannot list {}: {}", destination_dir, e);
+                return;
+            }
+        };
+        names.sort();
+        for name in &names {
+            if relative.is_empty() && manifest::is_meta(name) {
+                continue;
+            }
+            let child = if relative.is_empty() { name.clone() } else { format!("{}/{}", relative, name) };
+            self.entry(&child);
+        }
+        // The copies that are gone, of what didn't change since the last run
+        let Ok(entries) = fs::read_dir(&source_dir) else {
+            return;
+        };
+        let mut missing: Vec<String> = entries
+            .flatten()
+            .filter_map(|e| e.file_name().to_str().map(|n| n.to_string()))
+            .filter(|name| !names.contains(name))
+            .collect();
+        missing.sort();
+        for name in missing {
+            let child = if relative.is_empty() { name } else { format!("{}/{}", relative, name) };
+            let source = format!("{}/{}", self.source, child);
+            if self.manifest.entries.contains_key(&child) && !self.opts.policy.is_excluded(&source) {
+                self.correct(&format!("{}/{}", self.destination, child), "missing from the mirror");
+            }
+        }
+    }
+
+    /// Look at an entry of the mirror
+    fn entry(&mut self, relative: &str) {
+        let source = format!("{}/{}", self.source, relative);
+        let path = format!("{}/{}", self.destination, relative);
+        let skipped = self.opts.skip_list.as_ref().is_some_and(|s| s.skips(&source));
+        if self.opts.policy.is_excluded(&source) || skipped {
+            return;
+        }
+        let Ok(metadata) = fs::symlink_metadata(&source) else {
+            // What was in the source as of the last run is removed (or kept)
+            // by the deletion pass
+            let pending = self.opts.deletions.iter().flat_map(|d| d.pending()).any(|p| p == relative);
+            if !self.manifest.entries.contains_key(relative) && !pending {
+                self.remove(&path, "not in the source");
+            }
+            return;
+        };
+        let Some(copy) = self.target.metadata(&path) else {
+            return;
+        };
+        // Symlinks are stored as the symlink policy has it, which the copy
+        // pass looks after
+        if metadata.file_type().is_symlink() {
+            return;
+        }
+        if metadata.is_dir() {
+            if copy.kind != Kind::Directory {
+                return self.remove(&path, &format!("{} instead of a directory", kind(copy.kind)));
+            }
+            self.check_permissions(&path, &metadata);
+            return self.directory(relative);
+        }
+        if !metadata.is_file() {
+            return;
+        }
+        if copy.kind != Kind::File {
+            return self.remove(&path, &format!("{} instead of a file", kind(copy.kind)));
+        }
+        // The copies of files that changed since are updated by the copy
+        // pass, and those of transformed files can't be compared
+        if self.backed_up(relative, &metadata) && !self.manifest.transforms.contains_key(relative) {
+            if let Some(problem) = self.compare(&source, &path, metadata.len(), copy.size) {
+                return self.remove(&path, &problem);
+            }
+        }
+        self.check_permissions(&path, &metadata);
+    }
+}
+
+
+fn kind(kind: Kind) -> &'static str {
+    match kind {
+        Kind::File => "a file",
+        Kind::Directory => "a directory",
+        Kind::Symlink => "a symlink",
+        Kind::Other => "a special file",
+    }
+}
+
+
+/// Compare a mirror with its source and the manifest of the last run, and
+/// correct the damage done to it by other means than backups: entries that
+/// aren't in the source are removed, permissions are set back, and damaged
+/// copies are removed for the copy pass to make them again. Every entry
+/// corrected is reported. Returns false if the mirror can't be repaired.
+pub fn repair(source: &str, destination: &str, opts: &Options, target: &dyn Backend) -> bool {
+    if opts.encrypt || opts.compress.is_some() {
+        error!("mirrors made with --encrypt or --compress can't be repaired");
+        return false;
+    }
+    let Some(manifest) = Manifest::load(target, destination) else {
+        error!("{} has no manifest (made by runs with --manifest); it can't be repaired", destination);
+        return false;
+    };
+    info!("Comparing {} with {}...", destination, source);
+    let mut repair = Repair { source, destination, manifest, opts, target, corrected: 0 };
+    if let Ok(metadata) = fs::metadata(source) {
+        repair.check_permissions(destination, &metadata);
+    }
+    repair.directory("");
+    match repair.corrected {
+        0 => info!("Found no damage in {}", destination),
+        n => info!("Repaired {} entries of {}", n, destination),
+    }
+    true
+}
@@ -0,0 +1,103 @@
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::log;
+
+
+
+/// A path of the source that a run couldn't back up
+pub struct Failure {
+    pub path: String,
+    /// What went wrong, as one of `problem()`
+    pub problem: &'static str,
+    /// The message printed for it
+    pub message: String,
+}
+
+
+/// The paths of the source that a run couldn't back up, while it went on
+/// with the rest, printed at its end and written as JSON with
+/// `--error-report`
+#[derive(Default)]
+pub struct ErrorReport {
+    failures: RefCell<Vec<Failure>>,
+}
+
+
+/// What kind of failure an error is, for whoever goes through the report
+fn problem(e: &io::Error, vanished: bool) -> &'static str {
+    match e.kind() {
+        _ if vanished => "vanished",
+        io::ErrorKind::PermissionDenied => "permission denied",
+        io::ErrorKind::TimedOut => "timed out",
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => "no space",
+        _ => "i/o error",
+    }
+}
+
+
+/// A string as a JSON string literal
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+
+impl ErrorReport {
+    /// Record that `path` failed with `e` (`vanished` if it is no longer
+    /// in the source), `message` being what was printed about it
+    pub fn add(&self, path: &str, e: &io::Error, vanished: bool, message: &str) {
+        self.failures.borrow_mut().push(Failure {
+            path: path.to_string(),
+            problem: problem(e, vanished),
+            message: message.to_string(),
+        });
+    }
+
+    /// Print the paths that failed, if any
+    pub fn print(&self) {
+        let failures = self.failures.borrow();
+        if failures.is_empty() {
+            return;
+        }
+        log::separator();
+        info!("{} path(s) couldn't be backed up:", failures.len());
+        for failure in failures.iter() {
+            info!("  {}: {}", failure.problem, failure.message);
+        }
+    }
+
+    /// Write the report to `path`, as a JSON object with the ID of the run
+    /// and the list of failures (each with its `path`, `problem` and
+    /// `message`)
+    pub fn write_json(&self, path: &Path, run: &str) -> io::Result<()> {
+        let mut content = format!("{{\n  \"run_id\": {},\n  \"failures\": [", json_string(run));
+        for (i, failure) in self.failures.borrow().iter().enumerate() {
+            content.push_str(if i == 0 { "\n" } else { ",\n" });
+            write!(
+                content,
+                "    {{\"path\": {}, \"problem\": {}, \"message\": {}}}",
+                json_string(&failure.path),
+                json_string(failure.problem),
+                json_string(&failure.message)
+            )
+            .unwrap();
+        }
+        content.push_str("\n  ]\n}\n");
+        fs::write(path, content)
+    }
+}
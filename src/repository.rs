@@ -0,0 +1,788 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::backend::{self, Backend};
+use crate::chunker;
+use crate::hash::{self, Digest, Sha256};
+use crate::log;
+use crate::manifest;
+use crate::schedule;
+use crate::stats::human_bytes;
+use crate::Options;
+
+
+
+// A repository stores the contents of files split into chunks, each stored
+// once however many files and snapshots contain it:
+//
+//   REPO/config             marks the directory as a repository
+//   REPO/data/XX/PACK       chunks, concatenated into packs of ~16 MiB
+//   REPO/index/ID           where each chunk is: CHUNK PACK OFFSET LENGTH
+//   REPO/snapshots/ID       the tree of one backup, with the chunks of files
+//
+// Packs are written before the index that references them, and the index
+// before the snapshots that need it, so an interrupted run only leaves
+// unreferenced packs behind, which gc removes.
+
+const CONFIG: &str = "config";
+const CONFIG_HEADER: &str = "backup-rs repository 1";
+const INDEX_HEADER: &str = "backup-rs index 1";
+const SNAPSHOT_HEADER: &str = "backup-rs snapshot 1";
+
+/// Size from which the pack being filled is written
+const PACK_SIZE: usize = 16 * 1024 * 1024;
+
+
+/// Where a chunk is stored
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    pack: Digest,
+    offset: usize,
+    length: usize,
+}
+
+
+#[derive(Clone, Debug)]
+enum Node {
+    Directory { mode: u32, mtime: (i64, u32) },
+    File { mode: u32, mtime: (i64, u32), size: u64, chunks: Vec<Digest> },
+    Symlink { target: String },
+}
+
+
+/// The tree of the source at the time of one backup
+#[derive(Debug, Default)]
+struct Snapshot {
+    /// Seconds and nanoseconds since the epoch, which orders the snapshots
+    time: (i64, u32),
+    source: String,
+    /// Paths relative to the source, parents before their children
+    entries: Vec<(String, Node)>,
+}
+
+
+impl Snapshot {
+    fn to_text(&self) -> String {
+        let mut text = format!(
+            "{}\ntime {}.{:09}\nsource {}\n",
+            SNAPSHOT_HEADER,
+            self.time.0,
+            self.time.1,
+            manifest::escape(&self.source)
+        );
+        for (relative, node) in &self.entries {
+            let relative = manifest::escape(relative);
+            match node {
+                Node::Directory { mode, mtime } => {
+                    writeln!(text, "D\t{:o}\t{}\t{}\t{}", mode, mtime.0, mtime.1, relative).unwrap()
+                }
+                Node::File { mode, mtime, size, chunks } => {
+                    let chunks: Vec<String> = chunks.iter().map(hash::to_hex).collect();
+                    writeln!(
+                        text,
+                        "F\t{:o}\t{}\t{}\t{}\t{}\t{}",
+                        mode, mtime.0, mtime.1, size, chunks.join(","), relative
+                    )
+                    .unwrap()
+                }
+                Node::Symlink { target } => {
+                    writeln!(text, "L\t{}\t{}", manifest::escape(target), relative).unwrap()
+                }
+            }
+        }
+        text
+    }
+
+    fn parse(content: &str) -> Option<Snapshot> {
+        let mut lines = content.lines();
+        if lines.next() != Some(SNAPSHOT_HEADER) {
+            return None;
+        }
+        let mut snapshot = Snapshot::default();
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let entry = match fields[..] {
+                [header] => {
+                    match header.split_once(' ') {
+                        Some(("time", time)) => {
+                            let (seconds, nanoseconds) = time.split_once('.')?;
+                            snapshot.time = (seconds.parse().ok()?, nanoseconds.parse().ok()?);
+                        }
+                        Some(("source", source)) => snapshot.source = manifest::unescape(source),
+                        _ => (),
+                    }
+                    continue;
+                }
+                ["D", mode, seconds, nanoseconds, relative] => (
+                    relative,
+                    Node::Directory {
+                        mode: u32::from_str_radix(mode, 8).ok()?,
+                        mtime: (seconds.parse().ok()?, nanoseconds.parse().ok()?),
+                    },
+                ),
+                ["F", mode, seconds, nanoseconds, size, chunks, relative] => (
+                    relative,
+                    Node::File {
+                        mode: u32::from_str_radix(mode, 8).ok()?,
+                        mtime: (seconds.parse().ok()?, nanoseconds.parse().ok()?),
+                        size: size.parse().ok()?,
+                        chunks: chunks
+                            .split(',')
+                            .filter(|c| !c.is_empty())
+                            .map(hash::from_hex)
+                            .collect::<Option<Vec<Digest>>>()?,
+                    },
+                ),
+                ["L", target, relative] => (relative, Node::Symlink { target: manifest::unescape(target) }),
+                _ => return None,
+            };
+            snapshot.entries.push((manifest::unescape(entry.0), entry.1));
+        }
+        Some(snapshot)
+    }
+
+    fn file_count(&self) -> usize {
+        self.entries.iter().filter(|(_, n)| matches!(n, Node::File { .. })).count()
+    }
+
+    fn size(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|(_, n)| match n {
+                Node::File { size, .. } => *size,
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+
+/// A repository, in any storage a destination can be in (so it can be
+/// remote and encrypted too)
+struct Repository {
+    target: Box<dyn Backend>,
+    root: String,
+}
+
+
+impl Repository {
+    fn open(location: &str, opts: &Options) -> Result<Repository, String> {
+        let (target, root) = backend::open(location, opts)?;
+        let repository = Repository { target, root };
+        match repository.target.read(&repository.path(CONFIG)) {
+            Ok(content) if String::from_utf8_lossy(&content).lines().next() == Some(CONFIG_HEADER) => {
+                Ok(repository)
+            }
+            Ok(_) => Err(format!("{} is not a backup-rs repository", location)),
+            Err(_) => Err(format!(
+                "{} is not a repository (create one with: backup-rs repo init {})",
+                location, location
+            )),
+        }
+    }
+
+    fn path(&self, relative: &str) -> String {
+        format!("{}/{}", self.root, relative)
+    }
+
+    fn pack_path(&self, pack: &Digest) -> String {
+        let hex = hash::to_hex(pack);
+        self.path(&format!("data/{}/{}", &hex[..2], hex))
+    }
+
+    /// Names of the files of a directory of the repository
+    fn names(&self, directory: &str) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .target
+            .list(&self.path(directory))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|name| hash::from_hex(name).is_some())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Location of every stored chunk, and the names of the index files
+    fn load_index(&self) -> Result<(HashMap<Digest, Location>, Vec<String>), String> {
+        let mut index = HashMap::new();
+        let names = self.names("index");
+        for name in &names {
+            let path = self.path(&format!("index/{}", name));
+            let content = self.target.read(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+            let content = String::from_utf8_lossy(&content);
+            let mut lines = content.lines();
+            if lines.next() != Some(INDEX_HEADER) {
+                return Err(format!("invalid {}", path));
+            }
+            for line in lines {
+                let entry = (|| {
+                    let mut fields = line.split(' ');
+                    let chunk = hash::from_hex(fields.next()?)?;
+                    let location = Location {
+                        pack: hash::from_hex(fields.next()?)?,
+                        offset: fields.next()?.parse().ok()?,
+                        length: fields.next()?.parse().ok()?,
+                    };
+                    Some((chunk, location))
+                })();
+                let (chunk, location) = entry.ok_or_else(|| format!("invalid {}", path))?;
+                index.insert(chunk, location);
+            }
+        }
+        Ok((index, names))
+    }
+
+    /// Write an index file, returning its name
+    fn save_index(&self, entries: &[(Digest, Location)]) -> io::Result<String> {
+        let mut content = format!("{}\n", INDEX_HEADER);
+        for (chunk, location) in entries {
+            writeln!(
+                content,
+                "{} {} {} {}",
+                hash::to_hex(chunk),
+                hash::to_hex(&location.pack),
+                location.offset,
+                location.length
+            )
+            .unwrap();
+        }
+        let name = hash::to_hex(&digest(content.as_bytes()));
+        self.target.write(&self.path(&format!("index/{}", name)), content.as_bytes())?;
+        Ok(name)
+    }
+
+    fn load_snapshot(&self, id: &str) -> Result<Snapshot, String> {
+        let path = self.path(&format!("snapshots/{}", id));
+        let content = self.target.read(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        Snapshot::parse(&String::from_utf8_lossy(&content)).ok_or_else(|| format!("invalid {}", path))
+    }
+
+    /// Every snapshot, oldest first
+    fn snapshots(&self) -> Result<Vec<(String, Snapshot)>, String> {
+        let mut snapshots = Vec::new();
+        for id in self.names("snapshots") {
+            let snapshot = self.load_snapshot(&id)?;
+            snapshots.push((id, snapshot));
+        }
+        snapshots.sort_by_key(|(_, s)| s.time);
+        Ok(snapshots)
+    }
+
+    /// The full ID of a snapshot given by a prefix of it, or `latest`
+    fn find_snapshot(&self, name: &str) -> Result<String, String> {
+        if name == "latest" {
+            let snapshots = self.snapshots()?;
+            return match snapshots.last() {
+                Some((id, _)) => Ok(id.clone()),
+                None => Err("the repository has no snapshots".to_string()),
+            };
+        }
+        let found: Vec<String> = self.names("snapshots").into_iter().filter(|id| id.starts_with(name)).collect();
+        match &found[..] {
+            [id] if !name.is_empty() => Ok(id.clone()),
+            [] | [_] => Err(format!("no snapshot {}", name)),
+            _ => Err(format!("{} matches several snapshots", name)),
+        }
+    }
+}
+
+
+fn now() -> (i64, u32) {
+    let now = std::time::SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (now.as_secs() as i64, now.subsec_nanos())
+}
+
+
+fn digest(data: &[u8]) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finish()
+}
+
+
+/// Gathers new chunks into packs
+struct Packer<'a> {
+    repository: &'a Repository,
+    dry_run: bool,
+    buffer: Vec<u8>,
+    /// Chunks of the pack being filled, with their offset and length
+    pending: Vec<(Digest, usize, usize)>,
+    /// Chunks of the packs written so far
+    written: Vec<(Digest, Location)>,
+    packs: usize,
+    bytes: u64,
+}
+
+
+impl<'a> Packer<'a> {
+    fn new(repository: &'a Repository, dry_run: bool) -> Packer<'a> {
+        Packer { repository, dry_run, buffer: Vec::new(), pending: Vec::new(), written: Vec::new(), packs: 0, bytes: 0 }
+    }
+
+    fn add(&mut self, chunk: Digest, data: &[u8]) -> io::Result<()> {
+        self.pending.push((chunk, self.buffer.len(), data.len()));
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= PACK_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pack = digest(&self.buffer);
+        if !self.dry_run {
+            self.repository.target.write(&self.repository.pack_path(&pack), &self.buffer)?;
+        }
+        for (chunk, offset, length) in self.pending.drain(..) {
+            self.written.push((chunk, Location { pack, offset, length }));
+        }
+        self.bytes += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.packs += 1;
+        Ok(())
+    }
+}
+
+
+/// Create an empty repository
+pub fn init(location: &str, opts: &Options) -> Result<(), String> {
+    let (target, root) = backend::open(location, opts)?;
+    let config = format!("{}/{}", root, CONFIG);
+    if target.metadata(&config).is_some() {
+        return Err(format!("{} is already a repository", location));
+    }
+    if target.list(&root).map(|names| names.iter().any(|n| n != manifest::META_DIR)).unwrap_or(false) {
+        return Err(format!("{} is not empty", location));
+    }
+    if opts.dry_run {
+        return Ok(());
+    }
+    target
+        .write(&config, format!("{}\n", CONFIG_HEADER).as_bytes())
+        .map_err(|e| format!("cannot write {}: {}", config, e))?;
+    info!("Created repository {}", location);
+    Ok(())
+}
+
+
+/// State of one backup into a repository
+struct Run<'a> {
+    packer: Packer<'a>,
+    /// Chunks already in the repository or added by this run
+    known: HashSet<Digest>,
+    /// Files of the previous snapshot of the same source, whose chunks are
+    /// reused for files that didn't change since then
+    parent: HashMap<String, Node>,
+    snapshot: Snapshot,
+    unchanged: usize,
+    new_chunks: usize,
+    new_bytes: u64,
+    failed: usize,
+}
+
+
+impl Run<'_> {
+    fn add_file(&mut self, path: &Path, relative: &str, metadata: &fs::Metadata) -> io::Result<()> {
+        let mtime = (metadata.mtime(), metadata.mtime_nsec() as u32);
+        let mode = metadata.mode() & 0o7777;
+        if let Some(Node::File { size, mtime: parent_mtime, chunks, .. }) = self.parent.get(relative) {
+            if *size == metadata.len() && *parent_mtime == mtime && chunks.iter().all(|c| self.known.contains(c)) {
+                self.unchanged += 1;
+                let chunks = chunks.clone();
+                self.snapshot.entries.push((relative.to_string(), Node::File { mode, mtime, size: *size, chunks }));
+                return Ok(());
+            }
+        }
+        action!("Adding {}", relative);
+        let mut chunks = Vec::new();
+        let mut size = 0;
+        let mut file = fs::File::open(path)?;
+        chunker::split(&mut file, &mut |data: &[u8]| {
+            let chunk = digest(data);
+            size += data.len() as u64;
+            if self.known.insert(chunk) {
+                self.new_chunks += 1;
+                self.new_bytes += data.len() as u64;
+                self.packer.add(chunk, data)?;
+            }
+            chunks.push(chunk);
+            Ok(())
+        })?;
+        self.snapshot.entries.push((relative.to_string(), Node::File { mode, mtime, size, chunks }));
+        Ok(())
+    }
+
+    /// Add the entries of a directory of the source, recursively
+    fn add_dir(&mut self, path: &Path, relative: &str) {
+        let mut entries: Vec<(String, fs::DirEntry)> = match fs::read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| Some((e.file_name().to_str()?.to_string(), e)))
+                .collect(),
+            Err(e) => {
+                error!("cannot read {}: {}", path.display(), e);
+                self.failed += 1;
+                return;
+            }
+        };
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, entry) in entries {
+            let child = entry.path();
+            let child_relative = if relative.is_empty() {
+                name
+            } else {
+                format!("{}/{}", relative, name)
+            };
+            let metadata = match fs::symlink_metadata(&child) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    error!("cannot read {}: {}", child.display(), e);
+                    self.failed += 1;
+                    continue;
+                }
+            };
+            let result = if metadata.file_type().is_symlink() {
+                fs::read_link(&child).map(|target| {
+                    let target = target.to_string_lossy().to_string();
+                    self.snapshot.entries.push((child_relative.clone(), Node::Symlink { target }));
+                })
+            } else if metadata.is_dir() {
+                let mtime = (metadata.mtime(), metadata.mtime_nsec() as u32);
+                let mode = metadata.mode() & 0o7777;
+                self.snapshot.entries.push((child_relative.clone(), Node::Directory { mode, mtime }));
+                self.add_dir(&child, &child_relative);
+                Ok(())
+            } else if metadata.is_file() {
+                self.add_file(&child, &child_relative, &metadata)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                error!("cannot back up {}: {}", child.display(), e);
+                self.failed += 1;
+            }
+        }
+    }
+}
+
+
+/// Back up a directory into a repository, as a new snapshot. Returns false
+/// if anything couldn't be backed up.
+pub fn backup(source: &str, location: &str, opts: &Options) -> bool {
+    log::separator();
+    info!("Source: {}", source);
+    info!("Repository: {}", location);
+    log::separator();
+    let repository = match Repository::open(location, opts) {
+        Ok(repository) => repository,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    let source_path = fs::canonicalize(source).map(|p| p.to_string_lossy().to_string());
+    let source_path = match source_path {
+        Ok(path) if Path::new(&path).is_dir() => path,
+        _ => {
+            error!("{} is not a directory", source);
+            return false;
+        }
+    };
+    let (index, snapshots) = match repository.load_index().and_then(|(i, _)| Ok((i, repository.snapshots()?))) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    let mut parent = HashMap::new();
+    if let Some((_, snapshot)) = snapshots.into_iter().rev().find(|(_, s)| s.source == source_path) {
+        parent.extend(snapshot.entries.into_iter().filter(|(_, node)| matches!(node, Node::File { .. })));
+    }
+    let mut run = Run {
+        packer: Packer::new(&repository, opts.dry_run),
+        known: index.keys().copied().collect(),
+        parent,
+        snapshot: Snapshot { time: now(), source: source_path.clone(), entries: Vec::new() },
+        unchanged: 0,
+        new_chunks: 0,
+        new_bytes: 0,
+        failed: 0,
+    };
+    run.add_dir(Path::new(&source_path), "");
+
+    let result = run.packer.flush().and_then(|_| {
+        if opts.dry_run {
+            return Ok(None);
+        }
+        if !run.packer.written.is_empty() {
+            repository.save_index(&run.packer.written)?;
+        }
+        let content = run.snapshot.to_text();
+        let id = hash::to_hex(&digest(content.as_bytes()));
+        repository.target.write(&repository.path(&format!("snapshots/{}", id)), content.as_bytes())?;
+        Ok(Some(id))
+    });
+    log::separator();
+    info!(
+        "{} files ({} unchanged), {} new chunks in {} packs ({})",
+        run.snapshot.file_count(),
+        run.unchanged,
+        run.new_chunks,
+        run.packer.packs,
+        human_bytes(run.new_bytes)
+    );
+    match result {
+        Ok(Some(id)) => info!("Created snapshot {}", &id[..8]),
+        Ok(None) => (),
+        Err(e) => {
+            error!("cannot write to the repository: {}", e);
+            return false;
+        }
+    }
+    if run.failed > 0 {
+        error!("{} entries couldn't be backed up", run.failed);
+    }
+    run.failed == 0
+}
+
+
+/// List the snapshots of a repository
+pub fn list(location: &str, opts: &Options) -> Result<(), String> {
+    let repository = Repository::open(location, opts)?;
+    for (id, snapshot) in repository.snapshots()? {
+        info!(
+            "{}  {}  {}  {} files, {}",
+            &id[..8],
+            schedule::local_time(snapshot.time.0).format(),
+            snapshot.source,
+            snapshot.file_count(),
+            human_bytes(snapshot.size())
+        );
+    }
+    Ok(())
+}
+
+
+fn system_time(mtime: (i64, u32)) -> std::time::SystemTime {
+    if mtime.0 >= 0 {
+        UNIX_EPOCH + Duration::new(mtime.0 as u64, mtime.1)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(mtime.0.unsigned_abs()) + Duration::from_nanos(mtime.1 as u64)
+    }
+}
+
+
+/// Reads chunks, keeping the last pack read in memory since the chunks of
+/// a file are usually stored together
+struct ChunkReader<'a> {
+    repository: &'a Repository,
+    index: HashMap<Digest, Location>,
+    pack: Option<(Digest, Vec<u8>)>,
+}
+
+
+impl ChunkReader<'_> {
+    fn read(&mut self, chunk: &Digest) -> io::Result<&[u8]> {
+        let location = *self
+            .index
+            .get(chunk)
+            .ok_or_else(|| io::Error::other(format!("missing chunk {}", hash::to_hex(chunk))))?;
+        if self.pack.as_ref().map(|(id, _)| id) != Some(&location.pack) {
+            let data = self.repository.target.read(&self.repository.pack_path(&location.pack))?;
+            self.pack = Some((location.pack, data));
+        }
+        let data = &self.pack.as_ref().unwrap().1;
+        let data = data
+            .get(location.offset..location.offset + location.length)
+            .ok_or_else(|| io::Error::other(format!("truncated pack {}", hash::to_hex(&location.pack))))?;
+        if digest(data) != *chunk {
+            return Err(io::Error::other(format!("corrupted chunk {}", hash::to_hex(chunk))));
+        }
+        Ok(data)
+    }
+}
+
+
+/// Restore a snapshot into a local directory. Returns false if anything
+/// couldn't be restored.
+pub fn restore(location: &str, name: &str, output: &str, opts: &Options) -> bool {
+    log::separator();
+    info!("Restoring snapshot {} of {} to {}", name, location, output);
+    log::separator();
+    let loaded = Repository::open(location, opts).and_then(|repository| {
+        let id = repository.find_snapshot(name)?;
+        let snapshot = repository.load_snapshot(&id)?;
+        let (index, _) = repository.load_index()?;
+        Ok((repository, snapshot, index))
+    });
+    let (repository, snapshot, index) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    if let Err(e) = fs::create_dir_all(output) {
+        error!("cannot create {}: {}", output, e);
+        return false;
+    }
+    let mut reader = ChunkReader { repository: &repository, index, pack: None };
+    let mut failed = 0;
+    for (relative, node) in &snapshot.entries {
+        let path = Path::new(output).join(relative);
+        let result = match node {
+            Node::Directory { .. } => fs::create_dir_all(&path),
+            Node::Symlink { target } => {
+                action!("Restoring {}", path.display());
+                let _ = fs::remove_file(&path);
+                std::os::unix::fs::symlink(target, &path)
+            }
+            Node::File { mode, mtime, chunks, .. } => {
+                action!("Restoring {}", path.display());
+                (|| {
+                    let mut file = fs::File::create(&path)?;
+                    for chunk in chunks {
+                        file.write_all(reader.read(chunk)?)?;
+                    }
+                    file.set_permissions(fs::Permissions::from_mode(*mode))?;
+                    file.set_modified(system_time(*mtime))
+                })()
+            }
+        };
+        if let Err(e) = result {
+            error!("cannot restore {}: {}", path.display(), e);
+            failed += 1;
+        }
+    }
+    // Restoring the contents of directories changes their times, so these
+    // are set afterwards, children first
+    for (relative, node) in snapshot.entries.iter().rev() {
+        if let Node::Directory { mode, mtime } = node {
+            let path = Path::new(output).join(relative);
+            let result = fs::set_permissions(&path, fs::Permissions::from_mode(*mode))
+                .and_then(|_| fs::File::open(&path)?.set_modified(system_time(*mtime)));
+            if let Err(e) = result {
+                warning!("cannot set the attributes of {}: {}", path.display(), e);
+            }
+        }
+    }
+    if failed > 0 {
+        error!("{} entries couldn't be restored", failed);
+    }
+    failed == 0
+}
+
+
+/// Remove snapshots from a repository (their data is only removed by gc)
+pub fn forget(location: &str, names: &[&String], opts: &Options) -> Result<(), String> {
+    let repository = Repository::open(location, opts)?;
+    let ids = names
+        .iter()
+        .map(|name| repository.find_snapshot(name))
+        .collect::<Result<Vec<String>, String>>()?;
+    for id in ids {
+        info!("Forgetting snapshot {}", &id[..8]);
+        if !opts.dry_run {
+            let path = repository.path(&format!("snapshots/{}", id));
+            repository.target.remove_file(&path).map_err(|e| format!("cannot remove {}: {}", path, e))?;
+        }
+    }
+    Ok(())
+}
+
+
+/// Remove the chunks no snapshot references anymore: packs with only such
+/// chunks are removed, and packs with some of them are rewritten
+pub fn gc(location: &str, opts: &Options) -> Result<(), String> {
+    let repository = Repository::open(location, opts)?;
+    let (index, index_names) = repository.load_index()?;
+    let mut used = HashSet::new();
+    for (_, snapshot) in repository.snapshots()? {
+        for (_, node) in snapshot.entries {
+            if let Node::File { chunks, .. } = node {
+                used.extend(chunks);
+            }
+        }
+    }
+    if let Some(chunk) = used.iter().find(|c| !index.contains_key(*c)) {
+        return Err(format!("chunk {} is missing from the repository; not collecting", hash::to_hex(chunk)));
+    }
+
+    // Chunks of each pack, and whether they are used
+    let mut packs: HashMap<Digest, Vec<(Digest, Location, bool)>> = HashMap::new();
+    for (chunk, location) in &index {
+        packs.entry(location.pack).or_default().push((*chunk, *location, used.contains(chunk)));
+    }
+    let mut packer = Packer::new(&repository, opts.dry_run);
+    let mut kept = Vec::new();
+    let mut obsolete = Vec::new();
+    let (mut removed, mut repacked) = (0, 0);
+    let mut reader = ChunkReader { repository: &repository, index: index.clone(), pack: None };
+    for (pack, mut chunks) in packs {
+        chunks.sort_by_key(|(_, location, _)| location.offset);
+        if chunks.iter().all(|(_, _, used)| *used) {
+            kept.extend(chunks.into_iter().map(|(chunk, location, _)| (chunk, location)));
+            continue;
+        }
+        if chunks.iter().any(|(_, _, used)| *used) {
+            repacked += 1;
+            for (chunk, _, _) in chunks.iter().filter(|(_, _, used)| *used) {
+                let data = reader.read(chunk).map_err(|e| e.to_string())?.to_vec();
+                packer.add(*chunk, &data).map_err(|e| format!("cannot write a pack: {}", e))?;
+            }
+        } else {
+            removed += 1;
+        }
+        obsolete.push(pack);
+    }
+    packer.flush().map_err(|e| format!("cannot write a pack: {}", e))?;
+    kept.extend(packer.written.iter().copied());
+
+    // Packs no index references, left by interrupted runs
+    let referenced: HashSet<Digest> = kept.iter().map(|(_, location)| location.pack).collect();
+    let mut stray = Vec::new();
+    for prefix in repository.target.list(&repository.path("data")).unwrap_or_default() {
+        for name in repository.names(&format!("data/{}", prefix)) {
+            let pack = hash::from_hex(&name).unwrap();
+            if !referenced.contains(&pack) && !obsolete.contains(&pack) {
+                stray.push(pack);
+            }
+        }
+    }
+
+    let mut freed = 0;
+    for pack in obsolete.iter().chain(&stray) {
+        freed += repository.target.metadata(&repository.pack_path(pack)).map_or(0, |m| m.size);
+    }
+    if !opts.dry_run && (!obsolete.is_empty() || !stray.is_empty()) {
+        // The new index is complete before the old ones and packs go
+        kept.sort_by_key(|(chunk, _)| *chunk);
+        let name = repository.save_index(&kept).map_err(|e| format!("cannot write the index: {}", e))?;
+        for old in index_names.iter().filter(|old| **old != name) {
+            let path = repository.path(&format!("index/{}", old));
+            repository.target.remove_file(&path).map_err(|e| format!("cannot remove {}: {}", path, e))?;
+        }
+        for pack in obsolete.iter().chain(&stray) {
+            let path = repository.pack_path(pack);
+            repository.target.remove_file(&path).map_err(|e| format!("cannot remove {}: {}", path, e))?;
+        }
+    }
+    info!(
+        "Removed {} unused packs and {} unreferenced ones, rewrote {}, freeing {}",
+        removed,
+        stray.len(),
+        repacked,
+        human_bytes(freed.saturating_sub(packer.bytes))
+    );
+    Ok(())
+}
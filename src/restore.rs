@@ -0,0 +1,249 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::backend::{self, Backend, Kind};
+use crate::capabilities;
+use crate::checksums::Checksums;
+use crate::hash;
+use crate::log;
+use crate::manifest;
+use crate::special::{self, Special};
+use crate::status;
+use crate::Options;
+
+
+
+/// Give a restored file the capabilities its copy was given, if any,
+/// reporting those that can't be restored
+fn restore_capabilities(target: &dyn Backend, path: &str, local: &Path) {
+    match target.capabilities(path) {
+        Ok(Some(value)) => {
+            if let Err(e) = capabilities::set(local, &value) {
+                warning!("cannot restore the capabilities of {}: {}", local.display(), e);
+            }
+        }
+        Ok(None) => (),
+        Err(e) => warning!("cannot read the capabilities of {}: {}", path, e),
+    }
+}
+
+
+/// Check a restored file against the size and hash its copy had when it
+/// was backed up, if known
+fn check(checksums: &Checksums, relative: &str, size: u64, local: &Path) -> Result<(), String> {
+    let Some((recorded, digest)) = checksums.get(relative) else {
+        return Ok(());
+    };
+    if size != recorded {
+        return Err(format!("its copy is {} bytes, but was {} when backed up", size, recorded));
+    }
+    match hash::hash_file(&local.to_string_lossy()) {
+        Ok(restored) if restored == digest => Ok(()),
+        Ok(_) => Err("it doesn't match the hash of its copy when backed up".to_string()),
+        Err(e) => Err(format!("cannot read it back: {}", e)),
+    }
+}
+
+
+/// Parse a `FROM=TO` rule of --rewrite-prefix, between absolute paths
+pub fn parse_rewrite(text: &str) -> Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((from, to)) if from.starts_with('/') && to.starts_with('/') => Ok((
+            format!("/{}", from.trim_matches('/')),
+            format!("/{}", to.trim_matches('/')),
+        )),
+        _ => Err(format!("invalid prefix rewrite '{}' (expected /FROM=/TO)", text)),
+    }
+}
+
+
+/// Where the entries of the destination go, once the prefixes of their
+/// original paths are rewritten, and what they are checked against
+struct Layout<'a> {
+    output: &'a Path,
+    /// The source the destination is a backup of
+    source: String,
+    rewrites: &'a [(String, String)],
+    checksums: Checksums,
+    /// Keep the restored files that fail their check
+    best_effort: bool,
+}
+
+
+impl Layout<'_> {
+    /// Rewrite an absolute path by the rule with the longest FROM it is in,
+    /// if any
+    fn rewrite(&self, path: &str) -> Option<String> {
+        self.rewrites
+            .iter()
+            .filter_map(|(from, to)| {
+                let rest = path.strip_prefix(from.trim_end_matches('/'))?;
+                (rest.is_empty() || rest.starts_with('/')).then(|| (from.len(), format!("{}{}", to.trim_end_matches('/'), rest)))
+            })
+            .max_by_key(|(length, _)| *length)
+            .map(|(_, rewritten)| rewritten)
+    }
+
+    /// Where to restore the entry of the destination at a relative path:
+    /// its original path in the source, rewritten, taken relative to the
+    /// source again, below the output directory
+    fn place(&self, relative: &str) -> Result<PathBuf, String> {
+        let original = format!("{}/{}", self.source.trim_end_matches('/'), relative);
+        let Some(rewritten) = self.rewrite(&original) else {
+            return Ok(self.output.join(relative));
+        };
+        match Path::new(&rewritten).strip_prefix(&self.source) {
+            Ok(rest) => Ok(self.output.join(rest)),
+            Err(_) => Err(format!("{} would be rewritten to {}, outside of {}", original, rewritten, self.source)),
+        }
+    }
+
+    /// Rewrite the target of a symlink, if absolute
+    fn link(&self, link: &Path) -> PathBuf {
+        match link.to_str().filter(|l| l.starts_with('/')).and_then(|l| self.rewrite(l)) {
+            Some(rewritten) => PathBuf::from(rewritten),
+            None => link.to_path_buf(),
+        }
+    }
+}
+
+
+/// Copy a directory of the destination back to the local filesystem,
+/// returning the number of entries that couldn't be restored
+fn restore_dir(target: &dyn Backend, path: &str, relative: &str, layout: &Layout) -> usize {
+    let mut failed = 0;
+    let mut names = match target.list(path) {
+        Ok(names) => names,
+        Err(e) => {
+            error!("cannot list {}: {}", path, e);
+            return 1;
+        }
+    };
+    names.sort();
+    for name in names {
+        if relative.is_empty() && manifest::is_meta(&name) {
+            continue;
+        }
+        let source = format!("{}/{}", path, name);
+        let child_relative = if relative.is_empty() { name } else { format!("{}/{}", relative, name) };
+        let metadata = match target.metadata(&source) {
+            Some(metadata) => metadata,
+            None => continue,
+        };
+        let destination = match layout.place(&child_relative) {
+            Ok(destination) => destination,
+            Err(e) => {
+                error!("cannot restore {}: {}", source, e);
+                failed += 1;
+                continue;
+            }
+        };
+        // The directory of an entry whose path was rewritten may not exist
+        // yet
+        if let Some(parent) = destination.parent().filter(|p| !p.exists()) {
+            if let Err(e) = fs::create_dir_all(parent) {
+                error!("cannot create {}: {}", parent.display(), e);
+                failed += 1;
+                continue;
+            }
+        }
+        let result = match metadata.kind {
+            Kind::Directory => {
+                if let Err(e) = fs::create_dir_all(&destination) {
+                    error!("cannot create {}: {}", destination.display(), e);
+                    failed += 1;
+                    continue;
+                }
+                failed += restore_dir(target, &source, &child_relative, layout);
+                continue;
+            }
+            Kind::File => {
+                action!("Restoring {}", destination.display());
+                target
+                    .fetch(&source, destination.to_str().unwrap())
+                    .and_then(|_| {
+                        fs::File::options()
+                            .write(true)
+                            .open(&destination)?
+                            .set_modified(metadata.modified)
+                    })
+                    .inspect(|_| restore_capabilities(target, &source, &destination))
+                    .and_then(|_| match check(&layout.checksums, &child_relative, metadata.size, &destination) {
+                        Ok(()) => Ok(()),
+                        Err(e) if layout.best_effort => {
+                            error!("{} is corrupt: {}; restored anyway (--best-effort)", destination.display(), e);
+                            failed += 1;
+                            Ok(())
+                        }
+                        Err(e) => {
+                            let _ = fs::remove_file(&destination);
+                            Err(std::io::Error::other(format!("corrupt backup: {}", e)))
+                        }
+                    })
+            }
+            Kind::Symlink => {
+                action!("Restoring {}", destination.display());
+                let _ = fs::remove_file(&destination);
+                match &metadata.target {
+                    Some(link) => std::os::unix::fs::symlink(layout.link(link), &destination),
+                    None => Err(std::io::Error::other("unreadable symlink target")),
+                }
+            }
+            // Special files recreated with --devices and --specials, which
+            // only local mirrors tell the kind of
+            Kind::Other => match target.local_file(&source).and_then(|local| fs::symlink_metadata(local).ok()) {
+                Some(copy) if Special::of(&copy).is_some() => {
+                    action!("Restoring {}", destination.display());
+                    let _ = fs::remove_file(&destination);
+                    special::create(destination.to_str().unwrap(), copy.mode(), copy.rdev())
+                }
+                _ => continue,
+            },
+        };
+        if let Err(e) = result {
+            error!("cannot restore {}: {}", source, e);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+
+/// Restore the whole destination into a local directory, decrypting it if
+/// needed. The prefixes of the original paths of the entries (and of the
+/// absolute targets of symlinks) are rewritten by `rewrites`. Returns false
+/// if anything couldn't be restored.
+pub fn restore(destination: &str, output: &str, rewrites: &[(String, String)], opts: &Options) -> bool {
+    log::separator();
+    info!("Restoring {} to {}", destination, output);
+    log::separator();
+    let (target, destination) = match backend::open(destination, opts) {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    if let Err(e) = fs::create_dir_all(output) {
+        error!("cannot create {}: {}", output, e);
+        return false;
+    }
+    // Paths are only known relative to the source, which the status of the
+    // last run records
+    let source = match status::source(target.as_ref(), &destination) {
+        Some(source) => source,
+        None if rewrites.is_empty() => String::from("/"),
+        None => {
+            error!("{} doesn't record its source, so its paths can't be rewritten", destination);
+            return false;
+        }
+    };
+    let checksums = Checksums::load(target.as_ref(), &destination);
+    let layout = Layout { output: Path::new(output), source, rewrites, checksums, best_effort: opts.best_effort };
+    let failed = restore_dir(target.as_ref(), &destination, "", &layout);
+    if failed > 0 {
+        error!("{} entries couldn't be restored", failed);
+    }
+    failed == 0
+}
@@ -0,0 +1,57 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use crate::Options;
+
+
+
+/// How long to wait before trying again, without `--retry-delay`
+pub const DEFAULT_DELAY: Duration = Duration::from_secs(1);
+
+
+const EIO: i32 = 5;
+
+
+/// Whether an error may well go away if the operation is tried again:
+/// files in use (locked, or busy), and network file systems and links
+/// that stall, drop or go stale for a moment
+pub fn transient(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    // A mount that stops answering (a soft NFS mount times out) fails
+    // with EIO
+    matches!(
+        e.kind(),
+        Interrupted
+            | WouldBlock
+            | TimedOut
+            | ResourceBusy
+            | ExecutableFileBusy
+            | StaleNetworkFileHandle
+            | ConnectionReset
+            | ConnectionAborted
+            | BrokenPipe
+            | NetworkDown
+            | NetworkUnreachable
+            | HostUnreachable
+    ) || e.raw_os_error() == Some(EIO)
+}
+
+
+/// Do `operation` (`what` on `path`, e.g. copying it), trying it again up
+/// to `--retries` times as long as it fails with a transient error, so
+/// that only what fails every time is reported as failed
+pub fn retrying<T>(what: &str, path: &str, opts: &Options, mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let delay = opts.retry_delay.unwrap_or(DEFAULT_DELAY);
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(e) if attempt < opts.retries && transient(&e) => {
+                attempt += 1;
+                warning!("cannot {} {}: {}; trying again ({} of {})", what, path, e, attempt, opts.retries);
+                thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
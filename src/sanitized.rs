@@ -0,0 +1,312 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::backend::{Backend, Metadata};
+use crate::manifest::{self, META_DIR};
+use crate::Options;
+
+
+
+/// File, inside META_DIR, with the original names of the entries stored
+/// under other names
+const NAMES: &str = "names";
+const NAMES_HEADER: &str = "backup-rs names 1";
+
+
+/// Characters that FAT, exFAT and NTFS (as Windows and SMB shares use it)
+/// can't hold in names
+const INVALID: &str = "<>:\"\\|?*";
+
+
+/// Names Windows keeps for devices, whatever their extension
+const RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1",
+    "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+
+fn names_path(root: &str) -> String {
+    format!("{}/{}/{}", root, META_DIR, NAMES)
+}
+
+
+/// The fullwidth form of a printable ASCII character (`：` for `:`)
+fn fullwidth(c: char) -> char {
+    char::from_u32(c as u32 + 0xfee0).unwrap_or(c)
+}
+
+
+/// A name as FAT, exFAT and NTFS can hold it: the characters they can't
+/// are replaced by their fullwidth forms (`：` for `:`), control characters
+/// by their symbols (`␊` for a newline) and a trailing dot or space by `．`
+/// or `␠`, and the first letter of a device name (`CON`, `aux.txt`) by its
+/// fullwidth form
+fn sanitize(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            c if INVALID.contains(c) => fullwidth(c),
+            c if (c as u32) < 0x20 => char::from_u32(c as u32 + 0x2400).unwrap_or(c),
+            c => c,
+        })
+        .collect();
+    match sanitized.pop() {
+        Some('.') => sanitized.push('．'),
+        Some(' ') => sanitized.push('␠'),
+        Some(c) => sanitized.push(c),
+        None => (),
+    }
+    let stem = sanitized.split('.').next().unwrap_or_default();
+    if RESERVED.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        let first = sanitized.remove(0);
+        sanitized.insert(0, fullwidth(first));
+    }
+    sanitized
+}
+
+
+/// Store the entries whose names the destination can't hold (e.g. a USB
+/// stick or an SMB share) under sanitized names, recording the original
+/// ones so that listings (and so restores) give them back
+pub struct SanitizedBackend {
+    inner: Box<dyn Backend>,
+    root: String,
+    /// Path in the storage (relative to the root) -> original name, for
+    /// the entries stored under another name
+    names: RefCell<BTreeMap<String, String>>,
+    changed: Cell<bool>,
+}
+
+
+/// Wrap the storage of a destination if it has (or is to get) entries
+/// stored under sanitized names
+pub fn wrap(inner: Box<dyn Backend>, root: &str, opts: &Options) -> Result<Box<dyn Backend>, String> {
+    let content = inner
+        .read(&names_path(root))
+        .ok()
+        .map(|data| String::from_utf8_lossy(&data).to_string());
+    if content.is_none() && !opts.sanitize_names {
+        return Ok(inner);
+    }
+    let mut names = BTreeMap::new();
+    if let Some(content) = content {
+        let mut lines = content.lines();
+        if lines.next() != Some(NAMES_HEADER) {
+            return Err(format!("invalid {}", names_path(root)));
+        }
+        for line in lines {
+            if let Some((stored, original)) = line.split_once('\t') {
+                names.insert(manifest::unescape(stored), manifest::unescape(original));
+            }
+        }
+    }
+    Ok(Box::new(SanitizedBackend {
+        inner,
+        root: root.to_string(),
+        names: RefCell::new(names),
+        changed: Cell::new(false),
+    }))
+}
+
+
+impl SanitizedBackend {
+    fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(&self.root)?.strip_prefix('/')
+    }
+
+    /// Path of an entry inside the underlying storage, failing if it would
+    /// be that of another entry (e.g. `a:` once `a：` is stored)
+    fn translate(&self, path: &str) -> io::Result<String> {
+        let Some(relative) = self.relative(path) else {
+            return Ok(path.to_string());
+        };
+        let names = self.names.borrow();
+        let mut translated = self.root.clone();
+        for name in relative.split('/') {
+            let sanitized = sanitize(name);
+            translated.push('/');
+            translated.push_str(&sanitized);
+            let stored = &translated[self.root.len() + 1..];
+            let taken = match names.get(stored) {
+                Some(original) => original != name,
+                None => sanitized != name && self.inner.metadata(&translated).is_some(),
+            };
+            if taken {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} would be stored as {}, which another entry is", name, sanitized),
+                ));
+            }
+        }
+        Ok(translated)
+    }
+
+    /// Record the original names of the entries of a path about to be
+    /// written, returning its path inside the underlying storage
+    fn claim(&self, path: &str) -> io::Result<String> {
+        let translated = self.translate(path)?;
+        if let (Some(relative), Some(stored)) = (self.relative(path), self.relative(&translated)) {
+            let mut names = self.names.borrow_mut();
+            let mut prefix = 0;
+            for (name, sanitized) in relative.split('/').zip(stored.split('/')) {
+                prefix += sanitized.len();
+                if name != sanitized && !names.contains_key(&stored[..prefix]) {
+                    names.insert(stored[..prefix].to_string(), name.to_string());
+                    self.changed.set(true);
+                }
+                prefix += 1;
+            }
+        }
+        Ok(translated)
+    }
+
+    /// Forget the original names of the entries of the storage at and
+    /// below `stored` (relative to the root), returning those below it
+    fn forget(&self, stored: &str) -> Vec<(String, String)> {
+        let below = format!("{}/", stored);
+        let mut names = self.names.borrow_mut();
+        let forgotten: Vec<String> = names.keys().filter(|key| *key == stored || key.starts_with(&below)).cloned().collect();
+        let mut moved = Vec::new();
+        for key in forgotten {
+            let original = names.remove(&key).unwrap();
+            if let Some(rest) = key.strip_prefix(&below) {
+                moved.push((rest.to_string(), original));
+            }
+            self.changed.set(true);
+        }
+        moved
+    }
+
+    fn forget_path(&self, translated: &str) {
+        if let Some(stored) = self.relative(translated) {
+            self.forget(stored);
+        }
+    }
+}
+
+
+impl Backend for SanitizedBackend {
+    fn metadata(&self, path: &str) -> Option<Metadata> {
+        self.inner.metadata(&self.translate(path).ok()?)
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let translated = self.translate(path)?;
+        let names = self.inner.list(&translated)?;
+        let prefix = match self.relative(&translated) {
+            Some(stored) => format!("{}/", stored),
+            None if translated == self.root => String::new(),
+            None => return Ok(names),
+        };
+        let originals = self.names.borrow();
+        Ok(names
+            .into_iter()
+            .map(|name| originals.get(&format!("{}{}", prefix, name)).cloned().unwrap_or(name))
+            .collect())
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.inner.create_dir(&self.claim(path)?)
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        let translated = self.translate(path)?;
+        self.inner.remove_file(&translated)?;
+        self.forget_path(&translated);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        let translated = self.translate(path)?;
+        self.inner.remove_dir_all(&translated)?;
+        self.forget_path(&translated);
+        Ok(())
+    }
+
+    fn copy_file(&self, source: &str, path: &str) -> io::Result<()> {
+        self.inner.copy_file(source, &self.claim(path)?)
+    }
+
+    fn symlink(&self, target: &Path, path: &str) -> io::Result<()> {
+        self.inner.symlink(target, &self.claim(path)?)
+    }
+
+    fn mknod(&self, path: &str, mode: u32, device: u64) -> io::Result<()> {
+        self.inner.mknod(&self.claim(path)?, mode, device)
+    }
+
+    fn update_file(&self, source: &str, path: &str) -> io::Result<Option<u64>> {
+        self.inner.update_file(source, &self.translate(path)?)
+    }
+
+    fn capabilities(&self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        self.inner.capabilities(&self.translate(path)?)
+    }
+
+    fn set_capabilities(&self, path: &str, value: &[u8]) -> io::Result<()> {
+        self.inner.set_capabilities(&self.translate(path)?, value)
+    }
+
+    fn set_directory_metadata(&self, path: &str, source: &fs::Metadata) -> io::Result<()> {
+        self.inner.set_directory_metadata(&self.translate(path)?, source)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let from = self.translate(from)?;
+        let translated = self.claim(to)?;
+        self.inner.rename(&from, &translated)?;
+        // The entries below it keep their names
+        if let (Some(from), Some(to)) = (self.relative(&from), self.relative(&translated)) {
+            let moved = self.forget(from);
+            let mut names = self.names.borrow_mut();
+            for (rest, original) in moved {
+                names.insert(format!("{}/{}", to, rest), original);
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&self, path: &str) -> io::Result<()> {
+        self.inner.sync(&self.translate(path)?)
+    }
+
+    fn fetch(&self, path: &str, local: &str) -> io::Result<()> {
+        self.inner.fetch(&self.translate(path)?, local)
+    }
+
+    fn local_file(&self, path: &str) -> Option<String> {
+        self.inner.local_file(&self.translate(path).ok()?)
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.inner.read(&self.translate(path)?)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.inner.write(&self.claim(path)?, data)
+    }
+
+    fn is_local(&self) -> bool {
+        self.inner.is_local()
+    }
+
+    fn small_file_size(&self) -> Option<u64> {
+        self.inner.small_file_size()
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        if self.changed.get() {
+            let mut content = format!("{}\n", NAMES_HEADER);
+            for (stored, original) in self.names.borrow().iter() {
+                writeln!(content, "{}\t{}", manifest::escape(stored), manifest::escape(original)).unwrap();
+            }
+            self.inner.write(&names_path(&self.root), content.as_bytes())?;
+            self.changed.set(false);
+        }
+        self.inner.finish()
+    }
+}
@@ -0,0 +1,272 @@
+use std::os::raw::{c_char, c_int, c_long};
+
+
+
+/// Broken-down local time, as filled in by `localtime_r()`
+#[repr(C)]
+struct Tm {
+    tm_sec: c_int,
+    tm_min: c_int,
+    tm_hour: c_int,
+    tm_mday: c_int,
+    tm_mon: c_int,
+    tm_year: c_int,
+    tm_wday: c_int,
+    tm_yday: c_int,
+    tm_isdst: c_int,
+    tm_gmtoff: c_long,
+    tm_zone: *const c_char,
+}
+
+
+extern "C" {
+    fn localtime_r(timep: *const i64, result: *mut Tm) -> *mut Tm;
+    fn mktime(tm: *mut Tm) -> i64;
+}
+
+
+/// Local calendar time of a Unix timestamp
+pub struct LocalTime {
+    pub year: i32,
+    /// 1-12
+    pub month: u32,
+    /// 1-31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// 0-6, 0 is Sunday
+    pub weekday: u32,
+}
+
+
+pub fn local_time(timestamp: i64) -> LocalTime {
+    let mut tm = std::mem::MaybeUninit::<Tm>::zeroed();
+    unsafe { localtime_r(&timestamp, tm.as_mut_ptr()) };
+    let tm = unsafe { tm.assume_init() };
+    LocalTime {
+        year: tm.tm_year + 1900,
+        month: tm.tm_mon as u32 + 1,
+        day: tm.tm_mday as u32,
+        hour: tm.tm_hour as u32,
+        minute: tm.tm_min as u32,
+        second: tm.tm_sec as u32,
+        weekday: tm.tm_wday as u32,
+    }
+}
+
+
+/// Offset of the local time from UTC at a time, in seconds east of it
+pub fn utc_offset(timestamp: i64) -> i64 {
+    let mut tm = std::mem::MaybeUninit::<Tm>::zeroed();
+    unsafe { localtime_r(&timestamp, tm.as_mut_ptr()) };
+    unsafe { tm.assume_init() }.tm_gmtoff
+}
+
+
+impl LocalTime {
+    /// Unix timestamp of a local time (the weekday is ignored)
+    pub fn timestamp(&self) -> i64 {
+        let mut tm = Tm {
+            tm_sec: self.second as c_int,
+            tm_min: self.minute as c_int,
+            tm_hour: self.hour as c_int,
+            tm_mday: self.day as c_int,
+            tm_mon: self.month as c_int - 1,
+            tm_year: self.year - 1900,
+            tm_wday: 0,
+            tm_yday: 0,
+            // Let mktime() work out whether daylight saving time applies
+            tm_isdst: -1,
+            tm_gmtoff: 0,
+            tm_zone: std::ptr::null(),
+        };
+        unsafe { mktime(&mut tm) }
+    }
+
+    /// Format as `YYYY-MM-DDTHH:MM:SS`
+    pub fn format(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+
+/// Current Unix timestamp
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+
+/// Parse a duration such as `90s`, `30m`, `12h` or `7d`, in seconds
+pub fn parse_duration(text: &str) -> Result<i64, String> {
+    let invalid = || format!("invalid duration '{}' (expected e.g. 30m, 12h or 7d)", text);
+    let split = text.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let (number, unit) = text.split_at(split);
+    let number: i64 = number.parse().map_err(|_| invalid())?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(unit).ok_or_else(invalid)
+}
+
+
+/// Parse a point in time: a local date and time, `YYYY-MM-DD` with an
+/// optional `THH:MM[:SS]` (or a space instead of the T), or a duration
+/// (as for `parse_duration`) before now
+pub fn parse_time(text: &str) -> Result<i64, String> {
+    if let Ok(ago) = parse_duration(text) {
+        return Ok(now() - ago);
+    }
+    let invalid = || format!("invalid time '{}' (expected e.g. 2024-05-01, 2024-05-01T12:30 or 7d)", text);
+    let (date, time) = text.split_once(['T', ' ']).unwrap_or((text, "00:00"));
+    let number = |n: Option<&str>| n.and_then(|n| n.parse::<u32>().ok()).ok_or_else(invalid);
+    let mut date = date.split('-');
+    let (year, month, day) = (number(date.next())?, number(date.next())?, number(date.next())?);
+    let mut time = time.split(':');
+    let (hour, minute) = (number(time.next())?, number(time.next())?);
+    let second = time.next().map_or(Ok(0), |s| number(Some(s)))?;
+    if date.next().is_some() || time.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day)
+        || hour > 23 || minute > 59 || second > 60
+    {
+        return Err(invalid());
+    }
+    let time = LocalTime { year: year as i32, month, day, hour, minute, second, weekday: 0 };
+    Ok(time.timestamp())
+}
+
+
+/// The set of allowed values of one field of a cron expression
+struct Field {
+    allowed: u64,
+    /// Whether the field was `*` (needed for the day-of-month/day-of-week rule)
+    any: bool,
+}
+
+
+impl Field {
+    fn parse(text: &str, min: u32, max: u32, name: &str) -> Result<Field, String> {
+        let mut allowed = 0u64;
+        let invalid = || format!("invalid {} field '{}'", name, text);
+        for part in text.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => match step.parse::<u32>() {
+                    Ok(s) if s > 0 => (range, s),
+                    _ => return Err(invalid()),
+                },
+                None => (part, 1),
+            };
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some((a, b)) = range.split_once('-') {
+                match (a.parse::<u32>(), b.parse::<u32>()) {
+                    (Ok(a), Ok(b)) => (a, b),
+                    _ => return Err(invalid()),
+                }
+            } else {
+                match range.parse::<u32>() {
+                    // `N/S` means from N to the maximum, every S
+                    Ok(a) if step > 1 => (a, max),
+                    Ok(a) => (a, a),
+                    Err(_) => return Err(invalid()),
+                }
+            };
+            if start < min || end > max || start > end {
+                return Err(format!(
+                    "{} field '{}' out of range ({}-{})",
+                    name, text, min, max
+                ));
+            }
+            let mut value = start;
+            while value <= end {
+                allowed |= 1 << value;
+                value += step;
+            }
+        }
+        Ok(Field { allowed, any: text == "*" })
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.allowed & (1 << value) != 0
+    }
+}
+
+
+/// A cron-like schedule: `minute hour day-of-month month day-of-week`
+pub struct Schedule {
+    minute: Field,
+    hour: Field,
+    day: Field,
+    month: Field,
+    weekday: Field,
+}
+
+
+impl Schedule {
+    pub fn parse(text: &str) -> Result<Schedule, String> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "invalid schedule '{}': expected 5 fields (minute hour \
+                day-of-month month day-of-week)",
+                text
+            ));
+        }
+        let mut weekday = Field::parse(fields[4], 0, 7, "day-of-week")?;
+        // Both 0 and 7 are Sunday
+        if weekday.matches(7) {
+            weekday.allowed |= 1;
+        }
+        Ok(Schedule {
+            minute: Field::parse(fields[0], 0, 59, "minute")?,
+            hour: Field::parse(fields[1], 0, 23, "hour")?,
+            day: Field::parse(fields[2], 1, 31, "day-of-month")?,
+            month: Field::parse(fields[3], 1, 12, "month")?,
+            weekday,
+        })
+    }
+
+    fn day_matches(&self, t: &LocalTime) -> bool {
+        let day = self.day.matches(t.day);
+        let weekday = self.weekday.matches(t.weekday);
+        // As in cron, if both day fields are restricted, either may match
+        if !self.day.any && !self.weekday.any {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// First time strictly after `after` at which the schedule fires, if any
+    /// within the next few years
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        let mut t = after - after.rem_euclid(60) + 60;
+        let limit = after + 5 * 366 * 24 * 3600;
+        while t < limit {
+            let local = local_time(t);
+            if !self.month.matches(local.month) || !self.day_matches(&local) {
+                // Skip to the next day
+                t += (((23 - local.hour) * 60 + 60 - local.minute) * 60) as i64;
+            } else if !self.hour.matches(local.hour) {
+                // Skip to the next hour
+                t += ((60 - local.minute) * 60) as i64;
+            } else if !self.minute.matches(local.minute) {
+                t += 60;
+            } else {
+                return Some(t);
+            }
+            // Stay aligned to the minute across DST/leap second changes
+            t -= t.rem_euclid(60);
+        }
+        None
+    }
+}
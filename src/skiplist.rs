@@ -0,0 +1,146 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::path::Path;
+
+use crate::backend::Backend;
+use crate::manifest::{self, META_DIR};
+use crate::schedule;
+
+
+
+/// File, inside META_DIR, with the paths of the source (relative to it, one
+/// per line, `#` starting a comment) that runs leave alone, keeping their
+/// copies as they are. Whoever looks after the backup can edit it; paths
+/// are also added to it once they fail in enough runs in a row.
+const SKIP_LIST: &str = "skip-list";
+
+/// File, inside META_DIR, with the paths of the source that failed in the
+/// last runs, and in how many in a row
+const FAILURES: &str = "failures";
+const FAILURES_HEADER: &str = "backup-rs failures 1";
+
+/// Runs in a row a path has to fail in to be added to the skip-list
+const THRESHOLD: u32 = 3;
+
+
+pub fn path(destination: &str) -> String {
+    format!("{}/{}/{}", destination, META_DIR, SKIP_LIST)
+}
+
+
+fn failures_path(destination: &str) -> String {
+    format!("{}/{}/{}", destination, META_DIR, FAILURES)
+}
+
+
+/// The paths of the source known to be bad (e.g. a broken NFS export or a
+/// FUSE mount that hangs), bypassed instead of stalling or failing every
+/// run, and the failures that may add more of them
+pub struct SkipList {
+    destination: String,
+    source: String,
+    skipped: BTreeSet<String>,
+    /// Relative path -> runs in a row it failed in, as of the last run
+    previous: BTreeMap<String, u32>,
+    /// The paths that failed in this run
+    failed: RefCell<BTreeSet<String>>,
+}
+
+
+impl SkipList {
+    pub fn load(target: &dyn Backend, destination: &str, source: &str) -> SkipList {
+        let mut skipped = BTreeSet::new();
+        if let Ok(content) = target.read(&path(destination)) {
+            for line in String::from_utf8_lossy(&content).lines() {
+                let line = line.trim().trim_matches('/');
+                if !line.is_empty() && !line.starts_with('#') {
+                    skipped.insert(line.to_string());
+                }
+            }
+        }
+        let mut previous = BTreeMap::new();
+        if let Ok(content) = target.read(&failures_path(destination)) {
+            let content = String::from_utf8_lossy(&content);
+            let mut lines = content.lines();
+            if lines.next() == Some(FAILURES_HEADER) {
+                for line in lines {
+                    if let Some((runs, relative)) = line.split_once('\t') {
+                        if let Ok(runs) = runs.parse() {
+                            previous.insert(manifest::unescape(relative), runs);
+                        }
+                    }
+                }
+            }
+        }
+        SkipList {
+            destination: destination.to_string(),
+            source: source.trim_end_matches('/').to_string(),
+            skipped,
+            previous,
+            failed: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let relative = path.strip_prefix(&self.source)?;
+        relative.starts_with('/').then(|| relative.trim_start_matches('/'))
+    }
+
+    /// Whether a path of the source is in the skip-list, or inside a
+    /// directory that is
+    pub fn skips(&self, path: &str) -> bool {
+        self.relative(path).is_some_and(|relative| {
+            Path::new(relative).ancestors().any(|a| self.skipped.contains(a.to_string_lossy().as_ref()))
+        })
+    }
+
+    /// Record that a path of the source couldn't be backed up
+    pub fn failed(&self, path: &str) {
+        if let Some(relative) = self.relative(path) {
+            self.failed.borrow_mut().insert(relative.to_string());
+        }
+    }
+
+    /// The paths (relative to the source) that failed in this run
+    pub fn failures(&self) -> Vec<String> {
+        self.failed.borrow().iter().cloned().collect()
+    }
+
+    /// Write the failures of this run, counting the runs in a row each path
+    /// failed in, and add to the skip-list those that failed in enough of
+    /// them. A path that didn't fail starts over.
+    pub fn save(&self, target: &dyn Backend) -> std::io::Result<()> {
+        let failed = self.failed.borrow();
+        if failed.is_empty() && self.previous.is_empty() {
+            return Ok(());
+        }
+        let mut content = format!("{}\n", FAILURES_HEADER);
+        let mut added = String::new();
+        for relative in failed.iter() {
+            let runs = self.previous.get(relative).copied().unwrap_or(0) + 1;
+            if runs < THRESHOLD {
+                writeln!(content, "{}\t{}", runs, manifest::escape(relative)).unwrap();
+                continue;
+            }
+            warning!(
+                "{}/{} failed in {} runs in a row; skipping it from now on (see {})",
+                self.source,
+                relative,
+                runs,
+                path(&self.destination)
+            );
+            let today = schedule::local_time(schedule::now()).format();
+            writeln!(added, "# failed in {} runs in a row, as of {}\n{}", runs, today, relative).unwrap();
+        }
+        if !added.is_empty() {
+            let mut list = target.read(&path(&self.destination)).unwrap_or_default();
+            if !list.is_empty() && !list.ends_with(b"\n") {
+                list.push(b'\n');
+            }
+            list.extend_from_slice(added.as_bytes());
+            target.write(&path(&self.destination), &list)?;
+        }
+        target.write(&failures_path(&self.destination), content.as_bytes())
+    }
+}
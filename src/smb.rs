@@ -0,0 +1,404 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backend::{Backend, Kind, Metadata};
+use crate::schedule::LocalTime;
+use crate::ssh::parent_and_name;
+use crate::temporary;
+use crate::throttle;
+
+
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+
+/// Quote an argument of an smbclient command. smbclient splits the command
+/// line at every `;` and has no way to escape quotes, so names holding
+/// either can't be passed to it (Windows doesn't allow `"` anyway).
+fn quote(text: &str) -> io::Result<String> {
+    if text.contains(';') || text.contains('"') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} can't be passed to smbclient", text),
+        ));
+    }
+    Ok(format!("\"{}\"", text))
+}
+
+
+/// Quote a path of the share, which uses backslashes as separators
+fn quote_remote(path: &str) -> io::Result<String> {
+    quote(&path.replace('/', "\\"))
+}
+
+
+/// Parse a line of a listing, `  NAME  ATTRIBUTES  SIZE  Www Mmm dd hh:mm:ss yyyy`,
+/// into a name and its metadata. The name is padded with spaces, so the
+/// fields are taken from the end.
+fn parse_entry(line: &str) -> Option<(String, Metadata)> {
+    let mut rest = line.strip_prefix("  ")?;
+    let mut fields = Vec::new();
+    for _ in 0..7 {
+        let (before, field) = rest.trim_end().rsplit_once(' ')?;
+        fields.push(field);
+        rest = before;
+    }
+    let name = rest.trim_end();
+    let (year, time, day, month, _weekday, size, attributes) =
+        (fields[0], fields[1], fields[2], fields[3], fields[4], fields[5], fields[6]);
+    if name.is_empty() || !attributes.chars().all(|c| c.is_ascii_uppercase()) {
+        return None;
+    }
+    let mut time = time.split(':').map(|t| t.parse::<u32>());
+    let (Some(Ok(hour)), Some(Ok(minute)), Some(Ok(second))) = (time.next(), time.next(), time.next()) else {
+        return None;
+    };
+    let local = LocalTime {
+        year: year.parse().ok()?,
+        month: MONTHS.iter().position(|m| *m == month)? as u32 + 1,
+        day: day.parse().ok()?,
+        hour,
+        minute,
+        second,
+        weekday: 0,
+    };
+    let metadata = Metadata {
+        kind: if attributes.contains('D') { Kind::Directory } else { Kind::File },
+        size: size.parse().ok()?,
+        modified: UNIX_EPOCH + Duration::from_secs(local.timestamp().max(0) as u64),
+        target: None,
+    };
+    Some((name.to_string(), metadata))
+}
+
+
+/// A destination on an SMB/CIFS share (e.g. of a Windows machine), reached
+/// with smbclient, from Samba, so that the share doesn't have to be mounted.
+///
+/// Paths are those inside the share, such as `/backups/home`. As with SSH,
+/// the destination tree is listed once when it is opened and the listing is
+/// kept up to date as the backup modifies it.
+pub struct SmbBackend {
+    /// `//HOST/SHARE`
+    service: String,
+    user: Option<String>,
+    entries: RefCell<HashMap<String, Metadata>>,
+    children: RefCell<HashMap<String, BTreeSet<String>>>,
+}
+
+
+impl SmbBackend {
+    /// Open a `smb://[USER@]HOST/SHARE[/PATH]` destination, returning the
+    /// storage and the path of the destination in the share
+    pub fn new(url: &str) -> Result<(SmbBackend, String), String> {
+        let invalid = || format!("invalid SMB destination '{}' (expected smb://[USER@]HOST/SHARE[/PATH])", url);
+        let rest = url.strip_prefix("smb://").ok_or_else(invalid)?;
+        let (authority, rest) = rest.split_once('/').ok_or_else(invalid)?;
+        let (user, host) = match authority.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, authority),
+        };
+        let (share, path) = rest.split_once('/').unwrap_or((rest, ""));
+        if host.is_empty() || share.is_empty() {
+            return Err(invalid());
+        }
+        if user.is_some() && std::env::var_os("PASSWD").is_none() {
+            return Err(format!(
+                "set PASSWD to the password of {} on {}",
+                user.as_deref().unwrap(),
+                host
+            ));
+        }
+        let root = match path.trim_end_matches('/') {
+            "" => "/".to_string(),
+            path => format!("/{}", path.trim_start_matches('/')),
+        };
+        let backend = SmbBackend {
+            service: format!("//{}/{}", host, share),
+            user,
+            entries: RefCell::new(HashMap::new()),
+            children: RefCell::new(HashMap::new()),
+        };
+        backend
+            .load(&root)
+            .map_err(|e| format!("cannot list {}{}: {}", backend.service, root, e))?;
+        Ok((backend, root))
+    }
+
+    /// Run smbclient commands on the share, returning what they printed
+    fn run(&self, commands: &[String]) -> io::Result<String> {
+        let mut command = Command::new("smbclient");
+        command.arg(&self.service);
+        // The password comes from PASSWD, which smbclient reads itself
+        match &self.user {
+            Some(user) => command.arg("-U").arg(user),
+            None => command.arg("-N"),
+        };
+        let output = command
+            .arg("-c")
+            .arg(commands.join("; "))
+            .stdin(Stdio::null())
+            .output()?;
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        // smbclient goes on after a command fails, reporting the NT status
+        // of the failure
+        if let Some(line) = stdout.lines().chain(stderr.lines()).find(|l| l.contains("NT_STATUS_")) {
+            let missing = ["NT_STATUS_OBJECT_NAME_NOT_FOUND", "NT_STATUS_OBJECT_PATH_NOT_FOUND", "NT_STATUS_NO_SUCH_FILE"];
+            // Files opened by someone else on the server, and a link that
+            // drops, are worth trying again
+            let busy = ["NT_STATUS_SHARING_VIOLATION", "NT_STATUS_FILE_LOCK_CONFLICT", "NT_STATUS_LOCK_NOT_GRANTED"];
+            let dropped = ["NT_STATUS_IO_TIMEOUT", "NT_STATUS_CONNECTION_RESET", "NT_STATUS_CONNECTION_DISCONNECTED"];
+            let any = |statuses: &[&str]| statuses.iter().any(|status| line.contains(status));
+            let kind = if any(&missing) {
+                io::ErrorKind::NotFound
+            } else if any(&busy) {
+                io::ErrorKind::ResourceBusy
+            } else if any(&dropped) {
+                io::ErrorKind::ConnectionReset
+            } else {
+                io::ErrorKind::Other
+            };
+            return Err(io::Error::new(kind, line.trim().to_string()));
+        }
+        if !output.status.success() {
+            return Err(io::Error::other(match stderr.trim() {
+                "" => "smbclient failed".to_string(),
+                message => message.to_string(),
+            }));
+        }
+        Ok(stdout)
+    }
+
+    /// List the whole tree below the root of the destination
+    fn load(&self, root: &str) -> io::Result<()> {
+        if root == "/" {
+            self.entries.borrow_mut().insert(root.to_string(), Metadata {
+                kind: Kind::Directory,
+                size: 0,
+                modified: UNIX_EPOCH,
+                target: None,
+            });
+        } else {
+            match self.run(&[format!("ls {}", quote_remote(root)?)]) {
+                Ok(output) => match output.lines().find_map(parse_entry) {
+                    Some((_, metadata)) => {
+                        let kind = metadata.kind;
+                        self.entries.borrow_mut().insert(root.to_string(), metadata);
+                        if kind != Kind::Directory {
+                            return Ok(());
+                        }
+                    }
+                    None => return Ok(()),
+                },
+                // The destination doesn't exist yet
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        let pattern = if root == "/" { "/*".to_string() } else { format!("{}/*", root) };
+        let output = self.run(&["recurse ON".to_string(), format!("ls {}", quote_remote(&pattern)?)])?;
+        // The entries of each subdirectory follow a line with its path in
+        // the share
+        let mut directory = root.to_string();
+        for line in output.lines() {
+            if line.starts_with('\\') {
+                directory = line.trim_end().trim_end_matches('\\').replace('\\', "/");
+                continue;
+            }
+            if let Some((name, metadata)) = parse_entry(line) {
+                if name == "." || name == ".." {
+                    continue;
+                }
+                self.insert(&join(&directory, &name), metadata);
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&self, path: &str, metadata: Metadata) {
+        let (parent, name) = parent_and_name(path);
+        self.children.borrow_mut().entry(parent).or_default().insert(name);
+        self.entries.borrow_mut().insert(path.to_string(), metadata);
+    }
+
+    fn forget(&self, path: &str) {
+        let (parent, name) = parent_and_name(path);
+        if let Some(names) = self.children.borrow_mut().get_mut(&parent) {
+            names.remove(&name);
+        }
+        let prefix = format!("{}/", path);
+        self.entries
+            .borrow_mut()
+            .retain(|p, _| p != path && !p.starts_with(&prefix));
+        self.children
+            .borrow_mut()
+            .retain(|p, _| p != path && !p.starts_with(&prefix));
+    }
+
+    /// Upload a local file through a temporary file, so that an interrupted
+    /// transfer never leaves a truncated file behind
+    fn put(&self, local: &str, path: &str) -> io::Result<()> {
+        let temporary = temporary::beside(path);
+        self.run(&[
+            format!("put {} {}", quote(local)?, quote_remote(&temporary)?),
+            format!("rename {} {} -f", quote_remote(&temporary)?, quote_remote(path)?),
+        ])
+        .map(|_| ())
+    }
+
+    fn insert_file(&self, path: &str, size: u64) {
+        self.insert(path, Metadata {
+            kind: Kind::File,
+            size,
+            modified: SystemTime::now(),
+            target: None,
+        });
+    }
+}
+
+
+fn join(directory: &str, name: &str) -> String {
+    if directory == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", directory, name)
+    }
+}
+
+
+/// A path of the share, as built by appending to the root of the share
+/// (`/` then `//NAME`)
+fn in_share(path: &str) -> &str {
+    match path.strip_prefix('/') {
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
+
+impl Backend for SmbBackend {
+    fn metadata(&self, path: &str) -> Option<Metadata> {
+        let path = in_share(path);
+        self.entries.borrow().get(path).cloned()
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let path = in_share(path);
+        match self.children.borrow().get(path) {
+            Some(names) => Ok(names.iter().cloned().collect()),
+            None if self.metadata(path).map(|m| m.kind) == Some(Kind::Directory) => {
+                Ok(Vec::new())
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such directory")),
+        }
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        let path = in_share(path);
+        self.run(&[format!("mkdir {}", quote_remote(path)?)])?;
+        self.insert(path, Metadata {
+            kind: Kind::Directory,
+            size: 0,
+            modified: SystemTime::now(),
+            target: None,
+        });
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        let path = in_share(path);
+        self.run(&[format!("del {}", quote_remote(path)?)])?;
+        self.forget(path);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        let path = in_share(path);
+        self.run(&[format!("deltree {}", quote_remote(path)?)])?;
+        self.forget(path);
+        Ok(())
+    }
+
+    fn copy_file(&self, source: &str, path: &str) -> io::Result<()> {
+        let path = in_share(path);
+        let size = fs::metadata(source)?.len();
+        // smbclient reads the file itself, so the copies are paced a whole
+        // file at a time
+        throttle::take(size);
+        self.put(source, path)?;
+        self.insert_file(path, size);
+        Ok(())
+    }
+
+    fn symlink(&self, _target: &Path, _path: &str) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "SMB shares can't hold symlinks"))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (in_share(from), in_share(to));
+        self.run(&[format!("rename {} {}", quote_remote(from)?, quote_remote(to)?)])?;
+        let prefix = format!("{}/", from);
+        let moved = |p: &str| match p.strip_prefix(&prefix) {
+            Some(rest) => Some(format!("{}/{}", to, rest)),
+            None if p == from => Some(to.to_string()),
+            None => None,
+        };
+        let entries: Vec<(String, Metadata)> = self
+            .entries
+            .borrow()
+            .iter()
+            .filter_map(|(p, m)| Some((moved(p)?, m.clone())))
+            .collect();
+        self.forget(from);
+        for (path, metadata) in entries {
+            self.insert(&path, metadata);
+        }
+        Ok(())
+    }
+
+    fn fetch(&self, path: &str, local: &str) -> io::Result<()> {
+        let path = in_share(path);
+        self.run(&[format!("get {} {}", quote_remote(path)?, quote(local)?)]).map(|_| ())
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let path = in_share(path);
+        if self.metadata(path).is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        }
+        let local = temporary::local("smb");
+        let result = self.fetch(path, &local).and_then(|_| fs::read(&local));
+        let _ = fs::remove_file(&local);
+        result
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let path = in_share(path);
+        // Create the missing parent directories, outermost first
+        let mut missing = Vec::new();
+        let mut directory = parent_and_name(path).0;
+        while self.metadata(&directory).is_none() && directory != "/" {
+            missing.push(directory.clone());
+            directory = parent_and_name(&directory).0;
+        }
+        for directory in missing.into_iter().rev() {
+            self.create_dir(&directory)?;
+        }
+        let local = temporary::local("smb");
+        let result = fs::write(&local, data).and_then(|_| self.put(&local, path));
+        let _ = fs::remove_file(&local);
+        result?;
+        self.insert_file(path, data.len() as u64);
+        Ok(())
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+}
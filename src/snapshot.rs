@@ -0,0 +1,198 @@
+use std::process::Command;
+
+use crate::manifest::META_DIR;
+use crate::schedule::{self, LocalTime};
+
+
+
+/// Filesystem used to snapshot the destination after a backup
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnapshotKind {
+    /// The destination is a btrfs subvolume; read-only snapshots are created
+    /// in DESTINATION/.backup-rs/snapshots
+    Btrfs,
+    /// The destination is the mountpoint of a ZFS dataset; snapshots are
+    /// named DATASET@backup-rs-TIMESTAMP
+    Zfs,
+}
+
+
+impl SnapshotKind {
+    pub fn parse(text: &str) -> Result<SnapshotKind, String> {
+        match text {
+            "btrfs" => Ok(SnapshotKind::Btrfs),
+            "zfs" => Ok(SnapshotKind::Zfs),
+            _ => Err(format!(
+                "invalid snapshot type '{}' (expected btrfs or zfs)",
+                text
+            )),
+        }
+    }
+}
+
+
+/// Run a command, turning a failure into an error message
+fn run(command: &mut Command) -> Result<String, String> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command
+        .output()
+        .map_err(|e| format!("cannot run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+
+/// Create a read-only snapshot of the destination, returning its name
+pub fn create(kind: SnapshotKind, destination: &str) -> Result<String, String> {
+    let name = schedule::local_time(schedule::now()).format();
+    match kind {
+        SnapshotKind::Btrfs => {
+            let directory = format!("{}/{}/snapshots", destination, META_DIR);
+            std::fs::create_dir_all(&directory)
+                .map_err(|e| format!("cannot create {}: {}", directory, e))?;
+            let snapshot = format!("{}/{}", directory, name);
+            run(Command::new("btrfs")
+                .args(["subvolume", "snapshot", "-r", destination, &snapshot]))?;
+            Ok(snapshot)
+        }
+        SnapshotKind::Zfs => {
+            // Find the dataset mounted at the destination
+            let dataset = run(Command::new("zfs")
+                .args(["list", "-H", "-o", "name", destination]))?;
+            let snapshot = format!("{}@backup-rs-{}", dataset, name);
+            run(Command::new("zfs").args(["snapshot", &snapshot]))?;
+            Ok(snapshot)
+        }
+    }
+}
+
+
+/// A snapshot of the destination made by `create()`
+pub struct Snapshot {
+    /// Path of a btrfs snapshot, or name of a ZFS one
+    pub id: String,
+    /// When it was taken, from its name
+    pub time: LocalTime,
+}
+
+
+impl Snapshot {
+    /// Name of the snapshot, as created: when it was taken
+    pub fn name(&self) -> String {
+        self.time.format()
+    }
+}
+
+
+/// Filesystem the snapshots of a local destination are made with, unless
+/// known: btrfs if it has any, ZFS otherwise
+pub fn detect(destination: &str, kind: Option<SnapshotKind>) -> SnapshotKind {
+    kind.unwrap_or(match std::path::Path::new(destination).join(META_DIR).join("snapshots").exists() {
+        true => SnapshotKind::Btrfs,
+        false => SnapshotKind::Zfs,
+    })
+}
+
+
+/// Refuse destinations that aren't local, which can't be snapshotted
+pub fn check_local(destination: &str) -> Result<(), String> {
+    match destination.starts_with("smb://") || crate::backend::parse_remote(destination).is_some() {
+        true => Err("snapshots are only supported for local destinations".to_string()),
+        false => Ok(()),
+    }
+}
+
+
+/// Directory where the contents of a snapshot can be read
+pub fn directory(kind: SnapshotKind, destination: &str, snapshot: &Snapshot) -> String {
+    match kind {
+        SnapshotKind::Btrfs => snapshot.id.clone(),
+        SnapshotKind::Zfs => format!("{}/.zfs/snapshot/backup-rs-{}", destination, snapshot.name()),
+    }
+}
+
+
+/// Parse the `YYYY-MM-DDTHH:MM:SS` names of snapshots
+fn parse_time(name: &str) -> Option<LocalTime> {
+    let (date, time) = name.split_once('T')?;
+    let date: Vec<u32> = date.split('-').map(|n| n.parse().ok()).collect::<Option<_>>()?;
+    let time: Vec<u32> = time.split(':').map(|n| n.parse().ok()).collect::<Option<_>>()?;
+    match (&date[..], &time[..]) {
+        (&[year, month, day], &[hour, minute, second]) => Some(LocalTime {
+            year: year as i32,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+            weekday: 0,
+        }),
+        _ => None,
+    }
+}
+
+
+/// The snapshots of the destination, oldest first
+pub fn list(kind: SnapshotKind, destination: &str) -> Result<Vec<Snapshot>, String> {
+    let mut snapshots: Vec<Snapshot> = match kind {
+        SnapshotKind::Btrfs => {
+            let directory = format!("{}/{}/snapshots", destination, META_DIR);
+            let entries = match std::fs::read_dir(&directory) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(format!("cannot list {}: {}", directory, e)),
+            };
+            entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| {
+                    let time = parse_time(&e.file_name().to_string_lossy())?;
+                    Some(Snapshot { id: e.path().to_string_lossy().to_string(), time })
+                })
+                .collect()
+        }
+        SnapshotKind::Zfs => {
+            let dataset = run(Command::new("zfs")
+                .args(["list", "-H", "-o", "name", destination]))?;
+            run(Command::new("zfs")
+                .args(["list", "-H", "-t", "snapshot", "-o", "name", &dataset]))?
+                .lines()
+                .filter_map(|name| {
+                    let time = parse_time(name.strip_prefix(&format!("{}@backup-rs-", dataset))?)?;
+                    Some(Snapshot { id: name.to_string(), time })
+                })
+                .collect()
+        }
+    };
+    snapshots.sort_by_key(|s| s.time.timestamp());
+    Ok(snapshots)
+}
+
+
+/// The space only a snapshot uses, which removing it frees
+pub fn exclusive_size(kind: SnapshotKind, snapshot: &Snapshot) -> Option<u64> {
+    let output = match kind {
+        SnapshotKind::Btrfs => {
+            // Total, exclusive and shared sizes, below a header
+            let output = run(Command::new("btrfs").args(["filesystem", "du", "-s", "--raw", &snapshot.id])).ok()?;
+            output.lines().nth(1)?.split_whitespace().nth(1)?.to_string()
+        }
+        SnapshotKind::Zfs => run(Command::new("zfs").args(["list", "-Hp", "-o", "used", &snapshot.id])).ok()?,
+    };
+    output.trim().parse().ok()
+}
+
+
+/// Remove a snapshot of the destination
+pub fn remove(kind: SnapshotKind, snapshot: &Snapshot) -> Result<(), String> {
+    match kind {
+        SnapshotKind::Btrfs => run(Command::new("btrfs").args(["subvolume", "delete", &snapshot.id])),
+        SnapshotKind::Zfs => run(Command::new("zfs").args(["destroy", &snapshot.id])),
+    }
+    .map(|_| ())
+}
@@ -0,0 +1,235 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::raw::{c_char, c_int, c_ulong};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::{Backend, Kind};
+use crate::manifest::META_DIR;
+use crate::snapshot::{self, SnapshotKind};
+use crate::stats::human_bytes;
+use crate::symlinks::Ancestors;
+use crate::unicode;
+use crate::versions;
+use crate::Options;
+
+
+
+#[repr(C)]
+struct Statvfs {
+    f_bsize: c_ulong,
+    f_frsize: c_ulong,
+    f_blocks: c_ulong,
+    f_bfree: c_ulong,
+    f_bavail: c_ulong,
+    f_files: c_ulong,
+    f_ffree: c_ulong,
+    f_favail: c_ulong,
+    f_fsid: c_ulong,
+    f_flag: c_ulong,
+    f_namemax: c_ulong,
+    f_spare: [c_int; 6],
+}
+
+
+extern "C" {
+    fn statvfs(path: *const c_char, buf: *mut Statvfs) -> c_int;
+}
+
+
+/// Space of a filesystem, in bytes
+pub struct Space {
+    /// Free space that can be used without privileges
+    pub available: u64,
+    /// Size of the blocks files take space in
+    pub block_size: u64,
+}
+
+
+/// Space of the filesystem a path is on, or would be on if it doesn't
+/// exist yet
+pub fn space(path: &Path) -> io::Result<Space> {
+    let path = std::path::absolute(path)?;
+    let existing = path.ancestors().find(|a| a.exists()).unwrap_or(Path::new("/"));
+    let path = CString::new(existing.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buf: Statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { statvfs(path.as_ptr(), &mut buf) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let block_size = if buf.f_frsize > 0 { buf.f_frsize } else { buf.f_bsize } as u64;
+    Ok(Space { available: buf.f_bavail as u64 * block_size, block_size: block_size.max(1) })
+}
+
+
+/// Estimate the space the copy pass will take in the destination, going
+/// through the source like it does: the size of the files to copy, rounded
+/// up to whole blocks, less that of the copies they replace (which --atomic
+/// keeps until the end)
+pub fn estimate(source: &str, destination: &str, block_size: u64, opts: &Options, target: &dyn Backend) -> u64 {
+    estimate_below(source, destination, block_size, &Ancestors::default(), opts, target)
+}
+
+
+/// Estimate the space the copy of a directory of the source, reached
+/// through `ancestors`, will take
+fn estimate_below(
+    source: &str,
+    destination: &str,
+    block_size: u64,
+    ancestors: &Ancestors,
+    opts: &Options,
+    target: &dyn Backend,
+) -> u64 {
+    let blocks = |size: u64| size.div_ceil(block_size) * block_size;
+    let Ok(dir) = fs::read_dir(source) else {
+        return 0;
+    };
+    let directory = fs::metadata(source).ok();
+    let ancestors = ancestors.enter(directory.as_ref());
+    let device = directory.filter(|_| opts.one_file_system).map(|m| m.dev());
+    let mut needed = 0;
+    for entry in dir.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let Some(source_path) = path.to_str() else {
+            continue;
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        let destination_path = format!("{}/{}", destination, unicode::normalize(&name, opts.normalize));
+        if opts.policy.is_excluded(source_path) || opts.protected.contains(Path::new(&destination_path)) {
+            continue;
+        }
+        if let Some(metadata) = fs::metadata(&path).ok().filter(|m| m.is_dir()) {
+            let mounted = device.is_some_and(|device| metadata.dev() != device);
+            if !mounted && !ancestors.contains(&metadata) && !opts.unchanged.contains(&path) {
+                needed += estimate_below(source_path, &destination_path, block_size, &ancestors, opts, target);
+            }
+            continue;
+        }
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if metadata.file_type().is_symlink() {
+            continue;
+        }
+        let size = blocks(metadata.len());
+        match target.metadata(&destination_path) {
+            Some(existing) if existing.kind == Kind::File => {
+                let changed = metadata.len() != existing.size
+                    || metadata.modified().is_ok_and(|m| m > existing.modified + Duration::from_secs(opts.modify_window));
+                if changed {
+                    let freed = if opts.atomic { 0 } else { blocks(existing.size) };
+                    needed += size.saturating_sub(freed);
+                }
+            }
+            _ => needed += size,
+        }
+    }
+    needed
+}
+
+
+/// How often to look at the free space while waiting for it
+const POLL: Duration = Duration::from_secs(10);
+
+
+/// Whether an error is the destination running out of space (or quota)
+pub fn is_full(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded)
+}
+
+
+/// What to do when a local destination runs out of space in mid-run
+pub struct OutOfSpace {
+    destination: String,
+    /// Remove old snapshots and previous versions to make room
+    prune: bool,
+    snapshot: Option<SnapshotKind>,
+    /// Wait this long for room to be made
+    wait: Option<Duration>,
+}
+
+
+impl OutOfSpace {
+    pub fn new(destination: &str, opts: &Options) -> OutOfSpace {
+        let btrfs = Path::new(destination).join(META_DIR).join("snapshots").exists();
+        OutOfSpace {
+            destination: destination.to_string(),
+            prune: opts.emergency_prune,
+            snapshot: opts.snapshot.or(btrfs.then_some(SnapshotKind::Btrfs)),
+            wait: opts.wait_for_space,
+        }
+    }
+
+    fn available(&self) -> u64 {
+        space(Path::new(&self.destination)).map(|s| s.available).unwrap_or(0)
+    }
+
+    /// Remove the oldest snapshots (the last one is kept), and then the
+    /// previous versions of the files, until there are `needed` bytes free
+    fn prune(&self, needed: u64) {
+        if let Some(kind) = self.snapshot {
+            let snapshots = snapshot::list(kind, &self.destination).unwrap_or_default();
+            for snapshot in snapshots.iter().take(snapshots.len().saturating_sub(1)) {
+                if self.available() >= needed {
+                    return;
+                }
+                warning!("Removing snapshot {} to make room", snapshot.id);
+                if let Err(e) = snapshot::remove(kind, snapshot) {
+                    error!("cannot remove {}: {}", snapshot.id, e);
+                    break;
+                }
+            }
+        }
+        let versions = versions::root(&self.destination);
+        if self.available() < needed && Path::new(&versions).exists() {
+            warning!("Removing the previous versions of the files of {} to make room", self.destination);
+            if let Err(e) = fs::remove_dir_all(&versions) {
+                error!("cannot remove {}: {}", versions, e);
+            }
+        }
+    }
+
+    /// Make room for the copy of `source` (`needed` bytes), which failed for
+    /// lack of space: report what is missing, prune the destination if
+    /// asked to (`--emergency-prune`) and wait for space to be freed
+    /// (`--wait-for-space`). Returns whether there is room for it now, for
+    /// the copy to be tried again.
+    pub fn make_room(&self, source: &str, needed: u64) -> bool {
+        let available = self.available();
+        warning!(
+            "{} is full: copying {} needs {}, and {} is available",
+            self.destination,
+            source,
+            human_bytes(needed),
+            human_bytes(available)
+        );
+        if self.prune && available < needed {
+            self.prune(needed);
+        }
+        if self.available() >= needed {
+            return true;
+        }
+        let Some(wait) = self.wait else {
+            return false;
+        };
+        info!(
+            "Pausing for up to {}s, until {} more are freed in {}...",
+            wait.as_secs(),
+            human_bytes(needed - self.available().min(needed)),
+            self.destination
+        );
+        let deadline = Instant::now() + wait;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) {
+            thread::sleep(left.min(POLL));
+            if self.available() >= needed {
+                info!("Resuming: {} is available in {}", human_bytes(self.available()), self.destination);
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,94 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::fs::FileTypeExt;
+
+use crate::Options;
+
+
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn mknod(path: *const c_char, mode: u32, device: u64) -> c_int;
+}
+
+
+/// A special file of the source, which has no contents to copy (reading a
+/// FIFO blocks until something writes to it, and reading a device reads
+/// the device)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Special {
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+
+impl Special {
+    /// The kind of special file some metadata is that of, if any
+    pub fn of(metadata: &fs::Metadata) -> Option<Special> {
+        let file_type = metadata.file_type();
+        if file_type.is_fifo() {
+            Some(Special::Fifo)
+        } else if file_type.is_socket() {
+            Some(Special::Socket)
+        } else if file_type.is_char_device() {
+            Some(Special::CharDevice)
+        } else if file_type.is_block_device() {
+            Some(Special::BlockDevice)
+        } else {
+            None
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Special::Fifo => "a FIFO",
+            Special::Socket => "a socket",
+            Special::CharDevice => "a character device",
+            Special::BlockDevice => "a block device",
+        }
+    }
+
+    pub fn is_device(&self) -> bool {
+        matches!(self, Special::CharDevice | Special::BlockDevice)
+    }
+
+    /// The option recreating the special files of this kind
+    pub fn option(&self) -> &'static str {
+        match self.is_device() {
+            true => "--devices",
+            false => "--specials",
+        }
+    }
+
+    /// Whether special files of this kind are recreated in the destination
+    /// (device nodes with --devices, FIFOs and sockets with --specials)
+    pub fn recreated(&self, opts: &Options) -> bool {
+        match self.is_device() {
+            true => opts.devices,
+            false => opts.specials,
+        }
+    }
+}
+
+
+/// Create a special file with the given mode (holding its type and
+/// permissions) and device number, as mknod(2) does. Making device nodes
+/// takes CAP_MKNOD (i.e. running as root).
+#[cfg(target_os = "linux")]
+pub fn create(path: &str, mode: u32, device: u64) -> io::Result<()> {
+    let path = CString::new(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if unsafe { mknod(path.as_ptr(), mode, device) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+#[cfg(not(target_os = "linux"))]
+pub fn create(_path: &str, _mode: u32, _device: u64) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
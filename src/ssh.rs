@@ -0,0 +1,439 @@
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::backend::{Backend, Kind, Metadata};
+use crate::delta;
+use crate::hash;
+use crate::temporary;
+use crate::throttle::Throttled;
+
+
+
+/// Size of the blocks compared by `update_file`
+const REMOTE_BLOCK_SIZE: usize = 1024 * 1024;
+/// Most runs (of changed data, or of blocks of the copy) an update is made
+/// of, with one command each, beyond which the file is copied again
+const MAX_RUNS: usize = 256;
+
+
+/// Quote a string for the remote POSIX shell
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+
+pub fn parent_and_name(path: &str) -> (String, String) {
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/".to_string(), name.to_string()),
+        Some((parent, name)) => (parent.to_string(), name.to_string()),
+        None => (".".to_string(), path.to_string()),
+    }
+}
+
+
+/// A destination on a remote host, reached by running commands over SSH.
+///
+/// The whole destination tree is listed once when it is opened, and the
+/// listing is kept up to date as the backup modifies it, so that comparing
+/// files costs no round trips.
+pub struct SshBackend {
+    host: String,
+    control_path: String,
+    entries: RefCell<HashMap<String, Metadata>>,
+    children: RefCell<HashMap<String, BTreeSet<String>>>,
+}
+
+
+impl SshBackend {
+    pub fn new(host: &str, root: &str) -> Result<SshBackend, String> {
+        let control_path = std::env::temp_dir()
+            .join("backup-rs-ssh-%C")
+            .to_string_lossy()
+            .to_string();
+        let backend = SshBackend {
+            host: host.to_string(),
+            control_path,
+            entries: RefCell::new(HashMap::new()),
+            children: RefCell::new(HashMap::new()),
+        };
+        backend
+            .load(root)
+            .map_err(|e| format!("cannot list {}:{}: {}", host, root, e))?;
+        Ok(backend)
+    }
+
+    /// Run a shell command on the remote host, optionally feeding it data,
+    /// and copy its output to `output`
+    fn run_to(
+        &self,
+        command: &str,
+        input: Option<&mut dyn Read>,
+        output: &mut dyn Write,
+    ) -> io::Result<()> {
+        let mut child = Command::new("ssh")
+            .args(["-o", "ControlMaster=auto"])
+            .arg("-o")
+            .arg(format!("ControlPath={}", self.control_path))
+            .args(["-o", "ControlPersist=60"])
+            .arg(&self.host)
+            .arg("--")
+            .arg(command)
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(input) = input {
+            let mut stdin = child.stdin.take().unwrap();
+            io::copy(input, &mut stdin)?;
+        }
+        io::copy(&mut child.stdout.take().unwrap(), output)?;
+        let result = child.wait_with_output()?;
+        if !result.status.success() {
+            let message = String::from_utf8_lossy(&result.stderr).trim().to_string();
+            // ssh exits with 255 when the connection, not the command, fails
+            let kind = match result.status.code() {
+                Some(255) => io::ErrorKind::ConnectionAborted,
+                _ => io::ErrorKind::Other,
+            };
+            return Err(io::Error::new(kind, if message.is_empty() {
+                format!("remote command failed: {}", command)
+            } else {
+                message
+            }));
+        }
+        Ok(())
+    }
+
+    /// Run a shell command on the remote host, optionally feeding it data
+    fn run(&self, command: &str, input: Option<&mut dyn Read>) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        self.run_to(command, input, &mut output)?;
+        Ok(output)
+    }
+
+    /// List the whole tree below the root of the destination
+    fn load(&self, root: &str) -> io::Result<()> {
+        let command = format!(
+            "if [ -e {0} ] || [ -L {0} ]; then \
+            find {0} -printf '%y\\0%s\\0%T@\\0%p\\0%l\\0'; fi",
+            quote(root)
+        );
+        let output = self.run(&command, None)?;
+        let mut fields = output.split(|&b| b == 0);
+        while let (Some(kind), Some(size), Some(modified), Some(path), Some(target)) = (
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+            fields.next(),
+        ) {
+            let path = match std::str::from_utf8(path) {
+                Ok(p) => p.to_string(),
+                Err(_) => continue,
+            };
+            let kind = match kind {
+                b"f" => Kind::File,
+                b"d" => Kind::Directory,
+                b"l" => Kind::Symlink,
+                _ => Kind::Other,
+            };
+            let modified = String::from_utf8_lossy(modified)
+                .parse::<f64>()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs_f64(secs.max(0.0)))
+                .unwrap_or(UNIX_EPOCH);
+            let target = if kind == Kind::Symlink {
+                Some(PathBuf::from(String::from_utf8_lossy(target).to_string()))
+            } else {
+                None
+            };
+            let metadata = Metadata {
+                kind,
+                size: String::from_utf8_lossy(size).parse().unwrap_or(0),
+                modified,
+                target,
+            };
+            if path == root {
+                self.entries.borrow_mut().insert(path, metadata);
+            } else {
+                self.insert(&path, metadata);
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&self, path: &str, metadata: Metadata) {
+        let (parent, name) = parent_and_name(path);
+        self.children.borrow_mut().entry(parent).or_default().insert(name);
+        self.entries.borrow_mut().insert(path.to_string(), metadata);
+    }
+
+    /// Stream data into a remote file through a temporary file, so that an
+    /// interrupted transfer never leaves a truncated file behind
+    fn write_from(&self, input: &mut dyn Read, path: &str) -> io::Result<()> {
+        let temporary = temporary::beside(path);
+        let command = format!(
+            "cat > {0} && mv -f -- {0} {1}",
+            quote(&temporary),
+            quote(path)
+        );
+        self.run(&command, Some(input)).map(|_| ())
+    }
+
+    fn forget(&self, path: &str) {
+        let (parent, name) = parent_and_name(path);
+        if let Some(names) = self.children.borrow_mut().get_mut(&parent) {
+            names.remove(&name);
+        }
+        let prefix = format!("{}/", path);
+        self.entries
+            .borrow_mut()
+            .retain(|p, _| p != path && !p.starts_with(&prefix));
+        self.children
+            .borrow_mut()
+            .retain(|p, _| p != path && !p.starts_with(&prefix));
+    }
+}
+
+
+impl Backend for SshBackend {
+    fn metadata(&self, path: &str) -> Option<Metadata> {
+        self.entries.borrow().get(path).cloned()
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        match self.children.borrow().get(path) {
+            Some(names) => Ok(names.iter().cloned().collect()),
+            None if self.metadata(path).map(|m| m.kind) == Some(Kind::Directory) => {
+                Ok(Vec::new())
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such directory")),
+        }
+    }
+
+    fn create_dir(&self, path: &str) -> io::Result<()> {
+        self.run(&format!("mkdir -- {}", quote(path)), None)?;
+        self.insert(path, Metadata {
+            kind: Kind::Directory,
+            size: 0,
+            modified: SystemTime::now(),
+            target: None,
+        });
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &str) -> io::Result<()> {
+        self.run(&format!("rm -f -- {}", quote(path)), None)?;
+        self.forget(path);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &str) -> io::Result<()> {
+        self.run(&format!("rm -rf -- {}", quote(path)), None)?;
+        self.forget(path);
+        Ok(())
+    }
+
+    fn copy_file(&self, source: &str, path: &str) -> io::Result<()> {
+        let file = fs::File::open(source)?;
+        let size = file.metadata()?.len();
+        self.write_from(&mut Throttled(file), path)?;
+        self.insert(path, Metadata {
+            kind: Kind::File,
+            size,
+            modified: SystemTime::now(),
+            target: None,
+        });
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, path: &str) -> io::Result<()> {
+        let target_str = target.to_string_lossy();
+        self.run(&format!("ln -sfn -- {} {}", quote(&target_str), quote(path)), None)?;
+        self.insert(path, Metadata {
+            kind: Kind::Symlink,
+            size: target_str.len() as u64,
+            modified: SystemTime::now(),
+            target: Some(target.to_path_buf()),
+        });
+        Ok(())
+    }
+
+    fn update_file(&self, source: &str, path: &str) -> io::Result<Option<u64>> {
+        // The remote side only needs coreutils to sum the blocks, and to
+        // patch or rebuild the copy
+        let listing = self.run(
+            &format!(
+                "split -b {0} --filter=cksum -- {1} && split -b {0} --filter=sha256sum -- {1}",
+                REMOTE_BLOCK_SIZE,
+                quote(path)
+            ),
+            None,
+        )?;
+        let listing = String::from_utf8_lossy(&listing);
+        let mut weak = Vec::new();
+        let mut strong = Vec::new();
+        for line in listing.lines() {
+            let mut fields = line.split_whitespace();
+            let first = fields.next().unwrap_or_default();
+            match hash::from_hex(first) {
+                Some(digest) => strong.push(digest),
+                None => weak.extend(first.parse::<u32>().ok().zip(fields.next().and_then(|s| s.parse::<u64>().ok()))),
+            }
+        }
+        if weak.len() != strong.len() {
+            return Ok(None);
+        }
+        let old: Vec<delta::Sums> = weak
+            .into_iter()
+            .zip(strong)
+            .map(|((weak, size), strong)| delta::Sums { weak, strong, size })
+            .collect();
+        let size = fs::metadata(source)?.len();
+        let runs = delta::changed_runs(&mut fs::File::open(source)?, &old, REMOTE_BLOCK_SIZE)?;
+        if runs.len() > MAX_RUNS {
+            return Ok(None);
+        }
+        let changed: Vec<(u64, u64)> = runs
+            .iter()
+            .filter_map(|run| match *run {
+                delta::Run::Changed { offset, length } => Some((offset, length)),
+                delta::Run::Old { .. } => None,
+            })
+            .collect();
+        // Blocks that stayed where they were are kept as they are, and the
+        // rest patched in place; when some moved, the copy is rebuilt from
+        // them and the changed data beside it, and put in its place
+        let mut offset = 0;
+        let in_place = runs.iter().all(|run| {
+            let (kept, length) = match *run {
+                delta::Run::Old { start, count } => (
+                    start * REMOTE_BLOCK_SIZE as u64 == offset,
+                    old[start as usize..(start + count) as usize].iter().map(|sums| sums.size).sum(),
+                ),
+                delta::Run::Changed { length, .. } => (true, length),
+            };
+            offset += length;
+            kept
+        });
+        let command = if in_place {
+            let mut commands = vec![format!("touch -d @0 -- {}", quote(path))];
+            for (offset, length) in &changed {
+                commands.push(format!(
+                    "dd of={} bs={} seek={} count={} oflag=seek_bytes \
+                     iflag=fullblock,count_bytes conv=notrunc status=none",
+                    quote(path),
+                    REMOTE_BLOCK_SIZE,
+                    offset,
+                    length
+                ));
+            }
+            commands.push(format!("truncate -s {} -- {}", size, quote(path)));
+            commands.push(format!("touch -- {}", quote(path)));
+            commands.join(" && ")
+        } else {
+            let parts: Vec<String> = runs
+                .iter()
+                .map(|run| match *run {
+                    delta::Run::Old { start, count } => format!(
+                        "dd if={} bs={} skip={} count={} status=none",
+                        quote(path),
+                        REMOTE_BLOCK_SIZE,
+                        start,
+                        count
+                    ),
+                    delta::Run::Changed { length, .. } => format!(
+                        "dd bs={} count={} iflag=fullblock,count_bytes status=none",
+                        REMOTE_BLOCK_SIZE,
+                        length
+                    ),
+                })
+                .collect();
+            let temporary = temporary::beside(path);
+            format!(
+                "{{ {}; }} > {1} && chmod --reference={2} -- {1} && mv -f -- {1} {2}",
+                parts.join(" && "),
+                quote(&temporary),
+                quote(path)
+            )
+        };
+        let mut data = Throttled(delta::Ranges::new(fs::File::open(source)?, changed.iter().copied()));
+        self.run(&command, Some(&mut data))?;
+        self.insert(path, Metadata {
+            kind: Kind::File,
+            size,
+            modified: SystemTime::now(),
+            target: None,
+        });
+        Ok(Some(changed.iter().map(|(_, length)| length).sum()))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.run(&format!("mv -- {} {}", quote(from), quote(to)), None)?;
+        let prefix = format!("{}/", from);
+        let moved = |p: &str| match p.strip_prefix(&prefix) {
+            Some(rest) => Some(format!("{}/{}", to, rest)),
+            None if p == from => Some(to.to_string()),
+            None => None,
+        };
+        let entries: Vec<(String, Metadata)> = self
+            .entries
+            .borrow()
+            .iter()
+            .filter_map(|(p, m)| Some((moved(p)?, m.clone())))
+            .collect();
+        self.forget(from);
+        for (path, metadata) in entries {
+            self.insert(&path, metadata);
+        }
+        Ok(())
+    }
+
+    fn fetch(&self, path: &str, local: &str) -> io::Result<()> {
+        let mut file = fs::File::create(local)?;
+        self.run_to(&format!("cat -- {}", quote(path)), None, &mut file)
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        if self.metadata(path).is_none() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        }
+        self.run(&format!("cat -- {}", quote(path)), None)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let (parent, _) = parent_and_name(path);
+        self.run(&format!("mkdir -p -- {}", quote(&parent)), None)?;
+        // Record the directories created by mkdir -p
+        let mut missing = Vec::new();
+        let mut directory = parent;
+        while self.metadata(&directory).is_none() && directory != "/" && directory != "." {
+            missing.push(directory.clone());
+            directory = parent_and_name(&directory).0;
+        }
+        for directory in missing.into_iter().rev() {
+            self.insert(&directory, Metadata {
+                kind: Kind::Directory,
+                size: 0,
+                modified: SystemTime::now(),
+                target: None,
+            });
+        }
+        self.write_from(&mut &data[..], path)?;
+        self.insert(path, Metadata {
+            kind: Kind::File,
+            size: data.len() as u64,
+            modified: SystemTime::now(),
+            target: None,
+        });
+        Ok(())
+    }
+
+    fn is_local(&self) -> bool {
+        false
+    }
+}
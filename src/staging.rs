@@ -0,0 +1,120 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::raw::{c_char, c_int, c_uint};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::manifest::META_DIR;
+
+
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn renameat2(old_dir: c_int, old: *const c_char, new_dir: c_int, new: *const c_char, flags: c_uint) -> c_int;
+}
+
+
+#[cfg(target_os = "linux")]
+const AT_FDCWD: c_int = -100;
+#[cfg(target_os = "linux")]
+const RENAME_EXCHANGE: c_uint = 2;
+
+
+/// Where the changes to a destination are staged: next to it, so that it
+/// is on the same filesystem
+fn path(destination: &Path) -> PathBuf {
+    let name = destination.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    destination.with_file_name(format!(".{}.backup-rs-staging", name))
+}
+
+
+/// Recreate a tree with hard links to its files, but for `skip`
+fn link_tree(from: &Path, to: &Path, skip: &Path) -> io::Result<()> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (from, to) = (entry.path(), to.join(entry.file_name()));
+        if from == skip {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            link_tree(&from, &to, skip)?;
+        } else {
+            // Symlinks are linked themselves, not what they point to
+            fs::hard_link(&from, &to)?;
+        }
+    }
+    Ok(())
+}
+
+
+/// Prepare the staging directory of a local destination: a copy of it made
+/// of hard links, so that a run can change it without touching what
+/// readers of the destination see, as long as it replaces files instead of
+/// writing to them. Returns its path.
+pub fn prepare(destination: &str) -> Result<String, String> {
+    let destination = std::path::absolute(destination).map_err(|e| format!("cannot stage {}: {}", destination, e))?;
+    let destination = destination.as_path();
+    let staging = path(destination);
+    let meta = destination.join(META_DIR);
+    if meta.join("snapshots").exists() {
+        return Err(format!("{} has snapshots, which can't be staged", destination.display()));
+    }
+    // Left behind by an interrupted run
+    if fs::symlink_metadata(&staging).is_ok() {
+        fs::remove_dir_all(&staging).map_err(|e| format!("cannot remove {}: {}", staging.display(), e))?;
+    }
+    // The lock isn't linked, as it would then be locked twice
+    let result = match destination.exists() {
+        true => link_tree(destination, &staging, &meta.join("lock")),
+        false => fs::create_dir(&staging),
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(format!("cannot stage {}: {}", destination.display(), e));
+    }
+    Ok(staging.to_string_lossy().to_string())
+}
+
+
+/// Swap two directories in a single step
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let c_path = |p: &Path| CString::new(p.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+    let (a, b) = (c_path(a)?, c_path(b)?);
+    if unsafe { renameat2(AT_FDCWD, a.as_ptr(), AT_FDCWD, b.as_ptr(), RENAME_EXCHANGE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+
+#[cfg(not(target_os = "linux"))]
+fn exchange(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+
+/// Put the staged tree in place of the destination, then remove the
+/// previous one. Where directories can't be swapped in a single step, the
+/// destination is missing for the moment between two renames.
+pub fn publish(destination: &str, staging: &str) -> io::Result<()> {
+    let destination = std::path::absolute(destination)?;
+    let destination = destination.as_path();
+    let staging = Path::new(staging);
+    if !destination.exists() {
+        return fs::rename(staging, destination);
+    }
+    if exchange(staging, destination).is_err() {
+        let previous = destination.with_file_name(format!(
+            ".{}.backup-rs-previous",
+            destination.file_name().map(|n| n.to_string_lossy()).unwrap_or_default()
+        ));
+        fs::rename(destination, &previous)?;
+        fs::rename(staging, destination)?;
+        return fs::remove_dir_all(previous);
+    }
+    // The staging directory now holds the previous tree
+    fs::remove_dir_all(staging)
+}
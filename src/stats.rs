@@ -0,0 +1,269 @@
+use std::fs;
+use std::os::raw::{c_int, c_long};
+use std::time::{Duration, Instant};
+
+use crate::log;
+
+
+
+#[repr(C)]
+struct Timeval {
+    tv_sec: c_long,
+    tv_usec: c_long,
+}
+
+
+#[repr(C)]
+struct Rusage {
+    ru_utime: Timeval,
+    ru_stime: Timeval,
+    ru_maxrss: c_long,
+    ru_ixrss: c_long,
+    ru_idrss: c_long,
+    ru_isrss: c_long,
+    ru_minflt: c_long,
+    ru_majflt: c_long,
+    ru_nswap: c_long,
+    ru_inblock: c_long,
+    ru_oublock: c_long,
+    ru_msgsnd: c_long,
+    ru_msgrcv: c_long,
+    ru_nsignals: c_long,
+    ru_nvcsw: c_long,
+    ru_nivcsw: c_long,
+}
+
+
+const RUSAGE_SELF: c_int = 0;
+
+extern "C" {
+    fn getrusage(who: c_int, usage: *mut Rusage) -> c_int;
+}
+
+
+/// A point-in-time reading of the resources used by the process
+#[derive(Clone, Copy, Default)]
+struct Usage {
+    user: Duration,
+    system: Duration,
+    /// Peak resident set size, in KiB
+    max_rss: u64,
+    /// Counters from /proc/self/io, if available
+    io: Option<Io>,
+}
+
+
+#[derive(Clone, Copy, Default)]
+struct Io {
+    /// Bytes passed to read-like syscalls
+    rchar: u64,
+    /// Bytes passed to write-like syscalls
+    wchar: u64,
+    /// Number of read-like syscalls
+    syscr: u64,
+    /// Number of write-like syscalls
+    syscw: u64,
+    /// Bytes actually fetched from the storage layer
+    read_bytes: u64,
+    /// Bytes actually sent to the storage layer
+    write_bytes: u64,
+}
+
+
+fn timeval_to_duration(tv: &Timeval) -> Duration {
+    Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+}
+
+
+/// Read the I/O counters of the current process (Linux only)
+fn read_proc_io() -> Option<Io> {
+    let content = fs::read_to_string("/proc/self/io").ok()?;
+    let mut io = Io::default();
+    for line in content.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let value = match value.trim().parse::<u64>() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        match key {
+            "rchar" => io.rchar = value,
+            "wchar" => io.wchar = value,
+            "syscr" => io.syscr = value,
+            "syscw" => io.syscw = value,
+            "read_bytes" => io.read_bytes = value,
+            "write_bytes" => io.write_bytes = value,
+            _ => (),
+        }
+    }
+    Some(io)
+}
+
+
+impl Usage {
+    fn now() -> Usage {
+        let mut usage = Usage {
+            io: read_proc_io(),
+            ..Usage::default()
+        };
+        let mut ru = std::mem::MaybeUninit::<Rusage>::zeroed();
+        if unsafe { getrusage(RUSAGE_SELF, ru.as_mut_ptr()) } == 0 {
+            let ru = unsafe { ru.assume_init() };
+            usage.user = timeval_to_duration(&ru.ru_utime);
+            usage.system = timeval_to_duration(&ru.ru_stime);
+            // Linux reports the maximum resident set size in KiB
+            usage.max_rss = ru.ru_maxrss as u64;
+        }
+        usage
+    }
+
+    /// Resources used between `earlier` and `self`
+    fn since(&self, earlier: &Usage) -> Usage {
+        let io = match (self.io, earlier.io) {
+            (Some(a), Some(b)) => Some(Io {
+                rchar: a.rchar.saturating_sub(b.rchar),
+                wchar: a.wchar.saturating_sub(b.wchar),
+                syscr: a.syscr.saturating_sub(b.syscr),
+                syscw: a.syscw.saturating_sub(b.syscw),
+                read_bytes: a.read_bytes.saturating_sub(b.read_bytes),
+                write_bytes: a.write_bytes.saturating_sub(b.write_bytes),
+            }),
+            _ => None,
+        };
+        Usage {
+            user: self.user.saturating_sub(earlier.user),
+            system: self.system.saturating_sub(earlier.system),
+            max_rss: self.max_rss,
+            io,
+        }
+    }
+}
+
+
+/// Resources used by one phase of the run
+struct Phase {
+    name: String,
+    wall: Duration,
+    usage: Usage,
+}
+
+
+/// Collects resource usage for the whole run and for each of its phases
+pub struct Stats {
+    start: Instant,
+    start_usage: Usage,
+    current: Option<(String, Instant, Usage)>,
+    phases: Vec<Phase>,
+}
+
+
+/// Format a number of bytes using binary units
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+
+fn seconds(d: Duration) -> String {
+    format!("{:.3}s", d.as_secs_f64())
+}
+
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            start: Instant::now(),
+            start_usage: Usage::now(),
+            current: None,
+            phases: Vec::new(),
+        }
+    }
+
+    /// Start measuring a new phase, finishing the current one if any
+    pub fn start_phase(&mut self, name: &str) {
+        self.finish_phase();
+        self.current = Some((name.to_string(), Instant::now(), Usage::now()));
+    }
+
+    /// Stop measuring the current phase
+    pub fn finish_phase(&mut self) {
+        if let Some((name, start, usage)) = self.current.take() {
+            self.phases.push(Phase {
+                name,
+                wall: start.elapsed(),
+                usage: Usage::now().since(&usage),
+            });
+        }
+    }
+
+    /// Print the resource usage report
+    pub fn print(&mut self) {
+        self.finish_phase();
+        let wall = self.start.elapsed();
+        let total = Usage::now().since(&self.start_usage);
+        log::separator();
+        info!("Resource usage:");
+        info!("  Wall time: {}", seconds(wall));
+        info!(
+            "  CPU time: {} user, {} system",
+            seconds(total.user),
+            seconds(total.system)
+        );
+        info!("  Peak RSS: {}", human_bytes(total.max_rss * 1024));
+        match total.io {
+            Some(io) => {
+                info!(
+                    "  Read: {} from storage, {} in {} syscalls",
+                    human_bytes(io.read_bytes),
+                    human_bytes(io.rchar),
+                    io.syscr
+                );
+                info!(
+                    "  Written: {} to storage, {} in {} syscalls",
+                    human_bytes(io.write_bytes),
+                    human_bytes(io.wchar),
+                    io.syscw
+                );
+            }
+            None => info!("  Read/written: unavailable (no /proc/self/io)"),
+        }
+        if self.phases.is_empty() {
+            return;
+        }
+        info!("Phases:");
+        for phase in &self.phases {
+            let syscalls = match phase.usage.io {
+                Some(io) => format!(", {} syscalls", io.syscr + io.syscw),
+                None => String::new(),
+            };
+            // A phase spending more time in the kernel than in user space
+            // is dominated by syscalls (stat, readdir, open, ...)
+            let heavy = if phase.usage.system > phase.usage.user {
+                " (syscall-heavy)"
+            } else {
+                ""
+            };
+            info!(
+                "  {}: {} wall, {} user, {} system{}{}",
+                phase.name,
+                seconds(phase.wall),
+                seconds(phase.usage.user),
+                seconds(phase.usage.system),
+                syscalls,
+                heavy
+            );
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::backend::Backend;
+use crate::budget;
+use crate::crypto;
+use crate::hooks::Context;
+use crate::log;
+use crate::manifest::META_DIR;
+use crate::schedule;
+use crate::summary::{Summary, Totals};
+
+
+
+/// File, inside META_DIR, describing the last run that updated the
+/// destination, for monitoring and for whoever looks at the disk
+const STATUS: &str = "status";
+const STATUS_HEADER: &str = "backup-rs status 1";
+
+
+pub fn path(destination: &str) -> String {
+    format!("{}/{}/{}", destination, META_DIR, STATUS)
+}
+
+
+/// The source of the last run that updated the destination, as an absolute
+/// path
+pub fn source(target: &dyn Backend, destination: &str) -> Option<String> {
+    let content = target.read(&path(destination)).ok()?;
+    let content = String::from_utf8_lossy(&content);
+    let mut lines = content.lines();
+    if lines.next() != Some(STATUS_HEADER) {
+        return None;
+    }
+    lines.find_map(|line| line.strip_prefix("source ")).map(|s| s.to_string())
+}
+
+
+/// Bytes copied by the last run that updated the destination, and the
+/// seconds it took, to tell the pace of the next ones
+pub fn pace(target: &dyn Backend, destination: &str) -> Option<(u64, u64)> {
+    let content = target.read(&path(destination)).ok()?;
+    let content = String::from_utf8_lossy(&content);
+    if content.lines().next() != Some(STATUS_HEADER) {
+        return None;
+    }
+    let field = |key: &str| content.lines().find_map(|line| line.strip_prefix(key)?.strip_prefix(' '));
+    let started: i64 = field("started")?.parse().ok()?;
+    let finished: i64 = field("finished")?.parse().ok()?;
+    Some((field("bytes")?.parse().ok()?, finished.saturating_sub(started).max(0) as u64))
+}
+
+
+/// ID of the last run this process started, and what it changed once it
+/// recorded its status, for notifications
+static LAST_RUN: Mutex<Option<(String, Totals)>> = Mutex::new(None);
+
+
+pub fn last_run() -> Option<(String, Totals)> {
+    LAST_RUN.lock().unwrap().clone()
+}
+
+
+/// Forget the last run, not to take it for one that fails before it starts
+pub fn forget_last_run() {
+    *LAST_RUN.lock().unwrap() = None;
+}
+
+
+/// The result of a run, `ok`, `errors` if it printed errors (or `errors`
+/// of them since it started) or `failed` if it failed as a whole
+pub fn result(ok: bool, errors: u64) -> &'static str {
+    match (ok, errors) {
+        (false, _) => "failed",
+        (true, 0) => "ok",
+        (true, _) => "errors",
+    }
+}
+
+
+/// The exit status of the process after a run, by its result, so that
+/// scripts and systemd units (e.g. with OnFailure=) can tell the outcomes
+/// apart: 1 if some paths couldn't be backed up, 2 if it went over its
+/// change budget and 3 if it failed as a whole
+pub fn exit_status(result: &str, within_budget: bool) -> i32 {
+    match (result, within_budget) {
+        ("failed", _) => 3,
+        (_, false) => 2,
+        ("errors", true) => 1,
+        _ => 0,
+    }
+}
+
+
+/// How a run went, for those telling about it (mails, notifications and
+/// metrics)
+pub struct Outcome {
+    pub result: &'static str,
+    pub exit_status: i32,
+    /// Errors and warnings printed during the run
+    pub errors: u64,
+    pub warnings: u64,
+    /// ID of the run, empty if it didn't start
+    pub run: String,
+    pub totals: Totals,
+    pub duration: Duration,
+}
+
+
+/// Run a backup and call `tell` with how it went, unless it is a dry run,
+/// which isn't told about. Returns whether the backup succeeded.
+pub fn around(context: &Context, backup: impl FnOnce() -> bool, tell: impl FnOnce(&Outcome)) -> bool {
+    if context.dry_run {
+        return backup();
+    }
+    let start = Instant::now();
+    let (errors, warnings) = log::counts();
+    forget_last_run();
+    let ok = backup();
+    let (errors, warnings) = (log::counts().0 - errors, log::counts().1 - warnings);
+    let result = result(ok, errors);
+    let (run, totals) = last_run().unwrap_or_default();
+    tell(&Outcome {
+        result,
+        exit_status: exit_status(result, !budget::exceeded()),
+        errors,
+        warnings,
+        run,
+        totals,
+        duration: start.elapsed(),
+    });
+    ok
+}
+
+
+/// A random (version 4) UUID, identifying a run
+fn new_id() -> String {
+    let mut bytes = [0u8; 16];
+    if crypto::random_bytes(&mut bytes).is_err() {
+        // Unique enough without /dev/urandom
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        bytes = (nanos ^ ((std::process::id() as u128) << 96)).to_be_bytes();
+    }
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+
+/// A run in progress, for the status written at its end
+pub struct Run {
+    /// Unique ID of the run, in its output, status and manifest, to tell
+    /// which run a message or a file comes from
+    pub id: String,
+    started: i64,
+    /// Errors and warnings printed before the run
+    errors: u64,
+    warnings: u64,
+}
+
+
+impl Run {
+    pub fn start() -> Run {
+        let (errors, warnings) = log::counts();
+        let id = new_id();
+        *LAST_RUN.lock().unwrap() = Some((id.clone(), Totals::default()));
+        Run { id, started: schedule::now(), errors, warnings }
+    }
+
+    /// Keep what a dry run would have changed, for `last_run()`, without
+    /// writing a status
+    pub fn simulated(&self, summary: Option<&Summary>) {
+        let totals = summary.map(|s| s.total()).unwrap_or_default();
+        *LAST_RUN.lock().unwrap() = Some((self.id.clone(), totals));
+    }
+
+    /// Write the status of the run to the destination, as `key value`
+    /// lines. `result` is `ok`, `errors` if some paths couldn't be
+    /// backed up (or the run went over its change budget), or `failed` if
+    /// the run as a whole failed.
+    pub fn record(
+        &self,
+        source: &str,
+        destination: &str,
+        ok: bool,
+        within_budget: bool,
+        summary: Option<&Summary>,
+        target: &dyn Backend,
+    ) {
+        let finished = schedule::now();
+        let (errors, warnings) = log::counts();
+        let (errors, warnings) = (errors - self.errors, warnings - self.warnings);
+        let result = result(ok, errors);
+        let totals = summary.map(|s| s.total()).unwrap_or_default();
+        *LAST_RUN.lock().unwrap() = Some((self.id.clone(), totals));
+        let mut content = format!("{}\n", STATUS_HEADER);
+        writeln!(content, "version {}", env!("CARGO_PKG_VERSION")).unwrap();
+        writeln!(content, "run_id {}", self.id).unwrap();
+        let absolute = std::path::absolute(source).map(|p| p.to_string_lossy().to_string());
+        writeln!(content, "source {}", absolute.as_deref().unwrap_or(source)).unwrap();
+        writeln!(content, "started {}", self.started).unwrap();
+        writeln!(content, "finished {}", finished).unwrap();
+        writeln!(content, "finished_local {}", schedule::local_time(finished).format()).unwrap();
+        writeln!(content, "result {}", result).unwrap();
+        writeln!(content, "exit_status {}", exit_status(result, within_budget)).unwrap();
+        writeln!(content, "errors {}", errors).unwrap();
+        writeln!(content, "warnings {}", warnings).unwrap();
+        writeln!(content, "copied {}", totals.copied).unwrap();
+        writeln!(content, "bytes {}", totals.bytes).unwrap();
+        writeln!(content, "removed {}", totals.removed).unwrap();
+        writeln!(content, "within_budget {}", if within_budget { "yes" } else { "no" }).unwrap();
+        if let Err(e) = target.write(&path(destination), content.as_bytes()) {
+            warning!("cannot write {}: {}", path(destination), e);
+        }
+    }
+}
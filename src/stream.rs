@@ -0,0 +1,85 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::budget::parse_size;
+use crate::delta;
+use crate::hash::Digest;
+
+
+
+/// Size of the buffer the copies go through, set with --buffer-size (0
+/// leaving each storage to the size it copies best with)
+static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+
+thread_local! {
+    /// The local file the last copy on this thread read, with the hash of
+    /// what it read, for `hashing`
+    static HASHED: RefCell<Option<(String, Digest)>> = const { RefCell::new(None) };
+}
+
+
+/// Parse the size of a buffer, such as `256K` or `4M`
+pub fn parse_buffer_size(text: &str) -> Result<usize, String> {
+    match parse_size(text) {
+        Some(size) if size > 0 && size <= 1 << 30 => Ok(size as usize),
+        _ => Err(format!("invalid buffer size '{}' (expected e.g. 256K or 4M, up to 1G)", text)),
+    }
+}
+
+
+/// Copy through buffers of `size` bytes (or of the size each storage
+/// prefers)
+pub fn set_buffer_size(size: Option<usize>) {
+    BUFFER_SIZE.store(size.unwrap_or(0), Ordering::SeqCst);
+}
+
+
+/// The size of the buffers to copy through, `default` unless another was
+/// asked for
+pub fn buffer_size(default: usize) -> usize {
+    match BUFFER_SIZE.load(Ordering::SeqCst) {
+        0 => default,
+        size => size,
+    }
+}
+
+
+/// Copy everything read from `input` to `output` through a buffer of
+/// `buffer_size` bytes, handing each chunk to `chunk` once it is written
+/// (to hash what is copied, or follow the copy of a large file). Returns
+/// the number of bytes copied.
+pub fn copy(input: &mut impl Read, output: &mut impl Write, buffer_size: usize, mut chunk: impl FnMut(&[u8])) -> io::Result<u64> {
+    let mut buffer = vec![0u8; buffer_size];
+    let mut copied = 0;
+    loop {
+        let n = delta::read_full(input, &mut buffer)?;
+        if n == 0 {
+            return Ok(copied);
+        }
+        output.write_all(&buffer[..n])?;
+        chunk(&buffer[..n]);
+        copied += n as u64;
+    }
+}
+
+
+/// Record the hash of a local file, as read by the copy that just streamed
+/// it
+pub fn hashed(source: &str, digest: Digest) {
+    HASHED.with(|hashed| *hashed.borrow_mut() = Some((source.to_string(), digest)));
+}
+
+
+/// Run a copy of the local file `source`, returning its result along with
+/// the hash of `source` if it was hashed as it was copied (as the copies
+/// to local destinations do), rather than reading it again for it. The
+/// copies of other files made by the storage on the way (e.g. of the
+/// compressed file, for a compressed destination) don't count.
+pub fn hashing<T>(source: &str, copy: impl FnOnce() -> io::Result<T>) -> (io::Result<T>, Option<Digest>) {
+    HASHED.with(|hashed| *hashed.borrow_mut() = None);
+    let result = copy();
+    let digest = HASHED.with(|hashed| hashed.borrow_mut().take());
+    (result, digest.filter(|(hashed, _)| hashed == source).map(|(_, digest)| digest))
+}
@@ -0,0 +1,112 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use crate::log;
+use crate::progress;
+use crate::stats::human_bytes;
+
+
+
+#[derive(Clone, Copy, Default)]
+pub struct Totals {
+    /// Files copied or updated
+    pub copied: u64,
+    /// Bytes written for them
+    pub bytes: u64,
+    /// Entries removed
+    pub removed: u64,
+}
+
+
+/// What a run changed in the destination, by top-level directory
+pub struct Summary {
+    destination: String,
+    directories: RefCell<BTreeMap<String, Totals>>,
+    /// Files of the source the run looked at
+    files: Cell<u64>,
+}
+
+
+impl Summary {
+    pub fn new(destination: &str) -> Summary {
+        Summary { destination: destination.to_string(), directories: RefCell::new(BTreeMap::new()), files: Cell::new(0) }
+    }
+
+    /// Update the totals of the top-level directory a path of the
+    /// destination is (or is in), `.` being the files at its root
+    fn totals<F: FnOnce(&mut Totals)>(&self, path: &str, is_directory: bool, update: F) {
+        let relative = path.strip_prefix(&self.destination).unwrap_or(path).trim_start_matches('/');
+        let key = match relative.split_once('/') {
+            Some((directory, _)) => directory.to_string(),
+            None if is_directory => relative.to_string(),
+            None => ".".to_string(),
+        };
+        update(self.directories.borrow_mut().entry(key).or_default());
+    }
+
+    /// Record a file copied (or updated) into the destination
+    pub fn copied(&self, path: &str, bytes: u64) {
+        self.totals(path, false, |t| {
+            t.copied += 1;
+            t.bytes += bytes;
+        });
+        progress::copied(bytes);
+    }
+
+    /// Record a file of the source looked at, whether it was copied or not
+    pub fn examined(&self) {
+        self.files.set(self.files.get() + 1);
+    }
+
+    /// Files of the source looked at so far
+    pub fn files(&self) -> u64 {
+        self.files.get()
+    }
+
+    /// Record an entry removed from the destination
+    pub fn removed(&self, path: &str, is_directory: bool) {
+        self.totals(path, is_directory, |t| t.removed += 1);
+        progress::removed();
+    }
+
+    /// Changes in the whole destination
+    pub fn total(&self) -> Totals {
+        self.directories.borrow().values().fold(Totals::default(), |sum, t| Totals {
+            copied: sum.copied + t.copied,
+            bytes: sum.bytes + t.bytes,
+            removed: sum.removed + t.removed,
+        })
+    }
+
+    /// Print the changes, the directories with the most data copied first
+    pub fn print(&self) {
+        let directories = self.directories.borrow();
+        if directories.is_empty() {
+            return;
+        }
+        let mut rows: Vec<(&String, &Totals)> = directories.iter().collect();
+        rows.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(b.1.removed.cmp(&a.1.removed)));
+        let width = rows.iter().map(|(name, _)| name.chars().count()).max().unwrap_or(0).clamp("total".len(), 40);
+        let total = self.total();
+        log::separator();
+        info!("Changes by directory:");
+        for (name, totals) in &rows {
+            info!(
+                "  {:width$}  {} copied ({}), {} removed",
+                name,
+                totals.copied,
+                human_bytes(totals.bytes),
+                totals.removed,
+                width = width
+            );
+        }
+        info!(
+            "  {:width$}  {} copied ({}), {} removed",
+            "total",
+            total.copied,
+            human_bytes(total.bytes),
+            total.removed,
+            width = width
+        );
+    }
+}
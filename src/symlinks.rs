@@ -0,0 +1,125 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{self, Component, Path, PathBuf};
+
+
+
+/// What to do with the symlinks of the source
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Recreate them in the destination, pointing to the same target
+    /// (symlinks to directories are followed)
+    #[default]
+    Preserve,
+    /// Copy what they point to instead
+    Dereference,
+    /// Leave them out of the backup
+    Skip,
+    /// Store them as files holding their target, as git does where
+    /// symlinks aren't supported
+    Placeholder,
+    /// Recreate them, but with their absolute targets inside the source
+    /// made relative, so that they point inside the copy wherever the
+    /// destination is mounted
+    Relative,
+}
+
+
+impl SymlinkPolicy {
+    pub fn parse(text: &str) -> Result<SymlinkPolicy, String> {
+        match text {
+            "preserve" => Ok(SymlinkPolicy::Preserve),
+            "dereference" => Ok(SymlinkPolicy::Dereference),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "placeholder" => Ok(SymlinkPolicy::Placeholder),
+            "relative" => Ok(SymlinkPolicy::Relative),
+            _ => Err(format!(
+                "invalid symlink policy '{}' (expected preserve, dereference, skip, placeholder or relative)",
+                text
+            )),
+        }
+    }
+
+    /// Whether symlinks are copied as symlinks
+    pub fn preserves(self) -> bool {
+        matches!(self, SymlinkPolicy::Preserve | SymlinkPolicy::Relative)
+    }
+
+    /// The target to give the copy of the symlink of the source at `path`
+    /// (below `root`) pointing to `link`
+    pub fn target(self, link: PathBuf, path: &str, root: &str) -> PathBuf {
+        if self != SymlinkPolicy::Relative || !link.is_absolute() {
+            return link;
+        }
+        let (Ok(absolute), Some(parent)) = (path::absolute(root), Path::new(path).parent()) else {
+            return link;
+        };
+        let Ok(directory) = path::absolute(parent).map(|parent| lexical(&parent)) else {
+            return link;
+        };
+        let Ok(directory) = directory.strip_prefix(lexical(&absolute)) else {
+            return link;
+        };
+        // The source may be given through a symlink too
+        let link = lexical(&link);
+        let inner = [Ok(absolute), fs::canonicalize(root)]
+            .into_iter()
+            .flatten()
+            .find_map(|root| Some(link.strip_prefix(lexical(&root)).ok()?.to_path_buf()));
+        match inner {
+            Some(inner) => relative(&inner, directory),
+            None => link,
+        }
+    }
+}
+
+
+/// A path with its `.` and `..` components resolved, without looking at
+/// the filesystem
+fn lexical(path: &Path) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    resolved
+}
+
+
+/// The path leading from `from` to `to`, both relative to the same directory
+fn relative(to: &Path, from: &Path) -> PathBuf {
+    let common = to.components().zip(from.components()).take_while(|(a, b)| a == b).count();
+    let mut path: PathBuf = from.components().skip(common).map(|_| Component::ParentDir).collect();
+    path.extend(to.components().skip(common));
+    if path.as_os_str().is_empty() {
+        path.push(".");
+    }
+    path
+}
+
+
+/// The directories a walk of the source is in, by device and inode, to
+/// tell when the one it comes to (through a symlink or a bind mount) is one
+/// of them, which would have it go round in circles
+#[derive(Clone, Default)]
+pub struct Ancestors(Vec<(u64, u64)>);
+
+
+impl Ancestors {
+    /// The ancestors of the entries of a directory: these and itself
+    pub fn enter(&self, directory: Option<&fs::Metadata>) -> Ancestors {
+        let mut ancestors = self.clone();
+        ancestors.0.extend(directory.map(|m| (m.dev(), m.ino())));
+        ancestors
+    }
+
+    /// Whether a directory is one of them
+    pub fn contains(&self, directory: &fs::Metadata) -> bool {
+        self.0.contains(&(directory.dev(), directory.ino()))
+    }
+}
@@ -0,0 +1,91 @@
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+
+
+/// Send the progress of a run at most this often
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+
+/// Files the run in progress is expected to look at, as the scan of the
+/// source found them (0 if unknown)
+static EXPECTED: AtomicU64 = AtomicU64::new(0);
+
+
+/// When the progress was last sent
+static LAST_PROGRESS: Mutex<Option<Instant>> = Mutex::new(None);
+
+
+/// Socket of the service manager, given in NOTIFY_SOCKET to services of
+/// Type=notify (a path, or an abstract socket as `@NAME`)
+fn socket() -> Option<&'static str> {
+    static SOCKET: OnceLock<Option<String>> = OnceLock::new();
+    SOCKET.get_or_init(|| std::env::var("NOTIFY_SOCKET").ok().filter(|s| !s.is_empty())).as_deref()
+}
+
+
+fn send(path: &str, state: &str) -> io::Result<()> {
+    let address = match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        _ => SocketAddr::from_pathname(path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+    Ok(())
+}
+
+
+/// Tell the service manager about a change of state (`READY=1`,
+/// `STATUS=...`), as sd_notify(3) does, when running as a systemd service
+/// of Type=notify
+pub fn notify(state: &str) {
+    if let Some(path) = socket() {
+        if let Err(e) = send(path, state) {
+            warning!("cannot notify systemd through {}: {}", path, e);
+        }
+    }
+}
+
+
+/// Tell the service manager that backup-rs is up (started its backup, or
+/// scheduled the jobs of the daemon)
+pub fn ready() {
+    notify("READY=1");
+}
+
+
+/// Set the status line the service manager shows (e.g. in systemctl
+/// status)
+pub fn status(text: &str) {
+    notify(&format!("STATUS={}", text.replace('\n', " ")));
+}
+
+
+/// Expect a run to look at this many files (0 if unknown), for the
+/// percentage it got to
+pub fn expect_files(files: u64) {
+    EXPECTED.store(files, Ordering::SeqCst);
+}
+
+
+/// Show which file a run is at and how far it got, having looked at
+/// `examined` files, once a second at most
+pub fn progress(current: &str, examined: u64) {
+    if socket().is_none() {
+        return;
+    }
+    let mut last = LAST_PROGRESS.lock().unwrap();
+    if last.is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL) {
+        return;
+    }
+    *last = Some(Instant::now());
+    match EXPECTED.load(Ordering::SeqCst) {
+        0 => status(&format!("Backing up {} ({} files looked at)", current, examined)),
+        expected => status(&format!("Backing up {} ({}%)", current, (examined * 100 / expected).min(99))),
+    }
+}
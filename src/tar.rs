@@ -0,0 +1,301 @@
+use std::io::{self, Read, Write};
+
+
+
+const BLOCK: usize = 512;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryType {
+    File,
+    Directory,
+    Symlink,
+}
+
+
+/// Header of an entry of a tar archive
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub path: String,
+    pub kind: EntryType,
+    pub size: u64,
+    pub mode: u32,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    /// Target of a symlink
+    pub link: String,
+}
+
+
+impl Header {
+    pub fn new(path: &str, kind: EntryType) -> Header {
+        Header {
+            path: path.to_string(),
+            kind,
+            size: 0,
+            mode: if kind == EntryType::Directory { 0o755 } else { 0o644 },
+            mtime: 0,
+            mtime_nsec: 0,
+            link: String::new(),
+        }
+    }
+}
+
+
+/// Write `value` as a NUL-terminated octal number filling `field`
+fn write_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    let digits = digits.as_bytes();
+    // Values that don't fit are set through pax records instead
+    field[..width].copy_from_slice(&digits[digits.len() - width..]);
+    field[width] = 0;
+}
+
+
+fn read_octal(field: &[u8]) -> u64 {
+    let text: String = field
+        .iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| b as char)
+        .collect();
+    u64::from_str_radix(text.trim(), 8).unwrap_or(0)
+}
+
+
+fn read_string(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+
+fn padding(size: u64) -> usize {
+    (BLOCK - (size as usize % BLOCK)) % BLOCK
+}
+
+
+/// Encode one `LENGTH key=value\n` pax record, whose length counts itself
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {}={}\n", key, value);
+    let mut length = body.len() + 1;
+    while format!("{}{}", length, body).len() != length {
+        length += 1;
+    }
+    format!("{}{}", length, body)
+}
+
+
+fn raw_header(name: &str, typeflag: u8, size: u64, mode: u32, mtime: i64, link: &str) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    let name = name.as_bytes();
+    let name = &name[..name.len().min(100)];
+    block[..name.len()].copy_from_slice(name);
+    write_octal(&mut block[100..108], mode as u64);
+    write_octal(&mut block[108..116], 0);
+    write_octal(&mut block[116..124], 0);
+    write_octal(&mut block[124..136], size);
+    write_octal(&mut block[136..148], mtime.max(0) as u64);
+    block[156] = typeflag;
+    let link = link.as_bytes();
+    let link = &link[..link.len().min(100)];
+    block[157..157 + link.len()].copy_from_slice(link);
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    // The checksum is computed with the checksum field filled with spaces
+    block[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = block.iter().map(|&b| b as u32).sum();
+    write_octal(&mut block[148..155], checksum as u64);
+    block[155] = b' ';
+    block
+}
+
+
+/// Writes a POSIX (pax) tar archive
+pub struct TarWriter<W: Write> {
+    out: W,
+}
+
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W) -> TarWriter<W> {
+        TarWriter { out }
+    }
+
+    /// Append an entry, whose contents (for files) are read from `data`
+    pub fn append(&mut self, header: &Header, data: &mut dyn Read) -> io::Result<()> {
+        let mut records = String::new();
+        if header.path.len() > 100 || !header.path.is_ascii() {
+            records += &pax_record("path", &header.path);
+        }
+        if header.link.len() > 100 || !header.link.is_ascii() {
+            records += &pax_record("linkpath", &header.link);
+        }
+        if header.mtime_nsec != 0 || header.mtime < 0 {
+            let mtime = format!("{}.{:09}", header.mtime, header.mtime_nsec);
+            records += &pax_record("mtime", &mtime);
+        }
+        if header.size >= 0o77777777777 {
+            records += &pax_record("size", &header.size.to_string());
+        }
+        if !records.is_empty() {
+            let block = raw_header("././@PaxHeader", b'x', records.len() as u64, 0o644, 0, "");
+            self.out.write_all(&block)?;
+            self.out.write_all(records.as_bytes())?;
+            self.out.write_all(&[0u8; BLOCK][..padding(records.len() as u64)])?;
+        }
+        let (typeflag, size) = match header.kind {
+            EntryType::File => (b'0', header.size),
+            EntryType::Directory => (b'5', 0),
+            EntryType::Symlink => (b'2', 0),
+        };
+        let name = if header.kind == EntryType::Directory {
+            format!("{}/", header.path)
+        } else {
+            header.path.clone()
+        };
+        let block = raw_header(&name, typeflag, size, header.mode, header.mtime, &header.link);
+        self.out.write_all(&block)?;
+        if size > 0 {
+            let copied = io::copy(&mut data.take(size), &mut self.out)?;
+            if copied != size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} changed size while being archived", header.path),
+                ));
+            }
+            self.out.write_all(&[0u8; BLOCK][..padding(size)])?;
+        }
+        Ok(())
+    }
+
+    /// Write the end-of-archive marker
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0u8; 2 * BLOCK])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+
+/// Reads a tar archive written by TarWriter (or any ustar/pax archive)
+pub struct TarReader<R: Read> {
+    input: R,
+    /// Bytes of the current entry (including padding) not read yet
+    remaining: u64,
+    /// Bytes of file data of the current entry not read yet
+    data_left: u64,
+}
+
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+
+impl<R: Read> TarReader<R> {
+    pub fn new(input: R) -> TarReader<R> {
+        TarReader { input, remaining: 0, data_left: 0 }
+    }
+
+    fn skip(&mut self, mut count: u64) -> io::Result<()> {
+        while count > 0 {
+            let mut buffer = [0u8; BLOCK];
+            let n = count.min(BLOCK as u64) as usize;
+            self.input.read_exact(&mut buffer[..n])?;
+            count -= n as u64;
+        }
+        Ok(())
+    }
+
+    /// Header of the next entry, or None at the end of the archive
+    pub fn next_header(&mut self) -> io::Result<Option<Header>> {
+        self.skip(self.remaining)?;
+        self.remaining = 0;
+        self.data_left = 0;
+        let mut pax: Vec<(String, String)> = Vec::new();
+        loop {
+            let mut block = [0u8; BLOCK];
+            self.input.read_exact(&mut block)?;
+            if block.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            let stored: u32 = read_octal(&block[148..156]) as u32;
+            block[148..156].copy_from_slice(b"        ");
+            let checksum: u32 = block.iter().map(|&b| b as u32).sum();
+            if stored != checksum {
+                return Err(invalid("bad tar header checksum"));
+            }
+            let mut size = read_octal(&block[124..136]);
+            let typeflag = block[156];
+            if typeflag == b'x' {
+                let mut records = vec![0u8; size as usize];
+                self.input.read_exact(&mut records)?;
+                self.skip(padding(size) as u64)?;
+                let records = String::from_utf8_lossy(&records).to_string();
+                let mut rest = records.as_str();
+                while let Some((length, _)) = rest.split_once(' ') {
+                    let length: usize = length.parse().map_err(|_| invalid("bad pax record"))?;
+                    if length == 0 || length > rest.len() {
+                        return Err(invalid("bad pax record"));
+                    }
+                    let record = &rest[..length];
+                    if let Some((key, value)) = record.split_once(' ').and_then(|(_, kv)| {
+                        kv.strip_suffix('\n')?.split_once('=')
+                    }) {
+                        pax.push((key.to_string(), value.to_string()));
+                    }
+                    rest = &rest[length..];
+                }
+                continue;
+            }
+            let prefix = read_string(&block[345..500]);
+            let name = read_string(&block[..100]);
+            let mut header = Header {
+                path: if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) },
+                kind: match typeflag {
+                    b'5' => EntryType::Directory,
+                    b'2' => EntryType::Symlink,
+                    _ => EntryType::File,
+                },
+                size: 0,
+                mode: read_octal(&block[100..108]) as u32,
+                mtime: read_octal(&block[136..148]) as i64,
+                mtime_nsec: 0,
+                link: read_string(&block[157..257]),
+            };
+            for (key, value) in &pax {
+                match key.as_str() {
+                    "path" => header.path = value.clone(),
+                    "linkpath" => header.link = value.clone(),
+                    "size" => size = value.parse().map_err(|_| invalid("bad pax size"))?,
+                    "mtime" => {
+                        let (secs, frac) = value.split_once('.').unwrap_or((value, "0"));
+                        header.mtime = secs.parse().unwrap_or(0);
+                        let frac = format!("{:0<9}", &frac[..frac.len().min(9)]);
+                        header.mtime_nsec = frac.parse().unwrap_or(0);
+                    }
+                    _ => (),
+                }
+            }
+            header.path = header.path.trim_end_matches('/').to_string();
+            if header.kind == EntryType::File {
+                header.size = size;
+            }
+            self.remaining = size + padding(size) as u64;
+            self.data_left = size;
+            return Ok(Some(header));
+        }
+    }
+
+    /// Copy the contents of the current entry
+    pub fn read_data(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        let size = self.data_left;
+        let copied = io::copy(&mut (&mut self.input).take(size), out)?;
+        if copied != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated archive"));
+        }
+        self.remaining -= size;
+        self.data_left = 0;
+        Ok(())
+    }
+}
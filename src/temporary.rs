@@ -0,0 +1,77 @@
+use std::fs;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+
+extern "C" {
+    fn kill(pid: c_int, signal: c_int) -> c_int;
+}
+
+
+/// What the names of temporary files start with in the local temporary
+/// directory, and what they are marked with elsewhere
+const PREFIX: &str = "backup-rs-";
+const MARK: &str = ".backup-rs-tmp";
+
+/// Temporary files made by this process so far
+static COUNT: AtomicU64 = AtomicU64::new(0);
+
+
+/// `PID.N`, unique to this call among all the runs of backup-rs
+fn unique() -> String {
+    format!("{}.{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed))
+}
+
+
+/// Name of a temporary file next to a path, to be renamed over it once
+/// complete: `PATH.backup-rs-tmp.PID.N`
+pub fn beside(path: &str) -> String {
+    format!("{}{}.{}", path, MARK, unique())
+}
+
+
+/// Path of a temporary file in the local temporary directory:
+/// `backup-rs-LABEL-PID.N`
+pub fn local(label: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("{}{}-{}", PREFIX, label, unique()))
+        .to_string_lossy()
+        .to_string()
+}
+
+
+/// Whether a name ends with `.PID.N`, returning PID
+fn owner(name: &str) -> Option<&str> {
+    let (rest, n) = name.rsplit_once('.')?;
+    let (rest, pid) = rest.rsplit_once(['.', '-'])?;
+    let number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    (number(pid) && number(n) && !rest.is_empty()).then_some(pid)
+}
+
+
+/// Whether the name of an entry of a destination is that of a temporary
+/// file left behind by `beside()`
+pub fn is_temporary(name: &str) -> bool {
+    owner(name).is_some() && name.rsplit_once(MARK).is_some_and(|(_, rest)| rest.matches('.').count() == 2)
+}
+
+
+/// Remove the temporary files of the local temporary directory left
+/// behind by runs that no longer exist (e.g. that crashed)
+pub fn clean_local() {
+    let Ok(entries) = fs::read_dir(std::env::temp_dir()) else {
+        return;
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(pid) = owner(&name).filter(|_| name.starts_with(PREFIX)).and_then(|p| p.parse().ok()) else {
+            continue;
+        };
+        // Signal 0 only checks that the process exists
+        let alive = unsafe { kill(pid, 0) } == 0 || std::io::Error::last_os_error().raw_os_error() == Some(1);
+        if !alive && entry.file_type().is_ok_and(|t| t.is_file()) && fs::remove_file(entry.path()).is_ok() {
+            action!("Removed the leftover temporary file {}", entry.path().display());
+        }
+    }
+}
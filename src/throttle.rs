@@ -0,0 +1,80 @@
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::budget::parse_size;
+
+
+
+/// The most read at once through `Throttled`, so that the pace is even
+const CHUNK: usize = 64 * 1024;
+
+
+/// Token bucket: tokens (bytes) come in at `rate` per second, up to a
+/// second's worth, and each byte copied takes one
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+
+/// The limit of the copies of this run, if any
+static BUCKET: Mutex<Option<Bucket>> = Mutex::new(None);
+
+
+/// Parse a rate of bytes per second, such as `10M`, `500K/s` or `2MiB/s`
+pub fn parse_rate(text: &str) -> Result<u64, String> {
+    match parse_size(text.trim_end_matches("/s")) {
+        Some(rate) if rate > 0 => Ok(rate),
+        _ => Err(format!("invalid rate '{}' (expected e.g. 500K or 10M, in bytes per second)", text)),
+    }
+}
+
+
+/// Limit the copies to and from the destination to `rate` bytes per
+/// second (or stop limiting them)
+pub fn set(rate: Option<u64>) {
+    let bucket = rate.map(|rate| Bucket { rate, tokens: rate as f64, refilled: Instant::now() });
+    *BUCKET.lock().unwrap_or_else(|e| e.into_inner()) = bucket;
+}
+
+
+/// Account for `bytes` copied, waiting for as long as needed to keep to
+/// the limit
+pub fn take(bytes: u64) {
+    let wait = {
+        let mut bucket = BUCKET.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(bucket) = bucket.as_mut() else {
+            return;
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.rate as f64).min(bucket.rate as f64);
+        bucket.refilled = now;
+        bucket.tokens -= bytes as f64;
+        // What was taken beyond the tokens there were is waited for
+        match bucket.tokens < 0.0 {
+            true => Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64),
+            false => Duration::ZERO,
+        }
+    };
+    if !wait.is_zero() {
+        thread::sleep(wait);
+    }
+}
+
+
+/// Reader whose reads keep to the limit of the copies
+pub struct Throttled<R>(pub R);
+
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let length = buffer.len().min(CHUNK);
+        let n = self.0.read(&mut buffer[..length])?;
+        take(n as u64);
+        Ok(n)
+    }
+}
@@ -0,0 +1,117 @@
+use std::fs;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+
+
+type Job = Box<dyn FnOnce() + Send>;
+
+
+/// Thread running the guarded operations. One that doesn't come back in
+/// time is left behind (it may be stuck for good, e.g. on a wedged network
+/// mount), and a new one is started for the next operation.
+static WORKER: Mutex<Option<Sender<Job>>> = Mutex::new(None);
+
+/// Bytes read from a file to check that it can be read
+const PROBE_SIZE: usize = 64 * 1024;
+
+
+fn start_worker() -> io::Result<Sender<Job>> {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    thread::Builder::new().name("backup-rs-io".to_string()).spawn(move || {
+        for job in receiver {
+            job();
+        }
+    })?;
+    Ok(sender)
+}
+
+
+/// Run an operation on the filesystem, giving up on it with a `TimedOut`
+/// error if it takes longer than `limit` (without a limit, it just runs)
+pub fn guard<T, F>(limit: Option<Duration>, operation: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    let Some(limit) = limit else {
+        return operation();
+    };
+    let mut worker = WORKER.lock().unwrap_or_else(|e| e.into_inner());
+    let sender = match worker.take() {
+        Some(sender) => sender,
+        None => start_worker()?,
+    };
+    let (result_sender, result) = mpsc::channel();
+    let job: Job = Box::new(move || {
+        let _ = result_sender.send(operation());
+    });
+    let sender = match sender.send(job) {
+        Ok(()) => sender,
+        // The worker is gone (an operation panicked), so start another one
+        Err(mpsc::SendError(job)) => {
+            let sender = start_worker()?;
+            let _ = sender.send(job);
+            sender
+        }
+    };
+    *worker = Some(sender);
+    match result.recv_timeout(limit) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => {
+            *worker = None;
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no answer after {}s", limit.as_secs_f64()),
+            ))
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            *worker = None;
+            Err(io::Error::other("the operation failed unexpectedly"))
+        }
+    }
+}
+
+
+/// Check that a path answers a `lstat()` in time, whether it exists or not
+/// (so that a path that doesn't answer isn't taken as missing)
+pub fn responds(path: &str, limit: Option<Duration>) -> io::Result<()> {
+    if limit.is_none() {
+        return Ok(());
+    }
+    let path = path.to_string();
+    match guard(limit, move || fs::symlink_metadata(path)) {
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(e),
+        _ => Ok(()),
+    }
+}
+
+
+/// Metadata of a path, following symlinks
+pub fn metadata(path: &str, limit: Option<Duration>) -> io::Result<fs::Metadata> {
+    let path = path.to_string();
+    guard(limit, move || fs::metadata(path))
+}
+
+
+/// Entries of a directory
+pub fn read_dir(path: &str, limit: Option<Duration>) -> io::Result<Vec<fs::DirEntry>> {
+    let path = path.to_string();
+    guard(limit, move || fs::read_dir(path)?.collect())
+}
+
+
+/// Check that a file can be opened and read in time, before copying it
+pub fn probe(path: &str, limit: Option<Duration>) -> io::Result<()> {
+    if limit.is_none() {
+        return Ok(());
+    }
+    let path = path.to_string();
+    guard(limit, move || {
+        let mut buffer = vec![0u8; PROBE_SIZE];
+        fs::File::open(path)?.read(&mut buffer).map(|_| ())
+    })
+}
@@ -0,0 +1,289 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, IsTerminal, Read, Write};
+use std::os::raw::{c_int, c_ulong};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::log;
+use crate::pause;
+use crate::progress;
+use crate::stats::human_bytes;
+use crate::status;
+
+
+
+#[repr(C)]
+#[derive(Default)]
+struct Winsize {
+    rows: u16,
+    columns: u16,
+    width: u16,
+    height: u16,
+}
+
+
+extern "C" {
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    fn write(fd: c_int, buffer: *const u8, count: usize) -> isize;
+    fn _exit(status: c_int) -> !;
+}
+
+
+#[cfg(not(target_os = "macos"))]
+const TIOCGWINSZ: c_ulong = 0x5413;
+#[cfg(target_os = "macos")]
+const TIOCGWINSZ: c_ulong = 0x40087468;
+const SIGINT: c_int = 2;
+
+
+/// Switch to the alternate screen, hiding the cursor, and back
+const ENTER: &str = "\x1b[?1049h\x1b[?25l";
+const LEAVE: &str = "\x1b[?25h\x1b[?1049l";
+
+
+/// How often the dashboard is drawn, and the throughput sampled
+const INTERVAL: Duration = Duration::from_millis(500);
+
+
+/// Errors and warnings shown at most
+const PROBLEMS: usize = 50;
+
+
+/// Set while the dashboard is shown
+static SHOWN: AtomicBool = AtomicBool::new(false);
+
+
+/// The thread drawing the dashboard
+static DRAWER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+
+/// Leave the alternate screen when interrupted, instead of leaving the
+/// terminal in it
+extern "C" fn interrupted(_signal: c_int) {
+    unsafe {
+        write(1, LEAVE.as_ptr(), LEAVE.len());
+        _exit(130);
+    }
+}
+
+
+/// Size of the terminal, in rows and columns
+fn size() -> (usize, usize) {
+    let mut size = Winsize::default();
+    if unsafe { ioctl(1, TIOCGWINSZ, &mut size as *mut Winsize) } != 0 || size.rows == 0 || size.columns == 0 {
+        return (24, 80);
+    }
+    (size.rows as usize, size.columns as usize)
+}
+
+
+/// A line cut to the width of the terminal
+fn fit(text: &str, width: usize) -> String {
+    match text.chars().count() > width {
+        true => text.chars().take(width.saturating_sub(1)).chain(['…']).collect(),
+        false => text.to_string(),
+    }
+}
+
+
+/// The path a line has room for, cutting its beginning
+fn fit_path(path: &str, width: usize) -> String {
+    let count = path.chars().count();
+    match count > width {
+        true => ['…'].into_iter().chain(path.chars().skip(count - width + 1)).collect(),
+        false => path.to_string(),
+    }
+}
+
+
+/// A heading spanning the width of the terminal
+fn rule(title: &str, width: usize) -> String {
+    let title = format!("── {} ", title);
+    let used = title.chars().count();
+    format!("\x1b[1m{}{}\x1b[0m", title, "─".repeat(width.saturating_sub(used)))
+}
+
+
+/// The throughput samples as a bar graph, one column per sample
+fn graph(samples: &VecDeque<u64>, width: usize) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let highest = samples.iter().copied().max().unwrap_or(0).max(1);
+    let shown = samples.iter().skip(samples.len().saturating_sub(width));
+    shown.map(|&sample| if sample == 0 { ' ' } else { BARS[(sample * 7 / highest) as usize] }).collect()
+}
+
+
+pub fn elapsed(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+
+/// Draw the dashboard: the run in progress, what it did so far, its
+/// throughput, what each worker copies and the errors and warnings
+fn draw(samples: &VecDeque<u64>) {
+    let (rows, width) = size();
+    let mut lines: Vec<String> = Vec::new();
+    let (errors, warnings) = log::counts();
+    match progress::snapshot() {
+        Some(snapshot) => {
+            let state = if pause::paused() { "\x1b[33mpaused\x1b[0m" } else { "running" };
+            let title = fit(&format!("backup-rs  {} → {}", snapshot.source, snapshot.destination), width.saturating_sub(20));
+            lines.push(format!("\x1b[1m{}\x1b[0m  {} for {}", title, state, elapsed(snapshot.elapsed)));
+            lines.push(String::new());
+            lines.push(fit(
+                &format!(
+                    "Looked at {}   Copied {} ({})   Removed {}   Errors {}   Warnings {}",
+                    snapshot.examined,
+                    snapshot.copied,
+                    human_bytes(snapshot.bytes),
+                    snapshot.removed,
+                    errors,
+                    warnings
+                ),
+                width,
+            ));
+            let current = samples.back().copied().unwrap_or(0) * 1000 / INTERVAL.as_millis() as u64;
+            lines.push(fit(
+                &format!("Throughput {}/s (average {}/s)", human_bytes(current), human_bytes(snapshot.rate())),
+                width,
+            ));
+            lines.push(format!("\x1b[32m{}\x1b[0m", graph(samples, width)));
+            lines.push(rule("Workers", width));
+            lines.push(fit(&format!("  run     at {}", fit_path(snapshot.path.as_deref().unwrap_or("the start"), width.saturating_sub(13))), width));
+            for (index, path) in progress::workers().iter().enumerate() {
+                let doing = match path {
+                    Some(path) => format!("copying {}", fit_path(path, width.saturating_sub(18))),
+                    None => "idle".to_string(),
+                };
+                lines.push(fit(&format!("  copy {:<2} {}", index + 1, doing), width));
+            }
+        }
+        None => lines.push("\x1b[1mbackup-rs\x1b[0m  starting...".to_string()),
+    }
+    lines.push(rule("Errors and warnings", width));
+    // The footer takes a line
+    let room = rows.saturating_sub(lines.len() + 1);
+    for problem in log::captured_problems(room.min(PROBLEMS)) {
+        let color = if problem.starts_with("Error") { "\x1b[31m" } else { "\x1b[33m" };
+        lines.push(format!("{}{}\x1b[0m", color, fit(&problem, width)));
+    }
+    lines.truncate(rows.saturating_sub(1));
+    while lines.len() < rows.saturating_sub(1) {
+        lines.push(String::new());
+    }
+    let footer = format!("kill -USR2 {} to pause, -CONT to resume; Ctrl-C to interrupt", std::process::id());
+    lines.push(format!("\x1b[2m{}\x1b[0m", fit(&footer, width)));
+    let mut frame = String::from("\x1b[H");
+    for (index, line) in lines.iter().enumerate() {
+        frame.push_str(line);
+        frame.push_str("\x1b[K");
+        if index + 1 < lines.len() {
+            frame.push_str("\r\n");
+        }
+    }
+    let mut stdout = io::stdout().lock();
+    let _ = stdout.write_all(frame.as_bytes()).and_then(|_| stdout.flush());
+}
+
+
+/// Show the dashboard instead of the messages of the run (which are
+/// printed once it is gone), until `finish()`. Fails if the standard
+/// output isn't a terminal.
+pub fn start() -> Result<(), String> {
+    if !io::stdout().is_terminal() {
+        return Err("--tui needs a terminal".to_string());
+    }
+    log::capture();
+    unsafe {
+        signal(SIGINT, interrupted);
+    }
+    print!("{}\x1b[2J", ENTER);
+    SHOWN.store(true, Ordering::SeqCst);
+    let drawer = std::thread::spawn(|| {
+        let mut samples = VecDeque::new();
+        let mut last = 0;
+        while SHOWN.load(Ordering::SeqCst) {
+            // The bytes copied by the last run until the next one starts
+            let bytes = progress::snapshot().map(|s| s.written()).unwrap_or(last);
+            samples.push_back(bytes.saturating_sub(last));
+            last = bytes;
+            if samples.len() > 1000 {
+                samples.pop_front();
+            }
+            draw(&samples);
+            std::thread::sleep(INTERVAL);
+        }
+    });
+    *DRAWER.lock().unwrap() = Some(drawer);
+    Ok(())
+}
+
+
+/// Wait for a key to be pressed on the terminal, reading it unbuffered
+fn wait_for_key() {
+    if !io::stdin().is_terminal() {
+        return;
+    }
+    let stty = |args: &[&str]| Command::new("stty").args(args).stdin(Stdio::inherit()).output();
+    let Ok(saved) = stty(&["-g"]) else {
+        return;
+    };
+    let saved = String::from_utf8_lossy(&saved.stdout).trim().to_string();
+    if stty(&["-icanon", "-echo", "min", "1"]).is_err() {
+        return;
+    }
+    let _ = io::stdin().read(&mut [0u8; 16]);
+    let _ = stty(&[saved.as_str()]);
+}
+
+
+/// Show the summary of the run, with its result, until a key is pressed,
+/// then leave the dashboard and print the messages of the run
+pub fn finish(result: &str, duration: Duration) {
+    if !SHOWN.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    if let Some(drawer) = DRAWER.lock().unwrap().take() {
+        let _ = drawer.join();
+    }
+    let (rows, width) = size();
+    let (errors, warnings) = log::counts();
+    let (_, totals) = status::last_run().unwrap_or_default();
+    let color = match result {
+        "ok" => "\x1b[1;32m",
+        "errors" => "\x1b[1;33m",
+        _ => "\x1b[1;31m",
+    };
+    let mut screen = String::from("\x1b[H\x1b[2J");
+    writeln!(screen, "\x1b[1mbackup-rs\x1b[0m  run over: {}{}\x1b[0m in {}\r\n\r", color, result, elapsed(duration)).unwrap();
+    writeln!(screen, "  Copied    {} ({})\r", totals.copied, human_bytes(totals.bytes)).unwrap();
+    writeln!(screen, "  Removed   {}\r", totals.removed).unwrap();
+    writeln!(screen, "  Errors    {}\r", errors).unwrap();
+    writeln!(screen, "  Warnings  {}\r\n\r", warnings).unwrap();
+    let problems = log::captured_problems(rows.saturating_sub(12).min(PROBLEMS));
+    if !problems.is_empty() {
+        writeln!(screen, "{}\r", rule("Errors and warnings", width)).unwrap();
+        for problem in problems {
+            writeln!(screen, "{}\r", fit(&problem, width)).unwrap();
+        }
+        screen.push_str("\r\n");
+    }
+    screen.push_str("\x1b[2mPress a key to exit\x1b[0m");
+    let mut stdout = io::stdout().lock();
+    let _ = stdout.write_all(screen.as_bytes()).and_then(|_| stdout.flush());
+    drop(stdout);
+    wait_for_key();
+    print!("{}", LEAVE);
+    let _ = io::stdout().flush();
+    log::release();
+}
@@ -0,0 +1,248 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::unicode_tables::{COMBINING_CLASSES, COMPOSITIONS, DECOMPOSITIONS};
+
+
+
+/// Unicode normalization form names are stored in: composed (NFC, as
+/// Linux and Windows usually write them) or decomposed (NFD, as macOS
+/// does), so that names typed on either are taken as the same
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Form {
+    Nfc,
+    Nfd,
+}
+
+
+impl Form {
+    pub fn parse(text: &str) -> Result<Form, String> {
+        match text.to_ascii_lowercase().as_str() {
+            "nfc" => Ok(Form::Nfc),
+            "nfd" => Ok(Form::Nfd),
+            _ => Err(format!("unknown normalization form '{}' (expected nfc or nfd)", text)),
+        }
+    }
+}
+
+
+/// Hangul syllables, which are composed and decomposed by algorithm
+const S_BASE: u32 = 0xac00;
+const L_BASE: u32 = 0x1100;
+const V_BASE: u32 = 0x1161;
+const T_BASE: u32 = 0x11a7;
+const L_COUNT: u32 = 19;
+const V_COUNT: u32 = 21;
+const T_COUNT: u32 = 28;
+const S_COUNT: u32 = L_COUNT * V_COUNT * T_COUNT;
+
+
+fn combining_class(c: char) -> u8 {
+    match COMBINING_CLASSES.binary_search_by(|&(start, end, _)| {
+        if end < c {
+            std::cmp::Ordering::Less
+        } else if start > c {
+            std::cmp::Ordering::Greater
+        } else {
+            std::cmp::Ordering::Equal
+        }
+    }) {
+        Ok(i) => COMBINING_CLASSES[i].2,
+        Err(_) => 0,
+    }
+}
+
+
+fn decompose_into(c: char, decomposed: &mut Vec<char>) {
+    let s = (c as u32).wrapping_sub(S_BASE);
+    if s < S_COUNT {
+        decomposed.push(char::from_u32(L_BASE + s / (V_COUNT * T_COUNT)).unwrap());
+        decomposed.push(char::from_u32(V_BASE + s % (V_COUNT * T_COUNT) / T_COUNT).unwrap());
+        if !s.is_multiple_of(T_COUNT) {
+            decomposed.push(char::from_u32(T_BASE + s % T_COUNT).unwrap());
+        }
+        return;
+    }
+    match DECOMPOSITIONS.binary_search_by_key(&c, |&(c, _)| c) {
+        Ok(i) => decomposed.extend(DECOMPOSITIONS[i].1.chars()),
+        Err(_) => decomposed.push(c),
+    }
+}
+
+
+/// The canonical decomposition of a string, with its combining marks in
+/// canonical order
+fn decompose(text: &str) -> Vec<char> {
+    let mut decomposed = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        decompose_into(c, &mut decomposed);
+    }
+    // Sort each run of combining marks by class, keeping the order of
+    // those of the same class
+    let mut start = 0;
+    while start < decomposed.len() {
+        if combining_class(decomposed[start]) == 0 {
+            start += 1;
+            continue;
+        }
+        let end = (start..decomposed.len()).find(|&i| combining_class(decomposed[i]) == 0).unwrap_or(decomposed.len());
+        decomposed[start..end].sort_by_key(|&c| combining_class(c));
+        start = end;
+    }
+    decomposed
+}
+
+
+fn compose_pair(first: char, second: char) -> Option<char> {
+    let (l, v) = ((first as u32).wrapping_sub(L_BASE), (second as u32).wrapping_sub(V_BASE));
+    if l < L_COUNT && v < V_COUNT {
+        return char::from_u32(S_BASE + (l * V_COUNT + v) * T_COUNT);
+    }
+    let (s, t) = ((first as u32).wrapping_sub(S_BASE), (second as u32).wrapping_sub(T_BASE));
+    if s < S_COUNT && s.is_multiple_of(T_COUNT) && t > 0 && t < T_COUNT {
+        return char::from_u32(first as u32 + t);
+    }
+    COMPOSITIONS
+        .binary_search_by_key(&(first, second), |&(pair, _)| pair)
+        .ok()
+        .map(|i| COMPOSITIONS[i].1)
+}
+
+
+/// Compose a decomposed string, as NFC does: each combining mark (or
+/// Hangul jamo) goes into the last starter unless another mark of the same
+/// or a higher class comes in between
+fn compose(decomposed: &[char]) -> String {
+    let mut composed: Vec<char> = Vec::with_capacity(decomposed.len());
+    let mut starter = None;
+    // Class of the last character since the starter, if any
+    let mut last_class = None;
+    for &c in decomposed {
+        let class = combining_class(c);
+        if let Some(i) = starter {
+            if last_class.is_none_or(|last| last < class) {
+                if let Some(pair) = compose_pair(composed[i], c) {
+                    composed[i] = pair;
+                    continue;
+                }
+            }
+        }
+        if class == 0 {
+            starter = Some(composed.len());
+            last_class = None;
+        } else {
+            last_class = Some(class);
+        }
+        composed.push(c);
+    }
+    composed.into_iter().collect()
+}
+
+
+/// A name (or path) in a normalization form, if given one
+pub fn normalize(text: &str, form: Option<Form>) -> Cow<'_, str> {
+    let Some(form) = form.filter(|_| !text.is_ascii()) else {
+        return Cow::Borrowed(text);
+    };
+    let normalized = match form {
+        Form::Nfd => decompose(text).into_iter().collect(),
+        Form::Nfc => compose(&decompose(text)),
+    };
+    match normalized == text {
+        true => Cow::Borrowed(text),
+        false => Cow::Owned(normalized),
+    }
+}
+
+
+/// The names of a directory that aren't all ASCII, by their normalized
+/// form, to find the entry a name stands for when it is in another form
+/// (e.g. as written by a Mac)
+pub struct Names {
+    form: Option<Form>,
+    names: HashMap<String, String>,
+}
+
+
+impl Names {
+    pub fn new(names: impl IntoIterator<Item = String>, form: Option<Form>) -> Names {
+        let names = names
+            .into_iter()
+            .filter(|name| !name.is_ascii())
+            .map(|name| (normalize(&name, form).into_owned(), name))
+            .collect();
+        Names { form, names }
+    }
+
+    /// The name of the entry that is the same as `name` once normalized,
+    /// if it isn't `name` itself
+    pub fn find(&self, name: &str) -> Option<&str> {
+        self.names.get(normalize(name, self.form).as_ref()).map(|n| n.as_str()).filter(|n| *n != name)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cases in the layout of the UCD's NormalizationTest.txt (source; NFC;
+    /// NFD), in code points: its specific cases, singletons, composition
+    /// exclusions, Hangul, and the reordering and blocking of marks
+    const CASES: &[&str] = &[
+        "1E0A;1E0A;0044 0307;",
+        "1E0C;1E0C;0044 0323;",
+        "1E0A 0323;1E0C 0307;0044 0323 0307;",
+        "1E0C 0307;1E0C 0307;0044 0323 0307;",
+        "0044 0307 0323;1E0C 0307;0044 0323 0307;",
+        "1E0A 031B 0323;1E0C 031B 0307;0044 031B 0323 0307;",
+        "00C5;00C5;0041 030A;",
+        "212B;00C5;0041 030A;",
+        "2126;03A9;03A9;",
+        "1E14;1E14;0045 0304 0300;",
+        "0045 0304 0300;1E14;0045 0304 0300;",
+        "1F71;03AC;03B1 0301;",
+        "0958;0915 093C;0915 093C;",
+        "0F73;0F71 0F72;0F71 0F72;",
+        "0344;0308 0301;0308 0301;",
+        "AC00;AC00;1100 1161;",
+        "AC01;AC01;1100 1161 11A8;",
+        "1100 1161 11A8;AC01;1100 1161 11A8;",
+        "AC00 11A8;AC01;1100 1161 11A8;",
+        "1100 AC00;1100 AC00;1100 1100 1161;",
+        "1E69;1E69;0073 0323 0307;",
+        "0073 0307 0323;1E69;0073 0323 0307;",
+        "00E0 0327;00E0 0327;0061 0327 0300;",
+        "0041 0327 030A;00C5 0327;0041 0327 030A;",
+        "0061 0300 0315 0062;00E0 0315 0062;0061 0300 0315 0062;",
+        "0061 0315 0300 05AE 0300 0062;00E0 05AE 0300 0315 0062;0061 05AE 0300 0300 0315 0062;",
+    ];
+
+    fn column(field: &str) -> String {
+        field.split_whitespace().map(|c| char::from_u32(u32::from_str_radix(c, 16).unwrap()).unwrap()).collect()
+    }
+
+    #[test]
+    fn normalization_test() {
+        for line in CASES {
+            let [source, nfc, nfd] = [0, 1, 2].map(|i| column(line.split(';').nth(i).unwrap()));
+            // As the file requires: NFC of each column is the NFC one, and
+            // NFD of each is the NFD one
+            for text in [&source, &nfc, &nfd] {
+                assert_eq!(normalize(text, Some(Form::Nfc)), nfc.as_str(), "NFC in {}", line);
+                assert_eq!(normalize(text, Some(Form::Nfd)), nfd.as_str(), "NFD in {}", line);
+            }
+        }
+    }
+
+    #[test]
+    fn names_in_another_form() {
+        assert!(matches!(normalize("plain.txt", Some(Form::Nfc)), Cow::Borrowed(_)));
+        assert!(matches!(normalize("caf\u{e9}", None), Cow::Borrowed(_)));
+        let names = Names::new(["cafe\u{301}".to_string(), "caf\u{e9}s".to_string()], Some(Form::Nfc));
+        assert_eq!(names.find("caf\u{e9}"), Some("cafe\u{301}"));
+        assert_eq!(names.find("cafe\u{301}"), None);
+        assert_eq!(names.find("cafe\u{301}s"), Some("caf\u{e9}s"));
+        assert_eq!(names.find("caf\u{e8}"), None);
+    }
+}
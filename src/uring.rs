@@ -0,0 +1,505 @@
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::raw::{c_int, c_long, c_void};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Once;
+
+use crate::hash::Sha256;
+use crate::progress;
+use crate::stream;
+use crate::throttle;
+
+
+
+extern "C" {
+    fn syscall(number: c_long, ...) -> c_long;
+    fn mmap(address: *mut c_void, length: usize, protection: c_int, flags: c_int, fd: c_int, offset: i64) -> *mut c_void;
+    fn munmap(address: *mut c_void, length: usize) -> c_int;
+    fn close(fd: c_int) -> c_int;
+}
+
+
+/// The numbers of the system calls are the same on every architecture
+const SYS_IO_URING_SETUP: c_long = 425;
+const SYS_IO_URING_ENTER: c_long = 426;
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x8000000;
+const IORING_OFF_SQES: i64 = 0x10000000;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_STATX: u8 = 21;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+const AT_FDCWD: i32 = -100;
+const AT_SYMLINK_NOFOLLOW: u32 = 0x100;
+const STATX_BASIC_STATS: u32 = 0x7ff;
+/// PROT_READ | PROT_WRITE, and MAP_SHARED | MAP_POPULATE
+const PROTECTION: c_int = 3;
+const MAPPING: c_int = 0x01 | 0x8000;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+const EINTR: i32 = 4;
+
+
+/// Entries of the submission queue (the completion queue has twice as
+/// many), and so the most operations in flight at once
+const ENTRIES: u32 = 64;
+
+
+/// Reads and writes of a copy in flight at once
+const DEPTH: usize = 8;
+
+
+/// Size of the `struct statx` the kernel fills
+const STATX_SIZE: usize = 256;
+
+
+/// Whether the copies and scans of local files go through io_uring
+/// (`--engine uring`)
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+
+/// An entry of the submission queue
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    /// Offset in the file, or the address of the buffer of a statx
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+
+/// An entry of the completion queue
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+
+/// Memory shared with the kernel
+struct Mapping {
+    address: *mut c_void,
+    length: usize,
+}
+
+
+impl Mapping {
+    fn new(fd: c_int, length: usize, offset: i64) -> io::Result<Mapping> {
+        let address = unsafe { mmap(ptr::null_mut(), length, PROTECTION, MAPPING, fd, offset) };
+        if address == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { address, length })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { (self.address as *mut u8).add(offset as usize) as *mut T }
+    }
+}
+
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { munmap(self.address, self.length) };
+    }
+}
+
+
+/// An io_uring of this thread: the queues shared with the kernel, which
+/// does the operations submitted to it on its own
+struct Ring {
+    fd: c_int,
+    sq: Mapping,
+    /// The completion queue, unless it shares the mapping of the
+    /// submission queue
+    cq: Option<Mapping>,
+    sqes: Mapping,
+    params: Params,
+    /// Entries queued but not submitted yet
+    queued: u32,
+}
+
+
+impl Ring {
+    fn new() -> io::Result<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe { syscall(SYS_IO_URING_SETUP, ENTRIES, &mut params as *mut Params) } as c_int;
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let sq_length = (params.sq_off.array + params.sq_entries * 4) as usize;
+        let cq_length = params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let single = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+        let mapped = (|| {
+            let sq = Mapping::new(fd, if single { sq_length.max(cq_length) } else { sq_length }, IORING_OFF_SQ_RING)?;
+            let cq = match single {
+                true => None,
+                false => Some(Mapping::new(fd, cq_length, IORING_OFF_CQ_RING)?),
+            };
+            let sqes = Mapping::new(fd, params.sq_entries as usize * std::mem::size_of::<Sqe>(), IORING_OFF_SQES)?;
+            Ok((sq, cq, sqes))
+        })();
+        let (sq, cq, sqes) = match mapped {
+            Ok(mapped) => mapped,
+            Err(e) => {
+                unsafe { close(fd) };
+                return Err(e);
+            }
+        };
+        let ring = Ring { fd, sq, cq, sqes, params, queued: 0 };
+        // Each slot of the queue always holds the entry of the same index
+        let array: *mut u32 = ring.sq.at(ring.params.sq_off.array);
+        for index in 0..ring.params.sq_entries {
+            unsafe { *array.add(index as usize) = index };
+        }
+        Ok(ring)
+    }
+
+    fn cq(&self) -> &Mapping {
+        self.cq.as_ref().unwrap_or(&self.sq)
+    }
+
+    /// Queue an operation; the caller keeps fewer than `ENTRIES` in flight
+    fn push(&mut self, sqe: Sqe) {
+        let tail = unsafe { &*self.sq.at::<AtomicU32>(self.params.sq_off.tail) };
+        let index = tail.load(Ordering::Relaxed);
+        let mask = unsafe { *self.sq.at::<u32>(self.params.sq_off.ring_mask) };
+        let entries: *mut Sqe = self.sqes.at(0);
+        unsafe { ptr::write(entries.add((index & mask) as usize), sqe) };
+        tail.store(index.wrapping_add(1), Ordering::Release);
+        self.queued += 1;
+    }
+
+    /// Submit the queued operations and wait for one to be done
+    fn wait(&mut self) -> io::Result<()> {
+        loop {
+            let submitted = unsafe {
+                syscall(SYS_IO_URING_ENTER, self.fd, self.queued, 1u32, IORING_ENTER_GETEVENTS, ptr::null::<c_void>(), 0usize)
+            };
+            if submitted >= 0 {
+                self.queued -= submitted as u32;
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(EINTR) {
+                return Err(e);
+            }
+        }
+    }
+
+    /// An operation done: what it was submitted with, and its result
+    fn complete(&mut self) -> Option<(u64, i32)> {
+        let cq = self.cq();
+        let head = unsafe { &*cq.at::<AtomicU32>(self.params.cq_off.head) };
+        let tail = unsafe { &*cq.at::<AtomicU32>(self.params.cq_off.tail) };
+        let index = head.load(Ordering::Relaxed);
+        if index == tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let mask = unsafe { *cq.at::<u32>(self.params.cq_off.ring_mask) };
+        let cqe = unsafe { &*cq.at::<Cqe>(self.params.cq_off.cqes).add((index & mask) as usize) };
+        let done = (cqe.user_data, cqe.res);
+        head.store(index.wrapping_add(1), Ordering::Release);
+        Some(done)
+    }
+}
+
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { close(self.fd) };
+    }
+}
+
+
+thread_local! {
+    /// The ring of this thread, once set up (None if it couldn't be)
+    static RING: RefCell<Option<Option<Ring>>> = const { RefCell::new(None) };
+}
+
+
+/// Parse the engine of --engine: `uring`, copying and scanning the local
+/// files through io_uring (on Linux), or `sync` (the default), with a
+/// system call at a time. Returns whether io_uring is used.
+pub fn parse_engine(text: &str) -> Result<bool, String> {
+    match text {
+        "uring" if cfg!(target_os = "linux") => Ok(true),
+        "uring" => Err("--engine uring is only available on Linux".to_string()),
+        "sync" => Ok(false),
+        _ => Err(format!("invalid engine '{}' (expected uring or sync)", text)),
+    }
+}
+
+
+/// Make the copies and scans of local files go through io_uring (or stop)
+pub fn set(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+
+/// Run `f` with the ring of this thread, if io_uring is used and works
+fn with_ring<T>(f: impl FnOnce(&mut Ring) -> T) -> Option<T> {
+    if !cfg!(target_os = "linux") || !ENABLED.load(Ordering::SeqCst) {
+        return None;
+    }
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        let ring = ring.get_or_insert_with(|| match Ring::new() {
+            Ok(ring) => Some(ring),
+            Err(e) => {
+                static WARNED: Once = Once::new();
+                WARNED.call_once(|| warning!("cannot set up io_uring: {}; copying synchronously", e));
+                None
+            }
+        });
+        ring.as_mut().map(f)
+    })
+}
+
+
+/// Look the local paths up all at once (with statx), so that the kernel
+/// reads their inodes in parallel rather than one at a time as they are
+/// looked at, which leaves fast storage mostly idle. What is found is left
+/// in the cache of the kernel for the scan to use; nothing is returned.
+pub fn prefetch(paths: &[String]) {
+    with_ring(|ring| {
+        for batch in paths.chunks(ENTRIES as usize) {
+            let names: Vec<CString> = batch.iter().filter_map(|path| CString::new(path.as_str()).ok()).collect();
+            let mut buffers = vec![[0u8; STATX_SIZE]; names.len()];
+            for (name, buffer) in names.iter().zip(buffers.iter_mut()) {
+                ring.push(Sqe {
+                    opcode: IORING_OP_STATX,
+                    fd: AT_FDCWD,
+                    off: buffer.as_mut_ptr() as u64,
+                    addr: name.as_ptr() as u64,
+                    len: STATX_BASIC_STATS,
+                    op_flags: AT_SYMLINK_NOFOLLOW,
+                    ..Sqe::default()
+                });
+            }
+            // The names and buffers are the kernel's until it is done
+            let mut pending = names.len();
+            while pending > 0 {
+                if ring.wait().is_err() {
+                    // What was submitted can't be waited for
+                    std::mem::forget(names);
+                    std::mem::forget(buffers);
+                    return;
+                }
+                while ring.complete().is_some() {
+                    pending -= 1;
+                }
+            }
+        }
+    });
+}
+
+
+/// A part of a file being copied: read into its buffer, then written
+#[derive(Default)]
+struct Chunk {
+    buffer: Vec<u8>,
+    offset: u64,
+    /// Bytes of the file it holds, once read
+    length: usize,
+    read: usize,
+    written: usize,
+    busy: bool,
+    hashed: bool,
+    used: bool,
+}
+
+
+/// Copy a local file to another as `backend::copy_contents` does, through
+/// io_uring: several chunks are read and written at once, the kernel
+/// doing them in parallel. Returns None (copying nothing) if io_uring
+/// isn't used or can't be.
+pub fn copy(source: &str, path: &str, buffer_size: usize) -> Option<io::Result<()>> {
+    with_ring(|ring| copy_with(ring, source, path, buffer_size))
+}
+
+
+fn copy_with(ring: &mut Ring, source: &str, path: &str, buffer_size: usize) -> io::Result<()> {
+    let input = fs::File::open(source)?;
+    let output = fs::File::create(path)?;
+    // The file is copied as long as it was when opened
+    let mut end = input.metadata()?.len();
+    let buffer_size = stream::buffer_size(buffer_size).min(end.max(1) as usize);
+    let mut chunks: Vec<Chunk> = (0..DEPTH).map(|_| Chunk::default()).collect();
+    let (mut next, mut hashed, mut in_flight) = (0u64, 0u64, 0usize);
+    let mut hasher = Sha256::new();
+    let mut failure = None;
+    let read = |ring: &mut Ring, index: usize, chunk: &mut Chunk| {
+        ring.push(Sqe {
+            opcode: IORING_OP_READ,
+            fd: input.as_raw_fd(),
+            off: chunk.offset + chunk.read as u64,
+            addr: chunk.buffer[chunk.read..].as_mut_ptr() as u64,
+            len: (chunk.length - chunk.read) as u32,
+            user_data: index as u64 * 2,
+            ..Sqe::default()
+        });
+        chunk.busy = true;
+    };
+    let write = |ring: &mut Ring, index: usize, chunk: &mut Chunk| {
+        ring.push(Sqe {
+            opcode: IORING_OP_WRITE,
+            fd: output.as_raw_fd(),
+            off: chunk.offset + chunk.written as u64,
+            addr: chunk.buffer[chunk.written..chunk.read].as_ptr() as u64,
+            len: (chunk.read - chunk.written) as u32,
+            user_data: index as u64 * 2 + 1,
+            ..Sqe::default()
+        });
+        chunk.busy = true;
+    };
+    loop {
+        for (index, chunk) in chunks.iter_mut().enumerate() {
+            if chunk.used || next >= end || failure.is_some() {
+                continue;
+            }
+            chunk.buffer.resize(buffer_size, 0);
+            *chunk = Chunk {
+                buffer: std::mem::take(&mut chunk.buffer),
+                offset: next,
+                length: (end - next).min(buffer_size as u64) as usize,
+                used: true,
+                ..Chunk::default()
+            };
+            next += chunk.length as u64;
+            read(ring, index, chunk);
+            in_flight += 1;
+        }
+        // What is hashed is what was read, in order
+        while let Some(chunk) = chunks.iter_mut().find(|c| c.used && !c.hashed && c.offset == hashed && c.read == c.length && !c.busy) {
+            hasher.update(&chunk.buffer[..chunk.read]);
+            hashed += chunk.read as u64;
+            chunk.hashed = true;
+        }
+        for chunk in chunks.iter_mut().filter(|c| c.used && !c.busy) {
+            // Past the end of a file that shrank, or done
+            if chunk.offset >= end || (chunk.hashed && chunk.written == chunk.length) {
+                chunk.used = false;
+            }
+        }
+        if in_flight == 0 && (next >= end || failure.is_some()) {
+            break;
+        }
+        if in_flight == 0 {
+            // Chunks were freed for the next reads
+            continue;
+        }
+        if let Err(e) = ring.wait() {
+            // What was submitted can't be waited for, and may still write
+            // to the buffers
+            std::mem::forget(chunks);
+            return Err(e);
+        }
+        while let Some((data, result)) = ring.complete() {
+            in_flight -= 1;
+            let (index, writing) = ((data / 2) as usize, data % 2 == 1);
+            let chunk = &mut chunks[index];
+            chunk.busy = false;
+            if result < 0 {
+                failure.get_or_insert(io::Error::from_raw_os_error(-result));
+                chunk.used = false;
+                continue;
+            }
+            if failure.is_some() {
+                continue;
+            }
+            let n = result as usize;
+            match writing {
+                false if n == 0 => {
+                    // The file shrank: it ends here
+                    chunk.length = chunk.read;
+                    end = end.min(chunk.offset + chunk.read as u64);
+                }
+                false => {
+                    throttle::take(n as u64);
+                    chunk.read += n;
+                }
+                true => {
+                    progress::streamed(n as u64);
+                    chunk.written += n;
+                }
+            }
+            if chunk.read < chunk.length {
+                read(ring, index, chunk);
+                in_flight += 1;
+            } else if chunk.written < chunk.read {
+                write(ring, index, chunk);
+                in_flight += 1;
+            }
+        }
+    }
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    output.set_len(end)?;
+    stream::hashed(source, hasher.finish());
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::io;
+use std::path::Path;
+
+use crate::backend::Backend;
+use crate::manifest::META_DIR;
+
+
+
+/// Directory, inside META_DIR, with the previous versions of the files of
+/// the destination, in a tree parallel to it: the last version of `PATH` is
+/// `PATH~1`, the one before it `PATH~2`, and so on
+const VERSIONS: &str = "versions";
+
+
+/// Root of the tree of previous versions of a destination
+pub fn root(destination: &str) -> String {
+    format!("{}/{}/{}", destination, META_DIR, VERSIONS)
+}
+
+
+/// The previous versions of the files overwritten in a destination, up to
+/// a number of them per file
+pub struct Versions {
+    destination: String,
+    count: usize,
+}
+
+
+impl Versions {
+    pub fn new(destination: &str, count: usize) -> Versions {
+        Versions { destination: destination.to_string(), count }
+    }
+
+    /// Move the copy of a file about to be overwritten into the tree of
+    /// versions as its last one, shifting the older ones and dropping the
+    /// oldest once there are enough of them
+    pub fn keep(&self, path: &str, target: &dyn Backend) -> io::Result<()> {
+        let relative = path
+            .strip_prefix(&self.destination)
+            .map(|r| r.trim_start_matches('/'))
+            .filter(|r| !r.is_empty())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} isn't in the destination", path)))?;
+        let kept = format!("{}/{}", root(&self.destination), relative);
+        let version = |n: usize| format!("{}~{}", kept, n);
+        // The directories of the tree are made as needed
+        let mut parent = self.destination.clone();
+        for component in Path::new(META_DIR).join(VERSIONS).join(relative).parent().into_iter().flat_map(|p| p.iter()) {
+            parent = format!("{}/{}", parent, component.to_string_lossy());
+            if target.metadata(&parent).is_none() {
+                target.create_dir(&parent)?;
+            }
+        }
+        if target.metadata(&version(self.count)).is_some() {
+            target.remove_file(&version(self.count))?;
+        }
+        for n in (1..self.count).rev() {
+            if target.metadata(&version(n)).is_some() {
+                target.rename(&version(n), &version(n + 1))?;
+            }
+        }
+        target.rename(path, &version(1))
+    }
+}
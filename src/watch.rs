@@ -0,0 +1,441 @@
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::CString;
+use std::fs;
+use std::io::Read;
+use std::os::raw::{c_char, c_int, c_ulong};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::backend::{self, Backend, Kind};
+use crate::checksums::{self, Checksums};
+use crate::fat;
+use crate::engine::{
+    backup, backup_file, copy_directory_metadata, create_directory, remove_missing, remove_path, remove_removed, wait_copies,
+};
+use crate::manifest::{MARKER, META_DIR};
+use crate::preconditions;
+use crate::progress;
+use crate::report::ErrorReport;
+use crate::skiplist::SkipList;
+use crate::space::OutOfSpace;
+use crate::status::{self, Run};
+use crate::summary::Summary;
+use crate::unicode;
+use crate::versions::Versions;
+use crate::{log, Options};
+
+
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: i16,
+    revents: i16,
+}
+
+
+extern "C" {
+    fn inotify_init1(flags: c_int) -> c_int;
+    fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int;
+    fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
+
+const IN_CLOEXEC: c_int = 0o2000000;
+const IN_NONBLOCK: c_int = 0o4000;
+const POLLIN: i16 = 0x1;
+
+const IN_MODIFY: u32 = 0x2;
+const IN_ATTRIB: u32 = 0x4;
+const IN_CLOSE_WRITE: u32 = 0x8;
+const IN_MOVED_FROM: u32 = 0x40;
+const IN_MOVED_TO: u32 = 0x80;
+const IN_CREATE: u32 = 0x100;
+const IN_DELETE: u32 = 0x200;
+const IN_Q_OVERFLOW: u32 = 0x4000;
+const IN_IGNORED: u32 = 0x8000;
+const IN_ONLYDIR: u32 = 0x1000000;
+const IN_DONT_FOLLOW: u32 = 0x2000000;
+const IN_ISDIR: u32 = 0x40000000;
+
+const WATCH_MASK: u32 = IN_MODIFY | IN_ATTRIB | IN_CLOSE_WRITE | IN_MOVED_FROM
+    | IN_MOVED_TO | IN_CREATE | IN_DELETE | IN_ONLYDIR | IN_DONT_FOLLOW;
+
+/// Size of the fixed part of `struct inotify_event`
+const EVENT_HEADER: usize = 16;
+
+
+/// Join a path relative to the source root with an entry name
+fn join(relative: &str, name: &str) -> String {
+    if relative.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", relative, name)
+    }
+}
+
+
+/// Watches every directory of the source tree with inotify
+pub struct Watcher {
+    fd: c_int,
+    file: fs::File,
+    source: String,
+    /// Watch descriptor -> directory, relative to the source root
+    watches: HashMap<c_int, String>,
+    /// Whether a directory couldn't be watched (e.g. because the limit of
+    /// inotify watches was reached), so that changes may go unnoticed
+    lost: bool,
+    /// Device of the source, with --one-file-system, whose directories
+    /// other file systems are mounted on aren't watched
+    device: Option<u64>,
+}
+
+
+impl Watcher {
+    /// Start watching the whole source tree (but for the other file systems
+    /// mounted in it, with `one_file_system`)
+    pub fn new(source: &str, one_file_system: bool) -> std::io::Result<Watcher> {
+        let fd = unsafe { inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut watcher = Watcher {
+            fd,
+            file: unsafe { fs::File::from_raw_fd(fd) },
+            source: source.to_string(),
+            watches: HashMap::new(),
+            lost: false,
+            device: fs::metadata(source).ok().filter(|_| one_file_system).map(|m| m.dev()),
+        };
+        watcher.add_tree("");
+        Ok(watcher)
+    }
+
+    /// Watch the whole tree again, after events were lost
+    pub fn reset(&mut self) {
+        self.watches.clear();
+        self.lost = false;
+        self.add_tree("");
+    }
+
+    pub fn fd(&self) -> c_int {
+        self.fd
+    }
+
+    /// Watch a directory and, recursively, all of its subdirectories
+    fn add_tree(&mut self, relative: &str) {
+        let path = if relative.is_empty() {
+            self.source.clone()
+        } else {
+            format!("{}/{}", self.source, relative)
+        };
+        if self.device.is_some_and(|device| fs::metadata(&path).is_ok_and(|m| m.dev() != device)) {
+            return;
+        }
+        let c_path = CString::new(path.as_str()).unwrap();
+        let wd = unsafe { inotify_add_watch(self.fd, c_path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            // The directory may have vanished in the meantime, which is fine
+            let error = std::io::Error::last_os_error().kind();
+            if error != std::io::ErrorKind::NotFound && error != std::io::ErrorKind::NotADirectory {
+                self.lost = true;
+            }
+            return;
+        }
+        self.watches.insert(wd, relative.to_string());
+        let dir = match fs::read_dir(&path) {
+            Ok(d) => d,
+            Err(_) => return,
+        };
+        for entry in dir {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            if let (true, Some(name)) = (is_dir, entry.file_name().to_str()) {
+                self.add_tree(&join(relative, name));
+            }
+        }
+    }
+
+    /// Wait up to `timeout` milliseconds (or forever if negative) for events
+    fn wait(&self, timeout: c_int) -> bool {
+        wait_any(&[self.fd], timeout)
+    }
+
+    /// Read the pending events, recording the paths that need to be synced.
+    /// Returns false if events were lost (the kernel queue overflowed or a
+    /// directory couldn't be watched).
+    pub fn read_events(&mut self, dirty: &mut BTreeSet<String>) -> bool {
+        let mut complete = true;
+        let mut buffer = [0u8; 64 * 1024];
+        loop {
+            let n = match self.file.read(&mut buffer) {
+                Ok(n) if n > 0 => n,
+                _ => break,
+            };
+            let mut offset = 0;
+            while offset + EVENT_HEADER <= n {
+                let field = |i: usize| {
+                    let start = offset + i * 4;
+                    u32::from_ne_bytes(buffer[start..start + 4].try_into().unwrap())
+                };
+                let wd = field(0) as c_int;
+                let mask = field(1);
+                let len = field(3) as usize;
+                let name_bytes = &buffer[offset + EVENT_HEADER..offset + EVENT_HEADER + len];
+                offset += EVENT_HEADER + len;
+
+                if mask & IN_Q_OVERFLOW != 0 {
+                    complete = false;
+                    continue;
+                }
+                if mask & IN_IGNORED != 0 {
+                    self.watches.remove(&wd);
+                    continue;
+                }
+                let directory = match self.watches.get(&wd) {
+                    Some(d) => d.clone(),
+                    None => continue,
+                };
+                // The name is NUL-padded; skip names that are not valid UTF-8
+                let name_end = name_bytes.iter().position(|&b| b == 0).unwrap_or(len);
+                let name = match std::str::from_utf8(&name_bytes[..name_end]) {
+                    Ok(name) if !name.is_empty() => name,
+                    _ => continue,
+                };
+                if mask & IN_ISDIR != 0 && mask & IN_ATTRIB != 0 {
+                    // Syncing would walk the whole subtree for its metadata
+                    // alone, which the next change in it brings over
+                    continue;
+                }
+                let relative = join(&directory, name);
+                if mask & IN_ISDIR != 0 && mask & (IN_CREATE | IN_MOVED_TO) != 0 {
+                    self.add_tree(&relative);
+                }
+                dirty.insert(relative);
+            }
+        }
+        complete && !self.lost
+    }
+}
+
+
+/// Wait up to `timeout` milliseconds (or forever if negative) for events on
+/// any of the given inotify descriptors
+pub fn wait_any(fds: &[c_int], timeout: c_int) -> bool {
+    let mut pfds: Vec<PollFd> = fds
+        .iter()
+        .map(|&fd| PollFd { fd, events: POLLIN, revents: 0 })
+        .collect();
+    loop {
+        let ret = unsafe { poll(pfds.as_mut_ptr(), pfds.len() as c_ulong, timeout) };
+        if ret >= 0 {
+            return ret > 0;
+        }
+        if std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+            return false;
+        }
+    }
+}
+
+
+/// Bring a single path of the destination in line with the source
+fn sync_path(
+    source: &str,
+    destination: &str,
+    relative: &str,
+    opts: &Options,
+    target: &dyn Backend,
+) {
+    let source_path = format!("{}/{}", source, relative);
+    let destination_path = format!("{}/{}", destination, unicode::normalize(relative, opts.normalize));
+    // The copies of skipped paths are left as they are too
+    if opts.policy.is_excluded(&source_path) || opts.skip_list.as_ref().is_some_and(|s| s.skips(&source_path)) {
+        return;
+    }
+    let existing = target.metadata(&destination_path).map(|m| m.kind);
+    if fs::symlink_metadata(&source_path).is_err() {
+        if existing.is_some() {
+            remove_missing(&destination_path, opts, target);
+        }
+        return;
+    }
+    let parent = Path::new(&destination_path).parent().unwrap();
+    if target.metadata(parent.to_str().unwrap()).is_none() {
+        // The parent directory is synced on its own
+        return;
+    }
+    // Symlinks to directories are followed, as in backup()
+    if Path::new(&source_path).is_dir() {
+        if !create_directory(&source_path, &destination_path, opts, target) {
+            return;
+        }
+        // Not created by a dry run, so there's nothing in it to sync
+        if target.metadata(&destination_path).is_none() {
+            return;
+        }
+        remove_removed(&source_path, &destination_path, opts, target);
+        backup(&source_path, &destination_path, opts, target);
+        copy_directory_metadata(&source_path, &destination_path, opts, target);
+    } else {
+        if existing == Some(Kind::Directory) {
+            remove_path(&destination_path, opts, target);
+        }
+        backup_file(&source_path, &destination_path, opts, target);
+    }
+    // Writing to the copy of its directory changed its modification time
+    if let Some((directory, _)) = relative.rsplit_once('/') {
+        copy_directory_metadata(
+            &format!("{}/{}", source, directory),
+            parent.to_str().unwrap(),
+            opts,
+            target,
+        );
+    }
+}
+
+
+/// Sync the paths (relative to the source root) reported as changed
+pub fn sync_dirty(
+    source: &str,
+    destination: &str,
+    dirty: &BTreeSet<String>,
+    opts: &Options,
+    target: &dyn Backend,
+) {
+    for relative in dirty {
+        // Skip paths inside a directory that is synced as a whole
+        let covered = relative.match_indices('/')
+            .any(|(i, _)| dirty.contains(&relative[..i]));
+        if !covered {
+            sync_path(source, destination, relative, opts, target);
+        }
+    }
+}
+
+
+/// Keep mirroring the source directory to the destination directory,
+/// syncing changes in batches once no new change has been seen for
+/// `debounce` milliseconds
+pub fn watch(source: &str, destination: &str, opts: &Options, debounce: u64) {
+    let mut opts = opts.clone();
+    opts.policy.root = source.to_string();
+    let location = destination;
+    let (target, destination) = match backend::open(destination, &opts) {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let target = target.as_ref();
+    let destination = destination.as_str();
+    if target.is_local() {
+        fat::detect(destination, &mut opts);
+    }
+    if !crate::check_marker(destination, &opts, target) {
+        std::process::exit(1);
+    }
+    crate::case::detect(destination, &mut opts, target);
+    let _lock = match crate::lock_destination(location, destination, &opts, target) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    opts.protected.insert(Path::new(destination).join(META_DIR));
+    opts.protected.insert(Path::new(destination).join(MARKER));
+    opts.versions = opts.keep_versions.map(|count| Rc::new(Versions::new(destination, count)));
+    opts.out_of_space = target.is_local().then(|| Rc::new(OutOfSpace::new(destination, &opts)));
+    opts.copies = crate::copy_pool(&opts, target);
+    opts.queued = crate::copy_queue(&opts);
+    let mut watcher = match Watcher::new(source, opts.one_file_system) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("cannot initialize inotify: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let debounce = debounce.min(c_int::MAX as u64) as c_int;
+    log::separator();
+    info!("Watching {} for changes...", source);
+    // Whether a batch was skipped because the source wasn't available
+    let mut skipped = false;
+    // What changed during the first backup, before the watches were in
+    // place, is only found by a full sync, done right away
+    let mut catching_up = true;
+    loop {
+        let first = std::mem::take(&mut catching_up);
+        let mut dirty = BTreeSet::new();
+        let mut complete = true;
+        if !first {
+            watcher.wait(-1);
+            complete = watcher.read_events(&mut dirty);
+            while watcher.wait(debounce) {
+                complete &= watcher.read_events(&mut dirty);
+            }
+        }
+        log::separator();
+        let run = Run::start();
+        let (errors, _) = log::counts();
+        progress::start(source, destination);
+        info!("Run: {}", run.id);
+        if let Err(e) = preconditions::check_all(&opts.preconditions, source) {
+            error!("the source isn't available: {}; not syncing it", e);
+            skipped = true;
+            continue;
+        }
+        opts.summary = Some(Rc::new(Summary::new(destination)));
+        opts.errors = Some(Rc::new(ErrorReport::default()));
+        opts.skip_list = Some(Rc::new(SkipList::load(target, destination, source)));
+        opts.checksums = Some(Rc::new(Checksums::load(target, destination)));
+        let opts = &opts;
+        let full = !complete || skipped || first;
+        if full {
+            if first {
+                info!("Syncing the changes made during the first backup...");
+            } else {
+                // Events were lost, so fall back to a full sync
+                info!("Events were lost, resyncing everything...");
+                watcher.reset();
+            }
+            skipped = false;
+            remove_removed(source, destination, opts, target);
+            backup(source, destination, opts, target);
+        } else {
+            sync_dirty(source, destination, &dirty, opts, target);
+        }
+        wait_copies(opts, target);
+        crate::finish(target, destination);
+        crate::report_failures(&run, opts);
+        let cancelled = progress::cancelled();
+        if cancelled {
+            error!("the run was cancelled");
+            // What it didn't sync is only found by a full sync
+            skipped = true;
+        }
+        if let (Some(skip_list), false) = (&opts.skip_list, opts.dry_run) {
+            if let Err(e) = skip_list.save(target) {
+                warning!("cannot record the failures of {}: {}", destination, e);
+            }
+        }
+        if let (Some(checksums), false) = (&opts.checksums, opts.dry_run) {
+            if let Err(e) = checksums.save(target) {
+                warning!("cannot write {}: {}", checksums::path(destination), e);
+            }
+        }
+        // The share of the files changed is only known after a full sync
+        let files = opts.summary.as_ref().filter(|_| full).map(|s| s.files());
+        let within_budget = crate::check_budget(opts, files);
+        if !opts.dry_run {
+            run.record(source, destination, !cancelled, within_budget, opts.summary.as_deref(), target);
+        }
+        crate::flush(opts, target, destination);
+        progress::finish(status::result(!cancelled, log::counts().0 - errors));
+    }
+}
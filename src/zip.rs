@@ -0,0 +1,258 @@
+use std::fs;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::schedule;
+use crate::tar::{EntryType, Header};
+
+
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END: u32 = 0x06054b50;
+const ZIP64_END: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+
+/// Extra fields: sizes and offsets too large for 32 bits, and the
+/// modification time as a Unix timestamp (DOS times are local, with a
+/// 2-second resolution)
+const ZIP64_EXTRA: u16 = 0x0001;
+const TIMESTAMP_EXTRA: u16 = 0x5455;
+
+/// Names are UTF-8
+const UTF8_FLAG: u16 = 1 << 11;
+/// Versions needed to extract: 2.0 (directories), or 4.5 for zip64
+const VERSION: u16 = 20;
+const ZIP64_VERSION: u16 = 45;
+/// Made on Unix, so that the external attributes hold the mode
+const UNIX: u16 = 3 << 8;
+
+/// The largest values of the 16- and 32-bit fields, which mean that the
+/// value is in the zip64 extra field or record instead
+const MAX16: u64 = 0xffff;
+const MAX32: u64 = 0xffff_ffff;
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+/// MS-DOS directory attribute
+const DOS_DIRECTORY: u32 = 0x10;
+
+
+/// What the central directory keeps of an entry
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+    mtime: i64,
+    external: u32,
+}
+
+
+/// CRC-32 (of zip, gzip and PNG) of data, continuing from `crc`
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    static TABLE: std::sync::OnceLock<[u32; 256]> = std::sync::OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = [0u32; 256];
+        for (n, value) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *value = c;
+        }
+        table
+    });
+    let mut crc = !crc;
+    for &byte in data {
+        crc = table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+
+/// MS-DOS time and date of a timestamp, in local time (1980 at the
+/// earliest)
+fn dos_time(mtime: i64) -> (u16, u16) {
+    let time = schedule::local_time(mtime);
+    if time.year < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let date = ((time.year - 1980).min(127) as u16) << 9 | (time.month as u16) << 5 | time.day as u16;
+    let clock = (time.hour as u16) << 11 | (time.minute as u16) << 5 | (time.second / 2) as u16;
+    (clock, date)
+}
+
+
+/// Writes a zip archive to a file. Entries are stored as they are (not
+/// compressed), their checksum being filled in once their data is written.
+pub struct ZipWriter {
+    out: BufWriter<fs::File>,
+    /// Bytes written so far
+    offset: u64,
+    entries: Vec<Entry>,
+}
+
+
+impl ZipWriter {
+    pub fn new(file: fs::File) -> ZipWriter {
+        ZipWriter { out: BufWriter::new(file), offset: 0, entries: Vec::new() }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// Append an entry, whose contents (for files) are read from `data`,
+    /// and the target for symlinks
+    pub fn append(&mut self, header: &Header, data: &mut dyn Read) -> io::Result<()> {
+        let (name, size, external) = match header.kind {
+            EntryType::Directory => (format!("{}/", header.path), 0, (S_IFDIR | header.mode) << 16 | DOS_DIRECTORY),
+            EntryType::File => (header.path.clone(), header.size, (S_IFREG | header.mode) << 16),
+            EntryType::Symlink => (header.path.clone(), header.link.len() as u64, (S_IFLNK | 0o777) << 16),
+        };
+        let zip64 = size >= MAX32;
+        let (time, date) = dos_time(header.mtime);
+        let offset = self.offset;
+        let mut extra = Vec::new();
+        extra.extend_from_slice(&TIMESTAMP_EXTRA.to_le_bytes());
+        extra.extend_from_slice(&5u16.to_le_bytes());
+        extra.push(1);
+        extra.extend_from_slice(&(header.mtime.clamp(0, MAX32 as i64) as u32).to_le_bytes());
+        if zip64 {
+            extra.extend_from_slice(&ZIP64_EXTRA.to_le_bytes());
+            extra.extend_from_slice(&16u16.to_le_bytes());
+            extra.extend_from_slice(&size.to_le_bytes());
+            extra.extend_from_slice(&size.to_le_bytes());
+        }
+        let mut block = Vec::with_capacity(30 + name.len() + extra.len());
+        block.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        block.extend_from_slice(&(if zip64 { ZIP64_VERSION } else { VERSION }).to_le_bytes());
+        block.extend_from_slice(&UTF8_FLAG.to_le_bytes());
+        // Stored
+        block.extend_from_slice(&0u16.to_le_bytes());
+        block.extend_from_slice(&time.to_le_bytes());
+        block.extend_from_slice(&date.to_le_bytes());
+        // The checksum, filled in below
+        block.extend_from_slice(&0u32.to_le_bytes());
+        block.extend_from_slice(&(size.min(MAX32) as u32).to_le_bytes());
+        block.extend_from_slice(&(size.min(MAX32) as u32).to_le_bytes());
+        block.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        block.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        block.extend_from_slice(name.as_bytes());
+        block.extend_from_slice(&extra);
+        self.write(&block)?;
+
+        let mut crc = 0;
+        if header.kind == EntryType::Symlink {
+            crc = crc32(crc, header.link.as_bytes());
+            self.write(header.link.as_bytes())?;
+        } else if size > 0 {
+            let mut data = data.take(size);
+            let mut buffer = vec![0u8; 128 * 1024];
+            let mut copied = 0;
+            loop {
+                let n = data.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                crc = crc32(crc, &buffer[..n]);
+                self.write(&buffer[..n])?;
+                copied += n as u64;
+            }
+            if copied != size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} changed size while being archived", header.path),
+                ));
+            }
+        }
+        if crc != 0 {
+            self.out.seek(SeekFrom::Start(offset + 14))?;
+            self.out.write_all(&crc.to_le_bytes())?;
+            self.out.seek(SeekFrom::Start(self.offset))?;
+        }
+        self.entries.push(Entry { name, crc, size, offset, mtime: header.mtime, external });
+        Ok(())
+    }
+
+    /// Write the central directory
+    pub fn finish(mut self) -> io::Result<fs::File> {
+        let start = self.offset;
+        let entries = std::mem::take(&mut self.entries);
+        for entry in &entries {
+            let (time, date) = dos_time(entry.mtime);
+            let mut extra = Vec::new();
+            extra.extend_from_slice(&TIMESTAMP_EXTRA.to_le_bytes());
+            extra.extend_from_slice(&5u16.to_le_bytes());
+            extra.push(1);
+            extra.extend_from_slice(&(entry.mtime.clamp(0, MAX32 as i64) as u32).to_le_bytes());
+            // Only the values that don't fit are in the zip64 field
+            let mut large = Vec::new();
+            if entry.size >= MAX32 {
+                large.extend_from_slice(&entry.size.to_le_bytes());
+                large.extend_from_slice(&entry.size.to_le_bytes());
+            }
+            if entry.offset >= MAX32 {
+                large.extend_from_slice(&entry.offset.to_le_bytes());
+            }
+            if !large.is_empty() {
+                extra.extend_from_slice(&ZIP64_EXTRA.to_le_bytes());
+                extra.extend_from_slice(&(large.len() as u16).to_le_bytes());
+                extra.extend_from_slice(&large);
+            }
+            let version = if large.is_empty() { VERSION } else { ZIP64_VERSION };
+            let mut block = Vec::with_capacity(46 + entry.name.len() + extra.len());
+            block.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+            block.extend_from_slice(&(UNIX | version).to_le_bytes());
+            block.extend_from_slice(&version.to_le_bytes());
+            block.extend_from_slice(&UTF8_FLAG.to_le_bytes());
+            block.extend_from_slice(&0u16.to_le_bytes());
+            block.extend_from_slice(&time.to_le_bytes());
+            block.extend_from_slice(&date.to_le_bytes());
+            block.extend_from_slice(&entry.crc.to_le_bytes());
+            block.extend_from_slice(&(entry.size.min(MAX32) as u32).to_le_bytes());
+            block.extend_from_slice(&(entry.size.min(MAX32) as u32).to_le_bytes());
+            block.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            block.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            // Comment length, disk number and internal attributes
+            block.extend_from_slice(&[0u8; 6]);
+            block.extend_from_slice(&entry.external.to_le_bytes());
+            block.extend_from_slice(&(entry.offset.min(MAX32) as u32).to_le_bytes());
+            block.extend_from_slice(entry.name.as_bytes());
+            block.extend_from_slice(&extra);
+            self.write(&block)?;
+        }
+        let (count, size) = (entries.len() as u64, self.offset - start);
+        let mut block = Vec::new();
+        if count >= MAX16 || size >= MAX32 || start >= MAX32 {
+            let record = self.offset;
+            block.extend_from_slice(&ZIP64_END.to_le_bytes());
+            // Size of the rest of the record
+            block.extend_from_slice(&44u64.to_le_bytes());
+            block.extend_from_slice(&(UNIX | ZIP64_VERSION).to_le_bytes());
+            block.extend_from_slice(&ZIP64_VERSION.to_le_bytes());
+            block.extend_from_slice(&[0u8; 8]);
+            block.extend_from_slice(&count.to_le_bytes());
+            block.extend_from_slice(&count.to_le_bytes());
+            block.extend_from_slice(&size.to_le_bytes());
+            block.extend_from_slice(&start.to_le_bytes());
+            block.extend_from_slice(&ZIP64_LOCATOR.to_le_bytes());
+            block.extend_from_slice(&0u32.to_le_bytes());
+            block.extend_from_slice(&record.to_le_bytes());
+            block.extend_from_slice(&1u32.to_le_bytes());
+        }
+        block.extend_from_slice(&END.to_le_bytes());
+        block.extend_from_slice(&[0u8; 4]);
+        block.extend_from_slice(&(count.min(MAX16) as u16).to_le_bytes());
+        block.extend_from_slice(&(count.min(MAX16) as u16).to_le_bytes());
+        block.extend_from_slice(&(size.min(MAX32) as u32).to_le_bytes());
+        block.extend_from_slice(&(start.min(MAX32) as u32).to_le_bytes());
+        // No comment
+        block.extend_from_slice(&0u16.to_le_bytes());
+        self.write(&block)?;
+        self.out.into_inner().map_err(|e| e.into_error())
+    }
+}